@@ -9,17 +9,22 @@ use wgpu_compute::{Device, Tensor};
 
 use criterion::BenchmarkId;
 use criterion::Criterion;
+use criterion::Throughput;
 use criterion::{criterion_group, criterion_main};
 
 use criterion::async_executor::FuturesExecutor;
 
-const SIZES: [usize; 2] = [100, 1000];
+const SIZES: [usize; 3] = [100, 1000, 4000];
 
 fn fused(c: &mut Criterion) {
     {
         let mut group = c.benchmark_group("add-const-fused-wgpu");
         let group = group.sample_size(20);
         for size in SIZES {
+            // Each element is read and written once
+            group.throughput(Throughput::Bytes(
+                (size * size * size_of::<f32>() * 2) as u64,
+            ));
             let device = block_on(Device::new()).unwrap();
             std::thread::spawn({
                 let device = device.clone();
@@ -61,6 +66,10 @@ fn fused(c: &mut Criterion) {
         let mut group = c.benchmark_group("add-const-separate-wgpu");
         let group = group.sample_size(20);
         for size in SIZES {
+            // Use the bytes of the fused operation so the throughput is comparable
+            group.throughput(Throughput::Bytes(
+                (size * size * size_of::<f32>() * 2) as u64,
+            ));
             let device = block_on(Device::new()).unwrap();
             std::thread::spawn({
                 let device = device.clone();
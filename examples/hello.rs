@@ -13,7 +13,7 @@ async fn main() {
     });
 
     let tensor = Tensor::new(&device, &vec![vec![[1.; 20]; 10]; 10]);
-    let new = tensor.sum(0).sum(0).sum(0);
+    let new = tensor.sum_all();
     let timing = new.all_timing_information().await;
     println!(
         "segment time: {:?}",
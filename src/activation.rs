@@ -0,0 +1,490 @@
+use crate::{
+    ElementWiseFunction, ElementWiseOperation,
+    element_wise::ERF,
+    tensor::{DataTypeEnum, float_tensor_ops},
+};
+
+#[cfg(test)]
+use crate::{Device, Tensor};
+
+impl ElementWiseFunction {
+    fn relu(datatype: DataTypeEnum) -> Self {
+        Self::new("let output = max(input, 0.0);", datatype)
+            .with_name("relu")
+            .scalar_only()
+    }
+
+    fn gelu(datatype: DataTypeEnum) -> Self {
+        Self::new(
+            format!(
+                "let erf_input = input * 0.7071067811865476;\n{ERF}\nlet output = 0.5 * input * (1.0 + erf_output);"
+            ),
+            datatype,
+        )
+        .with_name("gelu")
+    }
+
+    fn gelu_tanh(datatype: DataTypeEnum) -> Self {
+        Self::new(
+            "let output = 0.5 * input * (1.0 + tanh(0.7978845608028654 * (input + 0.044715 * input * input * input)));",
+            datatype,
+        )
+        .with_name("gelu_tanh")
+    }
+
+    fn gelu_quick(datatype: DataTypeEnum) -> Self {
+        Self::new(
+            "let output = input / (1.0 + exp(-1.702 * input));",
+            datatype,
+        )
+        .with_name("gelu_quick")
+    }
+
+    fn sigmoid(datatype: DataTypeEnum) -> Self {
+        Self::new("let output = 1.0 / (1.0 + exp(-input));", datatype).with_name("sigmoid")
+    }
+
+    fn silu(datatype: DataTypeEnum) -> Self {
+        Self::new("let output = input / (1.0 + exp(-input));", datatype).with_name("silu")
+    }
+
+    fn softplus(datatype: DataTypeEnum) -> Self {
+        // log(1 + exp(x)) rewritten so exp never overflows for large inputs
+        Self::new(
+            "let output = max(input, 0.0) + log(1.0 + exp(-abs(input)));",
+            datatype,
+        )
+        .with_name("softplus")
+        .scalar_only()
+    }
+
+    fn leaky_relu(negative_slope: f32, datatype: DataTypeEnum) -> Self {
+        Self::new(
+            "let output = select(scalar_0 * input, input, input >= 0.0);",
+            datatype,
+        )
+        .with_scalar(negative_slope)
+        .with_name("leaky_relu")
+        .scalar_only()
+    }
+
+    fn elu(alpha: f32, datatype: DataTypeEnum) -> Self {
+        Self::new(
+            "let output = select(scalar_0 * (exp(input) - 1.0), input, input > 0.0);",
+            datatype,
+        )
+        .with_scalar(alpha)
+        .with_name("elu")
+        .scalar_only()
+    }
+
+    fn mish(datatype: DataTypeEnum) -> Self {
+        Self::new(
+            "let softplus = max(input, 0.0) + log(1.0 + exp(-abs(input)));
+let output = input * tanh(softplus);",
+            datatype,
+        )
+        .with_name("mish")
+        .scalar_only()
+    }
+
+    fn hard_swish(datatype: DataTypeEnum) -> Self {
+        Self::new(
+            "let output = input * clamp(input + 3.0, 0.0, 6.0) / 6.0;",
+            datatype,
+        )
+        .with_name("hard_swish")
+        .scalar_only()
+    }
+}
+
+float_tensor_ops! {
+    pub fn relu(&self) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::relu(D::WGSL_TYPE),
+        })
+    }
+
+    /// The exact form of gelu: `0.5 * x * (1 + erf(x / sqrt(2)))`
+    pub fn gelu(&self) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::gelu(D::WGSL_TYPE),
+        })
+    }
+
+    /// The tanh approximation of gelu: `0.5 * x * (1 + tanh(sqrt(2 / pi) * (x + 0.044715 * x^3)))`
+    pub fn gelu_tanh(&self) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::gelu_tanh(D::WGSL_TYPE),
+        })
+    }
+
+    /// The sigmoid approximation of gelu: `x * sigmoid(1.702 * x)`
+    pub fn gelu_quick(&self) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::gelu_quick(D::WGSL_TYPE),
+        })
+    }
+
+    pub fn sigmoid(&self) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::sigmoid(D::WGSL_TYPE),
+        })
+    }
+
+    pub fn silu(&self) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::silu(D::WGSL_TYPE),
+        })
+    }
+
+    pub fn softplus(&self) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::softplus(D::WGSL_TYPE),
+        })
+    }
+
+    pub fn leaky_relu(&self, negative_slope: f32) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::leaky_relu(negative_slope, D::WGSL_TYPE),
+        })
+    }
+
+    pub fn elu(&self, alpha: f32) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::elu(alpha, D::WGSL_TYPE),
+        })
+    }
+
+    pub fn mish(&self) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::mish(D::WGSL_TYPE),
+        })
+    }
+
+    pub fn hard_swish(&self) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::hard_swish(D::WGSL_TYPE),
+        })
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_relu() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., -2.], [-3., 4.], [5., -6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = tensor.relu();
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    assert_eq!(output[[0, 0]], 1.);
+    assert_eq!(output[[0, 1]], 0.);
+    assert_eq!(output[[1, 0]], 0.);
+    assert_eq!(output[[1, 1]], 4.);
+    assert_eq!(output[[2, 0]], 5.);
+    assert_eq!(output[[2, 1]], 0.);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_gelu() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., -2.], [-3., 4.], [5., -6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = tensor.gelu();
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    // Reference values from scipy: x * norm.cdf(x)
+    assert!((output[[0, 0]] - 0.841_344_7).abs() < 0.0001);
+    assert!((output[[0, 1]] - -0.045_500_26).abs() < 0.0001);
+    assert!((output[[1, 0]] - -0.004_049_5).abs() < 0.0001);
+    assert!((output[[1, 1]] - 3.999_873_3).abs() < 0.0001);
+    assert!((output[[2, 0]] - 4.999_998_6).abs() < 0.0001);
+    assert!(output[[2, 1]].abs() < 0.0001);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_gelu_tanh() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., -2.], [-3., 4.], [5., -6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = tensor.gelu_tanh();
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    let gelu_tanh = |x: f32| {
+        0.5 * x * (1. + ((2. / std::f32::consts::PI).sqrt() * (x + 0.044715 * x.powi(3))).tanh())
+    };
+    for i in 0..3 {
+        for j in 0..2 {
+            assert!((output[[i, j]] - gelu_tanh(data[i][j])).abs() < 0.001);
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_gelu_quick() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., -2.], [-3., 4.], [5., -6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = tensor.gelu_quick();
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    let gelu_quick = |x: f32| x / (1. + (-1.702 * x).exp());
+    for i in 0..3 {
+        for j in 0..2 {
+            assert!((output[[i, j]] - gelu_quick(data[i][j])).abs() < 0.001);
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_sigmoid() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., -2.], [-3., 4.], [5., -6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = tensor.sigmoid();
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    let sigmoid = |x: f32| 1. / (1. + (-x).exp());
+    for i in 0..3 {
+        for j in 0..2 {
+            assert!((output[[i, j]] - sigmoid(data[i][j])).abs() < 0.001);
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_silu() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., -2.], [-3., 4.], [5., -6.]];
+
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = tensor.silu();
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    let silu = |x: f32| x / (1. + (-x).exp());
+    assert!((output[[0, 0]] - silu(data[0][0])).abs() < 0.001);
+    assert!((output[[0, 1]] - silu(data[0][1])).abs() < 0.001);
+    assert!((output[[1, 0]] - silu(data[1][0])).abs() < 0.001);
+    assert!((output[[1, 1]] - silu(data[1][1])).abs() < 0.001);
+    assert!((output[[2, 0]] - silu(data[2][0])).abs() < 0.001);
+    assert!((output[[2, 1]] - silu(data[2][1])).abs() < 0.001);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_softplus() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., -2.], [-3., 4.], [50., -60.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = tensor.softplus();
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    let softplus = |x: f32| x.max(0.) + (-x.abs()).exp().ln_1p();
+    for i in 0..3 {
+        for j in 0..2 {
+            assert!((output[[i, j]] - softplus(data[i][j])).abs() < 0.001);
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_leaky_relu() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., -2.], [-3., 4.], [5., -6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = tensor.leaky_relu(0.1);
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    let leaky_relu = |x: f32| if x >= 0. { x } else { 0.1 * x };
+    for i in 0..3 {
+        for j in 0..2 {
+            assert!((output[[i, j]] - leaky_relu(data[i][j])).abs() < 0.001);
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_elu() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., -2.], [-3., 4.], [5., -6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = tensor.elu(1.5);
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    let elu = |x: f32| if x > 0. { x } else { 1.5 * (x.exp() - 1.) };
+    for i in 0..3 {
+        for j in 0..2 {
+            assert!((output[[i, j]] - elu(data[i][j])).abs() < 0.001);
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_mish() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., -2.], [-3., 4.], [5., -6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = tensor.mish();
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    let mish = |x: f32| x * x.exp().ln_1p().tanh();
+    for i in 0..3 {
+        for j in 0..2 {
+            assert!((output[[i, j]] - mish(data[i][j])).abs() < 0.001);
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_hard_swish() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., -2.], [-3., 4.], [0.5, -6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = tensor.hard_swish();
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    let hard_swish = |x: f32| x * (x + 3.).clamp(0., 6.) / 6.;
+    for i in 0..3 {
+        for j in 0..2 {
+            assert!((output[[i, j]] - hard_swish(data[i][j])).abs() < 0.001);
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_activation_fused_f16() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [
+        [half::f16::from_f32(1.), half::f16::from_f32(-2.)],
+        [half::f16::from_f32(-3.), half::f16::from_f32(4.)],
+        [half::f16::from_f32(5.), half::f16::from_f32(-6.)],
+    ];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = (tensor.gelu() * 2.).sigmoid();
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    let expected = [[0.843_1, 0.477_3], [0.497_9, 0.999_7], [1.0, 0.5]];
+    for i in 0..3 {
+        for j in 0..2 {
+            assert!((output[[i, j]].to_f32() - expected[i][j]).abs() < 0.01);
+        }
+    }
+}
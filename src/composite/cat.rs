@@ -1,4 +1,4 @@
-use crate::{DataType, Tensor};
+use crate::{DataType, DynTensor, Tensor};
 
 impl<const R: usize, D: DataType> Tensor<R, D> {
     pub fn cat(vectors: impl IntoIterator<Item = Self>, dim: usize) -> Self {
@@ -40,6 +40,45 @@ impl<const R: usize, D: DataType> Tensor<R, D> {
     }
 }
 
+impl<D: DataType> DynTensor<D> {
+    pub fn cat(vectors: impl IntoIterator<Item = Self>, dim: usize) -> Self {
+        let vectors = vectors.into_iter().collect::<Vec<_>>();
+        let mut shape = vectors[0].shape().to_vec();
+        shape[dim] = 0;
+        for vector in &vectors {
+            assert_eq!(vector.rank(), shape.len());
+            for (i, (size, other)) in shape.iter_mut().zip(vector.shape()).enumerate() {
+                if i == dim {
+                    *size += other;
+                } else {
+                    assert_eq!(size, other);
+                }
+            }
+        }
+        let mut iter = vectors.into_iter();
+
+        let mut index = 0;
+        let first = iter.next().unwrap();
+        let mut larger = first.resize(&shape);
+        index += first.shape()[dim];
+        for vector in iter {
+            let length = vector.shape()[dim];
+            let slice = (0..shape.len())
+                .map(|i| {
+                    if i == dim {
+                        index..(index + length)
+                    } else {
+                        0..shape[i]
+                    }
+                })
+                .collect::<Vec<_>>();
+            larger = larger.slice_assign(&slice, &vector);
+            index += length;
+        }
+        larger
+    }
+}
+
 #[cfg(test)]
 #[tokio::test]
 async fn test_cat() {
@@ -2,7 +2,6 @@ mod arange;
 mod cat;
 mod narrow;
 mod rms_norm;
-mod silu;
 mod softmax;
 mod unsqueeze;
 pub use unsqueeze::Unsqueeze;
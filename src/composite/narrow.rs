@@ -1,4 +1,4 @@
-use crate::{DataType, Tensor};
+use crate::{DataType, DynTensor, Tensor};
 
 impl<const R: usize, D: DataType> Tensor<R, D> {
     pub fn narrow(&self, axis: usize, start: usize, length: usize) -> Self {
@@ -15,6 +15,26 @@ impl<const R: usize, D: DataType> Tensor<R, D> {
     }
 }
 
+impl<D: DataType> DynTensor<D> {
+    pub fn narrow(&self, axis: usize, start: usize, length: usize) -> Self {
+        let shape = self.shape();
+        assert!(axis < shape.len());
+        assert!(start + length <= shape[axis]);
+        let slices = shape
+            .iter()
+            .enumerate()
+            .map(|(i, &size)| {
+                if i == axis {
+                    start..start + length
+                } else {
+                    0..size
+                }
+            })
+            .collect::<Vec<_>>();
+        self.slice(&slices)
+    }
+}
+
 #[cfg(test)]
 #[tokio::test]
 async fn test_narrow() {
@@ -1,4 +1,4 @@
-use crate::{CastTensor, DataType, Sum, Tensor};
+use crate::{CastTensor, DataType, Mean, Tensor};
 
 impl<T> Tensor<2, T>
 where
@@ -6,10 +6,9 @@ where
     f32: CastTensor<T>,
 {
     pub fn layer_norm(self, weight: Tensor<1, T>, eps: f32) -> Self {
-        let hidden_size = *self.shape().last().unwrap();
         let self_shape = *self.shape();
         let f32_self = self.cast::<f32>();
-        let norm_x = f32_self.sqr().sum(1) / hidden_size as f32;
+        let norm_x = f32_self.sqr().mean(1);
         let x_normed = f32_self / (norm_x + eps).sqrt().broadcast(self_shape);
         x_normed.cast::<T>() * weight.broadcast(self_shape)
     }
@@ -1,12 +1,11 @@
-use crate::{DataType, Sum, Tensor};
+use crate::{FloatDataType, LogSumExp, Tensor};
 
-impl<D: DataType> Tensor<1, D> {
+impl<D: FloatDataType> Tensor<1, D> {
     pub fn softmax(&self) -> Self {
         let size = *self.shape();
-        let exp = self.exp();
-        let sum_all = exp.sum(0);
-        let sum_all: Tensor<1, D> = sum_all.broadcast(size);
-        exp / sum_all
+        // exp(x) / sum(exp(x)) = exp(x - logsumexp(x)) which doesn't overflow for large inputs
+        let logsumexp: Tensor<1, D> = self.logsumexp(0).broadcast(size);
+        (self - &logsumexp).exp()
     }
 }
 
@@ -43,3 +42,28 @@ async fn test_softmax() {
     assert!((output[[4]] - softmax_array[4]).abs() < 0.001);
     assert!((output[[5]] - softmax_array[5]).abs() < 0.001);
 }
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_softmax_large() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+
+    // exp(x) overflows for every element
+    let tensor = Tensor::new(&device, &[1000f32, 1000., 999.]);
+
+    let tensor = tensor.softmax();
+
+    let output = tensor.as_slice().await.unwrap();
+    let sum = 2. + (-1f32).exp();
+    assert!((output[[0]] - 1. / sum).abs() < 0.001);
+    assert!((output[[1]] - 1. / sum).abs() < 0.001);
+    assert!((output[[2]] - (-1f32).exp() / sum).abs() < 0.001);
+}
@@ -1,4 +1,4 @@
-use crate::{DataType, Tensor};
+use crate::{DataType, DynTensor, Tensor};
 
 fn unchecked_unsqueeze<const R1: usize, const R2: usize, D: DataType>(
     tensor: &Tensor<R1, D>,
@@ -31,6 +31,18 @@ macro_rules! impl_unsqueeze {
     };
 }
 
+impl<D: DataType> Unsqueeze for DynTensor<D> {
+    type Output = DynTensor<D>;
+
+    fn unsqueeze(&self, axis: usize) -> Self::Output {
+        let shape = self.shape();
+        assert!(axis < shape.len());
+        let mut new_shape = shape.to_vec();
+        new_shape.insert(axis, 1);
+        self.reshape(&new_shape)
+    }
+}
+
 impl_unsqueeze!(1);
 impl_unsqueeze!(2);
 impl_unsqueeze!(3);
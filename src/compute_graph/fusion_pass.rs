@@ -0,0 +1,57 @@
+use std::collections::HashSet;
+
+use super::{
+    AnyComputeKey, ComputeGraphInner, ElementWiseComputeNodeKey, NaryComputeNodeKey,
+    PairWiseComputeNodeKey,
+    resolve::FusedPairWise,
+    visit::{VisitComputeGraph, visit_element_wise},
+};
+
+/// Finds the groups of nodes the resolver merges into a single kernel
+#[derive(Default)]
+pub(crate) struct FusionPass {
+    pub(crate) groups: Vec<Vec<AnyComputeKey>>,
+    visited: HashSet<AnyComputeKey>,
+}
+
+impl FusionPass {
+    fn visit_fused(&mut self, graph: &ComputeGraphInner, fused: FusedPairWise) {
+        if !self.visited.insert(fused.nodes[0]) {
+            return;
+        }
+        for input in &fused.inputs {
+            self.visit(graph, *input);
+        }
+        if fused.nodes.len() > 1 {
+            self.groups.push(fused.nodes);
+        }
+    }
+}
+
+impl VisitComputeGraph for FusionPass {
+    fn visit_element_wise(&mut self, graph: &ComputeGraphInner, key: ElementWiseComputeNodeKey) {
+        let (functions, input) = graph.collect_element_wise_ops(key);
+        if let AnyComputeKey::PairWiseComputeNodeKey(_) | AnyComputeKey::NaryComputeNodeKey(_) =
+            input
+        {
+            let mut fused = graph.collect_fused(input, functions);
+            // The resolver merges the element-wise chain into the output of the fused kernel
+            let mut nodes = graph.element_wise_chain(key, input);
+            nodes.append(&mut fused.nodes);
+            fused.nodes = nodes;
+            self.visit_fused(graph, fused);
+        } else {
+            visit_element_wise(self, graph, key);
+        }
+    }
+
+    fn visit_pair_wise(&mut self, graph: &ComputeGraphInner, key: PairWiseComputeNodeKey) {
+        let fused = graph.collect_fused(key.into(), Vec::new());
+        self.visit_fused(graph, fused);
+    }
+
+    fn visit_nary(&mut self, graph: &ComputeGraphInner, key: NaryComputeNodeKey) {
+        let fused = graph.collect_fused(key.into(), Vec::new());
+        self.visit_fused(graph, fused);
+    }
+}
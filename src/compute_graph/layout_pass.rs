@@ -1,12 +1,13 @@
 use std::collections::HashMap;
 
-use crate::{Layout, TensorLayoutInfo};
+use crate::{DataTypeEnum, Layout, MatMulOperation, TensorLayoutInfo, sort::SortOutput};
 
 use super::{
     AnyComputeKey,
     visit::{
-        VisitComputeGraph, visit_element_wise, visit_mat_mul, visit_pair_wise, visit_reduce,
-        visit_resize, visit_slice, visit_slice_assign, visit_tensor,
+        VisitComputeGraph, visit_element_wise, visit_mat_mul, visit_nary, visit_pair_wise,
+        visit_reduce, visit_resize, visit_scan, visit_slice, visit_slice_assign, visit_sort,
+        visit_tensor,
     },
 };
 
@@ -42,6 +43,16 @@ impl VisitComputeGraph for LayoutPass {
         self.output_layout.insert(key.into(), first_layout.clone());
     }
 
+    fn visit_nary(&mut self, graph: &super::ComputeGraphInner, key: super::NaryComputeNodeKey) {
+        visit_nary(self, graph, key);
+        let operation = graph.nary.get(&key).unwrap();
+        let first = operation.inputs[0];
+        let first_layout = self.output_layout.get(&first).unwrap();
+        let output_layout =
+            TensorLayoutInfo::new(first_layout.layout().clone(), operation.function.datatype());
+        self.output_layout.insert(key.into(), output_layout);
+    }
+
     fn visit_mat_mul(
         &mut self,
         graph: &super::ComputeGraphInner,
@@ -52,9 +63,10 @@ impl VisitComputeGraph for LayoutPass {
         let first = operation.first;
         let first_layout = self.output_layout.get(&first).unwrap();
         let second_layout = self.output_layout.get(&operation.second).unwrap();
-        let first_shape = first_layout.layout().shape();
-        let second_shape = second_layout.layout().shape();
-        let output_shape = [first_shape[1], second_shape[0]];
+        let output_shape = MatMulOperation::output_shape(
+            first_layout.layout().shape(),
+            second_layout.layout().shape(),
+        );
         let output_layout = Layout::contiguous(&output_shape);
         self.output_layout.insert(
             key.into(),
@@ -66,19 +78,12 @@ impl VisitComputeGraph for LayoutPass {
         visit_reduce(self, graph, key);
         let operation = graph.reduce.get(&key).unwrap();
         let input = operation.value;
-        let dim = operation.axis;
         let input_layout = self.output_layout.get(&input).unwrap();
-        let new_shape = input_layout
-            .layout()
-            .shape()
-            .iter()
-            .enumerate()
-            .filter_map(|(i, x)| (i != dim).then_some(*x))
-            .collect::<Vec<_>>();
+        let new_shape = operation.output_shape(input_layout.layout().shape());
         let new_layout = Layout::contiguous(&new_shape);
         self.output_layout.insert(
             key.into(),
-            TensorLayoutInfo::new(new_layout, input_layout.datatype()),
+            TensorLayoutInfo::new(new_layout, operation.function.output_datatype()),
         );
     }
 
@@ -122,6 +127,34 @@ impl VisitComputeGraph for LayoutPass {
         self.output_layout.insert(key.into(), input_layout.clone());
     }
 
+    fn visit_scan(&mut self, graph: &super::ComputeGraphInner, key: super::ScanComputeNodeKey) {
+        visit_scan(self, graph, key);
+        let operation = graph.scan.get(&key).unwrap();
+        let input = operation.input;
+        let input_layout = self.output_layout.get(&input).unwrap();
+        let new_layout = Layout::contiguous(input_layout.layout().shape());
+        self.output_layout.insert(
+            key.into(),
+            TensorLayoutInfo::new(new_layout, operation.function.datatype()),
+        );
+    }
+
+    fn visit_sort(&mut self, graph: &super::ComputeGraphInner, key: super::SortComputeNodeKey) {
+        visit_sort(self, graph, key);
+        let operation = graph.sort.get(&key).unwrap();
+        let input = operation.input;
+        let input_layout = self.output_layout.get(&input).unwrap();
+        let mut new_shape: Box<[usize]> = input_layout.layout().shape().into();
+        new_shape[operation.axis] = operation.k;
+        let new_layout = Layout::contiguous(&new_shape);
+        let datatype = match operation.output {
+            SortOutput::Values => input_layout.datatype(),
+            SortOutput::Indices => DataTypeEnum::U32,
+        };
+        self.output_layout
+            .insert(key.into(), TensorLayoutInfo::new(new_layout, datatype));
+    }
+
     fn visit_tensor(&mut self, graph: &super::ComputeGraphInner, key: super::TensorComputeNodeKey) {
         visit_tensor(self, graph, key);
         let operation = graph.tensor.get(&key).unwrap();
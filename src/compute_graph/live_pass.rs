@@ -0,0 +1,27 @@
+use std::collections::HashSet;
+
+use super::{AnyComputeKey, ComputeGraphInner, visit::VisitComputeGraph};
+
+/// Finds the nodes a live tensor handle can still read
+#[derive(Default)]
+pub(crate) struct LivePass {
+    pub(crate) nodes: HashSet<AnyComputeKey>,
+}
+
+impl LivePass {
+    pub(crate) fn new(graph: &ComputeGraphInner) -> Self {
+        let mut pass = Self::default();
+        for (key, handle) in &graph.handles {
+            if handle.strong_count() > 0 {
+                pass.visit(graph, *key);
+            }
+        }
+        pass
+    }
+}
+
+impl VisitComputeGraph for LivePass {
+    fn enter(&mut self, key: AnyComputeKey) -> bool {
+        self.nodes.insert(key)
+    }
+}
@@ -1,20 +1,30 @@
 use std::{
-    collections::HashMap,
-    sync::{Arc, RwLock, atomic::AtomicUsize},
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock, Weak, atomic::AtomicUsize},
 };
 
 use arc_swap::ArcSwap;
 use tabbycat::Graph;
 
+mod fusion_pass;
 mod layout_pass;
+mod live_pass;
 mod resolve;
 mod visit;
 mod visualize;
 
+use live_pass::LivePass;
+
 use crate::{
-    Device, ElementWiseOperation, MatMulOperation, PairWiseOperation, PerformanceQueries,
-    QueryResults, ReduceOperation, map_layout::MapLayoutOperation, resize::ResizeOperation,
-    slice_assign::SliceAssignOperation, tensor::TensorData,
+    Device, ElementWiseFunction, ElementWiseOperation, MatMulOperation, PairWiseOperation,
+    PerformanceQueries, QueryResults, ReduceOperation,
+    map_layout::MapLayoutOperation,
+    nary::NaryOperation,
+    resize::ResizeOperation,
+    scan::ScanOperation,
+    slice_assign::SliceAssignOperation,
+    sort::{SortOperation, SortOutput},
+    tensor::TensorData,
 };
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
@@ -35,6 +45,15 @@ impl PairWiseComputeNodeKey {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub(crate) struct NaryComputeNodeKey(usize);
+impl NaryComputeNodeKey {
+    fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        Self(COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst))
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub(crate) struct MatMulComputeNodeKey(usize);
 impl MatMulComputeNodeKey {
@@ -80,6 +99,24 @@ impl SliceAssignComputeNodeKey {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub(crate) struct ScanComputeNodeKey(usize);
+impl ScanComputeNodeKey {
+    fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        Self(COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub(crate) struct SortComputeNodeKey(usize);
+impl SortComputeNodeKey {
+    fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        Self(COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst))
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub(crate) struct TensorComputeNodeKey(usize);
 impl TensorComputeNodeKey {
@@ -93,11 +130,14 @@ impl TensorComputeNodeKey {
 pub(crate) enum AnyComputeKey {
     ElementWiseComputeNodeKey(ElementWiseComputeNodeKey),
     PairWiseComputeNodeKey(PairWiseComputeNodeKey),
+    NaryComputeNodeKey(NaryComputeNodeKey),
     MatMulComputeNodeKey(MatMulComputeNodeKey),
     ReduceComputeNodeKey(ReduceComputeNodeKey),
     MapLayoutComputeNodeKey(MapLayoutComputeNodeKey),
     ResizeComputeNodeKey(ResizeComputeNodeKey),
     SliceAssignComputeNodeKey(SliceAssignComputeNodeKey),
+    ScanComputeNodeKey(ScanComputeNodeKey),
+    SortComputeNodeKey(SortComputeNodeKey),
     TensorComputeNodeKey(TensorComputeNodeKey),
 }
 
@@ -113,6 +153,12 @@ impl From<PairWiseComputeNodeKey> for AnyComputeKey {
     }
 }
 
+impl From<NaryComputeNodeKey> for AnyComputeKey {
+    fn from(value: NaryComputeNodeKey) -> Self {
+        Self::NaryComputeNodeKey(value)
+    }
+}
+
 impl From<MatMulComputeNodeKey> for AnyComputeKey {
     fn from(value: MatMulComputeNodeKey) -> Self {
         Self::MatMulComputeNodeKey(value)
@@ -149,6 +195,18 @@ impl From<SliceAssignComputeNodeKey> for AnyComputeKey {
     }
 }
 
+impl From<ScanComputeNodeKey> for AnyComputeKey {
+    fn from(value: ScanComputeNodeKey) -> Self {
+        Self::ScanComputeNodeKey(value)
+    }
+}
+
+impl From<SortComputeNodeKey> for AnyComputeKey {
+    fn from(value: SortComputeNodeKey) -> Self {
+        Self::SortComputeNodeKey(value)
+    }
+}
+
 #[derive(Clone, Default)]
 pub(crate) struct ComputeGraph {
     inner: Arc<ArcSwap<RwLock<ComputeGraphInner>>>,
@@ -173,12 +231,23 @@ impl ComputeGraph {
             other.with_mut(|other_inner| {
                 inner.element_wise.extend(other_inner.element_wise.drain());
                 inner.pair_wise.extend(other_inner.pair_wise.drain());
+                inner.nary.extend(other_inner.nary.drain());
                 inner.mat_mul.extend(other_inner.mat_mul.drain());
                 inner.reduce.extend(other_inner.reduce.drain());
                 inner.map_layout.extend(other_inner.map_layout.drain());
                 inner.resize.extend(other_inner.resize.drain());
                 inner.slice_assign.extend(other_inner.slice_assign.drain());
+                inner.scan.extend(other_inner.scan.drain());
+                inner.sort.extend(other_inner.sort.drain());
                 inner.tensor.extend(other_inner.tensor.drain());
+                inner.in_place.extend(other_inner.in_place.drain());
+                inner.resolved.extend(other_inner.resolved.drain());
+                inner.released.extend(other_inner.released.drain());
+                inner
+                    .ran_reductions
+                    .extend(other_inner.ran_reductions.drain());
+                inner.handles.append(&mut other_inner.handles);
+                inner.cached.extend(other_inner.cached.drain());
             })
         });
         other.inner.store(self.inner.load_full());
@@ -199,6 +268,61 @@ impl ComputeGraph {
         id
     }
 
+    /// Find the closest node that both keys are element-wise chains over. Returns the node and the
+    /// element-wise functions from that node to each key with the last function applied first
+    pub(crate) fn common_element_wise_input(
+        &self,
+        first: AnyComputeKey,
+        second: AnyComputeKey,
+    ) -> Option<(AnyComputeKey, [Vec<ElementWiseFunction>; 2])> {
+        self.with_mut(|inner| {
+            let chain = |key| {
+                let mut nodes = vec![key];
+                let mut current_key = key;
+                while let AnyComputeKey::ElementWiseComputeNodeKey(key) = current_key
+                    && !inner.resolved.contains_key(&current_key)
+                {
+                    current_key = inner.element_wise.get(&key).unwrap().value;
+                    nodes.push(current_key);
+                }
+                nodes
+            };
+            let first_chain = chain(first);
+            let second_chain = chain(second);
+            let (first_index, second_index) =
+                first_chain.iter().enumerate().find_map(|(i, key)| {
+                    second_chain
+                        .iter()
+                        .position(|other| other == key)
+                        .map(|j| (i, j))
+                })?;
+            let functions = |chain: &[AnyComputeKey]| {
+                chain
+                    .iter()
+                    .map(|key| match key {
+                        AnyComputeKey::ElementWiseComputeNodeKey(key) => {
+                            inner.element_wise.get(key).unwrap().function.clone()
+                        }
+                        _ => unreachable!(),
+                    })
+                    .collect()
+            };
+            Some((
+                first_chain[first_index],
+                [
+                    functions(&first_chain[..first_index]),
+                    functions(&second_chain[..second_index]),
+                ],
+            ))
+        })
+    }
+
+    pub(crate) fn create_nary(&self, function: NaryOperation) -> NaryComputeNodeKey {
+        let id = NaryComputeNodeKey::new();
+        self.with_mut(|inner| inner.nary.insert(id, function));
+        id
+    }
+
     pub(crate) fn create_mat_mul(&self, function: MatMulOperation) -> MatMulComputeNodeKey {
         let id = MatMulComputeNodeKey::new();
         self.with_mut(|inner| inner.mat_mul.insert(id, function));
@@ -211,6 +335,21 @@ impl ComputeGraph {
         id
     }
 
+    /// Create two reductions of the same input that run in one kernel
+    pub(crate) fn create_reduce_pair(
+        &self,
+        functions: [ReduceOperation; 2],
+    ) -> [ReduceComputeNodeKey; 2] {
+        let ids = [ReduceComputeNodeKey::new(), ReduceComputeNodeKey::new()];
+        self.with_mut(|inner| {
+            for (i, mut function) in functions.into_iter().enumerate() {
+                function.paired = Some(ids[1 - i]);
+                inner.reduce.insert(ids[i], function);
+            }
+        });
+        ids
+    }
+
     pub(crate) fn create_map_layout(&self, op: MapLayoutOperation) -> MapLayoutComputeNodeKey {
         let id = MapLayoutComputeNodeKey::new();
         self.with_mut(|inner| inner.map_layout.insert(id, op));
@@ -232,21 +371,91 @@ impl ComputeGraph {
         id
     }
 
+    pub(crate) fn create_scan(&self, op: ScanOperation) -> ScanComputeNodeKey {
+        let id = ScanComputeNodeKey::new();
+        self.with_mut(|inner| inner.scan.insert(id, op));
+        id
+    }
+
+    /// Create the nodes for the values and indices of a sort. Resolving either one computes both
+    pub(crate) fn create_sort(
+        &self,
+        input: AnyComputeKey,
+        axis: usize,
+        descending: bool,
+        k: usize,
+    ) -> [SortComputeNodeKey; 2] {
+        let values = SortComputeNodeKey::new();
+        let indices = SortComputeNodeKey::new();
+        let operation = |output, sibling| SortOperation {
+            input,
+            axis,
+            descending,
+            k,
+            output,
+            sibling,
+        };
+        self.with_mut(|inner| {
+            inner
+                .sort
+                .insert(values, operation(SortOutput::Values, indices));
+            inner
+                .sort
+                .insert(indices, operation(SortOutput::Indices, values));
+        });
+        [values, indices]
+    }
+
+    /// Track a handle to `key`. The node is live while the handle or a node that reads it is
+    pub(crate) fn add_handle(&self, key: AnyComputeKey, handle: &Arc<()>) {
+        self.with_mut(|inner| inner.handles.push((key, Arc::downgrade(handle))));
+    }
+
     pub(crate) fn create_tensor(&self, info: TensorData) -> TensorComputeNodeKey {
         let id = TensorComputeNodeKey::new();
         self.with_mut(|inner| inner.tensor.insert(id, info));
         id
     }
 
+    /// Check if any node reads the output of this node
+    pub(crate) fn has_dependents(&self, key: AnyComputeKey) -> bool {
+        self.with_mut(|inner| inner.has_dependents(key))
+    }
+
+    /// Let the kernel that resolves `key` write into the buffer of `input`. Nothing else may read
+    /// `input` once this is called
+    pub(crate) fn write_in_place(&self, key: AnyComputeKey, input: AnyComputeKey) {
+        self.with_mut(|inner| inner.in_place.insert(key, input));
+    }
+
     pub(crate) fn resolve(&self, key: AnyComputeKey, device: &Device) -> TensorData {
         let mut encoder = device
             .wgpu_device()
             .create_command_encoder(&Default::default());
-        let data = self.with_mut(|inner| inner.resolve(key, &mut encoder));
+        let data = self.with_mut(|inner| {
+            inner
+                .handles
+                .retain(|(_, handle)| handle.strong_count() > 0);
+            // Liveness can't change while the graph is locked, so find it once per resolve
+            let live = LivePass::new(inner).nodes;
+            inner.cached.retain(|key, _| live.contains(key));
+            inner.live = live;
+            inner.resolve(key, &mut encoder)
+        });
         device.wgpu_queue().submit(Some(encoder.finish()));
         data
     }
 
+    #[cfg(test)]
+    pub(crate) fn ran_reductions(&self) -> usize {
+        self.with_mut(|inner| inner.ran_reductions.len())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn cached_results(&self) -> usize {
+        self.with_mut(|inner| inner.cached.len())
+    }
+
     pub(crate) fn graphvis(&self, key: AnyComputeKey) -> Graph {
         self.with_mut(|inner| inner.graphvis(key))
     }
@@ -266,11 +475,55 @@ impl ComputeGraph {
 struct ComputeGraphInner {
     element_wise: HashMap<ElementWiseComputeNodeKey, ElementWiseOperation>,
     pair_wise: HashMap<PairWiseComputeNodeKey, PairWiseOperation>,
+    nary: HashMap<NaryComputeNodeKey, NaryOperation>,
     mat_mul: HashMap<MatMulComputeNodeKey, MatMulOperation>,
     reduce: HashMap<ReduceComputeNodeKey, ReduceOperation>,
     map_layout: HashMap<MapLayoutComputeNodeKey, MapLayoutOperation>,
     resize: HashMap<ResizeComputeNodeKey, ResizeOperation>,
     slice_assign: HashMap<SliceAssignComputeNodeKey, SliceAssignOperation>,
+    scan: HashMap<ScanComputeNodeKey, ScanOperation>,
+    sort: HashMap<SortComputeNodeKey, SortOperation>,
     tensor: HashMap<TensorComputeNodeKey, TensorData>,
+    /// Nodes that may write into the buffer of their input because nothing else reads it
+    in_place: HashMap<AnyComputeKey, AnyComputeKey>,
+    /// The results of in-place nodes that were already resolved. The inputs they consumed are
+    /// gone, so they are never computed again
+    resolved: HashMap<AnyComputeKey, TensorComputeNodeKey>,
+    /// Tensors taken out of the graph for the kernel that consumes them
+    released: HashMap<TensorComputeNodeKey, TensorData>,
+    /// Reductions that already ran. Later reductions of the same input don't compute them again
+    /// as siblings
+    ran_reductions: HashSet<ReduceComputeNodeKey>,
+    /// The nodes tensor handles point at. Nodes that no live handle can reach are never read again
+    handles: Vec<(AnyComputeKey, Weak<()>)>,
+    /// The nodes live handles can reach, found at the start of each resolve
+    live: HashSet<AnyComputeKey>,
+    /// Results of live nodes that were computed in the kernel of another node. They are removed
+    /// when they are first resolved or when their node is no longer live
+    cached: HashMap<AnyComputeKey, TensorData>,
     timing_information: HashMap<AnyComputeKey, PerformanceQueries>,
 }
+
+impl ComputeGraphInner {
+    fn has_dependents(&self, key: AnyComputeKey) -> bool {
+        self.element_wise.values().any(|op| op.value == key)
+            || self
+                .pair_wise
+                .values()
+                .any(|op| op.first == key || op.second == key)
+            || self.nary.values().any(|op| op.inputs.contains(&key))
+            || self
+                .mat_mul
+                .values()
+                .any(|op| op.first == key || op.second == key)
+            || self.reduce.values().any(|op| op.value == key)
+            || self.map_layout.values().any(|op| op.input == key)
+            || self.resize.values().any(|op| op.input == key)
+            || self
+                .slice_assign
+                .values()
+                .any(|op| op.input == key || op.value == key)
+            || self.scan.values().any(|op| op.input == key)
+            || self.sort.values().any(|op| op.input == key)
+    }
+}
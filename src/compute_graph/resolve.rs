@@ -1,22 +1,112 @@
 use wgpu::CommandEncoder;
 
 use crate::{
-    ElementWiseFunction, PerformanceQueries, UntypedElementWiseKernel, UntypedPairWiseKernel,
-    UntypedReduceKernel, element_wise, matmul::UntypedMatMul, resize::UntypedResizeKernel,
-    slice_assign::UntypedSliceAssignKernel, tensor::TensorData,
+    ElementWiseFunction, PairWiseExpression, PerformanceQueries, ReduceFunction,
+    UntypedElementWiseKernel, UntypedPairWiseKernel, UntypedReduceKernel, element_wise,
+    matmul::UntypedMatMul,
+    reduce::reduce_split_count,
+    resize::UntypedResizeKernel,
+    scan::UntypedScanKernel,
+    slice_assign::UntypedSliceAssignKernel,
+    sort::{SortOutput, UntypedSortKernel},
+    tensor::TensorData,
 };
 
 use super::{
     AnyComputeKey, ComputeGraphInner, ElementWiseComputeNodeKey, MapLayoutComputeNodeKey,
-    MatMulComputeNodeKey, PairWiseComputeNodeKey, ReduceComputeNodeKey, ResizeComputeNodeKey,
-    SliceAssignComputeNodeKey, TensorComputeNodeKey,
+    MatMulComputeNodeKey, NaryComputeNodeKey, PairWiseComputeNodeKey, ReduceComputeNodeKey,
+    ResizeComputeNodeKey, ScanComputeNodeKey, SliceAssignComputeNodeKey, SortComputeNodeKey,
+    TensorComputeNodeKey,
 };
 
+/// The default limit of storage buffers in a shader. Each tensor in a kernel takes one
+const MAX_STORAGE_BUFFERS: usize = 8;
+/// The default limit of uniform buffers in a shader. Each tensor in a kernel takes one for its
+/// layout and all of the scalars share one
+const MAX_UNIFORM_BUFFERS: usize = 12;
+
+/// A tree of pair-wise, n-ary and element-wise nodes that resolves in a single kernel
+pub(super) struct FusedPairWise {
+    pub(super) expression: PairWiseExpression,
+    /// The nodes the kernel reads from
+    pub(super) inputs: Vec<AnyComputeKey>,
+    /// The pair-wise and element-wise nodes merged into the kernel
+    pub(super) nodes: Vec<AnyComputeKey>,
+    /// Inputs for operands that are not collected yet
+    reserved_inputs: usize,
+}
+
+impl FusedPairWise {
+    fn fits_in_kernel(&self) -> bool {
+        // The inputs and the output tensor
+        let tensors = self.inputs.len() + self.reserved_inputs + 1;
+        // Each tensor also takes a uniform for its layout and the scalars share one more
+        tensors <= MAX_STORAGE_BUFFERS.min(MAX_UNIFORM_BUFFERS - 1)
+    }
+
+    fn input(&mut self, key: AnyComputeKey) -> PairWiseExpression {
+        let index = match self.inputs.iter().position(|input| *input == key) {
+            Some(index) => index,
+            None => {
+                self.inputs.push(key);
+                self.inputs.len() - 1
+            }
+        };
+        PairWiseExpression::Input(index)
+    }
+}
+
+/// A reduction of the same input that runs in the kernel of another reduction
+struct SiblingReduction {
+    key: ReduceComputeNodeKey,
+    function: ReduceFunction,
+    element_wise_before: Vec<ElementWiseFunction>,
+}
+
 impl ComputeGraphInner {
     pub(crate) fn resolve(
         &mut self,
         key: AnyComputeKey,
         command_encoder: &mut CommandEncoder,
+    ) -> TensorData {
+        if let Some(tensor) = self.resolved.get(&key) {
+            return self.resolve_tensor(*tensor, command_encoder);
+        }
+        if let Some(data) = self.cached.remove(&key) {
+            return data;
+        }
+        let Some(input) = self.in_place.remove(&key) else {
+            return self.resolve_node(key, command_encoder);
+        };
+        // Take the input out of the graph so the kernel sees it as owned and writes into it
+        self.release(input);
+        let result = self.resolve_node(key, command_encoder);
+        let tensor = TensorComputeNodeKey::new();
+        self.tensor.insert(tensor, result.clone());
+        self.resolved.insert(key, tensor);
+        result
+    }
+
+    /// Release the buffer that a node resolves to. If the node is an in-place node that has not
+    /// run yet, it will run inside the kernel that consumes it, so release its input instead
+    fn release(&mut self, key: AnyComputeKey) {
+        let key = match self.resolved.get(&key) {
+            Some(tensor) => (*tensor).into(),
+            None => key,
+        };
+        if let AnyComputeKey::TensorComputeNodeKey(key) = key {
+            if let Some(data) = self.tensor.remove(&key) {
+                self.released.insert(key, data);
+            }
+        } else if let Some(input) = self.in_place.remove(&key) {
+            self.release(input);
+        }
+    }
+
+    fn resolve_node(
+        &mut self,
+        key: AnyComputeKey,
+        command_encoder: &mut CommandEncoder,
     ) -> TensorData {
         match key {
             AnyComputeKey::ElementWiseComputeNodeKey(element_wise_compute_node_key) => {
@@ -25,6 +115,9 @@ impl ComputeGraphInner {
             AnyComputeKey::PairWiseComputeNodeKey(pair_wise_compute_node_key) => {
                 self.resolve_pair_wise(pair_wise_compute_node_key, command_encoder)
             }
+            AnyComputeKey::NaryComputeNodeKey(nary_compute_node_key) => {
+                self.resolve_nary(nary_compute_node_key, command_encoder)
+            }
             AnyComputeKey::MatMulComputeNodeKey(mat_mul_compute_node_key) => {
                 self.resolve_mat_mul(mat_mul_compute_node_key, command_encoder)
             }
@@ -43,16 +136,24 @@ impl ComputeGraphInner {
             AnyComputeKey::SliceAssignComputeNodeKey(slice_assign_compute_node_key) => {
                 self.resolve_slice_assign(slice_assign_compute_node_key, command_encoder)
             }
+            AnyComputeKey::ScanComputeNodeKey(scan_compute_node_key) => {
+                self.resolve_scan(scan_compute_node_key, command_encoder)
+            }
+            AnyComputeKey::SortComputeNodeKey(sort_compute_node_key) => {
+                self.resolve_sort(sort_compute_node_key, command_encoder)
+            }
         }
     }
 
-    fn collect_element_wise_ops(
-        &mut self,
+    pub(super) fn collect_element_wise_ops(
+        &self,
         key: ElementWiseComputeNodeKey,
     ) -> (Vec<ElementWiseFunction>, AnyComputeKey) {
         let mut functions = Vec::new();
         let mut current_key = AnyComputeKey::ElementWiseComputeNodeKey(key);
-        while let AnyComputeKey::ElementWiseComputeNodeKey(key) = current_key {
+        while let AnyComputeKey::ElementWiseComputeNodeKey(key) = current_key
+            && !self.resolved.contains_key(&current_key)
+        {
             let operation = self.element_wise.get(&key).unwrap();
             functions.push(operation.function.clone());
             current_key = operation.value;
@@ -69,12 +170,18 @@ impl ComputeGraphInner {
         let (functions, input) = self.collect_element_wise_ops(key);
 
         // Merge into the output of the reduce kernel if possible
-        if let AnyComputeKey::ReduceComputeNodeKey(key) = input {
+        if let AnyComputeKey::ReduceComputeNodeKey(key) = input
+            && !self.resolved.contains_key(&input)
+            && !self.cached.contains_key(&input)
+        {
             self.resolve_reduce_then(key, functions, command_encoder)
         }
-        // Merge into the output of the pair wise kernel if possible
-        else if let AnyComputeKey::PairWiseComputeNodeKey(key) = input {
-            self.resolve_pair_wise_then(key, functions, command_encoder)
+        // Merge into the output of the pair wise or n-ary kernel if possible
+        else if let AnyComputeKey::PairWiseComputeNodeKey(_)
+        | AnyComputeKey::NaryComputeNodeKey(_) = input
+            && !self.resolved.contains_key(&input)
+        {
+            self.resolve_fused_then(input, functions, command_encoder)
         } else {
             let input = self.resolve(input, &mut *command_encoder);
             let kernel = UntypedElementWiseKernel::new(functions, input.datatype());
@@ -90,51 +197,158 @@ impl ComputeGraphInner {
         key: PairWiseComputeNodeKey,
         command_encoder: &mut CommandEncoder,
     ) -> TensorData {
-        self.resolve_pair_wise_then(key, Vec::new(), command_encoder)
+        self.resolve_fused_then(key.into(), Vec::new(), command_encoder)
     }
 
-    fn resolve_pair_wise_then(
+    fn resolve_nary(
         &mut self,
-        key: PairWiseComputeNodeKey,
-        then: Vec<ElementWiseFunction>,
+        key: NaryComputeNodeKey,
         command_encoder: &mut CommandEncoder,
     ) -> TensorData {
-        let operation = self.pair_wise.get(&key).unwrap();
-        let function = operation.function.clone();
+        self.resolve_fused_then(key.into(), Vec::new(), command_encoder)
+    }
 
-        let mut first_input = operation.first;
-        let mut second_input = operation.second;
-        let first_pre_element_wise =
-            if let AnyComputeKey::ElementWiseComputeNodeKey(key) = first_input {
-                let (functions, element_wise_input) = self.collect_element_wise_ops(key);
-                first_input = element_wise_input;
-                functions
-            } else {
-                Vec::new()
-            };
-        let second_pre_element_wise =
-            if let AnyComputeKey::ElementWiseComputeNodeKey(key) = second_input {
-                let (functions, element_wise_input) = self.collect_element_wise_ops(key);
-                second_input = element_wise_input;
-                functions
-            } else {
-                Vec::new()
-            };
+    /// Resolve a pair-wise or n-ary node along with the nodes that fuse into the same kernel
+    fn resolve_fused_then(
+        &mut self,
+        key: AnyComputeKey,
+        then: Vec<ElementWiseFunction>,
+        command_encoder: &mut CommandEncoder,
+    ) -> TensorData {
+        let FusedPairWise {
+            expression, inputs, ..
+        } = self.collect_fused(key, then);
 
-        let first = self.resolve(first_input, &mut *command_encoder);
-        let second = self.resolve(second_input, &mut *command_encoder);
-        let mut kernel = UntypedPairWiseKernel::new(function, first.datatype());
-        let first_pre = UntypedElementWiseKernel::new(first_pre_element_wise, first.datatype());
-        let second_pre = UntypedElementWiseKernel::new(second_pre_element_wise, first.datatype());
-        let pre_element_wise_output = first_pre.out_datatype();
-        kernel.set_pre_element_wise([first_pre, second_pre]);
-        kernel.set_post_element_wise(UntypedElementWiseKernel::new(then, pre_element_wise_output));
-        let query = PerformanceQueries::new(first.device());
-        let result = kernel.run_with_query(first, second, Some(&query), command_encoder);
-        self.timing_information.insert(key.into(), query);
+        let inputs = inputs
+            .into_iter()
+            .map(|input| self.resolve(input, &mut *command_encoder))
+            .collect::<Vec<_>>();
+        let datatypes = inputs.iter().map(|input| input.datatype()).collect();
+        let kernel = UntypedPairWiseKernel::new(expression, datatypes);
+        let query = PerformanceQueries::new(inputs[0].device());
+        let result = kernel.run_with_query(inputs, Some(&query), command_encoder);
+        self.timing_information.insert(key, query);
         result
     }
 
+    /// Collect the tree of pair-wise, n-ary and element-wise nodes under a pair-wise or n-ary
+    /// node that can run in a single kernel along with the element-wise functions applied to
+    /// the result
+    pub(super) fn collect_fused(
+        &self,
+        key: AnyComputeKey,
+        then: Vec<ElementWiseFunction>,
+    ) -> FusedPairWise {
+        let mut fused = FusedPairWise {
+            expression: PairWiseExpression::Input(0),
+            inputs: Vec::new(),
+            nodes: Vec::new(),
+            reserved_inputs: 0,
+        };
+        fused.expression = self.collect_fused_node(key, &mut fused).element_wise(then);
+        fused
+    }
+
+    fn collect_fused_node(
+        &self,
+        key: AnyComputeKey,
+        fused: &mut FusedPairWise,
+    ) -> PairWiseExpression {
+        fused.nodes.push(key);
+        match key {
+            AnyComputeKey::PairWiseComputeNodeKey(key) => {
+                let operation = self.pair_wise.get(&key).unwrap();
+                let mut operands =
+                    self.collect_fused_operands(vec![operation.first, operation.second], fused);
+                let second = operands.pop().unwrap();
+                let first = operands.pop().unwrap();
+                PairWiseExpression::PairWise {
+                    first: Box::new(first),
+                    second: Box::new(second),
+                    function: operation.function.clone(),
+                }
+            }
+            AnyComputeKey::NaryComputeNodeKey(key) => {
+                let operation = self.nary.get(&key).unwrap();
+                PairWiseExpression::Nary {
+                    operands: self.collect_fused_operands(operation.inputs.clone(), fused),
+                    function: operation.function.clone(),
+                }
+            }
+            _ => unreachable!("only pair-wise and n-ary nodes are fused"),
+        }
+    }
+
+    fn collect_fused_operands(
+        &self,
+        operands: Vec<AnyComputeKey>,
+        fused: &mut FusedPairWise,
+    ) -> Vec<PairWiseExpression> {
+        let count = operands.len();
+        operands
+            .into_iter()
+            .enumerate()
+            .map(|(i, operand)| {
+                // Keep room for the operands after this one while collecting it
+                let reserved = count - i - 1;
+                fused.reserved_inputs += reserved;
+                let expression = self.collect_fused_operand(operand, fused);
+                fused.reserved_inputs -= reserved;
+                expression
+            })
+            .collect()
+    }
+
+    fn collect_fused_operand(
+        &self,
+        key: AnyComputeKey,
+        fused: &mut FusedPairWise,
+    ) -> PairWiseExpression {
+        let (functions, input) = match key {
+            AnyComputeKey::ElementWiseComputeNodeKey(key) => {
+                let (functions, input) = self.collect_element_wise_ops(key);
+                fused.nodes.extend(self.element_wise_chain(key, input));
+                (functions, input)
+            }
+            _ => (Vec::new(), key),
+        };
+
+        // Try to merge the operand into this kernel. If the kernel would need more bindings than
+        // the device supports, resolve the operand in a separate kernel instead
+        if let AnyComputeKey::PairWiseComputeNodeKey(_) | AnyComputeKey::NaryComputeNodeKey(_) =
+            input
+            && !self.resolved.contains_key(&input)
+        {
+            let inputs = fused.inputs.len();
+            let nodes = fused.nodes.len();
+            let expression = self.collect_fused_node(input, fused);
+            if fused.fits_in_kernel() {
+                return expression.element_wise(functions);
+            }
+            fused.inputs.truncate(inputs);
+            fused.nodes.truncate(nodes);
+        }
+
+        fused.input(input).element_wise(functions)
+    }
+
+    pub(super) fn element_wise_chain(
+        &self,
+        key: ElementWiseComputeNodeKey,
+        until: AnyComputeKey,
+    ) -> Vec<AnyComputeKey> {
+        let mut nodes = Vec::new();
+        let mut current_key = AnyComputeKey::ElementWiseComputeNodeKey(key);
+        while current_key != until {
+            nodes.push(current_key);
+            let AnyComputeKey::ElementWiseComputeNodeKey(key) = current_key else {
+                unreachable!()
+            };
+            current_key = self.element_wise.get(&key).unwrap().value;
+        }
+        nodes
+    }
+
     fn resolve_mat_mul(
         &mut self,
         key: MatMulComputeNodeKey,
@@ -146,7 +360,7 @@ impl ComputeGraphInner {
 
         let first = self.resolve(first, &mut *command_encoder);
         let second = self.resolve(second, &mut *command_encoder);
-        let kernel = UntypedMatMul::new(first.datatype());
+        let kernel = UntypedMatMul::new(first.datatype(), first.layout().rank() as u32);
         let query = PerformanceQueries::new(first.device());
         let result = kernel.run_with_query(&first, &second, Some(&query), command_encoder);
         self.timing_information.insert(key.into(), query);
@@ -169,7 +383,8 @@ impl ComputeGraphInner {
     ) -> TensorData {
         let operation = self.reduce.get(&key).unwrap();
         let mut input = operation.value;
-        let axis = operation.axis;
+        let axes = operation.axes.clone();
+        let keepdim = operation.keepdim;
         let function = operation.function.clone();
 
         let element_wise_before =
@@ -180,19 +395,99 @@ impl ComputeGraphInner {
             } else {
                 Vec::new()
             };
-
+        let input_key = input;
         let input = self.resolve(input, &mut *command_encoder);
+        let split = reduce_split_count(&input, &axes) > 1;
+        let siblings = self.sibling_reductions(key, input_key, &axes, keepdim, split);
+        self.ran_reductions.insert(key);
+
         let mut kernel = UntypedReduceKernel::new(function, input.datatype());
         let element_wise_before =
             element_wise::UntypedElementWiseKernel::new(element_wise_before, input.datatype());
         let element_wise_after =
-            element_wise::UntypedElementWiseKernel::new(then, element_wise_before.out_datatype());
+            element_wise::UntypedElementWiseKernel::new(then, kernel.reduce_output_datatype());
         kernel.set_post_element_wise(element_wise_after);
         kernel.set_pre_element_wise(element_wise_before);
+        for sibling in &siblings {
+            kernel.add_output(
+                sibling.function.clone(),
+                element_wise::UntypedElementWiseKernel::new(
+                    sibling.element_wise_before.clone(),
+                    input.datatype(),
+                ),
+            );
+        }
         let query = PerformanceQueries::new(input.device());
-        let result = kernel.run_with_query(&input, axis, Some(&query), command_encoder);
+        let mut results =
+            kernel.run_with_query(&input, &axes, keepdim, Some(&query), command_encoder);
         self.timing_information.insert(key.into(), query);
-        result
+        // The siblings are cached until they are resolved so they don't run again
+        for (sibling, result) in siblings.into_iter().zip(results.drain(1..)) {
+            self.cached.insert(sibling.key.into(), result);
+            self.ran_reductions.insert(sibling.key);
+        }
+        results.remove(0)
+    }
+
+    /// Find the other reductions that read the same input after their element-wise functions with
+    /// the same axes. They are merged into the same kernel so the input is only read once. Only
+    /// reductions a live tensor still needs are merged
+    fn sibling_reductions(
+        &self,
+        key: ReduceComputeNodeKey,
+        input: AnyComputeKey,
+        axes: &[usize],
+        keepdim: bool,
+        split: bool,
+    ) -> Vec<SiblingReduction> {
+        let operation = &self.reduce[&key];
+        let paired = operation.paired;
+        let mut siblings = self
+            .reduce
+            .iter()
+            .filter(|(other, operation)| {
+                **other != key
+                    && !self.ran_reductions.contains(*other)
+                    && self.live.contains(&(**other).into())
+                    && *operation.axes == *axes
+                    && operation.keepdim == keepdim
+            })
+            .filter_map(|(other, operation)| {
+                let (element_wise_before, other_input) = match operation.value {
+                    AnyComputeKey::ElementWiseComputeNodeKey(key) => {
+                        self.collect_element_wise_ops(key)
+                    }
+                    value => (Vec::new(), value),
+                };
+                (other_input == input).then(|| SiblingReduction {
+                    key: *other,
+                    function: operation.function.clone(),
+                    element_wise_before,
+                })
+            })
+            .collect::<Vec<_>>();
+        // The reduction this one was created with always runs with it, so it goes first
+        siblings.sort_by_key(|sibling| (Some(sibling.key) != paired, sibling.key.0));
+        // Each output takes a storage buffer and a uniform for its layout. The single kernel also
+        // binds the input. A split reduction keeps a partial state (and index) buffer for each
+        // output, and the kernel that combines them binds them with the outputs, which is more than
+        // the kernel that reads the input. Both kernels take three more uniforms
+        let storage_buffers = |function: &ReduceFunction| match split {
+            true => 2 + function.returns_index() as usize,
+            false => 1,
+        };
+        let mut storage = !split as usize + storage_buffers(&operation.function);
+        let mut uniforms = 4;
+        siblings.retain(|sibling| {
+            let needed = storage + storage_buffers(&sibling.function);
+            let fits = needed <= MAX_STORAGE_BUFFERS && uniforms < MAX_UNIFORM_BUFFERS;
+            if fits {
+                storage = needed;
+                uniforms += 1;
+            }
+            fits
+        });
+        siblings
     }
 
     fn resolve_slice(
@@ -243,7 +538,59 @@ impl ComputeGraphInner {
         result
     }
 
+    fn resolve_scan(
+        &mut self,
+        key: ScanComputeNodeKey,
+        command_encoder: &mut CommandEncoder,
+    ) -> TensorData {
+        let operation = self.scan.get(&key).unwrap();
+        let function = operation.function.clone();
+        let axis = operation.axis;
+        let exclusive = operation.exclusive;
+        let input = self.resolve(operation.input, &mut *command_encoder);
+        let kernel = UntypedScanKernel::new(function, axis, exclusive, input.datatype());
+
+        let query = PerformanceQueries::new(input.device());
+        let result = kernel.run_with_query(&input, Some(&query), command_encoder);
+        self.timing_information.insert(key.into(), query);
+        result
+    }
+
+    fn resolve_sort(
+        &mut self,
+        key: SortComputeNodeKey,
+        command_encoder: &mut CommandEncoder,
+    ) -> TensorData {
+        let operation = self.sort.get(&key).unwrap();
+        let axis = operation.axis;
+        let descending = operation.descending;
+        let k = operation.k;
+        let output = operation.output;
+        let sibling = operation.sibling;
+        let input = self.resolve(operation.input, &mut *command_encoder);
+        let kernel = UntypedSortKernel::new(axis, descending, input.datatype());
+
+        let query = PerformanceQueries::new(input.device());
+        let [values, indices] = kernel.run_with_query(&input, k, Some(&query), command_encoder);
+        self.timing_information.insert(key.into(), query);
+        // The other output is computed by the same kernels, so cache it if it can still be resolved
+        let (result, sibling_result) = match output {
+            SortOutput::Values => (values, indices),
+            SortOutput::Indices => (indices, values),
+        };
+        let sibling = AnyComputeKey::from(sibling);
+        if self.live.contains(&sibling) {
+            self.cached.insert(sibling, sibling_result);
+        } else {
+            self.cached.remove(&sibling);
+        }
+        result
+    }
+
     fn resolve_tensor(&mut self, key: TensorComputeNodeKey, _: &mut CommandEncoder) -> TensorData {
-        self.tensor.get(&key).unwrap().clone()
+        match self.released.remove(&key) {
+            Some(data) => data,
+            None => self.tensor.get(&key).unwrap().clone(),
+        }
     }
 }
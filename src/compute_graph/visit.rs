@@ -1,11 +1,25 @@
 use super::{
     AnyComputeKey, ComputeGraphInner, ElementWiseComputeNodeKey, MapLayoutComputeNodeKey,
-    MatMulComputeNodeKey, PairWiseComputeNodeKey, ReduceComputeNodeKey, ResizeComputeNodeKey,
-    SliceAssignComputeNodeKey, TensorComputeNodeKey,
+    MatMulComputeNodeKey, NaryComputeNodeKey, PairWiseComputeNodeKey, ReduceComputeNodeKey,
+    ResizeComputeNodeKey, ScanComputeNodeKey, SliceAssignComputeNodeKey, SortComputeNodeKey,
+    TensorComputeNodeKey,
 };
 
 pub(crate) trait VisitComputeGraph: Sized {
+    /// Called before each node is visited. Returning false skips the node and its inputs
+    fn enter(&mut self, _key: AnyComputeKey) -> bool {
+        true
+    }
+
     fn visit(&mut self, graph: &ComputeGraphInner, key: AnyComputeKey) {
+        // In-place nodes that already ran are stored as tensors
+        let key = match graph.resolved.get(&key) {
+            Some(tensor) => (*tensor).into(),
+            None => key,
+        };
+        if !self.enter(key) {
+            return;
+        }
         match key {
             AnyComputeKey::ElementWiseComputeNodeKey(element_wise_compute_node_key) => {
                 self.visit_element_wise(graph, element_wise_compute_node_key)
@@ -13,6 +27,9 @@ pub(crate) trait VisitComputeGraph: Sized {
             AnyComputeKey::PairWiseComputeNodeKey(pair_wise_compute_node_key) => {
                 self.visit_pair_wise(graph, pair_wise_compute_node_key)
             }
+            AnyComputeKey::NaryComputeNodeKey(nary_compute_node_key) => {
+                self.visit_nary(graph, nary_compute_node_key)
+            }
             AnyComputeKey::MatMulComputeNodeKey(mat_mul_compute_node_key) => {
                 self.visit_mat_mul(graph, mat_mul_compute_node_key);
             }
@@ -28,6 +45,12 @@ pub(crate) trait VisitComputeGraph: Sized {
             AnyComputeKey::SliceAssignComputeNodeKey(slice_assign_compute_node_key) => {
                 self.visit_slice_assign(graph, slice_assign_compute_node_key);
             }
+            AnyComputeKey::ScanComputeNodeKey(scan_compute_node_key) => {
+                self.visit_scan(graph, scan_compute_node_key);
+            }
+            AnyComputeKey::SortComputeNodeKey(sort_compute_node_key) => {
+                self.visit_sort(graph, sort_compute_node_key);
+            }
             AnyComputeKey::TensorComputeNodeKey(tensor_compute_node_key) => {
                 self.visit_tensor(graph, tensor_compute_node_key);
             }
@@ -42,6 +65,10 @@ pub(crate) trait VisitComputeGraph: Sized {
         visit_pair_wise(self, graph, key);
     }
 
+    fn visit_nary(&mut self, graph: &ComputeGraphInner, key: NaryComputeNodeKey) {
+        visit_nary(self, graph, key);
+    }
+
     fn visit_mat_mul(&mut self, graph: &ComputeGraphInner, key: MatMulComputeNodeKey) {
         visit_mat_mul(self, graph, key);
     }
@@ -62,6 +89,14 @@ pub(crate) trait VisitComputeGraph: Sized {
         visit_slice_assign(self, graph, key);
     }
 
+    fn visit_scan(&mut self, graph: &ComputeGraphInner, key: ScanComputeNodeKey) {
+        visit_scan(self, graph, key);
+    }
+
+    fn visit_sort(&mut self, graph: &ComputeGraphInner, key: SortComputeNodeKey) {
+        visit_sort(self, graph, key);
+    }
+
     fn visit_tensor(&mut self, graph: &ComputeGraphInner, key: TensorComputeNodeKey) {
         visit_tensor(self, graph, key);
     }
@@ -89,6 +124,17 @@ pub(crate) fn visit_pair_wise(
     visitor.visit(graph, second);
 }
 
+pub(crate) fn visit_nary(
+    visitor: &mut impl VisitComputeGraph,
+    graph: &ComputeGraphInner,
+    key: NaryComputeNodeKey,
+) {
+    let operation = graph.nary.get(&key).unwrap();
+    for input in operation.inputs.clone() {
+        visitor.visit(graph, input);
+    }
+}
+
 pub(crate) fn visit_mat_mul(
     visitor: &mut impl VisitComputeGraph,
     graph: &ComputeGraphInner,
@@ -143,6 +189,26 @@ pub(crate) fn visit_slice_assign(
     visitor.visit(graph, value);
 }
 
+pub(crate) fn visit_scan(
+    visitor: &mut impl VisitComputeGraph,
+    graph: &ComputeGraphInner,
+    key: ScanComputeNodeKey,
+) {
+    let operation = graph.scan.get(&key).unwrap();
+    let input = operation.input;
+    visitor.visit(graph, input);
+}
+
+pub(crate) fn visit_sort(
+    visitor: &mut impl VisitComputeGraph,
+    graph: &ComputeGraphInner,
+    key: SortComputeNodeKey,
+) {
+    let operation = graph.sort.get(&key).unwrap();
+    let input = operation.input;
+    visitor.visit(graph, input);
+}
+
 pub(crate) fn visit_tensor(
     _: &mut impl VisitComputeGraph,
     _: &ComputeGraphInner,
@@ -3,11 +3,14 @@ use std::collections::HashMap;
 use super::visit::VisitComputeGraph;
 use super::{
     AnyComputeKey, ComputeGraphInner, ElementWiseComputeNodeKey, MapLayoutComputeNodeKey,
-    MatMulComputeNodeKey, PairWiseComputeNodeKey, ReduceComputeNodeKey, ResizeComputeNodeKey,
-    SliceAssignComputeNodeKey, TensorComputeNodeKey, layout_pass,
+    MatMulComputeNodeKey, NaryComputeNodeKey, PairWiseComputeNodeKey, ReduceComputeNodeKey,
+    ResizeComputeNodeKey, ScanComputeNodeKey, SliceAssignComputeNodeKey, SortComputeNodeKey,
+    TensorComputeNodeKey, fusion_pass, layout_pass,
 };
 use tabbycat::Graph;
-use tabbycat::{Edge, GraphBuilder, GraphType, Identity, Stmt, StmtList};
+use tabbycat::{
+    AttrList, AttrType, Edge, GraphBuilder, GraphType, Identity, Stmt, StmtList, SubGraph,
+};
 
 impl ComputeGraphInner {
     pub(crate) fn graphvis(&self, root: AnyComputeKey) -> Graph {
@@ -16,6 +19,26 @@ impl ComputeGraphInner {
         let mut statements = Vec::new();
         let mut identities = HashMap::new();
         self.add_node_to_graph(&mut statements, root, &layout_pass, &mut identities);
+        let mut fusion_pass = fusion_pass::FusionPass::default();
+        fusion_pass.visit(self, root);
+        // Draw each group of nodes that run in a single kernel as a cluster
+        for (index, group) in fusion_pass.groups.iter().enumerate() {
+            let nodes = group.iter().map(|key| Stmt::Node {
+                id: identities[key].clone(),
+                port: None,
+                attr: None,
+            });
+            let label = AttrList::new().add_pair((
+                Identity::quoted("label"),
+                Identity::quoted(format!("fused kernel #{index}")),
+            ));
+            statements.push(Stmt::SubGraph(SubGraph::subgraph(
+                Some(Identity::quoted(format!("cluster_{index}"))),
+                StmtList::new()
+                    .add_attr(AttrType::Graph, label)
+                    .extend(nodes),
+            )));
+        }
         GraphBuilder::default()
             .graph_type(GraphType::DiGraph)
             .strict(false)
@@ -45,6 +68,9 @@ impl ComputeGraphInner {
                 ),
             AnyComputeKey::PairWiseComputeNodeKey(pair_wise_compute_node_key) => self
                 .add_pair_wise_to_graph(graph, pair_wise_compute_node_key, layout_pass, identities),
+            AnyComputeKey::NaryComputeNodeKey(nary_compute_node_key) => {
+                self.add_nary_to_graph(graph, nary_compute_node_key, layout_pass, identities)
+            }
             AnyComputeKey::MatMulComputeNodeKey(mat_mul_compute_node_key) => {
                 self.add_mat_mul_to_graph(graph, mat_mul_compute_node_key, layout_pass, identities)
             }
@@ -67,6 +93,12 @@ impl ComputeGraphInner {
                     layout_pass,
                     identities,
                 ),
+            AnyComputeKey::ScanComputeNodeKey(scan_compute_node_key) => {
+                self.add_scan_to_graph(graph, scan_compute_node_key, layout_pass, identities)
+            }
+            AnyComputeKey::SortComputeNodeKey(sort_compute_node_key) => {
+                self.add_sort_to_graph(graph, sort_compute_node_key, layout_pass, identities)
+            }
         };
         identities.insert(key, id.clone());
         id
@@ -130,6 +162,39 @@ impl ComputeGraphInner {
         id
     }
 
+    fn add_nary_to_graph(
+        &self,
+        graph: &mut Vec<Stmt>,
+        key: NaryComputeNodeKey,
+        layout_pass: &layout_pass::LayoutPass,
+        identities: &mut HashMap<AnyComputeKey, Identity>,
+    ) -> Identity {
+        let operation = self.nary.get(&key).unwrap();
+        let inputs = operation
+            .inputs
+            .iter()
+            .map(|input| self.add_node_to_graph(graph, *input, layout_pass, identities))
+            .collect::<Vec<_>>();
+        let output_layout = layout_pass.output_layout.get(&key.into()).unwrap();
+        let id = Identity::quoted(format!(
+            "{} ({}) #{}",
+            operation.function.name(),
+            output_layout,
+            key.0
+        ));
+        graph.push(Stmt::Node {
+            id: id.clone(),
+            port: None,
+            attr: None,
+        });
+        for input in inputs {
+            graph.push(Stmt::Edge(
+                Edge::head_node(input, None).arrow_to_node(id.clone(), None),
+            ));
+        }
+        id
+    }
+
     fn add_mat_mul_to_graph(
         &self,
         graph: &mut Vec<Stmt>,
@@ -253,6 +318,60 @@ impl ComputeGraphInner {
         id
     }
 
+    fn add_scan_to_graph(
+        &self,
+        graph: &mut Vec<Stmt>,
+        key: ScanComputeNodeKey,
+        layout_pass: &layout_pass::LayoutPass,
+        identities: &mut HashMap<AnyComputeKey, Identity>,
+    ) -> Identity {
+        let operation = self.scan.get(&key).unwrap();
+        let input = self.add_node_to_graph(graph, operation.input, layout_pass, identities);
+        let output_layout = layout_pass.output_layout.get(&key.into()).unwrap();
+        let id = Identity::quoted(format!(
+            "{} ({}) #{}",
+            operation.function.name(),
+            output_layout,
+            key.0
+        ));
+        graph.push(Stmt::Node {
+            id: id.clone(),
+            port: None,
+            attr: None,
+        });
+        graph.push(Stmt::Edge(
+            Edge::head_node(input, None).arrow_to_node(id.clone(), None),
+        ));
+        id
+    }
+
+    fn add_sort_to_graph(
+        &self,
+        graph: &mut Vec<Stmt>,
+        key: SortComputeNodeKey,
+        layout_pass: &layout_pass::LayoutPass,
+        identities: &mut HashMap<AnyComputeKey, Identity>,
+    ) -> Identity {
+        let operation = self.sort.get(&key).unwrap();
+        let input = self.add_node_to_graph(graph, operation.input, layout_pass, identities);
+        let output_layout = layout_pass.output_layout.get(&key.into()).unwrap();
+        let id = Identity::quoted(format!(
+            "{} ({}) #{}",
+            operation.name(),
+            output_layout,
+            key.0
+        ));
+        graph.push(Stmt::Node {
+            id: id.clone(),
+            port: None,
+            attr: None,
+        });
+        graph.push(Stmt::Edge(
+            Edge::head_node(input, None).arrow_to_node(id.clone(), None),
+        ));
+        id
+    }
+
     fn add_tensor_to_graph(
         &self,
         graph: &mut Vec<Stmt>,
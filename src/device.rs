@@ -1,8 +1,15 @@
-use std::{borrow::Cow, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use crate::kernel::ComputePipeline;
 
 struct DeviceInner {
     device: wgpu::Device,
     queue: wgpu::Queue,
+    compute_pipelines: RwLock<HashMap<String, Arc<ComputePipeline>>>,
 }
 
 #[derive(Clone)]
@@ -14,10 +21,27 @@ impl Device {
     pub async fn new() -> Result<Self, wgpu::RequestDeviceError> {
         let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
         let adapter = instance.request_adapter(&Default::default()).await.unwrap();
+        // Subgroups are optional. Reductions fall back to workgroup memory without them
+        let optional_features = adapter.features() & wgpu::Features::SUBGROUP;
+        Self::from_adapter(&adapter, optional_features).await
+    }
+
+    /// Create a device that doesn't use subgroup operations even if the adapter supports them
+    #[cfg(test)]
+    pub(crate) async fn without_subgroups() -> Result<Self, wgpu::RequestDeviceError> {
+        let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
+        let adapter = instance.request_adapter(&Default::default()).await.unwrap();
+        Self::from_adapter(&adapter, wgpu::Features::empty()).await
+    }
+
+    async fn from_adapter(
+        adapter: &wgpu::Adapter,
+        optional_features: wgpu::Features,
+    ) -> Result<Self, wgpu::RequestDeviceError> {
         let (device, queue) = adapter
             .request_device(
                 &wgpu::DeviceDescriptor {
-                    required_features: wgpu::Features::SUBGROUP
+                    required_features: optional_features
                         | wgpu::Features::TIMESTAMP_QUERY
                         | wgpu::Features::SHADER_F16,
                     ..Default::default()
@@ -27,7 +51,11 @@ impl Device {
             .await?;
 
         Ok(Self {
-            inner: Arc::new(DeviceInner { device, queue }),
+            inner: Arc::new(DeviceInner {
+                device,
+                queue,
+                compute_pipelines: Default::default(),
+            }),
         })
     }
 
@@ -43,6 +71,38 @@ impl Device {
             })
     }
 
+    /// Get the compute pipeline for a shader source or create it if it doesn't exist yet
+    pub(crate) fn cached_compute_pipeline(
+        &self,
+        source: &str,
+        create: impl FnOnce() -> ComputePipeline,
+    ) -> Arc<ComputePipeline> {
+        if let Some(pipeline) = self.inner.compute_pipelines.read().unwrap().get(source) {
+            return pipeline.clone();
+        }
+        let pipeline = Arc::new(create());
+        self.inner
+            .compute_pipelines
+            .write()
+            .unwrap()
+            .entry(source.to_string())
+            .or_insert(pipeline)
+            .clone()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn cached_compute_pipeline_count(&self) -> usize {
+        self.inner.compute_pipelines.read().unwrap().len()
+    }
+
+    /// Check if kernels can use subgroup operations on this device
+    pub fn subgroups_supported(&self) -> bool {
+        self.inner
+            .device
+            .features()
+            .contains(wgpu::Features::SUBGROUP)
+    }
+
     pub fn wgpu_device(&self) -> &wgpu::Device {
         &self.inner.device
     }
@@ -1,20 +1,21 @@
 use std::{
-    fmt::Display,
-    ops::{Add, Div, Mul, Neg, Sub},
+    fmt::{Display, Write},
+    marker::PhantomData,
+    ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign},
     sync::OnceLock,
 };
 
 use wgpu::CommandEncoder;
 
 use crate::{
-    Tensor,
+    DynTensor, Tensor,
     compute_graph::AnyComputeKey,
-    kernel::{Function, GenericKernel},
+    kernel::{Function, GenericKernel, InvalidFunctionError, check_function_body},
     layout::TILE_SIZE,
     padded_tensor_size,
     query::PerformanceQueries,
-    tensor::{DataType, DataTypeEnum, TensorData},
-    visit_tiled::VisitTiledKernel,
+    tensor::{DataType, DataTypeEnum, FloatDataType, TensorData, float_tensor_ops, tensor_ops},
+    visit_tiled::{MapOutput, VisitTiledKernel},
 };
 
 #[cfg(test)]
@@ -61,19 +62,42 @@ impl UntypedElementWiseKernel {
         }
     }
 
-    pub fn add_functions(&self, kernel: &mut GenericKernel) -> Vec<Function> {
+    /// Add the functions to the kernel in the order they run. If `vectorized` is true, they
+    /// take and return `vec4`s
+    pub fn add_functions(&self, kernel: &mut GenericKernel, vectorized: bool) -> Vec<Function> {
         let mut input_datatype = self.input_datatype;
         self.functions
             .iter()
             .rev()
             .map(|f| {
+                // Functions that only work on scalars are declared for scalars and run on each lane
+                let vector = vectorized && !f.scalar_only;
+                let ty = |datatype: DataTypeEnum| match vector {
+                    true => format!("vec4<{datatype}>"),
+                    false => datatype.to_string(),
+                };
+                // Scalars are passed in as uniforms so the same kernel can be reused for any value
+                let mut operation = String::new();
+                for (i, value) in f.scalars.iter().enumerate() {
+                    let scalar = kernel.add_float_input(*value);
+                    writeln!(
+                        &mut operation,
+                        "let scalar_{i} = {}({scalar});",
+                        ty(input_datatype)
+                    )
+                    .unwrap();
+                }
+                operation.push_str(&f.operation);
                 let function = kernel.add_function(
-                    f.datatype,
-                    f.operation.clone(),
-                    [("input".to_string(), input_datatype.to_string())],
+                    ty(f.datatype),
+                    operation,
+                    [("input".to_string(), ty(input_datatype))],
                 );
                 input_datatype = f.datatype;
-                function
+                match vectorized && !vector {
+                    true => kernel.add_lane_wise_function(&function),
+                    false => function,
+                }
             })
             .collect()
     }
@@ -95,36 +119,29 @@ impl UntypedElementWiseKernel {
         let contiguous = tensor.layout().is_contiguous();
         let rank = tensor.layout().rank();
         let output_type = self.out_datatype();
-        let re_use_allocation = self.input_datatype == output_type && tensor.owned();
+        let re_use_allocation = self.input_datatype == output_type
+            && tensor.owned()
+            && !tensor.layout().allocation_overlaps();
         let requires_new_tensor = !re_use_allocation;
 
         let functions = OnceLock::new();
         let create_kernel = || {
-            let mut datatypes = vec![tensor.datatype()];
-            if requires_new_tensor {
-                datatypes.push(output_type);
-            }
-            VisitTiledKernel::new(
+            let output = if requires_new_tensor {
+                MapOutput::New(output_type)
+            } else {
+                MapOutput::InPlace(0)
+            };
+            VisitTiledKernel::map(
                 rank as u32,
                 TILE_SIZE,
                 contiguous,
-                datatypes,
-                |kernel, indexes, tensors| match (indexes, tensors) {
-                    ([index], [tensor]) => {
-                        let result = functions
-                            .get_or_init(|| self.add_functions(kernel))
-                            .iter()
-                            .fold(format!("{tensor}[{index}]"), |acc, f| f.call(vec![acc]));
-                        format!("{tensor}[{index}] = {result};")
-                    }
-                    ([in_index, out_index], [tensor, output]) => {
-                        let result = functions
-                            .get_or_init(|| self.add_functions(kernel))
-                            .iter()
-                            .fold(format!("{tensor}[{in_index}]"), |acc, f| f.call(vec![acc]));
-                        format!("{output}[{out_index}] = {result};")
-                    }
-                    _ => panic!("invalid number of tensors"),
+                vec![tensor.datatype()],
+                output,
+                |kernel, values, vectorized| {
+                    functions
+                        .get_or_init(|| self.add_functions(kernel, vectorized))
+                        .iter()
+                        .fold(values[0].clone(), |acc, f| f.call(vec![acc]))
                 },
             )
         };
@@ -168,23 +185,40 @@ impl UntypedElementWiseKernel {
 pub struct ElementWiseFunction {
     name: Option<String>,
     operation: String,
+    scalars: Vec<f32>,
     datatype: DataTypeEnum,
+    scalar_only: bool,
 }
 
 impl ElementWiseFunction {
-    fn new(operation: impl Display, datatype: DataTypeEnum) -> Self {
+    pub(crate) fn new(operation: impl Display, datatype: DataTypeEnum) -> Self {
         Self {
             name: None,
             operation: operation.to_string(),
+            scalars: Vec::new(),
             datatype,
+            scalar_only: false,
         }
     }
 
-    fn with_name(mut self, name: impl ToString) -> Self {
+    /// Mark the operation as only valid for scalars. Kernels that work on `vec4`s run it on
+    /// each lane instead of on the whole vector
+    pub(crate) fn scalar_only(mut self) -> Self {
+        self.scalar_only = true;
+        self
+    }
+
+    pub(crate) fn with_name(mut self, name: impl ToString) -> Self {
         self.name = Some(name.to_string());
         self
     }
 
+    /// Add a scalar operand to the function. The operation can read the nth scalar as `scalar_n`
+    pub(crate) fn with_scalar(mut self, value: f32) -> Self {
+        self.scalars.push(value);
+        self
+    }
+
     pub(crate) fn name(&self) -> &str {
         self.name.as_deref().unwrap_or("element_wise")
     }
@@ -192,6 +226,315 @@ impl ElementWiseFunction {
     pub(crate) fn datatype(&self) -> DataTypeEnum {
         self.datatype
     }
+
+    pub(crate) fn operation(&self) -> &str {
+        &self.operation
+    }
+
+    pub(crate) fn scalars(&self) -> &[f32] {
+        &self.scalars
+    }
+
+    fn add_const(value: f32, datatype: DataTypeEnum) -> Self {
+        Self::new("let output = input + scalar_0;", datatype)
+            .with_scalar(value)
+            .with_name("add_const")
+    }
+
+    fn subtract_const(value: f32, datatype: DataTypeEnum) -> Self {
+        Self::new("let output = input - scalar_0;", datatype)
+            .with_scalar(value)
+            .with_name("subtract_const")
+    }
+
+    fn subtract_from_const(value: f32, datatype: DataTypeEnum) -> Self {
+        Self::new("let output = scalar_0 - input;", datatype)
+            .with_scalar(value)
+            .with_name("subtract_const")
+    }
+
+    fn multiply_const(value: f32, datatype: DataTypeEnum) -> Self {
+        Self::new("let output = input * scalar_0;", datatype)
+            .with_scalar(value)
+            .with_name("multiply_const")
+    }
+
+    fn divide_const(value: f32, datatype: DataTypeEnum) -> Self {
+        Self::new("let output = input / scalar_0;", datatype)
+            .with_scalar(value)
+            .with_name("divide_const")
+    }
+
+    fn divide_from_const(value: f32, datatype: DataTypeEnum) -> Self {
+        Self::new("let output = scalar_0 / input;", datatype)
+            .with_scalar(value)
+            .with_name("divide_const")
+    }
+
+    fn neg(datatype: DataTypeEnum) -> Self {
+        Self::new("let output = -input;", datatype).with_name("neg")
+    }
+
+    pub(crate) fn cast(datatype: DataTypeEnum) -> Self {
+        Self::new(format!("let output = {datatype}(input);"), datatype)
+            .with_name("cast")
+            .scalar_only()
+    }
+}
+
+/// A user defined element-wise function from elements of type `I` to elements of type `O`.
+///
+/// The body is a WGSL snippet that reads `input` and defines `output`. Custom functions fuse
+/// with the built in element-wise operations around them.
+///
+/// ```rust, no_run
+/// # use wgpu_compute::CustomElementWise;
+/// let cube = CustomElementWise::<f32, f32>::new("cube", "let output = input * input * input;")
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct CustomElementWise<I, O> {
+    function: ElementWiseFunction,
+    datatype: PhantomData<(I, O)>,
+}
+
+impl<I: DataType, O: DataType> CustomElementWise<I, O> {
+    /// Create a new function with a name that shows up in the graph visualization. The body is
+    /// validated up front so errors are reported here instead of when the kernel runs.
+    pub fn new(name: impl ToString, body: impl Display) -> Result<Self, InvalidFunctionError> {
+        let name = name.to_string();
+        let body = body.to_string();
+
+        check_function_body(&name, &body)?;
+
+        // The body is written for scalars, so it runs on each lane of vectorized kernels
+        let function = ElementWiseFunction::new(body, O::WGSL_TYPE)
+            .with_name(&name)
+            .scalar_only();
+
+        let mut kernel = GenericKernel::new();
+        let input = kernel.add_tensor_input(1, false, I::WGSL_TYPE);
+        let output = kernel.add_tensor_input(1, true, O::WGSL_TYPE);
+        let functions = UntypedElementWiseKernel::new(vec![function.clone()], I::WGSL_TYPE)
+            .add_functions(&mut kernel, false);
+        let result = functions[0].call(vec![format!("{input}[0]")]);
+        kernel.set_body(format!("{output}[0] = {result};"));
+        kernel
+            .validate()
+            .map_err(|message| InvalidFunctionError::new(&name, message))?;
+
+        Ok(Self {
+            function,
+            datatype: PhantomData,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        self.function.name()
+    }
+}
+
+impl<const R: usize, D: DataType> Tensor<R, D> {
+    /// Apply a custom element-wise function to each element of the tensor
+    pub fn apply<O: DataType>(&self, function: &CustomElementWise<D, O>) -> Tensor<R, O> {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: function.function.clone(),
+        })
+    }
+}
+
+impl<D: DataType> DynTensor<D> {
+    /// Apply a custom element-wise function to each element of the tensor
+    pub fn apply<O: DataType>(&self, function: &CustomElementWise<D, O>) -> DynTensor<O> {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: function.function.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_custom_element_wise() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., 2.], [3., 4.], [5., 6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let cube =
+        CustomElementWise::<f32, f32>::new("cube", "let output = input * input * input;").unwrap();
+    let tensor = (tensor.apply(&cube) + 1.).exp().log();
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    let graph = format!("{}", tensor.graphvis());
+    println!("{graph}");
+    assert!(graph.contains("cube"));
+    for i in 0..3 {
+        for j in 0..2 {
+            let expected = data[i][j] * data[i][j] * data[i][j] + 1.;
+            assert!((output[[i, j]] - expected).abs() < 0.01);
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_custom_element_wise_changes_type() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., 2.], [3., 4.], [5., 6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let half =
+        CustomElementWise::<f32, half::f16>::new("half", "let output = f16(input / 2.0);").unwrap();
+    let tensor = tensor.apply(&half);
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    for i in 0..3 {
+        for j in 0..2 {
+            assert_eq!(output[[i, j]], half::f16::from_f32(data[i][j] / 2.));
+        }
+    }
+}
+
+#[test]
+fn test_custom_element_wise_validation() {
+    assert!(CustomElementWise::<f32, f32>::new("valid", "let output = input * 2.0;").is_ok());
+
+    let error = CustomElementWise::<f32, f32>::new("missing_output", "let x = input;").unwrap_err();
+    assert_eq!(error.name(), "missing_output");
+
+    assert!(CustomElementWise::<f32, f32>::new("syntax", "let output = input +;").is_err());
+    assert!(CustomElementWise::<f32, f32>::new("wrong_type", "let output = input > 0.0;").is_err());
+    assert!(
+        CustomElementWise::<f32, f32>::new(
+            "escape",
+            "let output = input; return output; } fn other() -> f32 { let output = 0.0;"
+        )
+        .is_err()
+    );
+
+    // Braces in comments don't count
+    assert!(
+        CustomElementWise::<f32, f32>::new(
+            "comments",
+            "// the output is {input * 2}\nlet output = input * 2.0; /* } /* nested */ { */"
+        )
+        .is_ok()
+    );
+    assert!(
+        CustomElementWise::<f32, f32>::new("trailing_comment", "let output = input; // }").is_ok()
+    );
+    assert!(
+        CustomElementWise::<f32, f32>::new(
+            "commented_escape",
+            "let output = input; return output; } // {\nfn other() -> f32 { let output = 0.0;"
+        )
+        .is_err()
+    );
+    assert!(
+        CustomElementWise::<f32, f32>::new("unterminated", "let output = input; /* {").is_err()
+    );
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_many_scalars_fused() {
+    use crate::Sum;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., 2.], [3., 4.], [5., 6.]];
+    let tensor = Tensor::new(&device, &data);
+    let expected = |x: f32| (0..16).fold(x, |x, _| x * 0.5 + 1.);
+
+    // Every scalar shares one uniform, so the chain fits in one kernel
+    let output = (0..16).fold(tensor.clone(), |tensor, _| tensor * 0.5 + 1.);
+    let as_slice = output.as_slice().await.unwrap();
+    for (i, row) in data.iter().enumerate() {
+        for (j, value) in row.iter().enumerate() {
+            assert!((as_slice[[i, j]] - expected(*value)).abs() < 1e-5);
+        }
+    }
+    assert_eq!(output.all_timing_information().await.len(), 1);
+
+    let output = (0..16)
+        .fold(tensor.clone(), |tensor, _| tensor * 0.5 + 1.)
+        .sum(0);
+    let output = (0..16).fold(output, |tensor, _| tensor * 0.5 + 1.);
+    let as_slice = output.as_slice().await.unwrap();
+    for j in 0..2 {
+        let sum = data.iter().map(|row| expected(row[j])).sum::<f32>();
+        assert!((as_slice[[j]] - expected(sum)).abs() < 1e-4);
+    }
+    assert_eq!(output.all_timing_information().await.len(), 1);
+}
+
+#[test]
+fn test_many_scalars_kernel() {
+    let datatype = DataTypeEnum::F32;
+    let functions = (0..20)
+        .map(|i| {
+            ElementWiseFunction::new("let output = input * scalar_0;", datatype)
+                .with_scalar(i as f32)
+        })
+        .collect();
+    let mut kernel = GenericKernel::new();
+    let input = kernel.add_tensor_input(1, false, datatype);
+    let output = kernel.add_tensor_input(1, true, datatype);
+    let functions =
+        UntypedElementWiseKernel::new(functions, datatype).add_functions(&mut kernel, false);
+    let result = functions
+        .iter()
+        .fold(format!("{input}[0]"), |acc, f| f.call(vec![acc]));
+    kernel.set_body(format!("{output}[0] = {result};"));
+    kernel.validate().unwrap();
+    // Two tensors and the scalars
+    assert_eq!(kernel.source().matches("var<uniform>").count(), 3);
+}
+
+#[test]
+fn test_vectorized_functions_kernel() {
+    let datatype = DataTypeEnum::F16;
+    // The functions run last to first: add_const and exp work on whole vectors, and the cast
+    // runs on each lane
+    let functions = vec![
+        ElementWiseFunction::cast(DataTypeEnum::F32),
+        ElementWiseFunction::new("let output = exp(input);", datatype),
+        ElementWiseFunction::add_const(1., datatype),
+    ];
+    let mut kernel = GenericKernel::new();
+    let input = kernel.add_vectorized_tensor_input(1, false, datatype);
+    let output = kernel.add_vectorized_tensor_input(1, true, DataTypeEnum::F32);
+    let functions =
+        UntypedElementWiseKernel::new(functions, datatype).add_functions(&mut kernel, true);
+    let result = functions
+        .iter()
+        .fold(format!("{input}[0]"), |acc, f| f.call(vec![acc]));
+    kernel.set_body(format!("{output}[0] = {result};"));
+    kernel.validate().unwrap();
+    let source = kernel.source();
+    assert!(source.contains("exp(input)"));
+    assert!(source.contains("let scalar_0 = vec4<f16>("));
+    assert!(source.contains("-> vec4<f32>"));
 }
 
 impl<const R: usize, T: DataType> Add<f32> for Tensor<R, T> {
@@ -200,11 +543,7 @@ impl<const R: usize, T: DataType> Add<f32> for Tensor<R, T> {
     fn add(self, rhs: f32) -> Self::Output {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
-            function: ElementWiseFunction::new(
-                format!("let output = input + {};", rhs),
-                T::WGSL_TYPE,
-            )
-            .with_name("add_const"),
+            function: ElementWiseFunction::add_const(rhs, T::WGSL_TYPE),
         })
     }
 }
@@ -270,6 +609,35 @@ impl<const R: usize, T: DataType> Add<Tensor<R, T>> for f32 {
     }
 }
 
+#[cfg(test)]
+#[tokio::test]
+async fn test_element_wise_vectorized_tail() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    // Cover both whole vec4s and every length of the scalar tail
+    for len in 1..=9 {
+        let data = (0..len).map(|i| i as f32).collect::<Vec<_>>();
+        let tensor = Tensor::new(&device, &data);
+
+        let in_place = (tensor.clone() + 1.) * 2.;
+        let cast: Tensor<1, half::f16> = (tensor * 2.).cast();
+
+        let in_place = in_place.as_slice().await.unwrap();
+        let cast = cast.as_slice().await.unwrap();
+        println!("{:?}", in_place);
+        println!("{:?}", cast);
+        for i in 0..len {
+            assert_eq!(in_place[[i]], (data[i] + 1.) * 2.);
+            assert_eq!(cast[[i]], half::f16::from_f32(data[i] * 2.));
+        }
+    }
+}
+
 #[cfg(test)]
 #[tokio::test]
 async fn test_add_const_reversed() {
@@ -442,17 +810,36 @@ async fn test_merge_add_const() {
     assert_eq!(output[[2, 1]], 14.);
 }
 
+#[cfg(test)]
+#[tokio::test]
+async fn test_add_const_reuses_pipeline() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., 2.], [3., 4.], [5., 6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let output = (tensor.clone() + 1.0).as_slice().await.unwrap();
+    assert_eq!(output[[2, 1]], 7.);
+    let pipelines = device.cached_compute_pipeline_count();
+
+    let output = (tensor.clone() + 2.0).as_slice().await.unwrap();
+    assert_eq!(output[[0, 0]], 3.);
+    assert_eq!(output[[2, 1]], 8.);
+    assert_eq!(device.cached_compute_pipeline_count(), pipelines);
+}
+
 impl<const R: usize, T: DataType> Sub<f32> for Tensor<R, T> {
     type Output = Tensor<R, T>;
 
     fn sub(self, rhs: f32) -> Self::Output {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
-            function: ElementWiseFunction::new(
-                format!("let output = input - {};", rhs),
-                T::WGSL_TYPE,
-            )
-            .with_name("subtract_const"),
+            function: ElementWiseFunction::subtract_const(rhs, T::WGSL_TYPE),
         })
     }
 }
@@ -488,11 +875,7 @@ impl<const R: usize, T: DataType> Sub<Tensor<R, T>> for f32 {
     fn sub(self, rhs: Tensor<R, T>) -> Self::Output {
         rhs.element_wise(ElementWiseOperation {
             value: rhs.key(),
-            function: ElementWiseFunction::new(
-                format!("let output = {self} - input;"),
-                T::WGSL_TYPE,
-            )
-            .with_name("subtract_const"),
+            function: ElementWiseFunction::subtract_from_const(self, T::WGSL_TYPE),
         })
     }
 }
@@ -528,11 +911,7 @@ impl<const R: usize, T: DataType> Mul<f32> for Tensor<R, T> {
     fn mul(self, rhs: f32) -> Self::Output {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
-            function: ElementWiseFunction::new(
-                format!("let output = input * {};", rhs),
-                T::WGSL_TYPE,
-            )
-            .with_name("multiply_const"),
+            function: ElementWiseFunction::multiply_const(rhs, T::WGSL_TYPE),
         })
     }
 }
@@ -601,11 +980,7 @@ impl<const R: usize, T: DataType> Div<f32> for Tensor<R, T> {
     fn div(self, rhs: f32) -> Self::Output {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
-            function: ElementWiseFunction::new(
-                format!("let output = input / {};", rhs),
-                T::WGSL_TYPE,
-            )
-            .with_name("divide_const"),
+            function: ElementWiseFunction::divide_const(rhs, T::WGSL_TYPE),
         })
     }
 }
@@ -641,11 +1016,7 @@ impl<const R: usize, T: DataType> Div<Tensor<R, T>> for f32 {
     fn div(self, rhs: Tensor<R, T>) -> Self::Output {
         rhs.element_wise(ElementWiseOperation {
             value: rhs.key(),
-            function: ElementWiseFunction::new(
-                format!("let output = {} / input;", self),
-                T::WGSL_TYPE,
-            )
-            .with_name("divide_const"),
+            function: ElementWiseFunction::divide_from_const(self, T::WGSL_TYPE),
         })
     }
 }
@@ -675,7 +1046,7 @@ async fn test_div_const_reversed() {
     assert_eq!(output[[2, 1]], 6.0 / data[2][1]);
 }
 
-impl<const R: usize, D: DataType> Tensor<R, D> {
+float_tensor_ops! {
     pub fn exp(&self) -> Self {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
@@ -710,7 +1081,7 @@ async fn test_exp() {
     assert!((output[[2, 1]] - data[2][1].exp()).abs() < 0.001);
 }
 
-impl<const R: usize, D: DataType> Tensor<R, D> {
+float_tensor_ops! {
     pub fn exp2(&self) -> Self {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
@@ -745,7 +1116,7 @@ async fn test_exp2() {
     assert!((output[[2, 1]] - data[2][1].exp2()).abs() < 0.001);
 }
 
-impl<const R: usize, D: DataType> Tensor<R, D> {
+float_tensor_ops! {
     pub fn log(&self) -> Self {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
@@ -780,7 +1151,7 @@ async fn test_log() {
     assert!((output[[2, 1]] - data[2][1].ln()).abs() < 0.001);
 }
 
-impl<const R: usize, D: DataType> Tensor<R, D> {
+float_tensor_ops! {
     pub fn log2(&self) -> Self {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
@@ -815,13 +1186,13 @@ async fn test_log2() {
     assert!((output[[2, 1]] - data[2][1].log2()).abs() < 0.001);
 }
 
-impl<const R: usize, T: DataType> Tensor<R, T> {
+tensor_ops! {
     pub fn sqr(&self) -> Self {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
             function: ElementWiseFunction::new(
                 "let output = input * input;".to_string(),
-                T::WGSL_TYPE,
+                D::WGSL_TYPE,
             )
             .with_name("sqr"),
         })
@@ -856,7 +1227,7 @@ async fn test_sqr() {
     assert!((output[[2, 1]] - 36. * 36.) < 0.001);
 }
 
-impl<const R: usize, D: DataType> Tensor<R, D> {
+float_tensor_ops! {
     pub fn sqrt(&self) -> Self {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
@@ -891,7 +1262,7 @@ async fn test_sqrt() {
     assert!((output[[2, 1]] - data[2][1].sqrt()).abs() < 0.001);
 }
 
-impl<const R: usize, D: DataType> Tensor<R, D> {
+float_tensor_ops! {
     pub fn sin(&self) -> Self {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
@@ -926,7 +1297,7 @@ async fn test_sin() {
     assert!((output[[2, 1]] - data[2][1].sin()).abs() < 0.001);
 }
 
-impl<const R: usize, D: DataType> Tensor<R, D> {
+float_tensor_ops! {
     pub fn cos(&self) -> Self {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
@@ -961,7 +1332,7 @@ async fn test_cos() {
     assert!((output[[2, 1]] - data[2][1].cos()).abs() < 0.001);
 }
 
-impl<const R: usize, D: DataType> Tensor<R, D> {
+float_tensor_ops! {
     pub fn tan(&self) -> Self {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
@@ -996,7 +1367,7 @@ async fn test_tan() {
     assert!((output[[2, 1]] - data[2][1].tan()).abs() < 0.001);
 }
 
-impl<const R: usize, D: DataType> Tensor<R, D> {
+float_tensor_ops! {
     pub fn asin(&self) -> Self {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
@@ -1035,7 +1406,7 @@ async fn test_asin() {
     assert!((output[[2, 1]] - data[2][1].asin()).abs() < 0.001);
 }
 
-impl<const R: usize, D: DataType> Tensor<R, D> {
+float_tensor_ops! {
     pub fn acos(&self) -> Self {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
@@ -1074,7 +1445,7 @@ async fn test_acos() {
     assert!((output[[2, 1]] - data[2][1].acos()).abs() < 0.001);
 }
 
-impl<const R: usize, D: DataType> Tensor<R, D> {
+float_tensor_ops! {
     pub fn atan(&self) -> Self {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
@@ -1109,7 +1480,7 @@ async fn test_atan() {
     assert!((output[[2, 1]] - data[2][1].atan()).abs() < 0.001);
 }
 
-impl<const R: usize, D: DataType> Tensor<R, D> {
+float_tensor_ops! {
     pub fn sinh(&self) -> Self {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
@@ -1144,7 +1515,7 @@ async fn test_sinh() {
     assert!((output[[2, 1]] - data[2][1].sinh()).abs() < 0.001);
 }
 
-impl<const R: usize, D: DataType> Tensor<R, D> {
+float_tensor_ops! {
     pub fn cosh(&self) -> Self {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
@@ -1179,7 +1550,7 @@ async fn test_cosh() {
     assert!((output[[2, 1]] - data[2][1].cosh()).abs() < 0.001);
 }
 
-impl<const R: usize, D: DataType> Tensor<R, D> {
+float_tensor_ops! {
     pub fn tanh(&self) -> Self {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
@@ -1214,7 +1585,7 @@ async fn test_tanh() {
     assert!((output[[2, 1]] - data[2][1].tanh()).abs() < 0.001);
 }
 
-impl<const R: usize, D: DataType> Tensor<R, D> {
+float_tensor_ops! {
     pub fn asinh(&self) -> Self {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
@@ -1253,7 +1624,7 @@ async fn test_asinh() {
     assert!((output[[2, 1]] - data[2][1].asinh()).abs() < 0.001);
 }
 
-impl<const R: usize, D: DataType> Tensor<R, D> {
+float_tensor_ops! {
     pub fn acosh(&self) -> Self {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
@@ -1292,7 +1663,7 @@ async fn test_acosh() {
     assert!((output[[2, 1]] - data[2][1].acosh()).abs() < 0.001);
 }
 
-impl<const R: usize, D: DataType> Tensor<R, D> {
+float_tensor_ops! {
     pub fn atanh(&self) -> Self {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
@@ -1331,7 +1702,7 @@ async fn test_atanh() {
     assert!((output[[2, 1]] - data[2][1].atanh()).abs() < 0.001);
 }
 
-impl<const R: usize, D: DataType> Tensor<R, D> {
+tensor_ops! {
     pub fn abs(&self) -> Self {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
@@ -1367,14 +1738,13 @@ async fn test_abs() {
     assert!((output[[2, 1]] - data[2][1].abs()).abs() < 0.001);
 }
 
-impl<const R: usize, D: DataType> Neg for Tensor<R, D> {
+impl<const R: usize, D: FloatDataType> Neg for Tensor<R, D> {
     type Output = Tensor<R, D>;
 
     fn neg(self) -> Self {
         self.element_wise(ElementWiseOperation {
             value: self.key(),
-            function: ElementWiseFunction::new("let output = -input;", D::WGSL_TYPE)
-                .with_name("neg"),
+            function: ElementWiseFunction::neg(D::WGSL_TYPE),
         })
     }
 }
@@ -1405,39 +1775,94 @@ async fn test_neg() {
     assert!((output[[2, 1]] + data[2][1]).abs() < 0.001);
 }
 
-impl<const R: usize, T> Tensor<R, T> {
-    pub fn cast<T2>(self) -> Tensor<R, T2>
-    where
-        T: CastTensor<T2>,
-    {
-        T::cast(self)
+tensor_ops! {
+    /// Clamp each element to the range `[min, max]`
+    pub fn clamp(&self, min: f32, max: f32) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::new(
+                "let output = clamp(input, scalar_0, scalar_1);",
+                D::WGSL_TYPE,
+            )
+            .with_scalar(min)
+            .with_scalar(max)
+            .with_name("clamp"),
+        })
     }
 }
 
-pub trait CastTensor<T>: Sized {
-    /// Casts the tensor to another type
-    fn cast<const R: usize>(tensor: Tensor<R, Self>) -> Tensor<R, T>;
+#[cfg(test)]
+#[tokio::test]
+async fn test_clamp() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., -2.], [-3., 4.], [5., -6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = tensor.clamp(-2.5, 4.5);
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    assert_eq!(output[[0, 0]], 1.);
+    assert_eq!(output[[0, 1]], -2.);
+    assert_eq!(output[[1, 0]], -2.5);
+    assert_eq!(output[[1, 1]], 4.);
+    assert_eq!(output[[2, 0]], 4.5);
+    assert_eq!(output[[2, 1]], -2.5);
 }
 
-impl<T> CastTensor<T> for T {
-    fn cast<const R: usize>(tensor: Tensor<R, Self>) -> Tensor<R, Self> {
-        tensor
+float_tensor_ops! {
+    pub fn floor(&self) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::new("let output = floor(input);", D::WGSL_TYPE)
+                .with_name("floor"),
+        })
     }
 }
 
-impl CastTensor<half::f16> for f32 {
-    fn cast<const R: usize>(tensor: Tensor<R, Self>) -> Tensor<R, half::f16> {
-        tensor.element_wise(ElementWiseOperation {
-            value: tensor.key(),
-            function: ElementWiseFunction::new("let output = f16(input);", DataTypeEnum::F16)
-                .with_name("cast"),
+#[cfg(test)]
+#[tokio::test]
+async fn test_floor() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1.5, -2.5], [-3.2, 4.7], [5., -6.9]];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = tensor.floor();
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    for i in 0..3 {
+        for j in 0..2 {
+            assert_eq!(output[[i, j]], data[i][j].floor());
+        }
+    }
+}
+
+float_tensor_ops! {
+    pub fn ceil(&self) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::new("let output = ceil(input);", D::WGSL_TYPE)
+                .with_name("ceil"),
         })
     }
 }
 
 #[cfg(test)]
 #[tokio::test]
-async fn test_f32_to_f16_cast() {
+async fn test_ceil() {
     let device = Device::new().await.unwrap();
     std::thread::spawn({
         let device = device.clone();
@@ -1445,34 +1870,35 @@ async fn test_f32_to_f16_cast() {
             device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
         }
     });
-    let data = [[1.0f32, 2.0f32], [3.0f32, 4.0f32], [5.0f32, 6.0f32]];
+    let data = [[1.5, -2.5], [-3.2, 4.7], [5., -6.9]];
     let tensor = Tensor::new(&device, &data);
 
-    let tensor: Tensor<2, half::f16> = tensor.cast();
+    let tensor = tensor.ceil();
 
     let output = tensor.as_slice().await.unwrap();
     println!("{:?}", output);
-    assert_eq!(output[[0, 0]], half::f16::from_f32(data[0][0]));
-    assert_eq!(output[[0, 1]], half::f16::from_f32(data[0][1]));
-    assert_eq!(output[[1, 0]], half::f16::from_f32(data[1][0]));
-    assert_eq!(output[[1, 1]], half::f16::from_f32(data[1][1]));
-    assert_eq!(output[[2, 0]], half::f16::from_f32(data[2][0]));
-    assert_eq!(output[[2, 1]], half::f16::from_f32(data[2][1]));
+    for i in 0..3 {
+        for j in 0..2 {
+            assert_eq!(output[[i, j]], data[i][j].ceil());
+        }
+    }
 }
 
-impl CastTensor<f32> for half::f16 {
-    fn cast<const R: usize>(tensor: Tensor<R, Self>) -> Tensor<R, f32> {
-        tensor.element_wise(ElementWiseOperation {
-            value: tensor.key(),
-            function: ElementWiseFunction::new("let output = f32(input);", DataTypeEnum::F32)
-                .with_name("cast"),
+float_tensor_ops! {
+    /// Round each element to the nearest integer. Half way cases are rounded to the nearest even
+    /// integer.
+    pub fn round(&self) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::new("let output = round(input);", D::WGSL_TYPE)
+                .with_name("round"),
         })
     }
 }
 
 #[cfg(test)]
 #[tokio::test]
-async fn test_f16_to_f32_cast() {
+async fn test_round() {
     let device = Device::new().await.unwrap();
     std::thread::spawn({
         let device = device.clone();
@@ -1480,21 +1906,707 @@ async fn test_f16_to_f32_cast() {
             device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
         }
     });
-    let data = [
-        [half::f16::from_f32(1.0), half::f16::from_f32(2.0)],
-        [half::f16::from_f32(3.0), half::f16::from_f32(4.0)],
-        [half::f16::from_f32(5.0), half::f16::from_f32(6.0)],
-    ];
+    let data = [[1.5, -2.5], [-3.2, 4.7], [0.5, -6.9]];
     let tensor = Tensor::new(&device, &data);
 
-    let tensor: Tensor<2, f32> = tensor.cast();
+    let tensor = tensor.round();
 
     let output = tensor.as_slice().await.unwrap();
     println!("{:?}", output);
-    assert_eq!(output[[0, 0]], data[0][0].to_f32());
-    assert_eq!(output[[0, 1]], data[0][1].to_f32());
-    assert_eq!(output[[1, 0]], data[1][0].to_f32());
-    assert_eq!(output[[1, 1]], data[1][1].to_f32());
-    assert_eq!(output[[2, 0]], data[2][0].to_f32());
-    assert_eq!(output[[2, 1]], data[2][1].to_f32());
+    assert_eq!(output[[0, 0]], 2.);
+    assert_eq!(output[[0, 1]], -2.);
+    assert_eq!(output[[1, 0]], -3.);
+    assert_eq!(output[[1, 1]], 5.);
+    assert_eq!(output[[2, 0]], 0.);
+    assert_eq!(output[[2, 1]], -7.);
+}
+
+float_tensor_ops! {
+    pub fn trunc(&self) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::new("let output = trunc(input);", D::WGSL_TYPE)
+                .with_name("trunc"),
+        })
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_trunc() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1.5, -2.5], [-3.2, 4.7], [5., -6.9]];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = tensor.trunc();
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    for i in 0..3 {
+        for j in 0..2 {
+            assert_eq!(output[[i, j]], data[i][j].trunc());
+        }
+    }
+}
+
+float_tensor_ops! {
+    /// Returns 1 for positive elements, -1 for negative elements and 0 for zero
+    pub fn sign(&self) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::new("let output = sign(input);", D::WGSL_TYPE)
+                .with_name("sign"),
+        })
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_sign() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1.5, -2.5], [0., 4.7], [5., -6.9]];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = tensor.sign();
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    assert_eq!(output[[0, 0]], 1.);
+    assert_eq!(output[[0, 1]], -1.);
+    assert_eq!(output[[1, 0]], 0.);
+    assert_eq!(output[[1, 1]], 1.);
+    assert_eq!(output[[2, 0]], 1.);
+    assert_eq!(output[[2, 1]], -1.);
+}
+
+float_tensor_ops! {
+    pub fn recip(&self) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::new("let output = 1.0 / input;", D::WGSL_TYPE)
+                .with_name("recip"),
+        })
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_recip() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., -2.], [-3., 4.], [5., -6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = tensor.recip();
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    for i in 0..3 {
+        for j in 0..2 {
+            assert!((output[[i, j]] - data[i][j].recip()).abs() < 0.001);
+        }
+    }
+}
+
+float_tensor_ops! {
+    pub fn rsqrt(&self) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::new("let output = inverseSqrt(input);", D::WGSL_TYPE)
+                .with_name("rsqrt"),
+        })
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_rsqrt() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., 2.], [3., 4.], [5., 6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = tensor.rsqrt();
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    for i in 0..3 {
+        for j in 0..2 {
+            assert!((output[[i, j]] - data[i][j].sqrt().recip()).abs() < 0.001);
+        }
+    }
+}
+
+/// WGSL statements that compute `erf(erf_input)` into `erf_output`. WGSL doesn't have an erf
+/// builtin, so this uses the Abramowitz and Stegun 7.1.26 approximation which has a maximum
+/// absolute error of 1.5e-7.
+pub(crate) const ERF: &str = "let erf_sign = sign(erf_input);
+let erf_x = abs(erf_input);
+let erf_t = 1.0 / (1.0 + 0.3275911 * erf_x);
+let erf_polynomial = ((((1.061405429 * erf_t - 1.453152027) * erf_t + 1.421413741) * erf_t - 0.284496736) * erf_t + 0.254829592) * erf_t;
+let erf_output = erf_sign * (1.0 - erf_polynomial * exp(-erf_x * erf_x));";
+
+float_tensor_ops! {
+    pub fn erf(&self) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::new(
+                format!("let erf_input = input;\n{ERF}\nlet output = erf_output;"),
+                D::WGSL_TYPE,
+            )
+            .with_name("erf"),
+        })
+    }
+}
+
+/// A CPU reference for erf using the taylor series which converges quickly for small inputs
+#[cfg(test)]
+fn erf_reference(x: f64) -> f64 {
+    let mut sum = 0.;
+    let mut term = x;
+    for n in 0..100 {
+        sum += term / (2 * n + 1) as f64;
+        term *= -x * x / (n + 1) as f64;
+    }
+    sum * 2. / std::f64::consts::PI.sqrt()
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_erf() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = (-32..=32).map(|i| i as f32 / 8.).collect::<Vec<_>>();
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = tensor.erf();
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    for (i, x) in data.iter().enumerate() {
+        let expected = erf_reference(*x as f64) as f32;
+        // The approximation's error bound plus rounding in f32
+        assert!(
+            (output[[i]] - expected).abs() < 1.5e-7 + 2. * f32::EPSILON,
+            "erf({x}) = {} but expected {expected}",
+            output[[i]]
+        );
+    }
+}
+
+float_tensor_ops! {
+    /// Computes `exp(x) - 1` accurately for inputs close to zero
+    pub fn expm1(&self) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            // Kahan's trick: the rounding error in exp(x) cancels out in (exp(x) - 1) / log(exp(x))
+            function: ElementWiseFunction::new(
+                "let exp_input = exp(input);
+let exp_input_minus_one = exp_input - 1.0;
+let small = exp_input_minus_one * input / log(exp_input);
+let output = select(select(small, input, exp_input == 1.0), exp_input_minus_one, abs(input) > 0.5);",
+                D::WGSL_TYPE,
+            )
+            .with_name("expm1")
+            .scalar_only(),
+        })
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_expm1() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1e-6, -1e-5], [-3., 0.25], [5., 0.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = tensor.expm1();
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    for i in 0..3 {
+        for j in 0..2 {
+            let expected = data[i][j].exp_m1();
+            assert!((output[[i, j]] - expected).abs() <= expected.abs() * 0.001);
+        }
+    }
+}
+
+float_tensor_ops! {
+    /// Computes `log(1 + x)` accurately for inputs close to zero
+    pub fn log1p(&self) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            // The rounding error in 1 + x cancels out in log(1 + x) * x / ((1 + x) - 1)
+            function: ElementWiseFunction::new(
+                "let one_plus_input = 1.0 + input;
+let small = log(one_plus_input) * input / (one_plus_input - 1.0);
+let output = select(select(small, input, one_plus_input == 1.0), log(one_plus_input), abs(input) > 0.5);",
+                D::WGSL_TYPE,
+            )
+            .with_name("log1p")
+            .scalar_only(),
+        })
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_log1p() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1e-6, -1e-5], [-0.5, 0.25], [5., 0.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = tensor.log1p();
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    for i in 0..3 {
+        for j in 0..2 {
+            let expected = data[i][j].ln_1p();
+            assert!((output[[i, j]] - expected).abs() <= expected.abs() * 0.001);
+        }
+    }
+}
+
+float_tensor_ops! {
+    /// Raise each element to a constant power. Negative elements are only defined for integral
+    /// exponents
+    pub fn pow_scalar(&self, exponent: f32) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            // WGSL's pow is undefined for negative bases, so integral exponents raise the
+            // magnitude and restore the sign for odd exponents
+            function: ElementWiseFunction::new(
+                "let magnitude = pow(abs(input), scalar_0);
+let integral = trunc(scalar_0) == scalar_0;
+let odd = integral && abs(scalar_0 % 2.0) == 1.0;
+let with_sign = select(magnitude, -magnitude, odd && input < 0.0);
+let output = select(pow(input, scalar_0), with_sign, integral);",
+                D::WGSL_TYPE,
+            )
+            .with_scalar(exponent)
+            .with_name("pow_scalar")
+            .scalar_only(),
+        })
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_pow_scalar() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., 2.], [3., 4.], [5., 6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let output = tensor.pow_scalar(1.5).as_slice().await.unwrap();
+    println!("{:?}", output);
+    for i in 0..3 {
+        for j in 0..2 {
+            let expected = data[i][j].powf(1.5);
+            assert!((output[[i, j]] - expected).abs() < expected * 0.001);
+        }
+    }
+
+    // Negative bases with integral exponents
+    let data = [[-1., -2.], [3., -4.], [-0.5, 0.25]];
+    let tensor = Tensor::new(&device, &data);
+    for exponent in [2., 3., -1.] {
+        let output = tensor.pow_scalar(exponent).as_slice().await.unwrap();
+        println!("{:?}", output);
+        for i in 0..3 {
+            for j in 0..2 {
+                let expected = data[i][j].powf(exponent);
+                assert!(
+                    (output[[i, j]] - expected).abs() <= expected.abs() * 0.001,
+                    "{}^{exponent} = {} but expected {expected}",
+                    data[i][j],
+                    output[[i, j]]
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_unary_math_fused() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., 2.], [3., 4.], [5., 6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = (tensor * 0.5).floor().clamp(0., 2.).recip();
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    let graph = format!("{}", tensor.graphvis());
+    println!("{graph}");
+    assert_eq!(output[[0, 0]], 1. / 0.);
+    assert_eq!(output[[0, 1]], 1.);
+    assert_eq!(output[[1, 0]], 1.);
+    assert_eq!(output[[1, 1]], 0.5);
+    assert_eq!(output[[2, 0]], 0.5);
+    assert_eq!(output[[2, 1]], 0.5);
+}
+
+impl<const R: usize, T> Tensor<R, T> {
+    pub fn cast<T2>(self) -> Tensor<R, T2>
+    where
+        T: CastTensor<T2>,
+    {
+        T::cast(self)
+    }
+}
+
+pub trait CastTensor<T>: Sized {
+    /// Casts the tensor to another type
+    fn cast<const R: usize>(tensor: Tensor<R, Self>) -> Tensor<R, T>;
+}
+
+impl<T> CastTensor<T> for T {
+    fn cast<const R: usize>(tensor: Tensor<R, Self>) -> Tensor<R, Self> {
+        tensor
+    }
+}
+
+impl CastTensor<half::f16> for f32 {
+    fn cast<const R: usize>(tensor: Tensor<R, Self>) -> Tensor<R, half::f16> {
+        tensor.element_wise(ElementWiseOperation {
+            value: tensor.key(),
+            function: ElementWiseFunction::cast(DataTypeEnum::F16),
+        })
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_f32_to_f16_cast() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1.0f32, 2.0f32], [3.0f32, 4.0f32], [5.0f32, 6.0f32]];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor: Tensor<2, half::f16> = tensor.cast();
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    assert_eq!(output[[0, 0]], half::f16::from_f32(data[0][0]));
+    assert_eq!(output[[0, 1]], half::f16::from_f32(data[0][1]));
+    assert_eq!(output[[1, 0]], half::f16::from_f32(data[1][0]));
+    assert_eq!(output[[1, 1]], half::f16::from_f32(data[1][1]));
+    assert_eq!(output[[2, 0]], half::f16::from_f32(data[2][0]));
+    assert_eq!(output[[2, 1]], half::f16::from_f32(data[2][1]));
+}
+
+impl CastTensor<f32> for half::f16 {
+    fn cast<const R: usize>(tensor: Tensor<R, Self>) -> Tensor<R, f32> {
+        tensor.element_wise(ElementWiseOperation {
+            value: tensor.key(),
+            function: ElementWiseFunction::cast(DataTypeEnum::F32),
+        })
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_f16_to_f32_cast() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [
+        [half::f16::from_f32(1.0), half::f16::from_f32(2.0)],
+        [half::f16::from_f32(3.0), half::f16::from_f32(4.0)],
+        [half::f16::from_f32(5.0), half::f16::from_f32(6.0)],
+    ];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor: Tensor<2, f32> = tensor.cast();
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    assert_eq!(output[[0, 0]], data[0][0].to_f32());
+    assert_eq!(output[[0, 1]], data[0][1].to_f32());
+    assert_eq!(output[[1, 0]], data[1][0].to_f32());
+    assert_eq!(output[[1, 1]], data[1][1].to_f32());
+    assert_eq!(output[[2, 0]], data[2][0].to_f32());
+    assert_eq!(output[[2, 1]], data[2][1].to_f32());
+}
+
+impl CastTensor<f32> for u32 {
+    fn cast<const R: usize>(tensor: Tensor<R, Self>) -> Tensor<R, f32> {
+        tensor.element_wise(ElementWiseOperation {
+            value: tensor.key(),
+            function: ElementWiseFunction::cast(DataTypeEnum::F32),
+        })
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_u32_to_f32_cast() {
+    use crate::ArgReduce;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., 6., 2.], [7., 4., 5.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let indices: Tensor<1, f32> = tensor.argmax(1).cast();
+
+    let output = indices.as_slice().await.unwrap();
+    assert_eq!(output[[0]], 1.);
+    assert_eq!(output[[1]], 0.);
+}
+
+impl<T: DataType> Add<f32> for DynTensor<T> {
+    type Output = DynTensor<T>;
+
+    fn add(self, rhs: f32) -> Self::Output {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::add_const(rhs, T::WGSL_TYPE),
+        })
+    }
+}
+
+impl<T: DataType> Add<DynTensor<T>> for f32 {
+    type Output = DynTensor<T>;
+
+    fn add(self, rhs: DynTensor<T>) -> Self::Output {
+        rhs + self
+    }
+}
+
+impl<T: DataType> Sub<f32> for DynTensor<T> {
+    type Output = DynTensor<T>;
+
+    fn sub(self, rhs: f32) -> Self::Output {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::subtract_const(rhs, T::WGSL_TYPE),
+        })
+    }
+}
+
+impl<T: DataType> Sub<DynTensor<T>> for f32 {
+    type Output = DynTensor<T>;
+
+    fn sub(self, rhs: DynTensor<T>) -> Self::Output {
+        rhs.element_wise(ElementWiseOperation {
+            value: rhs.key(),
+            function: ElementWiseFunction::subtract_from_const(self, T::WGSL_TYPE),
+        })
+    }
+}
+
+impl<T: DataType> Mul<f32> for DynTensor<T> {
+    type Output = DynTensor<T>;
+
+    fn mul(self, rhs: f32) -> Self::Output {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::multiply_const(rhs, T::WGSL_TYPE),
+        })
+    }
+}
+
+impl<T: DataType> Mul<DynTensor<T>> for f32 {
+    type Output = DynTensor<T>;
+
+    fn mul(self, rhs: DynTensor<T>) -> Self::Output {
+        rhs * self
+    }
+}
+
+impl<T: DataType> Div<f32> for DynTensor<T> {
+    type Output = DynTensor<T>;
+
+    fn div(self, rhs: f32) -> Self::Output {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::divide_const(rhs, T::WGSL_TYPE),
+        })
+    }
+}
+
+impl<T: DataType> Div<DynTensor<T>> for f32 {
+    type Output = DynTensor<T>;
+
+    fn div(self, rhs: DynTensor<T>) -> Self::Output {
+        rhs.element_wise(ElementWiseOperation {
+            value: rhs.key(),
+            function: ElementWiseFunction::divide_from_const(self, T::WGSL_TYPE),
+        })
+    }
+}
+
+macro_rules! impl_assign_const {
+    ($T:ident, $f:ident, $function:ident) => {
+        impl<const R: usize, D: DataType> $T<f32> for Tensor<R, D> {
+            fn $f(&mut self, rhs: f32) {
+                self.assign(|tensor| {
+                    tensor.element_wise(ElementWiseOperation {
+                        value: tensor.key(),
+                        function: ElementWiseFunction::$function(rhs, D::WGSL_TYPE),
+                    })
+                });
+            }
+        }
+
+        impl<D: DataType> $T<f32> for DynTensor<D> {
+            fn $f(&mut self, rhs: f32) {
+                self.assign(|tensor| {
+                    tensor.element_wise(ElementWiseOperation {
+                        value: tensor.key(),
+                        function: ElementWiseFunction::$function(rhs, D::WGSL_TYPE),
+                    })
+                });
+            }
+        }
+    };
+}
+
+impl_assign_const!(AddAssign, add_assign, add_const);
+impl_assign_const!(SubAssign, sub_assign, subtract_const);
+impl_assign_const!(MulAssign, mul_assign, multiply_const);
+impl_assign_const!(DivAssign, div_assign, divide_const);
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_assign_const() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [1., 2., 3., 4., 5., 6.];
+    let mut tensor = DynTensor::new(&device, &[6], &data);
+    let buffer = std::sync::Arc::as_ptr(tensor.materialize().buffer());
+
+    tensor *= 2.;
+    tensor += 1.;
+
+    let output = tensor.as_slice().await.unwrap();
+    for i in 0..6 {
+        assert_eq!(output[&[i][..]], data[i] * 2. + 1.);
+    }
+    assert_eq!(
+        std::sync::Arc::as_ptr(tensor.materialize().buffer()),
+        buffer
+    );
+}
+
+impl<D: FloatDataType> Neg for DynTensor<D> {
+    type Output = DynTensor<D>;
+
+    fn neg(self) -> Self {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::neg(D::WGSL_TYPE),
+        })
+    }
+}
+
+impl<T: DataType> DynTensor<T> {
+    pub fn cast<T2: DataType>(self) -> DynTensor<T2> {
+        if T::WGSL_TYPE == T2::WGSL_TYPE {
+            return self.reinterpret();
+        }
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: ElementWiseFunction::cast(T2::WGSL_TYPE),
+        })
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_dyn_element_wise() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [1., 2., 3., 4., 5., 6.];
+    let tensor = DynTensor::new(&device, &[3, 2], &data);
+
+    let tensor = (2.0 * -tensor.exp() + 1.0).cast::<half::f16>();
+
+    let output = tensor.as_slice().await.unwrap();
+    assert_eq!(output.shape(), [3, 2]);
+    for i in 0..3 {
+        for j in 0..2 {
+            let expected = 2.0 * -data[i * 2 + j].exp() + 1.0;
+            assert!((output[[i, j]].to_f32() - expected).abs() / expected.abs() < 0.01);
+        }
+    }
 }
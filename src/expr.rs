@@ -0,0 +1,696 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt::Write,
+    marker::PhantomData,
+    ops::{Add, BitAnd, BitOr, Div, Mul, Neg, Not, Sub},
+};
+
+use crate::{
+    DynTensor, ElementWiseFunction, ElementWiseOperation, Tensor,
+    nary::{INPUT_NAMES, NaryFunction},
+    tensor::{DataType, DataTypeEnum, FloatDataType},
+};
+
+#[cfg(test)]
+use crate::{Device, UntypedElementWiseKernel, kernel::GenericKernel};
+
+enum ExprNode {
+    /// The nth tensor the expression maps over
+    Input(usize),
+    Literal(&'static str),
+    Scalar {
+        value: f32,
+        datatype: DataTypeEnum,
+    },
+    Unary {
+        op: &'static str,
+        value: usize,
+    },
+    Binary {
+        op: &'static str,
+        lhs: usize,
+        rhs: usize,
+    },
+    Call {
+        function: &'static str,
+        args: Vec<usize>,
+    },
+    Cast {
+        datatype: DataTypeEnum,
+        value: usize,
+    },
+}
+
+/// The nodes of the expression that is currently being built. Expressions are only handles into
+/// this list so they can be `Copy` and used more than once like `x * x.sigmoid()`
+struct ExprArena {
+    generation: u64,
+    nodes: Vec<ExprNode>,
+}
+
+thread_local! {
+    static ARENA: RefCell<Option<ExprArena>> = const { RefCell::new(None) };
+    static NEXT_GENERATION: Cell<u64> = const { Cell::new(0) };
+}
+
+/// A typed element-wise expression built inside [`Tensor::map`] or [`Tensor::map2`]. Expressions are type checked
+/// when they are built and lowered to a single WGSL function that fuses with the operations
+/// around it.
+pub struct Expr<T> {
+    id: usize,
+    generation: u64,
+    datatype: PhantomData<T>,
+}
+
+impl<T> Clone for Expr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Expr<T> {}
+
+impl<T> std::fmt::Debug for Expr<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Expr").field("id", &self.id).finish()
+    }
+}
+
+impl<T> Expr<T> {
+    fn push(node: ExprNode) -> Self {
+        ARENA.with_borrow_mut(|arena| {
+            let arena = arena
+                .as_mut()
+                .expect("expressions can only be created inside a map closure");
+            arena.nodes.push(node);
+            Self {
+                id: arena.nodes.len() - 1,
+                generation: arena.generation,
+                datatype: PhantomData,
+            }
+        })
+    }
+
+    fn id(&self) -> usize {
+        ARENA.with_borrow(|arena| {
+            assert_eq!(
+                arena.as_ref().map(|arena| arena.generation),
+                Some(self.generation),
+                "expressions can only be used inside the map closure that created them"
+            );
+        });
+        self.id
+    }
+
+    fn unary<O>(&self, op: &'static str) -> Expr<O> {
+        Expr::push(ExprNode::Unary {
+            op,
+            value: self.id(),
+        })
+    }
+
+    fn binary<O>(&self, op: &'static str, rhs: Expr<T>) -> Expr<O> {
+        Expr::push(ExprNode::Binary {
+            op,
+            lhs: self.id(),
+            rhs: rhs.id(),
+        })
+    }
+
+    fn call<O>(function: &'static str, args: &[usize]) -> Expr<O> {
+        Expr::push(ExprNode::Call {
+            function,
+            args: args.to_vec(),
+        })
+    }
+}
+
+/// Values that can be used as an operand in an expression of type `T`
+pub trait IntoExpr<T> {
+    fn into_expr(self) -> Expr<T>;
+}
+
+impl<T> IntoExpr<T> for Expr<T> {
+    fn into_expr(self) -> Expr<T> {
+        self
+    }
+}
+
+impl<T: DataType> IntoExpr<T> for f32 {
+    fn into_expr(self) -> Expr<T> {
+        Expr::constant(self)
+    }
+}
+
+impl<T: DataType> Expr<T> {
+    /// A constant value. Constants are passed to the kernel as uniforms so changing them doesn't
+    /// require a new shader.
+    pub fn constant(value: f32) -> Self {
+        Self::push(ExprNode::Scalar {
+            value,
+            datatype: T::WGSL_TYPE,
+        })
+    }
+
+    pub fn cast<T2: DataType>(self) -> Expr<T2> {
+        Expr::push(ExprNode::Cast {
+            datatype: T2::WGSL_TYPE,
+            value: self.id(),
+        })
+    }
+
+    pub fn abs(self) -> Self {
+        Self::call("abs", &[self.id()])
+    }
+
+    pub fn min(self, other: impl IntoExpr<T>) -> Self {
+        Self::call("min", &[self.id(), other.into_expr().id()])
+    }
+
+    pub fn max(self, other: impl IntoExpr<T>) -> Self {
+        Self::call("max", &[self.id(), other.into_expr().id()])
+    }
+
+    pub fn clamp(self, min: impl IntoExpr<T>, max: impl IntoExpr<T>) -> Self {
+        Self::call(
+            "clamp",
+            &[self.id(), min.into_expr().id(), max.into_expr().id()],
+        )
+    }
+
+    pub fn eq(self, other: impl IntoExpr<T>) -> Expr<bool> {
+        self.binary("==", other.into_expr())
+    }
+
+    pub fn ne(self, other: impl IntoExpr<T>) -> Expr<bool> {
+        self.binary("!=", other.into_expr())
+    }
+
+    pub fn lt(self, other: impl IntoExpr<T>) -> Expr<bool> {
+        self.binary("<", other.into_expr())
+    }
+
+    pub fn le(self, other: impl IntoExpr<T>) -> Expr<bool> {
+        self.binary("<=", other.into_expr())
+    }
+
+    pub fn gt(self, other: impl IntoExpr<T>) -> Expr<bool> {
+        self.binary(">", other.into_expr())
+    }
+
+    pub fn ge(self, other: impl IntoExpr<T>) -> Expr<bool> {
+        self.binary(">=", other.into_expr())
+    }
+}
+
+/// Math that WGSL only defines for floats
+impl<T: FloatDataType> Expr<T> {
+    pub fn exp(self) -> Self {
+        Self::call("exp", &[self.id()])
+    }
+
+    pub fn log(self) -> Self {
+        Self::call("log", &[self.id()])
+    }
+
+    pub fn sin(self) -> Self {
+        Self::call("sin", &[self.id()])
+    }
+
+    pub fn cos(self) -> Self {
+        Self::call("cos", &[self.id()])
+    }
+
+    pub fn tan(self) -> Self {
+        Self::call("tan", &[self.id()])
+    }
+
+    pub fn tanh(self) -> Self {
+        Self::call("tanh", &[self.id()])
+    }
+
+    pub fn sqrt(self) -> Self {
+        Self::call("sqrt", &[self.id()])
+    }
+
+    pub fn rsqrt(self) -> Self {
+        Self::call("inverseSqrt", &[self.id()])
+    }
+
+    pub fn floor(self) -> Self {
+        Self::call("floor", &[self.id()])
+    }
+
+    pub fn ceil(self) -> Self {
+        Self::call("ceil", &[self.id()])
+    }
+
+    pub fn round(self) -> Self {
+        Self::call("round", &[self.id()])
+    }
+
+    pub fn trunc(self) -> Self {
+        Self::call("trunc", &[self.id()])
+    }
+
+    pub fn sign(self) -> Self {
+        Self::call("sign", &[self.id()])
+    }
+
+    pub fn recip(self) -> Self {
+        Self::push(ExprNode::Literal("1.0")).binary("/", self)
+    }
+
+    pub fn sigmoid(self) -> Self {
+        let one = Self::push(ExprNode::Literal("1.0"));
+        one / (one + (-self).exp())
+    }
+
+    pub fn pow(self, exponent: impl IntoExpr<T>) -> Self {
+        Self::call("pow", &[self.id(), exponent.into_expr().id()])
+    }
+}
+
+impl Expr<bool> {
+    /// Returns `if_true` where the condition holds and `if_false` everywhere else
+    pub fn select<T: DataType>(
+        self,
+        if_true: impl IntoExpr<T>,
+        if_false: impl IntoExpr<T>,
+    ) -> Expr<T> {
+        Expr::<T>::call(
+            "select",
+            &[
+                if_false.into_expr().id(),
+                if_true.into_expr().id(),
+                self.id(),
+            ],
+        )
+    }
+}
+
+impl BitAnd for Expr<bool> {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        self.binary("&&", rhs)
+    }
+}
+
+impl BitOr for Expr<bool> {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        self.binary("||", rhs)
+    }
+}
+
+impl Not for Expr<bool> {
+    type Output = Self;
+
+    fn not(self) -> Self::Output {
+        self.unary("!")
+    }
+}
+
+impl<T: FloatDataType> Neg for Expr<T> {
+    type Output = Self;
+
+    fn neg(self) -> Self::Output {
+        self.unary("-")
+    }
+}
+
+macro_rules! impl_expr_binary_op {
+    ($trait:ident, $method:ident, $op:literal) => {
+        impl<T: DataType, Rhs: IntoExpr<T>> $trait<Rhs> for Expr<T> {
+            type Output = Self;
+
+            fn $method(self, rhs: Rhs) -> Self::Output {
+                self.binary($op, rhs.into_expr())
+            }
+        }
+
+        impl<T: DataType> $trait<Expr<T>> for f32 {
+            type Output = Expr<T>;
+
+            fn $method(self, rhs: Expr<T>) -> Self::Output {
+                Expr::constant(self).binary($op, rhs)
+            }
+        }
+    };
+}
+
+impl_expr_binary_op!(Add, add, "+");
+impl_expr_binary_op!(Sub, sub, "-");
+impl_expr_binary_op!(Mul, mul, "*");
+impl_expr_binary_op!(Div, div, "/");
+
+/// Build the expression returned by `f` from the expressions for `inputs` tensors. Returns the
+/// nodes of the expression and the id of the output node.
+fn build<O>(inputs: usize, f: impl FnOnce(u64) -> Expr<O>) -> (Vec<ExprNode>, usize) {
+    let generation = NEXT_GENERATION.replace(NEXT_GENERATION.get() + 1);
+    let previous = ARENA.replace(Some(ExprArena {
+        generation,
+        nodes: (0..inputs).map(ExprNode::Input).collect(),
+    }));
+    let output = f(generation).id();
+    let arena = ARENA.replace(previous).unwrap();
+    (arena.nodes, output)
+}
+
+fn input<T>(index: usize, generation: u64) -> Expr<T> {
+    Expr {
+        id: index,
+        generation,
+        datatype: PhantomData,
+    }
+}
+
+/// Lower the nodes to the body of a WGSL function that reads the inputs from `input_names` and
+/// the scalars it returns as `scalar_n`
+fn lower(nodes: &[ExprNode], output: usize, input_names: &[&str]) -> (String, Vec<f32>) {
+    let mut lowering = Lowering {
+        nodes,
+        input_names,
+        names: HashMap::new(),
+        body: String::new(),
+        scalars: Vec::new(),
+    };
+    let output = lowering.lower(output);
+    writeln!(&mut lowering.body, "let output = {output};").unwrap();
+    (lowering.body, lowering.scalars)
+}
+
+/// Build the expression returned by `f` and lower it to an element-wise function
+pub(crate) fn lower_map<I: DataType, O: DataType>(
+    f: impl FnOnce(Expr<I>) -> Expr<O>,
+) -> ElementWiseFunction {
+    let (nodes, output) = build(1, |generation| f(input(0, generation)));
+    let (body, scalars) = lower(&nodes, output, &["input"]);
+    // The lowered body converts scalars and casts with scalar type constructors
+    let mut function = ElementWiseFunction::new(body, O::WGSL_TYPE)
+        .with_name("map")
+        .scalar_only();
+    for scalar in scalars {
+        function = function.with_scalar(scalar);
+    }
+    function
+}
+
+/// Lower an expression over `inputs` tensors to an n-ary function
+fn lower_nary<O: DataType>(inputs: usize, f: impl FnOnce(u64) -> Expr<O>) -> NaryFunction {
+    let (nodes, output) = build(inputs, f);
+    let (body, scalars) = lower(&nodes, output, &INPUT_NAMES[..inputs]);
+    let mut function = NaryFunction::new(body, inputs, O::WGSL_TYPE)
+        .with_name("map")
+        .scalar_only();
+    for scalar in scalars {
+        function = function.with_scalar(scalar);
+    }
+    function
+}
+
+struct Lowering<'a> {
+    nodes: &'a [ExprNode],
+    input_names: &'a [&'a str],
+    names: HashMap<usize, String>,
+    body: String,
+    scalars: Vec<f32>,
+}
+
+impl Lowering<'_> {
+    /// Write the statements for a node and return the name of the variable that holds its value.
+    /// Each node is only computed once, even if it is used in multiple places.
+    fn lower(&mut self, id: usize) -> String {
+        if let Some(name) = self.names.get(&id) {
+            return name.clone();
+        }
+        let value = match &self.nodes[id] {
+            ExprNode::Input(index) => return self.input_names[*index].to_string(),
+            ExprNode::Literal(literal) => return literal.to_string(),
+            ExprNode::Scalar { value, datatype } => {
+                let index = self.scalars.len();
+                self.scalars.push(*value);
+                format!("{datatype}(scalar_{index})")
+            }
+            ExprNode::Unary { op, value } => format!("{op}{}", self.lower(*value)),
+            ExprNode::Binary { op, lhs, rhs } => {
+                let lhs = self.lower(*lhs);
+                let rhs = self.lower(*rhs);
+                format!("{lhs} {op} {rhs}")
+            }
+            ExprNode::Call { function, args } => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.lower(*arg))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!("{function}({args})")
+            }
+            ExprNode::Cast { datatype, value } => format!("{datatype}({})", self.lower(*value)),
+        };
+        let name = format!("expr_{id}");
+        writeln!(&mut self.body, "let {name} = {value};").unwrap();
+        self.names.insert(id, name.clone());
+        name
+    }
+}
+
+impl<const R: usize, D: DataType> Tensor<R, D> {
+    /// Apply an element-wise expression to each element of the tensor. The expression is fused
+    /// with the element-wise operations around it.
+    ///
+    /// ```rust, no_run
+    /// # use wgpu_compute::{Device, Tensor};
+    /// # async fn silu(device: &Device) {
+    /// let tensor = Tensor::new(device, &[1.0f32, 2.0, 3.0]);
+    /// let silu = tensor.map(|x| x * x.sigmoid());
+    /// # }
+    /// ```
+    pub fn map<O: DataType>(&self, f: impl FnOnce(Expr<D>) -> Expr<O>) -> Tensor<R, O> {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: lower_map(f),
+        })
+    }
+
+    /// Apply an element-wise expression to each pair of elements from this tensor and `other`
+    /// after broadcasting them to a common shape. The expression runs in a single kernel.
+    ///
+    /// ```rust, no_run
+    /// # use wgpu_compute::{Device, Tensor};
+    /// # async fn gate(device: &Device) {
+    /// let a = Tensor::new(device, &[1.0f32, 2.0, 3.0]);
+    /// let b = Tensor::new(device, &[0.5f32, -1.0, 2.0]);
+    /// let gated = a.map2(&b, |x, y| x * y.sigmoid());
+    /// # }
+    /// ```
+    pub fn map2<D2: DataType, O: DataType>(
+        &self,
+        other: &Tensor<R, D2>,
+        f: impl FnOnce(Expr<D>, Expr<D2>) -> Expr<O>,
+    ) -> Tensor<R, O> {
+        let function = lower_nary(2, |generation| {
+            f(input(0, generation), input(1, generation))
+        });
+        self.mixed_nary(&[other.data()], function)
+    }
+
+    /// Apply an element-wise expression to the elements of this tensor, `second` and `third`
+    /// after broadcasting them to a common shape. The expression runs in a single kernel.
+    pub fn map3<D2: DataType, D3: DataType, O: DataType>(
+        &self,
+        second: &Tensor<R, D2>,
+        third: &Tensor<R, D3>,
+        f: impl FnOnce(Expr<D>, Expr<D2>, Expr<D3>) -> Expr<O>,
+    ) -> Tensor<R, O> {
+        let function = lower_nary(3, |generation| {
+            f(
+                input(0, generation),
+                input(1, generation),
+                input(2, generation),
+            )
+        });
+        self.mixed_nary(&[second.data(), third.data()], function)
+    }
+}
+
+impl<D: DataType> DynTensor<D> {
+    /// Apply an element-wise expression to each element of the tensor. The expression is fused
+    /// with the element-wise operations around it.
+    pub fn map<O: DataType>(&self, f: impl FnOnce(Expr<D>) -> Expr<O>) -> DynTensor<O> {
+        self.element_wise(ElementWiseOperation {
+            value: self.key(),
+            function: lower_map(f),
+        })
+    }
+
+    /// Apply an element-wise expression to each pair of elements from this tensor and `other`
+    /// after broadcasting them to a common shape. The expression runs in a single kernel.
+    pub fn map2<D2: DataType, O: DataType>(
+        &self,
+        other: &DynTensor<D2>,
+        f: impl FnOnce(Expr<D>, Expr<D2>) -> Expr<O>,
+    ) -> DynTensor<O> {
+        let function = lower_nary(2, |generation| {
+            f(input(0, generation), input(1, generation))
+        });
+        self.mixed_nary(&[other.data()], function)
+    }
+
+    /// Apply an element-wise expression to the elements of this tensor, `second` and `third`
+    /// after broadcasting them to a common shape. The expression runs in a single kernel.
+    pub fn map3<D2: DataType, D3: DataType, O: DataType>(
+        &self,
+        second: &DynTensor<D2>,
+        third: &DynTensor<D3>,
+        f: impl FnOnce(Expr<D>, Expr<D2>, Expr<D3>) -> Expr<O>,
+    ) -> DynTensor<O> {
+        let function = lower_nary(3, |generation| {
+            f(
+                input(0, generation),
+                input(1, generation),
+                input(2, generation),
+            )
+        });
+        self.mixed_nary(&[second.data(), third.data()], function)
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_map() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., -2.], [-3., 4.], [5., -6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor = (tensor * 2.).map(|x| x * x.sigmoid());
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    for i in 0..3 {
+        for j in 0..2 {
+            let x: f32 = data[i][j] * 2.;
+            let expected = x / (1. + (-x).exp());
+            assert!((output[[i, j]] - expected).abs() < 0.001);
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_map_select_cast() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., -2.], [-3., 4.], [5., -6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let tensor: Tensor<2, half::f16> = tensor.map(|x| {
+        let clipped = (x.gt(0.) & x.lt(4.5)).select(x, x.abs().sqrt());
+        clipped.cast::<half::f16>() + 1.
+    });
+
+    let output = tensor.as_slice().await.unwrap();
+    println!("{:?}", output);
+    for i in 0..3 {
+        for j in 0..2 {
+            let x: f32 = data[i][j];
+            let clipped = if x > 0. && x < 4.5 { x } else { x.abs().sqrt() };
+            let expected = half::f16::from_f32(clipped + 1.);
+            assert!((output[[i, j]].to_f32() - expected.to_f32()).abs() < 0.01);
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_map2() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data_a = [[1., -2.], [-3., 4.], [5., -6.]];
+    let data_b = [[0.5, 1.], [-1., 2.], [3., -0.5]];
+    let tensor_a = Tensor::new(&device, &data_a);
+    let tensor_b = Tensor::new(&device, &data_b);
+
+    // The expression and the element-wise ops on its inputs run in one kernel
+    let tensor = (tensor_a.clone() * 2.).map2(&tensor_b, |x, y| x * y.sigmoid() + 1.);
+    let as_slice = tensor.as_slice().await.unwrap();
+    for i in 0..3 {
+        for j in 0..2 {
+            let x: f32 = data_a[i][j] * 2.;
+            let y: f32 = data_b[i][j];
+            let expected = x / (1. + (-y).exp()) + 1.;
+            assert!((as_slice[[i, j]] - expected).abs() < 0.001);
+        }
+    }
+    assert_eq!(tensor.all_timing_information().await.len(), 1);
+
+    // The inputs can have different datatypes
+    let weight: Tensor<2, half::f16> = tensor_b.clone().cast();
+    let tensor = tensor_a.map3(&tensor_b, &weight, |x, y, weight| {
+        weight.cast::<f32>().gt(0.).select(x, y)
+    });
+    let as_slice = tensor.as_slice().await.unwrap();
+    for i in 0..3 {
+        for j in 0..2 {
+            let expected = if data_b[i][j] > 0. {
+                data_a[i][j]
+            } else {
+                data_b[i][j]
+            };
+            assert_eq!(as_slice[[i, j]], expected);
+        }
+    }
+}
+
+#[test]
+fn test_map_lowering() {
+    let function = lower_map::<f32, f32>(|x| {
+        let y = x.exp();
+        (y * y).clamp(0., 10.).max(-x)
+    });
+    let mut kernel = GenericKernel::new();
+    let input = kernel.add_tensor_input(1, true, DataTypeEnum::F32);
+    let functions = UntypedElementWiseKernel::new(vec![function], DataTypeEnum::F32)
+        .add_functions(&mut kernel, false);
+    let result = functions[0].call(vec![format!("{input}[0]")]);
+    kernel.set_body(format!("{input}[0] = {result};"));
+    kernel.validate().unwrap();
+    // The shared subexpression is only computed once
+    assert_eq!(kernel.source().matches("exp(").count(), 1);
+}
+
+#[test]
+fn test_map_nary_lowering() {
+    let function = lower_nary::<f32>(2, |generation| {
+        let x = input::<f32>(0, generation);
+        let y = input::<half::f16>(1, generation);
+        x.max(y.cast::<f32>() * 2.).sqrt() + 1.
+    });
+    let mut kernel = GenericKernel::new();
+    let first = kernel.add_tensor_input(1, true, DataTypeEnum::F32);
+    let second = kernel.add_tensor_input(1, true, DataTypeEnum::F16);
+    let function =
+        function.add_function(&mut kernel, [DataTypeEnum::F32, DataTypeEnum::F16], false);
+    let result = function.call(vec![format!("{first}[0]"), format!("{second}[0]")]);
+    kernel.set_body(format!("{first}[0] = {result};"));
+    kernel.validate().unwrap();
+}
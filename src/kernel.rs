@@ -1,6 +1,9 @@
 use enumset::{EnumSet, EnumSetType};
 use std::fmt::{Debug, Write};
-use std::{fmt::Display, sync::OnceLock};
+use std::{
+    fmt::Display,
+    sync::{Arc, OnceLock},
+};
 use wgpu::{BindGroupLayout, CommandEncoder, PipelineCompilationOptions, util::DeviceExt};
 
 use crate::{DataTypeEnum, Device, PerformanceQueries, TensorData};
@@ -25,10 +28,21 @@ pub(crate) struct GenericKernel {
     functions: Vec<Function>,
     globals: Vec<KernelGlobal>,
     enabled_builtins: EnumSet<EnabledBuiltins>,
-    kernel: OnceLock<wgpu::ShaderModule>,
+    /// The values of every float input. They share one uniform buffer so the number of bindings
+    /// doesn't grow with the number of scalars
+    scalars: Vec<f32>,
+    scalars_binding: Option<u32>,
+    kernel: OnceLock<String>,
     body: String,
 }
 
+/// A compiled compute pipeline and the layout of its bind group. These are cached on the device
+/// by their shader source so kernels that only differ in their inputs can share a pipeline.
+pub(crate) struct ComputePipeline {
+    bind_group_layout: BindGroupLayout,
+    pipeline: wgpu::ComputePipeline,
+}
+
 impl GenericKernel {
     pub(crate) fn new() -> Self {
         Self {
@@ -40,6 +54,8 @@ impl GenericKernel {
             globals: Default::default(),
             max_global_id: 0,
             enabled_builtins: Default::default(),
+            scalars: Vec::new(),
+            scalars_binding: None,
             kernel: OnceLock::new(),
             body: String::new(),
         }
@@ -71,11 +87,55 @@ impl GenericKernel {
         function
     }
 
+    /// Add a function that runs a scalar `function` on each lane of `vec4` inputs
+    pub(crate) fn add_lane_wise_function(&mut self, function: &Function) -> Function {
+        let lanes = ["x", "y", "z", "w"]
+            .map(|lane| {
+                function.call(
+                    function
+                        .inputs
+                        .iter()
+                        .map(|(name, _)| format!("{name}.{lane}"))
+                        .collect(),
+                )
+            })
+            .join(", ");
+        self.add_function(
+            format!("vec4<{}>", function.ty),
+            format!("let output = vec4<{}>({lanes});", function.ty),
+            function
+                .inputs
+                .iter()
+                .map(|(name, ty)| (name.clone(), format!("vec4<{ty}>"))),
+        )
+    }
+
     pub(crate) fn add_tensor_input(
         &mut self,
         rank: u32,
         mutable: bool,
         datatype: DataTypeEnum,
+    ) -> TensorInput {
+        self.add_tensor_input_inner(rank, mutable, datatype, false)
+    }
+
+    /// Add a tensor input that is read and written as `vec4`s. Indexing the input returns four
+    /// contiguous elements at a time.
+    pub(crate) fn add_vectorized_tensor_input(
+        &mut self,
+        rank: u32,
+        mutable: bool,
+        datatype: DataTypeEnum,
+    ) -> TensorInput {
+        self.add_tensor_input_inner(rank, mutable, datatype, true)
+    }
+
+    fn add_tensor_input_inner(
+        &mut self,
+        rank: u32,
+        mutable: bool,
+        datatype: DataTypeEnum,
+        vectorized: bool,
     ) -> TensorInput {
         let start_index = self.max_binding;
         self.max_binding += 2;
@@ -85,10 +145,12 @@ impl GenericKernel {
             rank,
             mutable,
             datatype,
+            vectorized,
         };
 
         self.inputs.push(KernelInput {
             ty: KernelInputType::Tensor(input.clone()),
+            value: None,
         });
 
         input
@@ -102,29 +164,62 @@ impl GenericKernel {
 
         self.inputs.push(KernelInput {
             ty: KernelInputType::Integer(input.clone()),
+            value: None,
         });
 
         input
     }
 
-    #[allow(dead_code)]
-    pub(crate) fn add_float_input(&mut self) -> FloatInput {
+    /// Add a storage buffer without a layout. Each element has the WGSL type `ty`, so it can hold
+    /// values that are not tensor datatypes
+    pub(crate) fn add_array_input(&mut self, mutable: bool, ty: impl ToString) -> ArrayInput {
         let index = self.max_binding;
         self.max_binding += 1;
 
-        let input = FloatInput { index };
+        let input = ArrayInput {
+            index,
+            mutable,
+            ty: ty.to_string(),
+        };
 
         self.inputs.push(KernelInput {
-            ty: KernelInputType::Float(input.clone()),
+            ty: KernelInputType::Array(input.clone()),
+            value: None,
         });
 
         input
     }
 
+    /// Add a float uniform to the kernel. Unlike tensor and integer inputs, the value is stored
+    /// with the kernel instead of passed in when the kernel runs.
+    pub(crate) fn add_float_input(&mut self, value: f32) -> FloatInput {
+        let index = match self.scalars_binding {
+            Some(index) => index,
+            None => {
+                let index = self.max_binding;
+                self.max_binding += 1;
+                self.inputs.push(KernelInput {
+                    ty: KernelInputType::Float(ScalarsInput { index }),
+                    value: None,
+                });
+                self.scalars_binding = Some(index);
+                index
+            }
+        };
+
+        let input = FloatInput {
+            index,
+            slot: self.scalars.len() as u32,
+        };
+        self.scalars.push(value);
+
+        input
+    }
+
     pub(crate) fn add_global_array(
         &mut self,
         space: KernelGlobalSpace,
-        array_type: DataTypeEnum,
+        array_type: impl ToString,
         size: String,
     ) -> KernelGlobal {
         let index = self.max_global_id;
@@ -134,7 +229,7 @@ impl GenericKernel {
             space,
             KernelGlobalType::Array(ArrayType {
                 size,
-                datatype: array_type,
+                datatype: array_type.to_string(),
             }),
         );
         self.globals.push(global.clone());
@@ -230,6 +325,20 @@ impl GenericKernel {
                         count: None,
                     });
                 }
+                KernelInputType::Array(array_input) => {
+                    entries.push(wgpu::BindGroupLayoutEntry {
+                        binding: array_input.index,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage {
+                                read_only: !array_input.mutable,
+                            },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    });
+                }
             }
         }
 
@@ -241,34 +350,61 @@ impl GenericKernel {
             })
     }
 
-    fn compute_pipeline(
-        &self,
-        device: &crate::Device,
-        bind_group_layout: &BindGroupLayout,
-    ) -> wgpu::ComputePipeline {
-        let compute_pipeline_layout =
-            device
-                .wgpu_device()
-                .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                    label: None,
-                    bind_group_layouts: &[bind_group_layout],
-                    push_constant_ranges: &[],
-                });
-        let module = self.kernel.get_or_init(|| {
+    pub(crate) fn source(&self) -> &str {
+        self.kernel.get_or_init(|| {
             let mut kernel = String::new();
             self.kernel(&mut kernel).unwrap();
-            device.create_shader_module(kernel)
-        });
-        device
-            .wgpu_device()
-            .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-                label: None,
-                layout: Some(&compute_pipeline_layout),
-                module,
-                entry_point: Some("main"),
-                cache: None,
-                compilation_options: PipelineCompilationOptions::default(),
-            })
+            kernel
+        })
+    }
+
+    /// Parse and validate the kernel source with naga. This catches errors in user provided WGSL
+    /// before it reaches pipeline creation where wgpu would only report it through the device
+    /// error handler.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        let source = self.source();
+        let module =
+            naga::front::wgsl::parse_str(source).map_err(|err| err.emit_to_string(source))?;
+        // Feature support is checked by the device when the pipeline is created
+        let mut validator = naga::valid::Validator::new(
+            naga::valid::ValidationFlags::all(),
+            naga::valid::Capabilities::all(),
+        );
+        validator
+            .validate(&module)
+            .map_err(|err| err.emit_to_string(source))?;
+        Ok(())
+    }
+
+    fn compute_pipeline(&self, device: &crate::Device) -> Arc<ComputePipeline> {
+        let source = self.source();
+        device.cached_compute_pipeline(source, || {
+            let bind_group_layout = self.bind_group_layout(device);
+            let compute_pipeline_layout =
+                device
+                    .wgpu_device()
+                    .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                        label: None,
+                        bind_group_layouts: &[&bind_group_layout],
+                        push_constant_ranges: &[],
+                    });
+            let module = device.create_shader_module(source);
+            let pipeline =
+                device
+                    .wgpu_device()
+                    .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                        label: None,
+                        layout: Some(&compute_pipeline_layout),
+                        module: &module,
+                        entry_point: Some("main"),
+                        cache: None,
+                        compilation_options: PipelineCompilationOptions::default(),
+                    });
+            ComputePipeline {
+                bind_group_layout,
+                pipeline,
+            }
+        })
     }
 
     fn create_bind_group<'a>(
@@ -279,7 +415,17 @@ impl GenericKernel {
     ) -> wgpu::BindGroup {
         let mut entries = Vec::new();
         let mut owned_entries = Vec::new();
-        let tensors = tensors.into_iter().map(|x| x.into()).collect::<Vec<_>>();
+        let mut tensors = tensors.into_iter().map(|x| x.into());
+        // Values stored with the kernel are filled in before the values passed in
+        let values = self
+            .inputs
+            .iter()
+            .map(|input| match (&input.ty, &input.value) {
+                (KernelInputType::Float(_), _) => KernelInputValue::Float(self.scalars.clone()),
+                (_, Some(value)) => value.clone(),
+                (_, None) => tensors.next().unwrap(),
+            })
+            .collect::<Vec<_>>();
         fn create_u32_iter_buffer(
             device: &crate::Device,
             data: impl IntoIterator<Item = u32>,
@@ -301,16 +447,19 @@ impl GenericKernel {
                     usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
                 })
         };
-        let create_f32_buffer = |device: &crate::Device, data: f32| {
+        let create_f32_buffer = |device: &crate::Device, data: &[f32]| {
+            // Pad the values to whole vec4s
+            let mut data = data.to_vec();
+            data.resize(data.len().next_multiple_of(4), 0.0);
             device
                 .wgpu_device()
                 .create_buffer_init(&wgpu::util::BufferInitDescriptor {
                     label: None,
-                    contents: bytemuck::bytes_of(&data),
+                    contents: bytemuck::cast_slice(&data),
                     usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
                 })
         };
-        for (input, value) in self.inputs.iter().zip(tensors.iter()) {
+        for (input, value) in self.inputs.iter().zip(values.iter()) {
             match (&input.ty, value) {
                 (KernelInputType::Tensor(tensor_input), KernelInputValue::Tensor(tensor)) => {
                     // Tensor weight
@@ -337,8 +486,14 @@ impl GenericKernel {
                 (KernelInputType::Integer(integer_input), KernelInputValue::Integer(value)) => {
                     owned_entries.push((integer_input.index, create_u32_buffer(device, *value)));
                 }
-                (KernelInputType::Float(float_input), KernelInputValue::Float(value)) => {
-                    owned_entries.push((float_input.index, create_f32_buffer(device, *value)));
+                (KernelInputType::Float(scalars_input), KernelInputValue::Float(values)) => {
+                    owned_entries.push((scalars_input.index, create_f32_buffer(device, values)));
+                }
+                (KernelInputType::Array(array_input), KernelInputValue::Buffer(buffer)) => {
+                    entries.push(wgpu::BindGroupEntry {
+                        binding: array_input.index,
+                        resource: buffer.as_entire_binding(),
+                    });
                 }
                 _ => todo!(),
             }
@@ -368,16 +523,15 @@ impl GenericKernel {
         command_encoder: &mut CommandEncoder,
         workgroup_dispatch_size: [u32; 3],
     ) {
-        let bind_group_layout = self.bind_group_layout(device);
-        let bind_group = self.create_bind_group(device, &bind_group_layout, tensors);
-        let pipeline = self.compute_pipeline(device, &bind_group_layout);
+        let pipeline = self.compute_pipeline(device);
+        let bind_group = self.create_bind_group(device, &pipeline.bind_group_layout, tensors);
 
         {
             let mut cpass = command_encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
                 label: None,
                 timestamp_writes: query.map(|query| query.compute_timestamp_writes()),
             });
-            cpass.set_pipeline(&pipeline);
+            cpass.set_pipeline(&pipeline.pipeline);
             cpass.set_bind_group(0, &bind_group, &[]);
             let [workgroup_size_x, workgroup_size_y, workgroup_size_z] = workgroup_dispatch_size;
             cpass.dispatch_workgroups(workgroup_size_x, workgroup_size_y, workgroup_size_z);
@@ -392,7 +546,7 @@ impl GenericKernel {
         writeln!(f, "enable f16;")?;
 
         for input in &self.inputs {
-            write!(f, "{input}")?;
+            input.write_definition(f, self.scalars.len())?;
         }
 
         for global in &self.globals {
@@ -495,10 +649,12 @@ impl GenericKernel {
     }
 }
 
+#[derive(Clone)]
 pub(crate) enum KernelInputValue {
     Tensor(TensorData),
     Integer(u32),
-    Float(f32),
+    Float(Vec<f32>),
+    Buffer(wgpu::Buffer),
 }
 
 impl From<TensorData> for KernelInputValue {
@@ -513,12 +669,6 @@ impl From<u32> for KernelInputValue {
     }
 }
 
-impl From<f32> for KernelInputValue {
-    fn from(value: f32) -> Self {
-        Self::Float(value)
-    }
-}
-
 #[derive(Clone, Debug)]
 pub(crate) struct Function {
     id: u32,
@@ -549,7 +699,8 @@ impl Function {
         }
         let body = &self.body;
         let ty = &self.ty;
-        format!("fn {name}({inputs_string}) -> {ty} {{ {body} return output; }}")
+        // The body is on its own lines so a trailing line comment can't hide the return
+        format!("fn {name}({inputs_string}) -> {ty} {{\n{body}\nreturn output; }}")
     }
 
     fn function_name(&self) -> String {
@@ -623,15 +774,17 @@ pub enum KernelGlobalType {
 #[derive(Clone)]
 pub struct ArrayType {
     size: String,
-    datatype: DataTypeEnum,
+    datatype: String,
 }
 
 struct KernelInput {
     ty: KernelInputType,
+    value: Option<KernelInputValue>,
 }
 
-impl Display for KernelInput {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl KernelInput {
+    /// Write the binding definition. `scalars` is the number of values in the float input
+    fn write_definition(&self, f: &mut String, scalars: usize) -> std::fmt::Result {
         match &self.ty {
             KernelInputType::Tensor(tensor) => {
                 let start_index = tensor.start_index;
@@ -644,7 +797,11 @@ impl Display for KernelInput {
                     write!(f, "var<storage, read> ")?;
                 }
 
-                writeln!(f, "i_{start_index}: array<{datatype}>;")?;
+                if tensor.vectorized {
+                    writeln!(f, "i_{start_index}: array<vec4<{datatype}>>;")?;
+                } else {
+                    writeln!(f, "i_{start_index}: array<{datatype}>;")?;
+                }
 
                 writeln!(f, "struct Tensor{start_index}Info {{")?;
                 writeln!(f, "    offset: u32,")?;
@@ -667,7 +824,23 @@ impl Display for KernelInput {
                     "@group(0) @binding({index}) var<uniform> i_{index}: u32;"
                 )?
             }
-            KernelInputType::Float(float) => write!(f, "var<uniform> i_{}: f32;", float.index)?,
+            KernelInputType::Float(float) => {
+                let index = float.index;
+                let size = scalars.div_ceil(4);
+                writeln!(
+                    f,
+                    "@group(0) @binding({index}) var<uniform> i_{index}: array<vec4<f32>, {size}>;"
+                )?
+            }
+            KernelInputType::Array(array) => {
+                let index = array.index;
+                let ty = &array.ty;
+                let access = if array.mutable { "read_write" } else { "read" };
+                writeln!(
+                    f,
+                    "@group(0) @binding({index}) var<storage, {access}> i_{index}: array<{ty}>;"
+                )?
+            }
         }
 
         Ok(())
@@ -677,7 +850,21 @@ impl Display for KernelInput {
 enum KernelInputType {
     Tensor(TensorInput),
     Integer(IntegerInput),
-    Float(FloatInput),
+    Float(ScalarsInput),
+    Array(ArrayInput),
+}
+
+#[derive(Clone)]
+pub(crate) struct ArrayInput {
+    index: u32,
+    mutable: bool,
+    ty: String,
+}
+
+impl Display for ArrayInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "i_{}", self.index)
+    }
 }
 
 #[derive(Clone)]
@@ -691,14 +878,22 @@ impl Display for IntegerInput {
     }
 }
 
+/// The uniform buffer that holds every float input of a kernel
+#[derive(Clone)]
+pub(crate) struct ScalarsInput {
+    index: u32,
+}
+
+/// One value in the scalars uniform buffer
 #[derive(Clone)]
 pub(crate) struct FloatInput {
     index: u32,
+    slot: u32,
 }
 
 impl Display for FloatInput {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "i_{}", self.index)
+        write!(f, "i_{}[{}][{}]", self.index, self.slot / 4, self.slot % 4)
     }
 }
 
@@ -708,6 +903,7 @@ pub(crate) struct TensorInput {
     rank: u32,
     mutable: bool,
     datatype: DataTypeEnum,
+    vectorized: bool,
 }
 
 impl TensorInput {
@@ -751,6 +947,15 @@ impl TensorInput {
         write!(write, "}}").unwrap();
     }
 
+    /// The number of elements in the tensor
+    pub(crate) fn element_count(&self) -> String {
+        let mut count = "1".to_string();
+        for i in 0..self.rank {
+            write!(&mut count, " * {}", self.shape_binding(i)).unwrap();
+        }
+        count
+    }
+
     pub(crate) fn check_bounds_contiguous(
         &self,
         write: &mut String,
@@ -793,3 +998,102 @@ impl Display for TensorInput {
         write!(f, "i_{}", self.start_index)
     }
 }
+
+/// The error returned when a user provided WGSL function is not valid.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidFunctionError {
+    name: String,
+    message: String,
+}
+
+impl InvalidFunctionError {
+    pub(crate) fn new(name: impl ToString, message: impl ToString) -> Self {
+        Self {
+            name: name.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Display for InvalidFunctionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid function `{}`: {}", self.name, self.message)
+    }
+}
+
+impl std::error::Error for InvalidFunctionError {}
+
+/// User provided bodies are pasted into a function definition, so they must not close that
+/// function
+pub(crate) fn check_function_body(name: &str, body: &str) -> Result<(), InvalidFunctionError> {
+    let code = strip_comments(body).ok_or_else(|| {
+        InvalidFunctionError::new(name, "the body contains an unterminated block comment")
+    })?;
+    let mut depth = 0usize;
+    for c in code.chars() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth = depth.checked_sub(1).ok_or_else(|| {
+                    InvalidFunctionError::new(name, "the body contains an unmatched `}`")
+                })?
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        return Err(InvalidFunctionError::new(
+            name,
+            "the body contains an unmatched `{`",
+        ));
+    }
+    Ok(())
+}
+
+/// Remove the line and block comments from WGSL source. WGSL block comments nest. Returns `None`
+/// if a block comment is never closed
+fn strip_comments(source: &str) -> Option<String> {
+    let mut code = String::with_capacity(source.len());
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('/', Some('/')) => {
+                // Keep the newline so the tokens around the comment stay separate
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        code.push('\n');
+                        break;
+                    }
+                }
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut depth = 1;
+                while depth > 0 {
+                    match (chars.next()?, chars.peek()) {
+                        ('/', Some('*')) => {
+                            chars.next();
+                            depth += 1;
+                        }
+                        ('*', Some('/')) => {
+                            chars.next();
+                            depth -= 1;
+                        }
+                        _ => {}
+                    }
+                }
+                code.push(' ');
+            }
+            _ => code.push(c),
+        }
+    }
+    Some(code)
+}
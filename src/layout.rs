@@ -30,6 +30,27 @@ pub(crate) fn slice_shape(slices: &[Range<usize>], _shape: &[usize]) -> Box<[usi
     slices.iter().map(|range| range.len()).collect()
 }
 
+/// The shape two tensors broadcast to, or `None` if they are incompatible. Shapes are aligned
+/// from the last dimension. Missing leading dimensions and dimensions of size 1 are stretched to
+/// match the other shape.
+pub(crate) fn broadcast_shapes(first: &[usize], second: &[usize]) -> Option<Box<[usize]>> {
+    let rank = first.len().max(second.len());
+    let dim = |shape: &[usize], i: usize| {
+        (i + shape.len())
+            .checked_sub(rank)
+            .map(|i| shape[i])
+            .unwrap_or(1)
+    };
+    (0..rank)
+        .map(|i| match (dim(first, i), dim(second, i)) {
+            (a, b) if a == b => Some(a),
+            (1, b) => Some(b),
+            (a, 1) => Some(a),
+            _ => None,
+        })
+        .collect()
+}
+
 #[derive(Clone)]
 pub struct Layout {
     offset: usize,
@@ -100,3 +121,25 @@ fn test_contiguous() {
     assert!(!layout.slice(&[0..1, 0..1]).is_contiguous());
     assert!(!layout.slice(&[1..2, 0..3]).is_contiguous());
 }
+
+#[test]
+fn test_broadcast_shapes() {
+    assert_eq!(
+        broadcast_shapes(&[2, 3], &[2, 3]).as_deref(),
+        Some(&[2, 3][..])
+    );
+    assert_eq!(
+        broadcast_shapes(&[2, 1], &[1, 3]).as_deref(),
+        Some(&[2, 3][..])
+    );
+    assert_eq!(
+        broadcast_shapes(&[4, 2, 3], &[3]).as_deref(),
+        Some(&[4, 2, 3][..])
+    );
+    assert_eq!(
+        broadcast_shapes(&[1], &[5, 1, 2]).as_deref(),
+        Some(&[5, 1, 2][..])
+    );
+    assert_eq!(broadcast_shapes(&[2, 3], &[3, 2]), None);
+    assert_eq!(broadcast_shapes(&[4, 3], &[2, 1, 2]), None);
+}
@@ -1,7 +1,11 @@
 pub use composite::*;
 pub use device::*;
-pub use element_wise::CastTensor;
+pub use element_wise::{CastTensor, CustomElementWise};
+pub use expr::{Expr, IntoExpr};
+pub use kernel::InvalidFunctionError;
 pub use layout::*;
+pub use map_layout::BroadcastError;
+pub use pair_wise::Promote;
 pub use query::*;
 pub use reduce::*;
 pub use tensor::*;
@@ -10,18 +14,23 @@ pub(crate) use element_wise::*;
 pub(crate) use matmul::*;
 pub(crate) use pair_wise::*;
 
+mod activation;
 mod composite;
 mod compute_graph;
 mod device;
 mod element_wise;
+mod expr;
 mod kernel;
 mod layout;
 mod map_layout;
 mod matmul;
+mod nary;
 mod pair_wise;
 mod query;
 mod reduce;
 mod resize;
+mod scan;
 mod slice_assign;
+mod sort;
 mod tensor;
 mod visit_tiled;
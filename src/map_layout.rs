@@ -1,7 +1,8 @@
-use std::ops::Range;
+use std::{fmt::Display, ops::Range};
 
 use crate::{
-    DataType, Layout, Tensor, TensorData, compute_graph::AnyComputeKey, slice_shape, slice_strides,
+    DataType, DynTensor, Layout, Tensor, TensorData, compute_graph::AnyComputeKey, slice_shape,
+    slice_strides, tensor::tensor_ops,
 };
 
 pub(crate) struct MapLayoutOperation {
@@ -50,7 +51,74 @@ impl<const R: usize, T: DataType> Tensor<R, T> {
         ))
     }
 
-    pub fn transpose(&self, first_axis: usize, second_axis: usize) -> Tensor<R, T> {
+    pub fn broadcast<const R2: usize>(&self, out_shape: [usize; R2]) -> Tensor<R2, T> {
+        const { assert!(R2 == R + 1) };
+
+        self.expand(out_shape).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Broadcast the tensor to a shape without copying it. The shapes are aligned from the last
+    /// dimension like numpy: new leading dimensions are added and dimensions of size 1 are
+    /// stretched.
+    pub fn broadcast_to<const R2: usize>(
+        &self,
+        shape: [usize; R2],
+    ) -> Result<Tensor<R2, T>, BroadcastError> {
+        let operation = broadcast_to_operation(self.key(), self.shape(), &shape)?;
+        Ok(self.add_map_layout(operation))
+    }
+
+    /// Expand the tensor to a shape without copying it. Unlike [`Tensor::broadcast_to`], new
+    /// dimensions can be inserted anywhere. Each dimension of the tensor is matched with the next
+    /// dimension of the shape it can broadcast to, and the dimensions in between are new.
+    pub fn expand<const R2: usize>(
+        &self,
+        shape: [usize; R2],
+    ) -> Result<Tensor<R2, T>, BroadcastError> {
+        let operation = expand_operation(self.key(), self.shape(), &shape)?;
+        Ok(self.add_map_layout(operation))
+    }
+}
+
+impl<T: DataType> DynTensor<T> {
+    pub fn slice(&self, slices: &[Range<usize>]) -> Self {
+        assert_eq!(slices.len(), self.rank());
+        let slices: Box<[Range<usize>]> = slices.into();
+        self.add_map_layout(MapLayoutOperation::new(
+            self.key(),
+            {
+                let slices = slices.clone();
+                move |shape| slice_shape(&slices, shape)
+            },
+            move |offset, strides| slice_strides(&slices, offset, strides),
+        ))
+    }
+
+    pub fn broadcast(&self, out_shape: &[usize]) -> Self {
+        assert_eq!(out_shape.len(), self.rank() + 1);
+
+        self.expand(out_shape).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Broadcast the tensor to a shape without copying it. The shapes are aligned from the last
+    /// dimension like numpy: new leading dimensions are added and dimensions of size 1 are
+    /// stretched.
+    pub fn broadcast_to(&self, shape: &[usize]) -> Result<Self, BroadcastError> {
+        let operation = broadcast_to_operation(self.key(), self.shape(), shape)?;
+        Ok(self.add_map_layout(operation))
+    }
+
+    /// Expand the tensor to a shape without copying it. Unlike [`DynTensor::broadcast_to`], new
+    /// dimensions can be inserted anywhere. Each dimension of the tensor is matched with the next
+    /// dimension of the shape it can broadcast to, and the dimensions in between are new.
+    pub fn expand(&self, shape: &[usize]) -> Result<Self, BroadcastError> {
+        let operation = expand_operation(self.key(), self.shape(), shape)?;
+        Ok(self.add_map_layout(operation))
+    }
+}
+
+tensor_ops! {
+    pub fn transpose(&self, first_axis: usize, second_axis: usize) -> Self {
         self.add_map_layout(MapLayoutOperation::new(
             self.key(),
             move |shape| {
@@ -66,36 +134,129 @@ impl<const R: usize, T: DataType> Tensor<R, T> {
         ))
     }
 
-    pub fn broadcast<const R2: usize>(&self, out_shape: [usize; R2]) -> Tensor<R2, T> {
-        const { assert!(R2 == R + 1) };
+}
 
-        let new_dim = self
-            .shape()
-            .iter()
-            .zip(out_shape.iter())
-            .take_while(|(a, b)| a == b)
-            .count();
-        assert_eq!(self.shape()[..new_dim], out_shape[..new_dim]);
-        assert_eq!(self.shape()[new_dim..], out_shape[new_dim + 1..]);
+/// The error returned when a tensor cannot be broadcast to a shape.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BroadcastError {
+    shape: Box<[usize]>,
+    target: Box<[usize]>,
+}
 
-        self.add_map_layout(MapLayoutOperation::new(
-            self.key(),
-            move |_| out_shape.into(),
-            move |offset, strides| {
-                let mut new_strides = [0; R2];
-                let mut new_strides_fill = 0;
-                for i in 0..R {
-                    if i == new_dim {
-                        new_strides[new_strides_fill] = 0;
-                        new_strides_fill += 1;
-                    }
-                    new_strides[new_strides_fill] = strides[i];
-                    new_strides_fill += 1;
-                }
-                (offset, new_strides.into())
-            },
-        ))
+impl BroadcastError {
+    fn new(shape: &[usize], target: &[usize]) -> Self {
+        Self {
+            shape: shape.into(),
+            target: target.into(),
+        }
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    pub fn target(&self) -> &[usize] {
+        &self.target
+    }
+}
+
+impl Display for BroadcastError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cannot broadcast a tensor of shape {:?} to shape {:?}, each dimension must either match the target dimension or have size 1",
+            self.shape, self.target
+        )
+    }
+}
+
+impl std::error::Error for BroadcastError {}
+
+/// For each dimension of the target shape, find the dimension of the input it reads from. New
+/// and stretched dimensions are `None` and get a stride of 0.
+fn broadcast_to_dims(
+    shape: &[usize],
+    target: &[usize],
+) -> Result<Box<[Option<usize>]>, BroadcastError> {
+    let error = || BroadcastError::new(shape, target);
+    let new_dims = target.len().checked_sub(shape.len()).ok_or_else(error)?;
+    target
+        .iter()
+        .enumerate()
+        .map(|(i, size)| match i.checked_sub(new_dims) {
+            None => Ok(None),
+            Some(dim) if shape[dim] == *size => Ok(Some(dim)),
+            Some(dim) if shape[dim] == 1 => Ok(None),
+            Some(_) => Err(error()),
+        })
+        .collect()
+}
+
+/// Like [`broadcast_to_dims`], but new dimensions can be inserted anywhere. Each dimension of
+/// the input is matched with the next dimension of the target it can broadcast to.
+fn expand_dims(shape: &[usize], target: &[usize]) -> Result<Box<[Option<usize>]>, BroadcastError> {
+    let error = || BroadcastError::new(shape, target);
+    let mut dims = Vec::with_capacity(target.len());
+    let mut dim = 0;
+    for (i, size) in target.iter().enumerate() {
+        match shape.get(dim) {
+            Some(input_size) if input_size == size => {
+                dims.push(Some(dim));
+                dim += 1;
+            }
+            Some(1) => {
+                dims.push(None);
+                dim += 1;
+            }
+            // Only add a new dimension if the rest of the input still fits in the target
+            _ if target.len() - i > shape.len() - dim => dims.push(None),
+            _ => return Err(error()),
+        }
     }
+    if dim != shape.len() {
+        return Err(error());
+    }
+    Ok(dims.into())
+}
+
+fn map_dims_operation(
+    input: AnyComputeKey,
+    target: &[usize],
+    dims: Box<[Option<usize>]>,
+) -> MapLayoutOperation {
+    let target: Box<[usize]> = target.into();
+    MapLayoutOperation::new(
+        input,
+        move |_| target.clone(),
+        move |offset, strides| {
+            let strides = dims
+                .iter()
+                .map(|dim| dim.map(|dim| strides[dim]).unwrap_or(0))
+                .collect();
+            (offset, strides)
+        },
+    )
+}
+
+/// Broadcast a tensor to a shape without copying it. The shapes are aligned from the last
+/// dimension like numpy.
+pub(crate) fn broadcast_to_operation(
+    input: AnyComputeKey,
+    shape: &[usize],
+    target: &[usize],
+) -> Result<MapLayoutOperation, BroadcastError> {
+    let dims = broadcast_to_dims(shape, target)?;
+    Ok(map_dims_operation(input, target, dims))
+}
+
+/// Expand a tensor to a shape without copying it. New dimensions can be inserted anywhere.
+pub(crate) fn expand_operation(
+    input: AnyComputeKey,
+    shape: &[usize],
+    target: &[usize],
+) -> Result<MapLayoutOperation, BroadcastError> {
+    let dims = expand_dims(shape, target)?;
+    Ok(map_dims_operation(input, target, dims))
 }
 
 #[cfg(test)]
@@ -147,3 +308,87 @@ async fn test_broadcast() {
         assert_eq!(as_slice[[1, 1, i]], 4.);
     }
 }
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_broadcast_to() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1.], [2.], [3.]];
+    let tensor = Tensor::new(&device, &data);
+    let broadcasted = tensor.broadcast_to([2, 3, 4]).unwrap();
+    let as_slice = broadcasted.as_slice().await.unwrap();
+    println!("{:?}", as_slice);
+    for i in 0..2 {
+        for j in 0..3 {
+            for k in 0..4 {
+                assert_eq!(as_slice[[i, j, k]], data[j][0]);
+            }
+        }
+    }
+
+    let error = tensor.broadcast_to([3, 2]).err().unwrap();
+    assert_eq!(error.shape(), &[3, 1]);
+    assert_eq!(error.target(), &[3, 2]);
+    assert!(tensor.broadcast_to([2, 4]).is_err());
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_expand() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1.], [2.]];
+    let tensor = Tensor::new(&device, &data);
+    let expanded = tensor.expand([5, 2, 3, 4]).unwrap();
+    let as_slice = expanded.as_slice().await.unwrap();
+    for i in 0..5 {
+        for j in 0..2 {
+            for k in 0..3 {
+                for l in 0..4 {
+                    assert_eq!(as_slice[[i, j, k, l]], data[j][0]);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_broadcast_dims() {
+    assert_eq!(
+        broadcast_to_dims(&[3, 1], &[2, 3, 4]).unwrap().as_ref(),
+        &[None, Some(0), None]
+    );
+    assert!(broadcast_to_dims(&[3, 2], &[2, 3]).is_err());
+    assert!(broadcast_to_dims(&[2, 3], &[3]).is_err());
+
+    assert_eq!(
+        expand_dims(&[2, 3], &[2, 4, 3]).unwrap().as_ref(),
+        &[Some(0), None, Some(1)]
+    );
+    assert_eq!(
+        expand_dims(&[2, 1], &[5, 2, 3, 4]).unwrap().as_ref(),
+        &[None, Some(0), None, None]
+    );
+    assert_eq!(
+        expand_dims(&[3], &[4, 3]).unwrap().as_ref(),
+        &[None, Some(0)]
+    );
+    assert!(expand_dims(&[2, 3], &[3, 2]).is_err());
+    assert!(expand_dims(&[2, 3], &[2, 4, 5]).is_err());
+    assert!(expand_dims(&[2, 3], &[3]).is_err());
+}
@@ -3,13 +3,16 @@ use std::{fmt::Write, sync::OnceLock};
 use wgpu::CommandEncoder;
 
 use crate::{
-    Device, Tensor,
+    Device,
     compute_graph::AnyComputeKey,
     kernel::{GenericKernel, KernelGlobalSpace},
     query::PerformanceQueries,
-    tensor::{DataType, DataTypeEnum, TensorData, padded_tensor_size},
+    tensor::{DataTypeEnum, TensorData, float_tensor_ops, padded_tensor_size},
 };
 
+#[cfg(test)]
+use crate::{DynTensor, Tensor};
+
 #[derive(Clone)]
 pub(crate) struct MatMulOperation {
     pub(crate) first: AnyComputeKey,
@@ -20,70 +23,102 @@ impl MatMulOperation {
     pub fn new(first: AnyComputeKey, second: AnyComputeKey) -> Self {
         Self { first, second }
     }
+
+    /// The shape of `first @ second`. The last two axes are multiplied as matrices and the
+    /// leading batch axes are broadcast if either size is one
+    pub(crate) fn output_shape(first: &[usize], second: &[usize]) -> Box<[usize]> {
+        let rank = first.len();
+        assert!(
+            rank >= 2,
+            "mat_mul requires tensors with at least 2 dimensions, found {rank}"
+        );
+        assert_eq!(
+            rank,
+            second.len(),
+            "mat_mul requires tensors with the same rank"
+        );
+        assert_eq!(
+            first[rank - 1],
+            second[rank - 2],
+            "cannot multiply matrices with shapes {first:?} and {second:?}"
+        );
+        let mut shape: Box<[usize]> = first.into();
+        for (i, (first_size, second_size)) in first.iter().zip(second).take(rank - 2).enumerate() {
+            assert!(
+                first_size == second_size || *first_size == 1 || *second_size == 1,
+                "cannot broadcast the batch axis {i} of shapes {first:?} and {second:?}"
+            );
+            shape[i] = (*first_size).max(*second_size);
+        }
+        shape[rank - 1] = second[rank - 1];
+        shape
+    }
 }
 
-impl<const R: usize, T: DataType> Tensor<R, T> {
+float_tensor_ops! {
     pub fn mat_mul(&self, other: &Self) -> Self {
         self.add_mat_mul(other)
     }
 }
 
-const WORK_GROUP_BLOCK_M_SIZE: u32 = 8;
-const WORK_GROUP_BLOCK_N_SIZE: u32 = 8;
+const WORK_GROUP_BLOCK_M_SIZE: u32 = 32;
+const WORK_GROUP_BLOCK_N_SIZE: u32 = 32;
 const WORK_GROUP_BLOCK_K_SIZE: u32 = 8;
 
-const THREAD_BLOCK_M_SIZE: u32 = 8;
+const THREAD_BLOCK_M_SIZE: u32 = 4;
 
 const WORK_GROUP_SIZE_ELEMENT: u32 =
     (WORK_GROUP_BLOCK_N_SIZE * WORK_GROUP_BLOCK_M_SIZE) / THREAD_BLOCK_M_SIZE;
-const WORK_GROUP_SIZE: [u32; 3] = [WORK_GROUP_SIZE_ELEMENT, WORK_GROUP_SIZE_ELEMENT, 1];
+const WORK_GROUP_SIZE: [u32; 3] = [WORK_GROUP_SIZE_ELEMENT, 1, 1];
 
 pub(crate) struct UntypedMatMul {
-    sparse_kernel: OnceLock<GenericKernel>,
-    first_dim_dense_kernel: OnceLock<GenericKernel>,
+    kernel: OnceLock<GenericKernel>,
     datatype: DataTypeEnum,
+    rank: u32,
 }
 
 impl UntypedMatMul {
-    pub(crate) const fn new(datatype: DataTypeEnum) -> Self {
+    pub(crate) const fn new(datatype: DataTypeEnum, rank: u32) -> Self {
         Self {
-            sparse_kernel: OnceLock::new(),
-            first_dim_dense_kernel: OnceLock::new(),
+            kernel: OnceLock::new(),
             datatype,
+            rank,
         }
     }
 
     fn compile(&self) -> &GenericKernel {
-        self.first_dim_dense_kernel.get_or_init(|| {
+        self.kernel.get_or_init(|| {
             // based on https://siboehm.com/articles/22/CUDA-MMM
             let mut generic_kernel = GenericKernel::new();
             generic_kernel.set_workgroup_size(WORK_GROUP_SIZE);
 
             let mut kernel = String::new();
 
-            let input_a = generic_kernel.add_tensor_input(2, false, self.datatype);
-            let input_b = generic_kernel.add_tensor_input(2, false, self.datatype);
-            let output = generic_kernel.add_tensor_input(2, true, self.datatype);
+            let rank = self.rank;
+            let input_a = generic_kernel.add_tensor_input(rank, false, self.datatype);
+            let input_b = generic_kernel.add_tensor_input(rank, false, self.datatype);
+            let output = generic_kernel.add_tensor_input(rank, true, self.datatype);
 
             let cache_a = generic_kernel.add_global_array(
                 KernelGlobalSpace::Workgroup,
                 self.datatype,
-                (WORK_GROUP_SIZE_ELEMENT * WORK_GROUP_SIZE_ELEMENT).to_string(),
+                (WORK_GROUP_BLOCK_M_SIZE * WORK_GROUP_BLOCK_K_SIZE).to_string(),
             );
             let cache_b = generic_kernel.add_global_array(
                 KernelGlobalSpace::Workgroup,
                 self.datatype,
-                (WORK_GROUP_SIZE_ELEMENT * WORK_GROUP_SIZE_ELEMENT).to_string(),
+                (WORK_GROUP_BLOCK_K_SIZE * WORK_GROUP_BLOCK_N_SIZE).to_string(),
             );
 
             let datatype = self.datatype;
             let workgroup_index = generic_kernel.workgroup_index();
             let workgroup_local_index = generic_kernel.workgroup_local_index();
 
-            let m_size = input_a.shape_binding(0);
-            let n_size = input_b.shape_binding(1);
-            let k_size = input_a.shape_binding(1);
-            // 1.4743 ms
+            let row_axis = rank - 2;
+            let col_axis = rank - 1;
+            let m_size = input_a.shape_binding(row_axis);
+            let n_size = input_b.shape_binding(col_axis);
+            let k_size = input_a.shape_binding(col_axis);
             writeln!(&mut kernel, "let block_row = {workgroup_index}.y;").unwrap();
             writeln!(&mut kernel, "let block_col = {workgroup_index}.x;").unwrap();
             writeln!(&mut kernel, "let thread_col = {workgroup_local_index} % {WORK_GROUP_BLOCK_N_SIZE};").unwrap();
@@ -92,29 +127,52 @@ impl UntypedMatMul {
             writeln!(&mut kernel, "let a_thread_row = {workgroup_local_index} / {WORK_GROUP_BLOCK_K_SIZE};").unwrap();
             writeln!(&mut kernel, "let b_thread_col = {workgroup_local_index} % {WORK_GROUP_BLOCK_N_SIZE};").unwrap();
             writeln!(&mut kernel, "let b_thread_row = {workgroup_local_index} / {WORK_GROUP_BLOCK_N_SIZE};").unwrap();
-            writeln!(&mut kernel, "var results: array<{datatype}, {THREAD_BLOCK_M_SIZE}>;").unwrap();
-            writeln!(&mut kernel, "let a_row = {k_size} * (a_thread_row + block_row * {WORK_GROUP_SIZE_ELEMENT});").unwrap();
-            writeln!(&mut kernel, "var a_col = a_thread_col;").unwrap();
-            writeln!(&mut kernel, "let a_row_max = {m_size} * {k_size};").unwrap();
-            writeln!(&mut kernel, "let a_col_max = {k_size};").unwrap();
-            writeln!(&mut kernel, "var b_row = {n_size} * b_thread_row;").unwrap(); 
-            writeln!(&mut kernel, "let b_col = b_thread_col + block_col * {WORK_GROUP_SIZE_ELEMENT};").unwrap();
-            writeln!(&mut kernel, "let b_row_max = {k_size} * {n_size};").unwrap();
-            writeln!(&mut kernel, "let b_col_max = {n_size};").unwrap();
 
+            // The batch is flattened into the z workgroup index. Batch axes with a size of one
+            // are broadcast
+            let a_offset = input_a.offset_binding();
+            let b_offset = input_b.offset_binding();
+            let output_offset = output.offset_binding();
+            writeln!(&mut kernel, "var batch_remainder = {workgroup_index}.z;").unwrap();
+            writeln!(&mut kernel, "var a_start = {a_offset};").unwrap();
+            writeln!(&mut kernel, "var b_start = {b_offset};").unwrap();
+            writeln!(&mut kernel, "var output_start = {output_offset};").unwrap();
+            for axis in (0..row_axis).rev() {
+                let output_size = output.shape_binding(axis);
+                let a_size = input_a.shape_binding(axis);
+                let b_size = input_b.shape_binding(axis);
+                let a_stride = input_a.stride_binding(axis);
+                let b_stride = input_b.stride_binding(axis);
+                let output_stride = output.stride_binding(axis);
+                writeln!(&mut kernel, "let batch_index_{axis} = batch_remainder % {output_size};").unwrap();
+                writeln!(&mut kernel, "batch_remainder /= {output_size};").unwrap();
+                writeln!(&mut kernel, "a_start += (batch_index_{axis} % {a_size}) * {a_stride};").unwrap();
+                writeln!(&mut kernel, "b_start += (batch_index_{axis} % {b_size}) * {b_stride};").unwrap();
+                writeln!(&mut kernel, "output_start += batch_index_{axis} * {output_stride};").unwrap();
+            }
+
+            let a_row_stride = input_a.stride_binding(row_axis);
+            let a_col_stride = input_a.stride_binding(col_axis);
+            let b_row_stride = input_b.stride_binding(row_axis);
+            let b_col_stride = input_b.stride_binding(col_axis);
+            writeln!(&mut kernel, "var results: array<{datatype}, {THREAD_BLOCK_M_SIZE}>;").unwrap();
+            writeln!(&mut kernel, "let a_row = a_thread_row + block_row * {WORK_GROUP_BLOCK_M_SIZE};").unwrap();
+            writeln!(&mut kernel, "let b_col = b_thread_col + block_col * {WORK_GROUP_BLOCK_N_SIZE};").unwrap();
 
             writeln!(&mut kernel, "for (var block_index = 0u; block_index < {k_size}; block_index += {WORK_GROUP_BLOCK_K_SIZE}) {{").unwrap();
 
-            writeln!(&mut kernel, "if a_col < a_col_max && a_row < a_row_max {{").unwrap();
-            writeln!(&mut kernel, "let a_index = a_row + a_col;").unwrap();
+            writeln!(&mut kernel, "let a_col = block_index + a_thread_col;").unwrap();
+            writeln!(&mut kernel, "if a_col < {k_size} && a_row < {m_size} {{").unwrap();
+            writeln!(&mut kernel, "let a_index = a_start + a_row * {a_row_stride} + a_col * {a_col_stride};").unwrap();
             writeln!(&mut kernel, "{cache_a}[a_thread_row * {WORK_GROUP_BLOCK_K_SIZE} + a_thread_col] = {input_a}[a_index];").unwrap();
             writeln!(&mut kernel, "}}").unwrap();
             writeln!(&mut kernel, "else {{").unwrap();
             writeln!(&mut kernel, "{cache_a}[a_thread_row * {WORK_GROUP_BLOCK_K_SIZE} + a_thread_col] = 0.0;").unwrap();
             writeln!(&mut kernel, "}}").unwrap();
-            writeln!(&mut kernel, "if b_row < b_row_max && b_col < {n_size} {{").unwrap();
-            writeln!(&mut kernel, "let b_index = b_row + b_col;").unwrap();
-            writeln!(&mut kernel, "{cache_b}[b_thread_row * {WORK_GROUP_BLOCK_N_SIZE} + b_thread_col] = {input_b}[b_index];").unwrap(); 
+            writeln!(&mut kernel, "let b_row = block_index + b_thread_row;").unwrap();
+            writeln!(&mut kernel, "if b_row < {k_size} && b_col < {n_size} {{").unwrap();
+            writeln!(&mut kernel, "let b_index = b_start + b_row * {b_row_stride} + b_col * {b_col_stride};").unwrap();
+            writeln!(&mut kernel, "{cache_b}[b_thread_row * {WORK_GROUP_BLOCK_N_SIZE} + b_thread_col] = {input_b}[b_index];").unwrap();
             writeln!(&mut kernel, "}}").unwrap();
             writeln!(&mut kernel, "else {{").unwrap();
             writeln!(&mut kernel, "{cache_b}[b_thread_row * {WORK_GROUP_BLOCK_N_SIZE} + b_thread_col] = 0.0;").unwrap();
@@ -122,9 +180,6 @@ impl UntypedMatMul {
 
             writeln!(&mut kernel, "workgroupBarrier();").unwrap();
 
-            writeln!(&mut kernel, "a_col += {WORK_GROUP_BLOCK_K_SIZE};").unwrap();
-            writeln!(&mut kernel, "b_row += {WORK_GROUP_BLOCK_K_SIZE} * {n_size};").unwrap();
-
             writeln!(&mut kernel, "for (var dot_index = 0u; dot_index < {WORK_GROUP_BLOCK_K_SIZE}; dot_index += 1u) {{").unwrap();
             writeln!(&mut kernel, "let tmp = {cache_b}[dot_index * {WORK_GROUP_BLOCK_N_SIZE} + thread_col];").unwrap();
             writeln!(&mut kernel, "for (var result_index = 0u; result_index < {THREAD_BLOCK_M_SIZE}; result_index += 1u) {{").unwrap();
@@ -136,14 +191,15 @@ impl UntypedMatMul {
 
             writeln!(&mut kernel, "}}").unwrap();
 
-
+            let output_row_stride = output.stride_binding(row_axis);
+            let output_col_stride = output.stride_binding(col_axis);
             writeln!(&mut kernel, "let start_output_row = thread_row * {THREAD_BLOCK_M_SIZE} + block_row * {WORK_GROUP_BLOCK_M_SIZE};").unwrap();
             writeln!(&mut kernel, "let start_output_col = thread_col + block_col * {WORK_GROUP_BLOCK_N_SIZE};").unwrap();
             writeln!(&mut kernel, "for (var result_index = 0u; result_index < {THREAD_BLOCK_M_SIZE}; result_index += 1u) {{").unwrap();
             writeln!(&mut kernel, "let output_row = start_output_row + result_index;").unwrap();
             writeln!(&mut kernel, "let output_col = start_output_col;").unwrap();
             writeln!(&mut kernel, "if output_col < {n_size} && output_row < {m_size} {{").unwrap();
-            writeln!(&mut kernel, "let output_index = output_row * {n_size} + output_col;").unwrap();
+            writeln!(&mut kernel, "let output_index = output_start + output_row * {output_row_stride} + output_col * {output_col_stride};").unwrap();
             writeln!(&mut kernel, "{output}[output_index] = results[result_index];").unwrap();
             writeln!(&mut kernel, "}}").unwrap();
             writeln!(&mut kernel, "}}").unwrap();
@@ -162,22 +218,17 @@ impl UntypedMatMul {
         command_encoder: &mut CommandEncoder,
     ) -> TensorData {
         let device = a.device();
-        let a_shape = a.layout().shape();
-        let b_shape = b.layout().shape();
+        let output_shape = MatMulOperation::output_shape(a.layout().shape(), b.layout().shape());
         let output_buf = device.wgpu_device().create_buffer(&wgpu::BufferDescriptor {
             label: None,
             size: padded_tensor_size(
-                (a_shape[0] * b_shape[1] * a.datatype().element_size()) as u64,
+                (output_shape.iter().product::<usize>() * a.datatype().element_size()) as u64,
             ),
             usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
             mapped_at_creation: false,
         });
-        let output_tensor = TensorData::new_from_buffer(
-            device,
-            output_buf,
-            &[a_shape[0], b_shape[1]],
-            a.datatype(),
-        );
+        let output_tensor =
+            TensorData::new_from_buffer(device, output_buf, &output_shape, a.datatype());
         self.run_with_query_and_out_tensor(device, a, b, query, &output_tensor, command_encoder);
         output_tensor
     }
@@ -191,17 +242,25 @@ impl UntypedMatMul {
         output_tensor: &TensorData,
         command_encoder: &mut CommandEncoder,
     ) {
-        assert_eq!(a.layout().shape()[1], b.layout().shape()[0]);
+        let output_shape = MatMulOperation::output_shape(a.layout().shape(), b.layout().shape());
+        assert_eq!(*output_tensor.layout().shape(), *output_shape);
         let module = self.compile();
 
-        let a_shape = a.layout().shape();
-        let b_shape = b.layout().shape();
-        assert_eq!(*output_tensor.layout().shape(), [a_shape[0], b_shape[1]]);
+        let rank = output_shape.len();
+        let batch_size = output_shape[..rank - 2].iter().product::<usize>() as u32;
+        let max_workgroups = device
+            .wgpu_device()
+            .limits()
+            .max_compute_workgroups_per_dimension;
+        assert!(
+            batch_size <= max_workgroups,
+            "mat_mul supports at most {max_workgroups} batches, found {batch_size}"
+        );
 
         let workgroup_dispatch_size = [
-            (a_shape[0] as u32).div_ceil(WORK_GROUP_SIZE[0] * THREAD_BLOCK_M_SIZE),
-            (b_shape[1] as u32).div_ceil(WORK_GROUP_SIZE[1] * THREAD_BLOCK_M_SIZE),
-            1,
+            (output_shape[rank - 1] as u32).div_ceil(WORK_GROUP_BLOCK_N_SIZE),
+            (output_shape[rank - 2] as u32).div_ceil(WORK_GROUP_BLOCK_M_SIZE),
+            batch_size,
         ];
 
         module.run_with_query(
@@ -330,3 +389,57 @@ async fn fuzz_matmul() {
     assert_eq!(as_slice[[1, 0]], 3.);
     assert_eq!(as_slice[[1, 1]], 6.);
 }
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_batched_matmul() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let (batch, heads, seq, dim) = (2, 3, 5, 40);
+    let data_q = (0..batch * heads * seq * dim)
+        .map(|i| (i % 11) as f32 - 5.)
+        .collect::<Vec<_>>();
+    // One key matrix shared by every batch
+    let data_k = (0..heads * seq * dim)
+        .map(|i| (i % 7) as f32 - 3.)
+        .collect::<Vec<_>>();
+    let q = DynTensor::new(&device, &[batch, heads, seq, dim], &data_q);
+    let k = DynTensor::new(&device, &[1, heads, seq, dim], &data_k);
+
+    // Q @ K^T reads the transposed keys through their strides
+    let scores = q.mat_mul(&k.transpose(2, 3));
+    assert_eq!(scores.shape(), &[batch, heads, seq, seq]);
+    let as_slice = scores.as_slice().await.unwrap();
+    for b in 0..batch {
+        for h in 0..heads {
+            for i in 0..seq {
+                for j in 0..seq {
+                    let expected: f32 = (0..dim)
+                        .map(|d| {
+                            data_q[((b * heads + h) * seq + i) * dim + d]
+                                * data_k[(h * seq + j) * dim + d]
+                        })
+                        .sum();
+                    assert_eq!(as_slice[[b, h, i, j]], expected);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_matmul_kernel() {
+    for datatype in [DataTypeEnum::F32, DataTypeEnum::F16] {
+        for rank in [2, 3, 4] {
+            UntypedMatMul::new(datatype, rank)
+                .compile()
+                .validate()
+                .unwrap();
+        }
+    }
+}
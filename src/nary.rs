@@ -0,0 +1,306 @@
+use std::fmt::{Display, Write};
+
+use crate::{
+    compute_graph::AnyComputeKey,
+    kernel::{Function, GenericKernel},
+    tensor::{DataTypeEnum, float_tensor_ops, tensor_ops},
+};
+
+#[cfg(test)]
+use crate::{Device, DynTensor, Tensor};
+
+#[derive(Clone)]
+pub(crate) struct NaryOperation {
+    pub(crate) inputs: Vec<AnyComputeKey>,
+    pub(crate) function: NaryFunction,
+}
+
+impl NaryOperation {
+    pub fn new(function: NaryFunction, inputs: Vec<AnyComputeKey>) -> Self {
+        assert_eq!(function.input_count, inputs.len());
+        Self { inputs, function }
+    }
+}
+
+/// A function from one element of each input to one output element. The operation reads the
+/// inputs as `a`, `b`, `c`, ... in order.
+#[derive(Clone)]
+pub struct NaryFunction {
+    name: Option<String>,
+    operation: String,
+    input_count: usize,
+    scalars: Vec<f32>,
+    datatype: DataTypeEnum,
+    scalar_only: bool,
+}
+
+impl NaryFunction {
+    pub(crate) fn new(operation: impl Display, input_count: usize, datatype: DataTypeEnum) -> Self {
+        Self {
+            name: None,
+            operation: operation.to_string(),
+            input_count,
+            scalars: Vec::new(),
+            datatype,
+            scalar_only: false,
+        }
+    }
+
+    /// Mark the operation as only valid for scalars. Kernels that work on `vec4`s run it on
+    /// each lane instead of on the whole vector
+    pub(crate) fn scalar_only(mut self) -> Self {
+        self.scalar_only = true;
+        self
+    }
+
+    pub(crate) fn with_name(mut self, name: impl ToString) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Add a scalar operand to the function. The operation can read the nth scalar as the f32
+    /// `scalar_n`
+    pub(crate) fn with_scalar(mut self, value: f32) -> Self {
+        self.scalars.push(value);
+        self
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or("nary")
+    }
+
+    /// `b` where `a` is not zero and `c` everywhere else
+    pub(crate) fn where_(datatype: DataTypeEnum) -> Self {
+        Self::new(
+            format_args!("let output = select(c, b, a != {datatype}(0));"),
+            3,
+            datatype,
+        )
+        .with_name("where")
+        .scalar_only()
+    }
+
+    pub(crate) fn datatype(&self) -> DataTypeEnum {
+        self.datatype
+    }
+
+    pub(crate) fn add_function(
+        &self,
+        kernel: &mut GenericKernel,
+        inputs: impl IntoIterator<Item = DataTypeEnum>,
+        vectorized: bool,
+    ) -> Function {
+        let vector = vectorized && !self.scalar_only;
+        let ty = |datatype: DataTypeEnum| match vector {
+            true => format!("vec4<{datatype}>"),
+            false => datatype.to_string(),
+        };
+        let mut operation = String::new();
+        for (i, value) in self.scalars.iter().enumerate() {
+            let scalar = kernel.add_float_input(*value);
+            writeln!(&mut operation, "let scalar_{i} = {scalar};").unwrap();
+        }
+        operation.push_str(&self.operation);
+        let function = kernel.add_function(
+            ty(self.datatype),
+            operation,
+            inputs
+                .into_iter()
+                .zip(INPUT_NAMES)
+                .map(|(datatype, name)| (name.to_string(), ty(datatype))),
+        );
+        match vectorized && !vector {
+            true => kernel.add_lane_wise_function(&function),
+            false => function,
+        }
+    }
+}
+
+pub(crate) const INPUT_NAMES: [&str; 4] = ["a", "b", "c", "d"];
+
+tensor_ops! {
+    /// Choose the element from `on_true` where this tensor is not zero and from `on_false`
+    /// everywhere else
+    pub fn where_(&self, on_true: &Self, on_false: &Self) -> Self {
+        self.nary(&[on_true, on_false], NaryFunction::where_(D::WGSL_TYPE))
+    }
+
+    /// Clamp each element between the elements of `min` and `max`. If `min` is larger than
+    /// `max`, the result is `max`.
+    pub fn clamp_tensor(&self, min: &Self, max: &Self) -> Self {
+        self.nary(
+            &[min, max],
+            NaryFunction::new("let output = min(max(a, b), c);", 3, D::WGSL_TYPE)
+                .with_name("clamp_tensor"),
+        )
+    }
+}
+
+float_tensor_ops! {
+    /// Linearly interpolate from this tensor to `end` by `weight`
+    pub fn lerp(&self, end: &Self, weight: &Self) -> Self {
+        self.nary(
+            &[end, weight],
+            NaryFunction::new("let output = a + c * (b - a);", 3, D::WGSL_TYPE).with_name("lerp"),
+        )
+    }
+
+    /// `self * multiplier + addend` as one fused multiply add
+    pub fn fma(&self, multiplier: &Self, addend: &Self) -> Self {
+        self.nary(
+            &[multiplier, addend],
+            NaryFunction::new("let output = fma(a, b, c);", 3, D::WGSL_TYPE).with_name("fma"),
+        )
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_where() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let mask = [[1., 0.], [0., 1.], [1., 1.]];
+    let data_a = [[1., 2.], [3., 4.], [5., 6.]];
+    let data_b = [[-1., -2.], [-3., -4.], [-5., -6.]];
+    let mask_tensor = Tensor::new(&device, &mask);
+    let tensor_a = Tensor::new(&device, &data_a);
+    let tensor_b = Tensor::new(&device, &data_b);
+
+    let tensor = mask_tensor.where_(&tensor_a, &tensor_b);
+    let as_slice = tensor.as_slice().await.unwrap();
+
+    for i in 0..3 {
+        for j in 0..2 {
+            let expected = if mask[i][j] != 0. {
+                data_a[i][j]
+            } else {
+                data_b[i][j]
+            };
+            assert_eq!(as_slice[[i, j]], expected);
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_lerp() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data_a = [[1., 2.], [3., 4.], [5., 6.]];
+    let data_b = [[3., 4.], [-3., 8.], [5., 0.]];
+    let weight = [[0., 0.5], [1., 0.25], [0.75, 0.5]];
+    let tensor_a = Tensor::new(&device, &data_a);
+    let tensor_b = Tensor::new(&device, &data_b);
+    let weight_tensor = Tensor::new(&device, &weight);
+
+    let tensor = tensor_a.lerp(&tensor_b, &weight_tensor);
+    let as_slice = tensor.as_slice().await.unwrap();
+
+    for i in 0..3 {
+        for j in 0..2 {
+            let expected = data_a[i][j] + weight[i][j] * (data_b[i][j] - data_a[i][j]);
+            assert!((as_slice[[i, j]] - expected).abs() < 0.001);
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_fma() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data_a = [[1., 2.], [3., 4.], [5., 6.]];
+    let data_b = [[3., 4.], [-3., 8.], [5., 0.]];
+    let data_c = [[0., 0.5], [1., 0.25], [0.75, 0.5]];
+    let tensor_a = Tensor::new(&device, &data_a);
+    let tensor_b = Tensor::new(&device, &data_b);
+    let tensor_c = Tensor::new(&device, &data_c);
+
+    let tensor = tensor_a.fma(&tensor_b, &tensor_c);
+    let as_slice = tensor.as_slice().await.unwrap();
+
+    for i in 0..3 {
+        for j in 0..2 {
+            let expected: f32 = data_a[i][j] * data_b[i][j] + data_c[i][j];
+            assert!((as_slice[[i, j]] - expected).abs() < 0.001);
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_clamp_tensor() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [1., -2., 3., 4., 5., 6.];
+    let min = [0., 0., 0., 5., 5., 5.];
+    let max = [2., 2., 2.5, 6., 6., 4.];
+    let tensor = DynTensor::new(&device, &[6], &data);
+    let min_tensor = DynTensor::new(&device, &[6], &min);
+    let max_tensor = DynTensor::new(&device, &[6], &max);
+
+    let tensor = tensor.clamp_tensor(&min_tensor, &max_tensor);
+    let as_slice = tensor.as_slice().await.unwrap();
+
+    for i in 0..6 {
+        let expected: f32 = data[i].max(min[i]).min(max[i]);
+        assert_eq!(as_slice[&[i][..]], expected);
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_nary_fused() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data_a = [[1., 2.], [3., 4.], [5., 6.]];
+    let data_b = [[3., 4.], [-3., 8.], [5., 0.]];
+    let data_c = [[0., 0.5], [1., 0.25], [0.75, 0.5]];
+    let tensor_a = Tensor::new(&device, &data_a);
+    let tensor_b = Tensor::new(&device, &data_b);
+    let tensor_c = Tensor::new(&device, &data_c);
+
+    // Element-wise ops on every input and the output, and a pair-wise op as an input, all run in
+    // one kernel
+    let start = tensor_a.clone() * 2.;
+    let end = &tensor_b + &tensor_c;
+    let weight = tensor_c.sigmoid();
+    let tensor = start.lerp(&end, &weight).exp() - 1.;
+    let as_slice = tensor.as_slice().await.unwrap();
+
+    for i in 0..3 {
+        for j in 0..2 {
+            let start: f32 = data_a[i][j] * 2.;
+            let end = data_b[i][j] + data_c[i][j];
+            let weight = 1. / (1. + (-data_c[i][j]).exp());
+            let expected = (start + weight * (end - start)).exp() - 1.;
+            assert!((as_slice[[i, j]] - expected).abs() / expected.abs() < 0.001);
+        }
+    }
+    assert_eq!(tensor.all_timing_information().await.len(), 1);
+}
@@ -1,19 +1,20 @@
 use std::{
     fmt::{Display, Write},
-    ops::{Add, Div, Mul, Sub},
+    ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Sub, SubAssign},
     sync::OnceLock,
 };
 
 use wgpu::CommandEncoder;
 
 use crate::{
-    Tensor, UntypedElementWiseKernel,
+    DynTensor, ElementWiseFunction, Tensor, UntypedElementWiseKernel,
     compute_graph::AnyComputeKey,
     kernel::{Function, GenericKernel},
     layout::TILE_SIZE,
+    nary::NaryFunction,
     query::PerformanceQueries,
-    tensor::{DataType, DataTypeEnum, TensorData},
-    visit_tiled::VisitTiledKernel,
+    tensor::{DataType, DataTypeEnum, TensorData, float_tensor_ops, tensor_ops},
+    visit_tiled::{MapOutput, VisitTiledKernel},
 };
 
 #[derive(Clone)]
@@ -33,141 +34,215 @@ impl PairWiseOperation {
     }
 }
 
+/// A tree of pair-wise, n-ary and element-wise functions that is evaluated in a single kernel
+pub(crate) enum PairWiseExpression {
+    /// The nth input tensor of the kernel
+    Input(usize),
+    /// A chain of element-wise functions in the same order as the element-wise nodes are
+    /// collected: the last function is applied first
+    ElementWise {
+        value: Box<PairWiseExpression>,
+        functions: Vec<ElementWiseFunction>,
+    },
+    PairWise {
+        first: Box<PairWiseExpression>,
+        second: Box<PairWiseExpression>,
+        function: PairWiseFunction,
+    },
+    Nary {
+        operands: Vec<PairWiseExpression>,
+        function: NaryFunction,
+    },
+}
+
+impl PairWiseExpression {
+    pub(crate) fn element_wise(self, functions: Vec<ElementWiseFunction>) -> Self {
+        if functions.is_empty() {
+            self
+        } else {
+            Self::ElementWise {
+                value: Box::new(self),
+                functions,
+            }
+        }
+    }
+
+    fn datatype(&self, inputs: &[DataTypeEnum]) -> DataTypeEnum {
+        match self {
+            Self::Input(index) => inputs[*index],
+            Self::ElementWise { value, functions } => functions
+                .first()
+                .map(|function| function.datatype())
+                .unwrap_or_else(|| value.datatype(inputs)),
+            Self::PairWise { function, .. } => function.datatype,
+            Self::Nary { function, .. } => function.datatype(),
+        }
+    }
+
+    /// Add the functions for every node to the kernel in post-order
+    fn add_functions(
+        &self,
+        kernel: &mut GenericKernel,
+        inputs: &[DataTypeEnum],
+        functions: &mut Vec<Function>,
+        vectorized: bool,
+    ) {
+        match self {
+            Self::Input(_) => {}
+            Self::ElementWise {
+                value,
+                functions: element_wise,
+            } => {
+                value.add_functions(kernel, inputs, functions, vectorized);
+                let element_wise =
+                    UntypedElementWiseKernel::new(element_wise.clone(), value.datatype(inputs));
+                functions.extend(element_wise.add_functions(kernel, vectorized));
+            }
+            Self::PairWise {
+                first,
+                second,
+                function,
+            } => {
+                first.add_functions(kernel, inputs, functions, vectorized);
+                second.add_functions(kernel, inputs, functions, vectorized);
+                functions.push(function.add_function(
+                    kernel,
+                    first.datatype(inputs),
+                    second.datatype(inputs),
+                    vectorized,
+                ));
+            }
+            Self::Nary { operands, function } => {
+                for operand in operands {
+                    operand.add_functions(kernel, inputs, functions, vectorized);
+                }
+                functions.push(function.add_function(
+                    kernel,
+                    operands.iter().map(|operand| operand.datatype(inputs)),
+                    vectorized,
+                ));
+            }
+        }
+    }
+
+    /// Build the expression from the functions added by [`Self::add_functions`]
+    fn call<'a>(
+        &self,
+        values: &[String],
+        functions: &mut impl Iterator<Item = &'a Function>,
+    ) -> String {
+        match self {
+            Self::Input(index) => values[*index].clone(),
+            Self::ElementWise {
+                value,
+                functions: element_wise,
+            } => {
+                let value = value.call(values, functions);
+                functions
+                    .take(element_wise.len())
+                    .fold(value, |acc, f| f.call(vec![acc]))
+            }
+            Self::PairWise { first, second, .. } => {
+                let first = first.call(values, functions);
+                let second = second.call(values, functions);
+                functions.next().unwrap().call(vec![first, second])
+            }
+            Self::Nary { operands, .. } => {
+                let operands = operands
+                    .iter()
+                    .map(|operand| operand.call(values, functions))
+                    .collect();
+                functions.next().unwrap().call(operands)
+            }
+        }
+    }
+}
+
+/// A kernel that evaluates a [`PairWiseExpression`] over any number of inputs with the same shape
 pub(crate) struct UntypedPairWiseKernel {
-    pre_element_wise: [UntypedElementWiseKernel; 2],
-    function: PairWiseFunction,
-    post_element_wise: UntypedElementWiseKernel,
+    expression: PairWiseExpression,
     dense_kernel: OnceLock<VisitTiledKernel>,
     sparse_kernel: OnceLock<VisitTiledKernel>,
-    input_datatype: DataTypeEnum,
+    input_datatypes: Vec<DataTypeEnum>,
 }
 
 impl UntypedPairWiseKernel {
-    pub fn new(function: PairWiseFunction, datatype: DataTypeEnum) -> Self {
+    pub fn new(expression: PairWiseExpression, input_datatypes: Vec<DataTypeEnum>) -> Self {
         Self {
-            pre_element_wise: [
-                UntypedElementWiseKernel::empty(datatype),
-                UntypedElementWiseKernel::empty(datatype),
-            ],
-            function,
-            post_element_wise: UntypedElementWiseKernel::empty(datatype),
+            expression,
             dense_kernel: OnceLock::new(),
             sparse_kernel: OnceLock::new(),
-            input_datatype: datatype,
+            input_datatypes,
         }
     }
 
-    pub fn set_post_element_wise(&mut self, element_wise: UntypedElementWiseKernel) {
-        self.post_element_wise = element_wise;
-    }
-
-    pub fn set_pre_element_wise(&mut self, element_wise: [UntypedElementWiseKernel; 2]) {
-        self.pre_element_wise = element_wise;
+    fn output_datatype(&self) -> DataTypeEnum {
+        self.expression.datatype(&self.input_datatypes)
     }
 
-    fn output_datatype(&self) -> DataTypeEnum {
-        self.post_element_wise.out_datatype()
-    }
-
-    pub fn add_function(&self, kernel: &mut GenericKernel) -> Function {
-        kernel.add_function(
-            self.function.datatype,
-            self.function.operation.clone(),
-            ["a", "b"].iter().enumerate().map(|(i, x)| {
-                (
-                    x.to_string(),
-                    self.pre_element_wise[i].out_datatype().to_string(),
-                )
-            }),
+    fn create_kernel(&self, rank: u32, contiguous: bool, output: MapOutput) -> VisitTiledKernel {
+        let functions = OnceLock::new();
+        VisitTiledKernel::map(
+            rank,
+            TILE_SIZE,
+            contiguous,
+            self.input_datatypes.clone(),
+            output,
+            |kernel, values, vectorized| {
+                let functions: &Vec<Function> = functions.get_or_init(|| {
+                    let mut functions = Vec::new();
+                    self.expression.add_functions(
+                        kernel,
+                        &self.input_datatypes,
+                        &mut functions,
+                        vectorized,
+                    );
+                    functions
+                });
+                self.expression.call(values, &mut functions.iter())
+            },
         )
     }
 
     pub fn run_with_query(
         &self,
-        first: TensorData,
-        second: TensorData,
+        inputs: Vec<TensorData>,
         query: Option<&PerformanceQueries>,
         command_encoder: &mut CommandEncoder,
     ) -> TensorData {
-        assert_eq!(first.layout().shape(), second.layout().shape());
-        let contiguous = first.layout().is_contiguous() && second.layout().is_contiguous();
+        let first = &inputs[0];
+        for input in &inputs[1..] {
+            assert_eq!(first.layout().shape(), input.layout().shape());
+        }
+        let contiguous = inputs.iter().all(|input| input.layout().is_contiguous());
         let rank = first.layout().rank();
-        let re_used_allocation_index = if self.input_datatype == self.output_datatype() {
-            if first.owned() && !first.layout().allocation_overlaps() {
-                Some(0)
-            } else if second.owned() && !second.layout().allocation_overlaps() {
-                Some(1)
-            } else {
-                None
-            }
-        } else {
-            None
-        };
-        let output_tensor_index = re_used_allocation_index.unwrap_or(2);
+        let output_datatype = self.output_datatype();
+        let re_used_allocation_index = inputs.iter().position(|input| {
+            input.datatype() == output_datatype
+                && input.owned()
+                && !input.layout().allocation_overlaps()
+        });
+        let output_tensor_index = re_used_allocation_index.unwrap_or(inputs.len());
         let requires_new_tensor = re_used_allocation_index.is_none();
 
-        let pre_element_wise_functions: OnceLock<[Vec<Function>; 2]> = OnceLock::new();
-        let pair_wise_function = OnceLock::new();
-        let post_element_wise_functions = OnceLock::new();
         let create_kernel = || {
-            let mut datatypes = vec![self.input_datatype, self.input_datatype];
-
-            if requires_new_tensor {
-                datatypes.push(self.output_datatype());
-            }
-
-            VisitTiledKernel::new(
-                rank as u32,
-                TILE_SIZE,
-                contiguous,
-                datatypes,
-                |kernel, indexes, tensors| {
-                    let first_index = &indexes[0];
-                    let second_index = &indexes[1];
-                    let output_index = &indexes[output_tensor_index];
-                    let first_tensor = &tensors[0];
-                    let second_tensor = &tensors[1];
-                    let out_tensor = &tensors[output_tensor_index];
-                    let mut kernel_text = String::new();
-                    let pre_element_wise_functions = pre_element_wise_functions.get_or_init(|| {
-                        std::array::from_fn(|i| self.pre_element_wise[i].add_functions(kernel))
-                    });
-                    let first_value = pre_element_wise_functions[0]
-                        .iter()
-                        .fold(format!("{first_tensor}[{first_index}]"), |acc, f| {
-                            f.call(vec![acc])
-                        });
-                    writeln!(&mut kernel_text, "let a = {first_value};").unwrap();
-                    let second_value = pre_element_wise_functions[1]
-                        .iter()
-                        .fold(format!("{second_tensor}[{second_index}]"), |acc, f| {
-                            f.call(vec![acc])
-                        });
-                    writeln!(&mut kernel_text, "let b = {second_value};").unwrap();
-                    let pair_wise_function =
-                        pair_wise_function.get_or_init(|| self.add_function(kernel));
-                    let result = pair_wise_function.call(vec!["a".to_string(), "b".to_string()]);
-                    let post_element_wise_functions = post_element_wise_functions
-                        .get_or_init(|| self.post_element_wise.add_functions(kernel));
-                    let result = post_element_wise_functions
-                        .iter()
-                        .fold(result, |acc, f| f.call(vec![acc]));
-                    writeln!(&mut kernel_text, "{out_tensor}[{output_index}] = {result};").unwrap();
-                    kernel_text
-                },
-            )
+            let output = match re_used_allocation_index {
+                Some(index) => MapOutput::InPlace(index),
+                None => MapOutput::New(output_datatype),
+            };
+            self.create_kernel(rank as u32, contiguous, output)
         };
         let kernel = if contiguous {
             self.dense_kernel.get_or_init(create_kernel)
         } else {
             self.sparse_kernel.get_or_init(create_kernel)
         };
-        let mut tensors = vec![first.clone(), second.clone()];
+        let mut tensors = inputs;
         if requires_new_tensor {
             let output_tensor = TensorData::new_for_shape(
-                first.device(),
-                first.layout().shape(),
-                self.output_datatype(),
+                tensors[0].device(),
+                tensors[0].layout().shape(),
+                output_datatype,
             );
             tensors.push(output_tensor);
         }
@@ -181,6 +256,7 @@ pub struct PairWiseFunction {
     name: Option<String>,
     operation: String,
     datatype: DataTypeEnum,
+    scalar_only: bool,
 }
 
 impl PairWiseFunction {
@@ -189,9 +265,17 @@ impl PairWiseFunction {
             name: None,
             operation: operation.to_string(),
             datatype,
+            scalar_only: false,
         }
     }
 
+    /// Mark the operation as only valid for scalars. Kernels that work on `vec4`s run it on
+    /// each lane instead of on the whole vector
+    fn scalar_only(mut self) -> Self {
+        self.scalar_only = true;
+        self
+    }
+
     pub fn with_name(mut self, name: impl ToString) -> Self {
         self.name = Some(name.to_string());
         self
@@ -200,24 +284,139 @@ impl PairWiseFunction {
     pub(crate) fn name(&self) -> &str {
         self.name.as_deref().unwrap_or("pair_wise")
     }
+
+    /// Create an element-wise function that computes this function when both operands are
+    /// element-wise chains over the same value. The chains are in the order they are collected
+    /// from the graph: the last function is applied first
+    pub(crate) fn to_element_wise(
+        &self,
+        first: &[ElementWiseFunction],
+        second: &[ElementWiseFunction],
+    ) -> ElementWiseFunction {
+        let mut operation = String::new();
+        let mut scalars = Vec::new();
+        for (name, functions) in [("a", first), ("b", second)] {
+            let mut value = "input".to_string();
+            let mut input_datatype = None;
+            for (i, function) in functions.iter().rev().enumerate() {
+                // Each function runs in its own scope so the names it declares can't conflict
+                let output = format!("pair_wise_{name}_{i}");
+                writeln!(&mut operation, "var {output}: {};", function.datatype()).unwrap();
+                writeln!(&mut operation, "{{").unwrap();
+                writeln!(&mut operation, "let input = {value};").unwrap();
+                for (j, scalar) in function.scalars().iter().enumerate() {
+                    // Scalars are declared with the datatype of the input to the whole function
+                    let index = scalars.len();
+                    scalars.push(*scalar);
+                    match input_datatype {
+                        Some(datatype) => writeln!(
+                            &mut operation,
+                            "let scalar_{j} = {datatype}(scalar_{index});"
+                        ),
+                        None => writeln!(&mut operation, "let scalar_{j} = scalar_{index};"),
+                    }
+                    .unwrap();
+                }
+                writeln!(&mut operation, "{}", function.operation()).unwrap();
+                writeln!(&mut operation, "{output} = output;").unwrap();
+                writeln!(&mut operation, "}}").unwrap();
+                value = output;
+                input_datatype = Some(function.datatype());
+            }
+            writeln!(&mut operation, "let {name} = {value};").unwrap();
+        }
+        operation.push_str(&self.operation);
+
+        // The chains are declared with scalar types
+        scalars.into_iter().fold(
+            ElementWiseFunction::new(operation, self.datatype)
+                .with_name(self.name())
+                .scalar_only(),
+            |function, scalar| function.with_scalar(scalar),
+        )
+    }
+
+    fn add_function(
+        &self,
+        kernel: &mut GenericKernel,
+        first: DataTypeEnum,
+        second: DataTypeEnum,
+        vectorized: bool,
+    ) -> Function {
+        let vector = vectorized && !self.scalar_only;
+        let ty = |datatype: DataTypeEnum| match vector {
+            true => format!("vec4<{datatype}>"),
+            false => datatype.to_string(),
+        };
+        let function = kernel.add_function(
+            ty(self.datatype),
+            self.operation.clone(),
+            [("a".to_string(), ty(first)), ("b".to_string(), ty(second))],
+        );
+        match vectorized && !vector {
+            true => kernel.add_lane_wise_function(&function),
+            false => function,
+        }
+    }
+
+    fn add(datatype: DataTypeEnum) -> Self {
+        Self::new("let output = a + b;", datatype).with_name("add")
+    }
+
+    fn sub(datatype: DataTypeEnum) -> Self {
+        Self::new("let output = a - b;", datatype).with_name("sub")
+    }
+
+    fn mul(datatype: DataTypeEnum) -> Self {
+        Self::new("let output = a * b;", datatype).with_name("mul")
+    }
+
+    fn div(datatype: DataTypeEnum) -> Self {
+        Self::new("let output = a / b;", datatype).with_name("div")
+    }
+
+    fn copysign(datatype: DataTypeEnum) -> Self {
+        // Check the sign bit so -0.0 is negative. Widening f16 to f32 keeps the sign of zero
+        Self::new(
+            "let output = select(abs(a), -abs(a), (bitcast<u32>(f32(b)) & 0x80000000u) != 0u);",
+            datatype,
+        )
+        .with_name("copysign")
+        .scalar_only()
+    }
 }
 
-impl<const R: usize, T: DataType> Add<Tensor<R, T>> for Tensor<R, T> {
-    type Output = Tensor<R, T>;
+/// The datatype both sides of a pair-wise operator are converted to before the operator runs.
+/// Mixing precisions promotes the narrower side, so `f16` and `f32` promote to `f32`.
+pub trait Promote<T: DataType>: DataType {
+    type Promoted: DataType;
+}
+
+impl<T: DataType> Promote<T> for T {
+    type Promoted = T;
+}
+
+impl Promote<f32> for half::f16 {
+    type Promoted = f32;
+}
+
+impl Promote<half::f16> for f32 {
+    type Promoted = f32;
+}
+
+impl<const R: usize, T: Promote<T2>, T2: DataType> Add<Tensor<R, T2>> for Tensor<R, T> {
+    type Output = Tensor<R, T::Promoted>;
 
-    fn add(self, rhs: Tensor<R, T>) -> Self::Output {
+    fn add(self, rhs: Tensor<R, T2>) -> Self::Output {
         &self + &rhs
     }
 }
 
-impl<const R: usize, T: DataType> Add<&Tensor<R, T>> for &Tensor<R, T> {
-    type Output = Tensor<R, T>;
+impl<const R: usize, T: Promote<T2>, T2: DataType> Add<&Tensor<R, T2>> for &Tensor<R, T> {
+    type Output = Tensor<R, T::Promoted>;
 
-    fn add(self, rhs: &Tensor<R, T>) -> Self::Output {
-        self.pair_wise(
-            rhs,
-            PairWiseFunction::new("let output = a + b;".to_string(), T::WGSL_TYPE).with_name("add"),
-        )
+    fn add(self, rhs: &Tensor<R, T2>) -> Self::Output {
+        self.promoted_pair_wise(rhs, PairWiseFunction::add(T::Promoted::WGSL_TYPE))
     }
 }
 
@@ -250,6 +449,124 @@ async fn test_pair_wise_add() {
     assert_eq!(as_slice[[2, 1]], 6. + 6.);
 }
 
+#[cfg(test)]
+#[tokio::test]
+async fn test_pair_wise_vectorized_tail() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    // Cover both whole vec4s and every length of the scalar tail
+    for len in 1..=9 {
+        let data_a = (0..len).map(|i| i as f32).collect::<Vec<_>>();
+        let data_b = (0..len).map(|i| (i * 10) as f32).collect::<Vec<_>>();
+        let tensor_a = Tensor::new(&device, &data_a);
+        let tensor_b = Tensor::new(&device, &data_b);
+
+        let tensor = &tensor_a + &tensor_b;
+        let as_slice = tensor.as_slice().await.unwrap();
+        println!("{:?}", as_slice);
+
+        for i in 0..len {
+            assert_eq!(as_slice[[i]], data_a[i] + data_b[i]);
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_pair_wise_broadcast() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data_a = [[1.], [2.], [3.]];
+    let data_b = [[10., 20.]];
+    let tensor_a = Tensor::new(&device, &data_a);
+    let tensor_b = Tensor::new(&device, &data_b);
+
+    let tensor = &tensor_a + &tensor_b;
+    assert_eq!(tensor.shape(), &[3, 2]);
+    let as_slice = tensor.as_slice().await.unwrap();
+    println!("{:?}", as_slice);
+
+    for i in 0..3 {
+        for j in 0..2 {
+            assert_eq!(as_slice[[i, j]], data_a[i][0] + data_b[0][j]);
+        }
+    }
+
+    // Leading dimensions are added to the lower rank tensor
+    let data_a = DynTensor::new(
+        &device,
+        &[2, 2, 3],
+        &[1., 2., 3., 4., 5., 6., 7., 8., 9., 10., 11., 12.],
+    );
+    let data_b = DynTensor::new(&device, &[3], &[100., 200., 300.]);
+
+    let tensor = &data_a * &data_b;
+    assert_eq!(tensor.shape(), &[2, 2, 3]);
+    let as_slice = tensor.as_slice().await.unwrap();
+
+    for i in 0..2 {
+        for j in 0..2 {
+            for k in 0..3 {
+                let value = (i * 6 + j * 3 + k + 1) as f32;
+                assert_eq!(as_slice[&[i, j, k][..]], value * ((k + 1) * 100) as f32);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_pair_wise_broadcast_rank_4() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    // Like adding a [seq, seq] mask to [batch, heads, seq, seq] attention scores
+    let (b, h, s) = (2, 3, 5);
+    let scores = (0..b * h * s * s).map(|i| i as f32).collect::<Vec<_>>();
+    let mask = (0..s * s).map(|i| -(i as f32) * 0.5).collect::<Vec<_>>();
+    let scores_tensor = DynTensor::new(&device, &[b, h, s, s], &scores);
+    let mask_tensor = DynTensor::new(&device, &[1, 1, s, s], &mask);
+
+    for tensor in [
+        &scores_tensor + &mask_tensor,
+        // Missing leading dimensions are added to the mask
+        &scores_tensor + &mask_tensor.reshape(&[s, s]),
+    ] {
+        assert_eq!(tensor.shape(), &[b, h, s, s]);
+        let as_slice = tensor.as_slice().await.unwrap();
+        for i in 0..b {
+            for j in 0..h {
+                for k in 0..s {
+                    for l in 0..s {
+                        let expected = scores[((i * h + j) * s + k) * s + l] + mask[k * s + l];
+                        assert_eq!(as_slice[&[i, j, k, l][..]], expected);
+                    }
+                }
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 #[tokio::test]
 async fn test_pair_wise_add_f16() {
@@ -373,22 +690,19 @@ async fn test_pair_wise_add_sparse() {
     assert_eq!(as_slice[[2, 0]], 5. + 5.);
 }
 
-impl<const R: usize, T: DataType> Sub<Tensor<R, T>> for Tensor<R, T> {
-    type Output = Tensor<R, T>;
+impl<const R: usize, T: Promote<T2>, T2: DataType> Sub<Tensor<R, T2>> for Tensor<R, T> {
+    type Output = Tensor<R, T::Promoted>;
 
-    fn sub(self, rhs: Tensor<R, T>) -> Self::Output {
+    fn sub(self, rhs: Tensor<R, T2>) -> Self::Output {
         &self - &rhs
     }
 }
 
-impl<const R: usize, T: DataType> Sub<&Tensor<R, T>> for &Tensor<R, T> {
-    type Output = Tensor<R, T>;
+impl<const R: usize, T: Promote<T2>, T2: DataType> Sub<&Tensor<R, T2>> for &Tensor<R, T> {
+    type Output = Tensor<R, T::Promoted>;
 
-    fn sub(self, rhs: &Tensor<R, T>) -> Self::Output {
-        self.pair_wise(
-            rhs,
-            PairWiseFunction::new("let output = a - b;".to_string(), T::WGSL_TYPE).with_name("sub"),
-        )
+    fn sub(self, rhs: &Tensor<R, T2>) -> Self::Output {
+        self.promoted_pair_wise(rhs, PairWiseFunction::sub(T::Promoted::WGSL_TYPE))
     }
 }
 
@@ -421,22 +735,19 @@ async fn test_pair_wise_sub() {
     assert_eq!(as_slice[[2, 1]], 6. - 6.);
 }
 
-impl<const R: usize, T: DataType> Mul<Tensor<R, T>> for Tensor<R, T> {
-    type Output = Tensor<R, T>;
+impl<const R: usize, T: Promote<T2>, T2: DataType> Mul<Tensor<R, T2>> for Tensor<R, T> {
+    type Output = Tensor<R, T::Promoted>;
 
-    fn mul(self, rhs: Tensor<R, T>) -> Self::Output {
+    fn mul(self, rhs: Tensor<R, T2>) -> Self::Output {
         &self * &rhs
     }
 }
 
-impl<const R: usize, T: DataType> Mul<&Tensor<R, T>> for &Tensor<R, T> {
-    type Output = Tensor<R, T>;
+impl<const R: usize, T: Promote<T2>, T2: DataType> Mul<&Tensor<R, T2>> for &Tensor<R, T> {
+    type Output = Tensor<R, T::Promoted>;
 
-    fn mul(self, rhs: &Tensor<R, T>) -> Self::Output {
-        self.pair_wise(
-            rhs,
-            PairWiseFunction::new("let output = a * b;".to_string(), T::WGSL_TYPE).with_name("mul"),
-        )
+    fn mul(self, rhs: &Tensor<R, T2>) -> Self::Output {
+        self.promoted_pair_wise(rhs, PairWiseFunction::mul(T::Promoted::WGSL_TYPE))
     }
 }
 
@@ -469,22 +780,19 @@ async fn test_pair_wise_mul() {
     assert_eq!(as_slice[[2, 1]], 6. * 6.);
 }
 
-impl<const R: usize, T: DataType> Div<Tensor<R, T>> for Tensor<R, T> {
-    type Output = Tensor<R, T>;
+impl<const R: usize, T: Promote<T2>, T2: DataType> Div<Tensor<R, T2>> for Tensor<R, T> {
+    type Output = Tensor<R, T::Promoted>;
 
-    fn div(self, rhs: Tensor<R, T>) -> Self::Output {
+    fn div(self, rhs: Tensor<R, T2>) -> Self::Output {
         &self / &rhs
     }
 }
 
-impl<const R: usize, T: DataType> Div<&Tensor<R, T>> for &Tensor<R, T> {
-    type Output = Tensor<R, T>;
+impl<const R: usize, T: Promote<T2>, T2: DataType> Div<&Tensor<R, T2>> for &Tensor<R, T> {
+    type Output = Tensor<R, T::Promoted>;
 
-    fn div(self, rhs: &Tensor<R, T>) -> Self::Output {
-        self.pair_wise(
-            rhs,
-            PairWiseFunction::new("let output = a / b;".to_string(), T::WGSL_TYPE).with_name("div"),
-        )
+    fn div(self, rhs: &Tensor<R, T2>) -> Self::Output {
+        self.promoted_pair_wise(rhs, PairWiseFunction::div(T::Promoted::WGSL_TYPE))
     }
 }
 
@@ -517,16 +825,95 @@ async fn test_pair_wise_div() {
     assert_eq!(as_slice[[2, 1]], 6. / 6.);
 }
 
-impl<const R: usize, T: DataType> Tensor<R, T> {
+float_tensor_ops! {
     pub fn pow(&self, other: &Self) -> Self {
         self.pair_wise(
             other,
-            PairWiseFunction::new("let output = pow(a, b);".to_string(), T::WGSL_TYPE)
+            PairWiseFunction::new("let output = pow(a, b);".to_string(), D::WGSL_TYPE)
                 .with_name("pow"),
         )
     }
 }
 
+tensor_ops! {
+    /// The larger of the two elements
+    pub fn maximum(&self, other: &Self) -> Self {
+        self.pair_wise(
+            other,
+            PairWiseFunction::new("let output = max(a, b);", D::WGSL_TYPE).with_name("maximum"),
+        )
+    }
+
+    /// The smaller of the two elements
+    pub fn minimum(&self, other: &Self) -> Self {
+        self.pair_wise(
+            other,
+            PairWiseFunction::new("let output = min(a, b);", D::WGSL_TYPE).with_name("minimum"),
+        )
+    }
+
+    /// The remainder of `self / other` with the same sign as `self` (like C's `fmod`)
+    pub fn fmod(&self, other: &Self) -> Self {
+        self.pair_wise(
+            other,
+            PairWiseFunction::new("let output = a % b;", D::WGSL_TYPE).with_name("fmod"),
+        )
+    }
+
+    /// `(self - other)^2`
+    pub fn squared_difference(&self, other: &Self) -> Self {
+        self.pair_wise(
+            other,
+            PairWiseFunction::new(
+                "let difference = a - b;
+let output = difference * difference;",
+                D::WGSL_TYPE,
+            )
+            .with_name("squared_difference"),
+        )
+    }
+}
+
+float_tensor_ops! {
+    /// The angle of the point (other, self) in radians with `self` as the y coordinate
+    pub fn atan2(&self, other: &Self) -> Self {
+        self.pair_wise(
+            other,
+            PairWiseFunction::new("let output = atan2(a, b);", D::WGSL_TYPE).with_name("atan2"),
+        )
+    }
+
+    /// The remainder of `self / other` with the same sign as `other` (like python's `%`)
+    pub fn rem(&self, other: &Self) -> Self {
+        self.pair_wise(
+            other,
+            PairWiseFunction::new("let output = a - b * floor(a / b);", D::WGSL_TYPE)
+                .with_name("rem"),
+        )
+    }
+
+    /// The magnitude of `self` with the sign of `other`. The sign bit is used, so `-0.0` is
+    /// negative
+    pub fn copysign(&self, other: &Self) -> Self {
+        self.pair_wise(other, PairWiseFunction::copysign(D::WGSL_TYPE))
+    }
+
+    /// `sqrt(self^2 + other^2)` without overflowing for large elements
+    pub fn hypot(&self, other: &Self) -> Self {
+        self.pair_wise(
+            other,
+            PairWiseFunction::new(
+                "let larger = max(abs(a), abs(b));
+let ratio = min(abs(a), abs(b)) / larger;
+let output = select(larger * sqrt(1.0 + ratio * ratio), larger, larger == 0.0);",
+                D::WGSL_TYPE,
+            )
+            .with_name("hypot")
+            .scalar_only(),
+        )
+    }
+}
+
 #[cfg(test)]
 #[tokio::test]
 async fn test_pair_wise_pow() {
@@ -555,3 +942,750 @@ async fn test_pair_wise_pow() {
     assert!((as_slice[[2, 0]] - 5_f32.powf(5.)) < 0.001);
     assert!((as_slice[[2, 1]] - 6_f32.powf(6.)) < 0.001);
 }
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_pair_wise_maximum() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data_a = [[1., -2.], [3.5, -4.], [0., 6.]];
+    let data_b = [[2., 3.], [-1.5, -4.5], [5., -0.5]];
+    let tensor_a = Tensor::new(&device, &data_a);
+    let tensor_b = Tensor::new(&device, &data_b);
+
+    let tensor = tensor_a.maximum(&tensor_b);
+    let as_slice = tensor.as_slice().await.unwrap();
+
+    for i in 0..3 {
+        for j in 0..2 {
+            let a: f32 = data_a[i][j];
+            let b: f32 = data_b[i][j];
+            let expected = a.max(b);
+            assert_eq!(as_slice[[i, j]], expected);
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_pair_wise_minimum() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data_a = [[1., -2.], [3.5, -4.], [0., 6.]];
+    let data_b = [[2., 3.], [-1.5, -4.5], [5., -0.5]];
+    let tensor_a = Tensor::new(&device, &data_a);
+    let tensor_b = Tensor::new(&device, &data_b);
+
+    let tensor = tensor_a.minimum(&tensor_b);
+    let as_slice = tensor.as_slice().await.unwrap();
+
+    for i in 0..3 {
+        for j in 0..2 {
+            let a: f32 = data_a[i][j];
+            let b: f32 = data_b[i][j];
+            let expected = a.min(b);
+            assert_eq!(as_slice[[i, j]], expected);
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_pair_wise_atan2() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data_a = [[1., -2.], [3.5, -4.], [0., 6.]];
+    let data_b = [[2., 3.], [-1.5, -4.5], [5., -0.5]];
+    let tensor_a = Tensor::new(&device, &data_a);
+    let tensor_b = Tensor::new(&device, &data_b);
+
+    let tensor = tensor_a.atan2(&tensor_b);
+    let as_slice = tensor.as_slice().await.unwrap();
+
+    for i in 0..3 {
+        for j in 0..2 {
+            let a: f32 = data_a[i][j];
+            let b: f32 = data_b[i][j];
+            let expected = a.atan2(b);
+            assert!((as_slice[[i, j]] - expected).abs() < 0.001);
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_pair_wise_rem() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data_a = [[1., -2.], [3.5, -4.], [0., 6.]];
+    let data_b = [[2., 3.], [-1.5, -4.5], [5., -0.5]];
+    let tensor_a = Tensor::new(&device, &data_a);
+    let tensor_b = Tensor::new(&device, &data_b);
+
+    let tensor = tensor_a.rem(&tensor_b);
+    let as_slice = tensor.as_slice().await.unwrap();
+
+    for i in 0..3 {
+        for j in 0..2 {
+            let a: f32 = data_a[i][j];
+            let b: f32 = data_b[i][j];
+            let expected = a.rem_euclid(b)
+                + if b < 0. && a.rem_euclid(b) != 0. {
+                    b
+                } else {
+                    0.
+                };
+            assert!((as_slice[[i, j]] - expected).abs() < 0.001);
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_pair_wise_fmod() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data_a = [[1., -2.], [3.5, -4.], [0., 6.]];
+    let data_b = [[2., 3.], [-1.5, -4.5], [5., -0.5]];
+    let tensor_a = Tensor::new(&device, &data_a);
+    let tensor_b = Tensor::new(&device, &data_b);
+
+    let tensor = tensor_a.fmod(&tensor_b);
+    let as_slice = tensor.as_slice().await.unwrap();
+
+    for i in 0..3 {
+        for j in 0..2 {
+            let a: f32 = data_a[i][j];
+            let b: f32 = data_b[i][j];
+            let expected = a % b;
+            assert!((as_slice[[i, j]] - expected).abs() < 0.001);
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_pair_wise_copysign() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    // Signed zeros take the sign from their sign bit
+    let data_a = [[1., -2.], [3.5, -4.], [0., 6.]];
+    let data_b = [[2., -0.], [-1.5, 0.], [-0., -0.5]];
+    let tensor_a = Tensor::new(&device, &data_a);
+    let tensor_b = Tensor::new(&device, &data_b);
+
+    let tensor = tensor_a.copysign(&tensor_b);
+    let as_slice = tensor.as_slice().await.unwrap();
+    for i in 0..3 {
+        for j in 0..2 {
+            let a: f32 = data_a[i][j];
+            let b: f32 = data_b[i][j];
+            let expected = a.abs().copysign(b);
+            assert_eq!(as_slice[[i, j]].to_bits(), expected.to_bits());
+        }
+    }
+
+    let tensor = tensor_a
+        .cast::<half::f16>()
+        .copysign(&tensor_b.cast::<half::f16>());
+    let as_slice = tensor.as_slice().await.unwrap();
+    for i in 0..3 {
+        for j in 0..2 {
+            let a: f32 = data_a[i][j];
+            let b: f32 = data_b[i][j];
+            let expected = half::f16::from_f32(a.abs().copysign(b));
+            assert_eq!(as_slice[[i, j]].to_bits(), expected.to_bits());
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_pair_wise_hypot() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data_a = [[1., -2.], [3.5, -4.], [0., 6.]];
+    let data_b = [[2., 3.], [-1.5, -4.5], [5., -0.5]];
+    let tensor_a = Tensor::new(&device, &data_a);
+    let tensor_b = Tensor::new(&device, &data_b);
+
+    let tensor = tensor_a.hypot(&tensor_b);
+    let as_slice = tensor.as_slice().await.unwrap();
+
+    for i in 0..3 {
+        for j in 0..2 {
+            let a: f32 = data_a[i][j];
+            let b: f32 = data_b[i][j];
+            let expected = a.hypot(b);
+            assert!((as_slice[[i, j]] - expected).abs() < 0.001);
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_pair_wise_squared_difference() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data_a = [[1., -2.], [3.5, -4.], [0., 6.]];
+    let data_b = [[2., 3.], [-1.5, -4.5], [5., -0.5]];
+    let tensor_a = Tensor::new(&device, &data_a);
+    let tensor_b = Tensor::new(&device, &data_b);
+
+    let tensor = tensor_a.squared_difference(&tensor_b);
+    let as_slice = tensor.as_slice().await.unwrap();
+
+    for i in 0..3 {
+        for j in 0..2 {
+            let a: f32 = data_a[i][j];
+            let b: f32 = data_b[i][j];
+            let expected = (a - b) * (a - b);
+            assert_eq!(as_slice[[i, j]], expected);
+        }
+    }
+}
+
+impl<T: Promote<T2>, T2: DataType> Add<DynTensor<T2>> for DynTensor<T> {
+    type Output = DynTensor<T::Promoted>;
+
+    fn add(self, rhs: DynTensor<T2>) -> Self::Output {
+        &self + &rhs
+    }
+}
+
+impl<T: Promote<T2>, T2: DataType> Add<&DynTensor<T2>> for &DynTensor<T> {
+    type Output = DynTensor<T::Promoted>;
+
+    fn add(self, rhs: &DynTensor<T2>) -> Self::Output {
+        self.promoted_pair_wise(rhs, PairWiseFunction::add(T::Promoted::WGSL_TYPE))
+    }
+}
+
+impl<T: Promote<T2>, T2: DataType> Sub<DynTensor<T2>> for DynTensor<T> {
+    type Output = DynTensor<T::Promoted>;
+
+    fn sub(self, rhs: DynTensor<T2>) -> Self::Output {
+        &self - &rhs
+    }
+}
+
+impl<T: Promote<T2>, T2: DataType> Sub<&DynTensor<T2>> for &DynTensor<T> {
+    type Output = DynTensor<T::Promoted>;
+
+    fn sub(self, rhs: &DynTensor<T2>) -> Self::Output {
+        self.promoted_pair_wise(rhs, PairWiseFunction::sub(T::Promoted::WGSL_TYPE))
+    }
+}
+
+impl<T: Promote<T2>, T2: DataType> Mul<DynTensor<T2>> for DynTensor<T> {
+    type Output = DynTensor<T::Promoted>;
+
+    fn mul(self, rhs: DynTensor<T2>) -> Self::Output {
+        &self * &rhs
+    }
+}
+
+impl<T: Promote<T2>, T2: DataType> Mul<&DynTensor<T2>> for &DynTensor<T> {
+    type Output = DynTensor<T::Promoted>;
+
+    fn mul(self, rhs: &DynTensor<T2>) -> Self::Output {
+        self.promoted_pair_wise(rhs, PairWiseFunction::mul(T::Promoted::WGSL_TYPE))
+    }
+}
+
+impl<T: Promote<T2>, T2: DataType> Div<DynTensor<T2>> for DynTensor<T> {
+    type Output = DynTensor<T::Promoted>;
+
+    fn div(self, rhs: DynTensor<T2>) -> Self::Output {
+        &self / &rhs
+    }
+}
+
+impl<T: Promote<T2>, T2: DataType> Div<&DynTensor<T2>> for &DynTensor<T> {
+    type Output = DynTensor<T::Promoted>;
+
+    fn div(self, rhs: &DynTensor<T2>) -> Self::Output {
+        self.promoted_pair_wise(rhs, PairWiseFunction::div(T::Promoted::WGSL_TYPE))
+    }
+}
+
+macro_rules! impl_assign_pair_wise {
+    ($T:ident, $f:ident, $function:ident) => {
+        impl<const R: usize, D: DataType> $T<&Tensor<R, D>> for Tensor<R, D> {
+            fn $f(&mut self, rhs: &Tensor<R, D>) {
+                self.assign(|tensor| {
+                    tensor.pair_wise(rhs, PairWiseFunction::$function(D::WGSL_TYPE))
+                });
+            }
+        }
+
+        impl<const R: usize, D: DataType> $T<Tensor<R, D>> for Tensor<R, D> {
+            fn $f(&mut self, rhs: Tensor<R, D>) {
+                self.$f(&rhs);
+            }
+        }
+
+        impl<D: DataType> $T<&DynTensor<D>> for DynTensor<D> {
+            fn $f(&mut self, rhs: &DynTensor<D>) {
+                self.assign(|tensor| {
+                    tensor.pair_wise(rhs, PairWiseFunction::$function(D::WGSL_TYPE))
+                });
+            }
+        }
+
+        impl<D: DataType> $T<DynTensor<D>> for DynTensor<D> {
+            fn $f(&mut self, rhs: DynTensor<D>) {
+                self.$f(&rhs);
+            }
+        }
+    };
+}
+
+impl_assign_pair_wise!(AddAssign, add_assign, add);
+impl_assign_pair_wise!(SubAssign, sub_assign, sub);
+impl_assign_pair_wise!(MulAssign, mul_assign, mul);
+impl_assign_pair_wise!(DivAssign, div_assign, div);
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_pair_wise_assign_in_place() {
+    use crate::Device;
+    use std::sync::Arc;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data_a = [[1., 2.], [3., 4.], [5., 6.]];
+    let data_b = [[1., 3.], [2., 4.], [-1., 0.5]];
+    let mut tensor_a = Tensor::new(&device, &data_a);
+    let tensor_b = Tensor::new(&device, &data_b);
+    let buffer = Arc::as_ptr(tensor_a.materialize().buffer());
+
+    tensor_a += &tensor_b;
+    tensor_a *= &tensor_b;
+    tensor_a -= 1.;
+    tensor_a /= tensor_b.clone();
+
+    // The second read uses the stored result instead of running the kernel again
+    for _ in 0..2 {
+        let as_slice = tensor_a.as_slice().await.unwrap();
+        for i in 0..3 {
+            for j in 0..2 {
+                let expected: f32 =
+                    ((data_a[i][j] + data_b[i][j]) * data_b[i][j] - 1.) / data_b[i][j];
+                assert!((as_slice[[i, j]] - expected).abs() < 0.001);
+            }
+        }
+    }
+    assert_eq!(Arc::as_ptr(tensor_a.materialize().buffer()), buffer);
+    assert_eq!(tensor_a.all_timing_information().await.len(), 1);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_pair_wise_assign_copy_on_write() {
+    use crate::Device;
+    use std::sync::Arc;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data_a = [[1., 2.], [3., 4.], [5., 6.]];
+    let data_b = [[1., 3.], [2., 4.], [-1., 0.5]];
+    let mut tensor_a = Tensor::new(&device, &data_a);
+    let tensor_b = Tensor::new(&device, &data_b);
+    let copy = tensor_a.clone();
+    let doubled = tensor_a.clone() * 2.;
+
+    tensor_a += &tensor_b;
+
+    let as_slice = tensor_a.as_slice().await.unwrap();
+    let copy_slice = copy.as_slice().await.unwrap();
+    let doubled_slice = doubled.as_slice().await.unwrap();
+    for i in 0..3 {
+        for j in 0..2 {
+            assert_eq!(as_slice[[i, j]], data_a[i][j] + data_b[i][j]);
+            assert_eq!(copy_slice[[i, j]], data_a[i][j]);
+            assert_eq!(doubled_slice[[i, j]], data_a[i][j] * 2.);
+        }
+    }
+    assert_ne!(
+        Arc::as_ptr(tensor_a.materialize().buffer()),
+        Arc::as_ptr(copy.materialize().buffer())
+    );
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_dyn_pair_wise_assign() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data_a = [1., 2., 3., 4., 5., 6.];
+    let data_b = [2.];
+    let mut tensor_a = DynTensor::new(&device, &[6], &data_a);
+    let tensor_b = DynTensor::new(&device, &[1], &data_b);
+
+    // The right hand side broadcasts to the shape of the left hand side
+    tensor_a -= &tensor_b;
+
+    let as_slice = tensor_a.as_slice().await.unwrap();
+    for i in 0..6 {
+        assert_eq!(as_slice[&[i][..]], data_a[i] - data_b[0]);
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_dyn_pair_wise() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data_a = [1., 2., 3., 4., 5., 6.];
+    let data_b = [6., 5., 4., 3., 2., 1.];
+    let tensor_a = DynTensor::new(&device, &[3, 2], &data_a);
+    let tensor_b = DynTensor::new(&device, &[3, 2], &data_b);
+
+    let tensor = &(&tensor_a * &tensor_b) - &tensor_a.pow(&tensor_b);
+    let as_slice = tensor.as_slice().await.unwrap();
+
+    for i in 0..3 {
+        for j in 0..2 {
+            let a: f32 = data_a[i * 2 + j];
+            let b: f32 = data_b[i * 2 + j];
+            assert!((as_slice[[i, j]] - (a * b - a.powf(b))).abs() < 0.01);
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_pair_wise_fused() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data_a = [[1., 2.], [3., 4.], [5., 6.]];
+    let data_b = [[6., 5.], [4., 3.], [2., 1.]];
+    let data_c = [[1., 3.], [5., 7.], [9., 11.]];
+    let data_d = [[2., 4.], [6., 8.], [10., 12.]];
+    let tensor_a = Tensor::new(&device, &data_a);
+    let tensor_b = Tensor::new(&device, &data_b);
+    let tensor_c = Tensor::new(&device, &data_c);
+    let tensor_d = Tensor::new(&device, &data_d);
+
+    let sum = &(&tensor_a * &tensor_b) + &(&(tensor_c * 2.) - &tensor_d);
+    let tensor = &sum.exp() - &tensor_a;
+    let as_slice = tensor.as_slice().await.unwrap();
+
+    for i in 0..3 {
+        for j in 0..2 {
+            let expected = (data_a[i][j] * data_b[i][j] + (data_c[i][j] * 2. - data_d[i][j])).exp()
+                - data_a[i][j];
+            assert!((as_slice[[i, j]] - expected).abs() / expected.abs() < 0.001);
+        }
+    }
+
+    // The whole tree runs in a single kernel
+    assert_eq!(tensor.all_timing_information().await.len(), 1);
+}
+
+#[test]
+fn test_copysign_kernel() {
+    for datatype in [DataTypeEnum::F32, DataTypeEnum::F16] {
+        let expression = PairWiseExpression::PairWise {
+            first: Box::new(PairWiseExpression::Input(0)),
+            second: Box::new(PairWiseExpression::Input(1)),
+            function: PairWiseFunction::copysign(datatype),
+        };
+        let kernel = UntypedPairWiseKernel::new(expression, vec![datatype; 2]);
+        kernel
+            .create_kernel(2, true, MapOutput::New(datatype))
+            .kernel()
+            .validate()
+            .unwrap();
+    }
+}
+
+#[test]
+fn test_pair_wise_expression_kernel() {
+    // (input_0 * input_1) + exp(input_2 + 1) * input_0
+    let datatype = DataTypeEnum::F32;
+    let expression = PairWiseExpression::PairWise {
+        first: Box::new(PairWiseExpression::PairWise {
+            first: Box::new(PairWiseExpression::Input(0)),
+            second: Box::new(PairWiseExpression::Input(1)),
+            function: PairWiseFunction::mul(datatype),
+        }),
+        second: Box::new(PairWiseExpression::PairWise {
+            first: Box::new(PairWiseExpression::Input(2).element_wise(vec![
+                ElementWiseFunction::new("let output = exp(input);", datatype),
+                ElementWiseFunction::new("let output = input + scalar_0;", datatype)
+                    .with_scalar(1.),
+            ])),
+            second: Box::new(PairWiseExpression::Input(0)),
+            function: PairWiseFunction::mul(datatype),
+        }),
+        function: PairWiseFunction::add(datatype),
+    };
+    let kernel = UntypedPairWiseKernel::new(expression, vec![datatype; 3]);
+    for contiguous in [true, false] {
+        for output in [MapOutput::InPlace(1), MapOutput::New(datatype)] {
+            let kernel = kernel.create_kernel(2, contiguous, output);
+            let source = kernel.kernel().source();
+            assert_eq!(source.matches("exp(").count(), 1);
+            kernel.kernel().validate().unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_pair_wise_same_input() {
+    use crate::{Device, Sum};
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., 2.], [3., 4.], [5., 6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let squared = &tensor * &tensor;
+    let as_slice = squared.as_slice().await.unwrap();
+    for i in 0..3 {
+        for j in 0..2 {
+            assert_eq!(as_slice[[i, j]], data[i][j] * data[i][j]);
+        }
+    }
+
+    let silu = &tensor / &((-tensor.clone()).exp() + 1.);
+    let as_slice = silu.as_slice().await.unwrap();
+    for i in 0..3 {
+        for j in 0..2 {
+            let x: f32 = data[i][j];
+            assert!((as_slice[[i, j]] - x / (1. + (-x).exp())).abs() < 0.001);
+        }
+    }
+
+    // The square is an element-wise op so it fuses into the reduce kernel
+    let tensor = Tensor::new(&device, &data);
+    let sum = (&tensor * &tensor).sum(1);
+    let as_slice = sum.as_slice().await.unwrap();
+    for i in 0..3 {
+        assert_eq!(
+            as_slice[[i]],
+            data[i][0] * data[i][0] + data[i][1] * data[i][1]
+        );
+    }
+    assert_eq!(sum.all_timing_information().await.len(), 1);
+}
+
+#[test]
+fn test_pair_wise_to_element_wise() {
+    for datatype in [DataTypeEnum::F32, DataTypeEnum::F16] {
+        // (input * 2 + 3) - (f32(input - 1) * 4) with a cast in the second chain
+        let first = [
+            ElementWiseFunction::new("let output = input + scalar_0;", datatype).with_scalar(3.),
+            ElementWiseFunction::new("let output = input * scalar_0;", datatype).with_scalar(2.),
+        ];
+        let second = [
+            ElementWiseFunction::new(
+                format!("let output = {datatype}(input * scalar_0);"),
+                datatype,
+            )
+            .with_scalar(4.),
+            ElementWiseFunction::new("let output = f32(input - scalar_0);", DataTypeEnum::F32)
+                .with_scalar(1.),
+        ];
+        let function = PairWiseFunction::sub(datatype).to_element_wise(&first, &second);
+        assert_eq!(function.scalars(), &[2., 3., 1., 4.]);
+
+        let mut kernel = GenericKernel::new();
+        let input = kernel.add_tensor_input(1, true, datatype);
+        let functions = UntypedElementWiseKernel::new(vec![function], datatype)
+            .add_functions(&mut kernel, false);
+        let result = functions[0].call(vec![format!("{input}[0]")]);
+        kernel.set_body(format!("{input}[0] = {result};"));
+        kernel.validate().unwrap();
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_pair_wise_mixed_datatypes() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data_a = [[1., 2.], [3., 4.], [5., 6.]];
+    let data_b = [[0.5, 0.25], [0.125, 1.5], [2.5, 3.25]];
+    let tensor_a = Tensor::new(&device, &data_a.map(|row| row.map(half::f16::from_f32)));
+    let tensor_b = Tensor::new(&device, &data_b);
+
+    // The f16 side is promoted to f32
+    let tensor: Tensor<2, f32> = &tensor_a + &tensor_b;
+    let as_slice = tensor.as_slice().await.unwrap();
+    for i in 0..3 {
+        for j in 0..2 {
+            assert_eq!(as_slice[[i, j]], data_a[i][j] + data_b[i][j]);
+        }
+    }
+
+    let tensor: Tensor<2, f32> = &tensor_b * &tensor_a;
+    let as_slice = tensor.as_slice().await.unwrap();
+    for i in 0..3 {
+        for j in 0..2 {
+            assert_eq!(as_slice[[i, j]], data_a[i][j] * data_b[i][j]);
+        }
+    }
+
+    let tensor_a = DynTensor::new(
+        &device,
+        &[3, 2],
+        &data_a
+            .as_flattened()
+            .iter()
+            .map(|x| half::f16::from_f32(*x))
+            .collect::<Vec<_>>(),
+    );
+    let tensor_b = DynTensor::new(&device, &[2], &[10., 20.]);
+    let tensor: DynTensor<f32> = &tensor_a - &tensor_b;
+    let as_slice = tensor.as_slice().await.unwrap();
+    for i in 0..3 {
+        for j in 0..2 {
+            assert_eq!(as_slice[&[i, j][..]], data_a[i][j] - [10., 20.][j]);
+        }
+    }
+}
+
+#[test]
+fn test_nary_expression_kernel() {
+    // fma(input_0, input_1 + 1, input_0 * input_2)
+    let datatype = DataTypeEnum::F16;
+    let expression = PairWiseExpression::Nary {
+        operands: vec![
+            PairWiseExpression::Input(0),
+            PairWiseExpression::Input(1).element_wise(vec![
+                ElementWiseFunction::new("let output = input + scalar_0;", datatype)
+                    .with_scalar(1.),
+            ]),
+            PairWiseExpression::PairWise {
+                first: Box::new(PairWiseExpression::Input(0)),
+                second: Box::new(PairWiseExpression::Input(2)),
+                function: PairWiseFunction::mul(datatype),
+            },
+        ],
+        function: NaryFunction::new("let output = fma(a, b, c);", 3, datatype),
+    };
+    let kernel = UntypedPairWiseKernel::new(expression, vec![datatype; 3]);
+    for contiguous in [true, false] {
+        let kernel = kernel.create_kernel(3, contiguous, MapOutput::New(DataTypeEnum::F16));
+        kernel.kernel().validate().unwrap();
+    }
+}
+
+#[test]
+fn test_where_kernel() {
+    for datatype in [DataTypeEnum::F32, DataTypeEnum::F16, DataTypeEnum::U32] {
+        let expression = PairWiseExpression::Nary {
+            operands: (0..3).map(PairWiseExpression::Input).collect(),
+            function: NaryFunction::where_(datatype),
+        };
+        let kernel = UntypedPairWiseKernel::new(expression, vec![datatype; 3]);
+        kernel
+            .create_kernel(2, true, MapOutput::New(datatype))
+            .kernel()
+            .validate()
+            .unwrap();
+    }
+}
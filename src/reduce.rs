@@ -1,14 +1,18 @@
 use std::{
     fmt::{Display, Write},
+    marker::PhantomData,
     sync::OnceLock,
 };
 
 use wgpu::CommandEncoder;
 
 use crate::{
-    Layout, Tensor, UntypedElementWiseKernel,
-    compute_graph::AnyComputeKey,
-    kernel::{Function, GenericKernel, KernelGlobalSpace, KernelInputValue},
+    DynTensor, Tensor, UntypedElementWiseKernel,
+    compute_graph::{AnyComputeKey, ReduceComputeNodeKey},
+    kernel::{
+        Function, GenericKernel, InvalidFunctionError, KernelGlobal, KernelGlobalSpace,
+        KernelInputValue, check_function_body,
+    },
     query::PerformanceQueries,
     tensor::{DataType, DataTypeEnum, TensorData, padded_tensor_size},
 };
@@ -17,261 +21,453 @@ use crate::{
 pub(crate) struct ReduceOperation {
     pub(crate) value: AnyComputeKey,
     pub(crate) function: ReduceFunction,
-    pub(crate) axis: usize,
+    /// The axes to reduce over in increasing order
+    pub(crate) axes: Box<[usize]>,
+    /// Keep the reduced axes in the output with a size of one
+    pub(crate) keepdim: bool,
+    /// A reduction created with this one that always runs in the same kernel while it is live
+    pub(crate) paired: Option<ReduceComputeNodeKey>,
 }
 
 impl ReduceOperation {
-    pub fn new(value: AnyComputeKey, function: ReduceFunction, axis: usize) -> Self {
+    pub fn new(
+        value: AnyComputeKey,
+        function: ReduceFunction,
+        axes: &[usize],
+        keepdim: bool,
+    ) -> Self {
+        let mut axes: Box<[usize]> = axes.into();
+        axes.sort_unstable();
         Self {
             value,
             function,
-            axis,
+            axes,
+            keepdim,
+            paired: None,
         }
     }
+
+    /// The shape of the output for an input shape. Panics if an axis is out of bounds or
+    /// repeated
+    pub(crate) fn output_shape(&self, shape: &[usize]) -> Box<[usize]> {
+        let rank = shape.len();
+        for (i, axis) in self.axes.iter().enumerate() {
+            assert!(
+                *axis < rank,
+                "cannot reduce over axis {axis} of a tensor with rank {rank}"
+            );
+            assert!(
+                !self.axes[..i].contains(axis),
+                "cannot reduce over axis {axis} more than once"
+            );
+        }
+        reduced_shape(shape, &self.axes, self.keepdim)
+    }
 }
 
-pub(crate) struct UntypedReduceKernel {
+fn reduced_shape(shape: &[usize], axes: &[usize], keepdim: bool) -> Box<[usize]> {
+    shape
+        .iter()
+        .enumerate()
+        .filter_map(|(i, x)| match axes.contains(&i) {
+            true => keepdim.then_some(1),
+            false => Some(*x),
+        })
+        .collect()
+}
+
+/// One reduction in a reduce kernel. All outputs of a kernel read the same input tensor
+struct ReduceOutput {
     pre_element_wise: UntypedElementWiseKernel,
     reduce: ReduceFunction,
     post_element_wise: UntypedElementWiseKernel,
+}
+
+impl ReduceOutput {
+    fn new(reduce: ReduceFunction, datatype: DataTypeEnum) -> Self {
+        Self {
+            pre_element_wise: UntypedElementWiseKernel::empty(datatype),
+            post_element_wise: UntypedElementWiseKernel::empty(reduce.output_datatype()),
+            reduce,
+        }
+    }
+
+    fn out_datatype(&self) -> DataTypeEnum {
+        self.post_element_wise.out_datatype()
+    }
+}
+
+pub(crate) struct UntypedReduceKernel {
+    /// The first output is the one the kernel was created for. The rest are sibling
+    /// reductions over the same input that share the reads of the input
+    outputs: Vec<ReduceOutput>,
     kernel: OnceLock<GenericKernel>,
+    /// The kernels of long reductions that are split across many workgroups
+    partial_kernel: OnceLock<GenericKernel>,
+    combine_kernel: OnceLock<GenericKernel>,
     datatype: DataTypeEnum,
 }
 
 impl UntypedReduceKernel {
     pub fn new(reduce: ReduceFunction, datatype: DataTypeEnum) -> Self {
         Self {
-            pre_element_wise: UntypedElementWiseKernel::empty(datatype),
-            reduce,
-            post_element_wise: UntypedElementWiseKernel::empty(datatype),
+            outputs: vec![ReduceOutput::new(reduce, datatype)],
             kernel: OnceLock::new(),
+            partial_kernel: OnceLock::new(),
+            combine_kernel: OnceLock::new(),
             datatype,
         }
     }
 
     pub fn set_post_element_wise(&mut self, kernel: UntypedElementWiseKernel) {
-        self.post_element_wise = kernel;
+        self.outputs[0].post_element_wise = kernel;
     }
 
     pub fn set_pre_element_wise(&mut self, kernel: UntypedElementWiseKernel) {
-        self.pre_element_wise = kernel;
-    }
-
-    pub fn add_pre_element_wise_functions(&self, kernel: &mut GenericKernel) -> Vec<Function> {
-        self.pre_element_wise.add_functions(kernel)
+        self.outputs[0].pre_element_wise = kernel;
     }
 
-    pub fn add_post_element_wise_functions(&self, kernel: &mut GenericKernel) -> Vec<Function> {
-        self.post_element_wise.add_functions(kernel)
+    /// Add another reduction of the same input to the kernel. It is written to the next output
+    pub fn add_output(
+        &mut self,
+        reduce: ReduceFunction,
+        pre_element_wise: UntypedElementWiseKernel,
+    ) {
+        let mut output = ReduceOutput::new(reduce, self.datatype);
+        output.pre_element_wise = pre_element_wise;
+        self.outputs.push(output);
     }
 
-    pub fn add_function(&self, kernel: &mut GenericKernel) -> Function {
-        kernel.add_function(
-            self.reduce.datatype(),
-            self.reduce.operation.clone(),
-            [
-                ("a".to_string(), self.reduce.datatype().to_string()),
-                ("b".to_string(), self.reduce.datatype().to_string()),
-            ],
-        )
+    /// The datatype the reduction produces before the post element-wise functions
+    pub fn reduce_output_datatype(&self) -> DataTypeEnum {
+        self.outputs[0].reduce.output_datatype()
     }
 
-    pub fn out_datatype(&self) -> DataTypeEnum {
-        self.post_element_wise.out_datatype()
+    /// The bytes of workgroup memory each thread in the workgroup uses
+    fn workgroup_memory_per_thread(&self) -> u32 {
+        self.outputs
+            .iter()
+            .map(|output| output.reduce.state_size() + 4 * output.reduce.returns_index as u32)
+            .sum()
     }
 
-    fn tiled_map(&self, blocksize: u32, input_rank: u32) -> GenericKernel {
-        let dtype = self.reduce.datatype();
-        let out_datatype = self.out_datatype();
+    fn tiled_map(
+        &self,
+        blocksize: u32,
+        input_rank: u32,
+        axes: &[usize],
+        subgroups: bool,
+        stage: ReduceStage,
+    ) -> GenericKernel {
         let mut kernel = GenericKernel::new();
-        let output_rank = input_rank - 1;
+        let kept_axes = (0..input_rank)
+            .filter(|axis| !axes.contains(&(*axis as usize)))
+            .collect::<Vec<_>>();
+        let output_rank = kept_axes.len() as u32;
         // Based on v7 of https://developer.download.nvidia.com/assets/cuda/files/reduction.pdf
         // And the mlx implementation https://github.com/ml-explore/mlx/blob/b05bcfd27f5f1293401b74dce02e38c8fd7ef66a/mlx/backend/metal/kernels/arg_reduce.metal
         // We can't query the warp size in WGSL, but we can use subgroup operations
         // https://github.com/gpuweb/gpuweb/issues/4437 would unlock a better equivalent to warp synchronization
         // We also can't synchronize among workgroups without atomics. storageBarrier() is a barrier for
         // the storage memory only inside the workgroup.
-        // This kernel just uses one workgroup per reduction unit like the MLX kernel
-        let input_tensor = kernel.add_tensor_input(output_rank, false, self.datatype);
-        let output_tensor = kernel.add_tensor_input(output_rank, true, out_datatype);
+        // This kernel uses one workgroup per reduction unit like the MLX kernel. Long axes are
+        // split into chunks that each run in their own workgroup, then the partial results are
+        // merged in a second kernel
+        let input_tensor = (stage != ReduceStage::Combine)
+            .then(|| kernel.add_tensor_input(input_rank, false, self.datatype));
+        // The merged states (and indices) of each chunk of the split reduction
+        let partials = if stage == ReduceStage::Full {
+            Vec::new()
+        } else {
+            let mutable = stage == ReduceStage::Partial;
+            self.outputs
+                .iter()
+                .map(|output| {
+                    let states = kernel.add_array_input(mutable, output.reduce.state_type());
+                    let indices = output
+                        .reduce
+                        .returns_index
+                        .then(|| kernel.add_array_input(mutable, DataTypeEnum::U32));
+                    (states, indices)
+                })
+                .collect()
+        };
+        let output_tensors = if stage == ReduceStage::Partial {
+            Vec::new()
+        } else {
+            self.outputs
+                .iter()
+                .map(|output| kernel.add_tensor_input(output_rank, true, output.out_datatype()))
+                .collect()
+        };
+        // The reduced axes are flattened into a single index space of this size
         let reduce_size = kernel.add_integer_input();
-        let reduce_stride = kernel.add_integer_input();
-        let local_data =
-            kernel.add_global_array(KernelGlobalSpace::Workgroup, dtype, blocksize.to_string());
-        let reduce = self.add_function(&mut kernel);
-        let pre_element_wise = self.add_pre_element_wise_functions(&mut kernel);
-        let post_element_wise = self.add_post_element_wise_functions(&mut kernel);
+        let splits = (stage != ReduceStage::Full).then(|| kernel.add_integer_input());
+        // Every output carries its own merged value (and index) through each stage of the kernel
+        let outputs = self
+            .outputs
+            .iter()
+            .map(|output| {
+                let state = output.reduce.state_type();
+                let local_data = kernel.add_global_array(
+                    KernelGlobalSpace::Workgroup,
+                    &state,
+                    blocksize.to_string(),
+                );
+                let local_indices = output.reduce.returns_index.then(|| {
+                    kernel.add_global_array(
+                        KernelGlobalSpace::Workgroup,
+                        DataTypeEnum::U32,
+                        blocksize.to_string(),
+                    )
+                });
+                KernelOutput {
+                    initial_value: output.reduce.initial_state(),
+                    local_data,
+                    local_indices,
+                    reduce: output.reduce.add_function(&mut kernel),
+                    accumulator: output.reduce.add_accumulator_functions(&mut kernel),
+                    pre_element_wise: output.pre_element_wise.add_functions(&mut kernel, false),
+                    post_element_wise: output.post_element_wise.add_functions(&mut kernel, false),
+                }
+            })
+            .collect::<Vec<_>>();
         let workgroup_index = kernel.workgroup_index();
         let workgroup_local_index = kernel.workgroup_local_index();
-        let subgroup_id = kernel.subgroup_index();
-        let subgroup_local_id = kernel.subgroup_local_index();
-        let subgroups_per_workgroup = kernel.subgroups_per_workgroup();
-        let subgroup_size = kernel.subgroup_size();
 
         let mut kernel_body = String::new();
         // Each workgroup group works on a single column in the input tensor. This code calculates the
         // start offset of the input and output tensors for each thread group.
-        writeln!(
-            &mut kernel_body,
-            "var workgroup_index_remainder = {workgroup_index}.x;"
-        )
-        .unwrap();
-        for i in (0..output_rank).rev() {
-            let out_shape_i = output_tensor.shape_binding(i);
+        match &splits {
+            // The chunks of each column are next to each other
+            Some(splits) if stage == ReduceStage::Partial => {
+                writeln!(
+                    &mut kernel_body,
+                    "let split = {workgroup_index}.x % {splits};"
+                )
+                .unwrap();
+                writeln!(
+                    &mut kernel_body,
+                    "var workgroup_index_remainder = {workgroup_index}.x / {splits};"
+                )
+                .unwrap();
+            }
+            _ => {
+                writeln!(
+                    &mut kernel_body,
+                    "var workgroup_index_remainder = {workgroup_index}.x;"
+                )
+                .unwrap();
+            }
+        }
+        for (i, axis) in kept_axes.iter().enumerate().rev() {
+            let shape = match &input_tensor {
+                Some(input_tensor) => input_tensor.shape_binding(*axis),
+                None => output_tensors[0].shape_binding(i as u32),
+            };
             writeln!(
                 &mut kernel_body,
-                "let index_{i} = workgroup_index_remainder % {out_shape_i};",
+                "let index_{axis} = workgroup_index_remainder % {shape};",
             )
             .unwrap();
-            writeln!(
+            writeln!(&mut kernel_body, "workgroup_index_remainder /= {shape};",).unwrap();
+        }
+        if let Some(input_tensor) = &input_tensor {
+            write!(
                 &mut kernel_body,
-                "workgroup_index_remainder /= {out_shape_i};",
+                "var in_start_offset = {}",
+                input_tensor.offset_binding()
             )
             .unwrap();
+            for axis in &kept_axes {
+                let stride = input_tensor.stride_binding(*axis);
+                write!(&mut kernel_body, " + index_{axis}*{stride}").unwrap();
+            }
+            writeln!(&mut kernel_body, ";").unwrap();
+        }
+        for (i, output_tensor) in output_tensors.iter().enumerate() {
+            writeln!(&mut kernel_body, "var out_start_offset_{i} = ",).unwrap();
+            output_tensor.strided_index(
+                &mut kernel_body,
+                kept_axes.iter().map(|axis| format!("index_{axis}")),
+            );
+            writeln!(&mut kernel_body, ";").unwrap();
         }
-        writeln!(&mut kernel_body, "var in_start_offset = ",).unwrap();
-        input_tensor.strided_index(&mut kernel_body, (0..).map(|i| format!("index_{i}")));
-        writeln!(&mut kernel_body, ";").unwrap();
-        writeln!(&mut kernel_body, "var out_start_offset = ",).unwrap();
-        output_tensor.strided_index(&mut kernel_body, (0..).map(|i| format!("index_{i}")));
-        writeln!(&mut kernel_body, ";").unwrap();
         writeln!(&mut kernel_body).unwrap();
 
-        writeln!(
-            &mut kernel_body,
-            "var merged = {dtype}({});",
-            self.reduce.initial_value
-        )
-        .unwrap();
+        for (i, output) in outputs.iter().enumerate() {
+            writeln!(
+                &mut kernel_body,
+                "var merged_{i} = {};",
+                output.initial_value
+            )
+            .unwrap();
+            if output.local_indices.is_some() {
+                writeln!(&mut kernel_body, "var merged_index_{i} = {NO_INDEX};").unwrap();
+            }
+        }
+
+        // The range of the flattened reduce index space this workgroup merges
+        match (stage, &splits) {
+            (ReduceStage::Partial, Some(splits)) => {
+                writeln!(
+                    &mut kernel_body,
+                    "let chunk_size = {reduce_size} / {splits} + u32(({reduce_size} % {splits}) != 0u);"
+                )
+                .unwrap();
+                writeln!(&mut kernel_body, "let range_start = split * chunk_size;").unwrap();
+                writeln!(
+                    &mut kernel_body,
+                    "let range_end = min(range_start + chunk_size, {reduce_size});"
+                )
+                .unwrap();
+            }
+            // Each workgroup merges every partial of its column
+            (ReduceStage::Combine, Some(splits)) => {
+                writeln!(&mut kernel_body, "let range_start = 0u;").unwrap();
+                writeln!(&mut kernel_body, "let range_end = {splits};").unwrap();
+            }
+            _ => {
+                writeln!(&mut kernel_body, "let range_start = 0u;").unwrap();
+                writeln!(&mut kernel_body, "let range_end = {reduce_size};").unwrap();
+            }
+        }
 
         // First merge values on each thread individually. We divide the column allocated to the thread group into equal sized buckets
         // Round up
         writeln!(
             &mut kernel_body,
-            "let bucket_size = {reduce_size} / {blocksize}u + u32(({reduce_size} % {blocksize}u) != 0u);"
-        )
-        .unwrap();
-        // Then loop over this thread's portion of the column and merge the values
-        writeln!(
-            &mut kernel_body,
-            "for (var index = 0u; index < bucket_size; index += 1u) {{"
-        )
-        .unwrap();
-        writeln!(
-            &mut kernel_body,
-            "let axis_index = {workgroup_local_index} * bucket_size + index;"
+            "let range_size = max(range_end, range_start) - range_start;"
         )
         .unwrap();
-        writeln!(&mut kernel_body, "if axis_index < {reduce_size} {{").unwrap();
         writeln!(
             &mut kernel_body,
-            "let in_index = in_start_offset + axis_index * {reduce_stride};"
+            "let bucket_size = range_size / {blocksize}u + u32((range_size % {blocksize}u) != 0u);"
         )
         .unwrap();
+        // Then loop over this thread's portion of the column and merge the values
         writeln!(
             &mut kernel_body,
-            "let data = {};",
-            pre_element_wise
-                .iter()
-                .fold(format!("{input_tensor}[in_index]"), |acc, f| f
-                    .call(vec![acc]))
+            "for (var index = 0u; index < bucket_size; index += 1u) {{"
         )
         .unwrap();
         writeln!(
             &mut kernel_body,
-            "merged = {};",
-            reduce.call(vec!["data".to_string(), "merged".to_string()])
+            "let axis_index = range_start + {workgroup_local_index} * bucket_size + index;"
         )
         .unwrap();
+        writeln!(&mut kernel_body, "if axis_index < range_end {{").unwrap();
+        match (&input_tensor, &splits) {
+            (Some(input_tensor), _) => {
+                writeln!(&mut kernel_body, "var axis_index_remainder = axis_index;").unwrap();
+                for axis in axes.iter().rev() {
+                    let shape = input_tensor.shape_binding(*axis as u32);
+                    writeln!(
+                        &mut kernel_body,
+                        "let reduce_index_{axis} = axis_index_remainder % {shape};"
+                    )
+                    .unwrap();
+                    writeln!(&mut kernel_body, "axis_index_remainder /= {shape};").unwrap();
+                }
+                write!(&mut kernel_body, "let in_index = in_start_offset").unwrap();
+                for axis in axes {
+                    let stride = input_tensor.stride_binding(*axis as u32);
+                    write!(&mut kernel_body, " + reduce_index_{axis}*{stride}").unwrap();
+                }
+                writeln!(&mut kernel_body, ";").unwrap();
+                // The input is only read once for all of the outputs
+                writeln!(&mut kernel_body, "let input = {input_tensor}[in_index];").unwrap();
+                for (i, output) in outputs.iter().enumerate() {
+                    let data = output
+                        .pre_element_wise
+                        .iter()
+                        .fold("input".to_string(), |acc, f| f.call(vec![acc]));
+                    let data = match &output.accumulator {
+                        Some((lift, _)) => lift.call(vec![data]),
+                        None => data,
+                    };
+                    writeln!(&mut kernel_body, "let data_{i} = {data};").unwrap();
+                    output.merge(&mut kernel_body, i, &format!("data_{i}"), "axis_index");
+                }
+            }
+            // The partials are already merged states, so they are merged without lifting them
+            (None, Some(splits)) => {
+                writeln!(
+                    &mut kernel_body,
+                    "let partial_index = {workgroup_index}.x * {splits} + axis_index;"
+                )
+                .unwrap();
+                for (i, (output, (states, indices))) in outputs.iter().zip(&partials).enumerate() {
+                    writeln!(&mut kernel_body, "let data_{i} = {states}[partial_index];").unwrap();
+                    let index = match indices {
+                        Some(indices) => {
+                            writeln!(
+                                &mut kernel_body,
+                                "let data_index_{i} = {indices}[partial_index];"
+                            )
+                            .unwrap();
+                            format!("data_index_{i}")
+                        }
+                        None => String::new(),
+                    };
+                    output.merge(&mut kernel_body, i, &format!("data_{i}"), &index);
+                }
+            }
+            (None, None) => unreachable!("the combine stage always has splits"),
+        }
         writeln!(&mut kernel_body, "}}").unwrap();
         writeln!(&mut kernel_body, "}}").unwrap();
         writeln!(&mut kernel_body).unwrap();
 
-        // Next merge within each subgroup with shuffle down
-        writeln!(
-            &mut kernel_body,
-            "for (var offset = {subgroup_size} / 2u; offset > 0u; offset /= 2u) {{"
-        )
-        .unwrap();
-        writeln!(
-            &mut kernel_body,
-            "let neighbor = subgroupShuffleDown(merged, offset);"
-        )
-        .unwrap();
-        writeln!(
-            &mut kernel_body,
-            "merged = {};",
-            reduce.call(vec!["neighbor".to_string(), "merged".to_string()])
-        )
-        .unwrap();
-        writeln!(&mut kernel_body, "}}").unwrap();
-        writeln!(&mut kernel_body).unwrap();
-
-        // Write the output to the workgroup memory if this is the first thread in the subgroup
-        writeln!(&mut kernel_body, "if {subgroup_local_id} == 0u {{").unwrap();
-        writeln!(&mut kernel_body, "{local_data}[{subgroup_id}] = merged;").unwrap();
-        writeln!(&mut kernel_body, "}}").unwrap();
-
-        // Wait until all threads have written to the workgroup shared memory
-        writeln!(&mut kernel_body, "workgroupBarrier();").unwrap();
-
-        // Then if this is the first subgroup, do one final shuffle down reduction
-        writeln!(&mut kernel_body, "if {subgroup_id} == 0u {{").unwrap();
-        // Copy over the best value from each subgroup from the workgroup shared memory to the merged variable
-        writeln!(
-            &mut kernel_body,
-            "if {subgroup_local_id} < {subgroups_per_workgroup} {{"
-        )
-        .unwrap();
-        writeln!(
-            &mut kernel_body,
-            "merged = {local_data}[{subgroup_local_id}];"
-        )
-        .unwrap();
-        writeln!(&mut kernel_body, "}}").unwrap();
-        writeln!(&mut kernel_body, "else {{").unwrap();
-        writeln!(
-            &mut kernel_body,
-            "merged = {dtype}({});\n",
-            self.reduce.initial_value,
-        )
-        .unwrap();
-        writeln!(&mut kernel_body, "}}").unwrap();
-        writeln!(
-            &mut kernel_body,
-            "for (var offset = {subgroup_size} / 2u; offset > 0u; offset /= 2u) {{"
-        )
-        .unwrap();
-        writeln!(
-            &mut kernel_body,
-            "let neighbor = subgroupShuffleDown(merged, offset);"
-        )
-        .unwrap();
-        writeln!(&mut kernel_body, "var data = neighbor;").unwrap();
-        writeln!(
-            &mut kernel_body,
-            "merged = {};",
-            reduce.call(vec!["neighbor".to_string(), "merged".to_string()])
-        )
-        .unwrap();
-        writeln!(&mut kernel_body, "}}").unwrap();
+        // Merge the values of all threads into the first thread in the workgroup
+        if subgroups {
+            merge_subgroups(&mut kernel, &mut kernel_body, &outputs);
+        } else {
+            merge_workgroup_tree(
+                &mut kernel_body,
+                &outputs,
+                blocksize,
+                &workgroup_local_index,
+            );
+        }
 
         // Write the output to the output tensor if this is the first thread in the workgroup
         writeln!(&mut kernel_body, "if {workgroup_local_index} == 0u {{").unwrap();
-        writeln!(
-            &mut kernel_body,
-            "let data = {};",
-            post_element_wise
-                .iter()
-                .fold("merged".to_string(), |acc, f| f.call(vec![acc]))
-        )
-        .unwrap();
-        writeln!(
-            &mut kernel_body,
-            "{output_tensor}[out_start_offset] = data;"
-        )
-        .unwrap();
-        writeln!(&mut kernel_body, "}}").unwrap();
+        if stage == ReduceStage::Partial {
+            // The partial states are finished after they are merged in the combine stage
+            for (i, (states, indices)) in partials.iter().enumerate() {
+                writeln!(
+                    &mut kernel_body,
+                    "{states}[{workgroup_index}.x] = merged_{i};"
+                )
+                .unwrap();
+                if let Some(indices) = indices {
+                    writeln!(
+                        &mut kernel_body,
+                        "{indices}[{workgroup_index}.x] = merged_index_{i};"
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        for (i, (output, output_tensor)) in outputs.iter().zip(&output_tensors).enumerate() {
+            let merged = match (&output.accumulator, &output.local_indices) {
+                (Some((_, finish)), _) => {
+                    finish.call(vec![format!("merged_{i}"), format!("f32({reduce_size})")])
+                }
+                (None, Some(_)) => format!("merged_index_{i}"),
+                (None, None) => format!("merged_{i}"),
+            };
+            writeln!(
+                &mut kernel_body,
+                "{output_tensor}[out_start_offset_{i}] = {};",
+                output
+                    .post_element_wise
+                    .iter()
+                    .fold(merged, |acc, f| f.call(vec![acc]))
+            )
+            .unwrap();
+        }
         writeln!(&mut kernel_body, "}}").unwrap();
 
         kernel.set_body(kernel_body);
@@ -280,118 +476,483 @@ impl UntypedReduceKernel {
         kernel
     }
 
+    /// Run the kernel and return the result of each output in order
     pub fn run_with_query(
         &self,
         tensor: &TensorData,
-        dim: usize,
+        axes: &[usize],
+        keepdim: bool,
         query: Option<&PerformanceQueries>,
         command_encoder: &mut CommandEncoder,
-    ) -> TensorData {
+    ) -> Vec<TensorData> {
         let shape = tensor.layout().shape();
-        let new_tensor_shape = shape
+        let new_tensor_shape = reduced_shape(shape, axes, false);
+        let output_tensors = self
+            .outputs
             .iter()
-            .enumerate()
-            .filter_map(|(i, x)| (i != dim).then_some(*x))
+            .map(|output| {
+                let output_type = output.out_datatype();
+                let output_buf = create_storage_buffer(
+                    tensor.device(),
+                    new_tensor_shape.iter().product::<usize>() * output_type.element_size(),
+                );
+                TensorData::new_from_buffer(
+                    tensor.device(),
+                    output_buf,
+                    &new_tensor_shape,
+                    output_type,
+                )
+            })
             .collect::<Vec<_>>();
-        let output_type = self.out_datatype();
-        let output_buf = tensor
-            .device()
-            .wgpu_device()
-            .create_buffer(&wgpu::BufferDescriptor {
-                label: None,
-                size: padded_tensor_size(
-                    (new_tensor_shape.iter().product::<usize>() * output_type.element_size())
-                        as u64,
-                ),
-                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
-                mapped_at_creation: false,
-            });
-        let output_tensor = TensorData::new_from_buffer(
-            tensor.device(),
-            output_buf,
-            &new_tensor_shape,
-            output_type,
-        );
-
-        self.run_with_query_and_out_tensor(tensor, dim, query, &output_tensor, command_encoder);
 
-        output_tensor
+        self.run_with_query_and_out_tensors(tensor, axes, query, &output_tensors, command_encoder);
+
+        if keepdim {
+            // The reduced axes have a size of one, so the layout is still contiguous
+            let keepdim_shape = reduced_shape(shape, axes, true);
+            output_tensors
+                .into_iter()
+                .map(|output_tensor| {
+                    TensorData::new_from_buffer(
+                        tensor.device(),
+                        output_tensor.buffer().clone(),
+                        &keepdim_shape,
+                        output_tensor.datatype(),
+                    )
+                })
+                .collect()
+        } else {
+            output_tensors
+        }
     }
 
-    pub fn run_with_query_and_out_tensor(
+    pub fn run_with_query_and_out_tensors(
         &self,
         tensor: &TensorData,
-        dim: usize,
+        axes: &[usize],
         query: Option<&PerformanceQueries>,
-        output_tensor: &TensorData,
+        output_tensors: &[TensorData],
         command_encoder: &mut CommandEncoder,
     ) {
-        // assert_eq!(
-        //     *output_tensor.layout().shape(),
-        //     [tensor
-        //         .layout()
-        //         .shape()
-        //         .iter()
-        //         .enumerate()
-        //         .filter_map(|(i, x)| { (i != dim).then_some(*x as u32) })
-        //         .product::<u32>() as usize]
-        // );
-
-        let limits = tensor.device().wgpu_device().limits();
-        let max_blocksize = (tensor.layout().shape()[dim] as u32)
-            .min(limits.max_compute_workgroup_size_x)
-            .max(limits.min_subgroup_size)
-            .max(32);
-        let kernel = self
-            .kernel
-            .get_or_init(|| self.tiled_map(max_blocksize, tensor.layout().rank() as u32));
-
-        let workgroup_size = output_tensor.layout().shape().iter().product::<usize>() as u32;
-        let workgroup_dispatch_size = [workgroup_size, 1, 1];
-        let trimmed_tensor_layout = Layout::from_parts(
-            0,
-            tensor
-                .layout()
-                .shape()
-                .iter()
-                .enumerate()
-                .filter_map(|(i, x)| (i != dim).then_some(*x))
-                .collect(),
-            tensor
-                .layout()
-                .strides()
-                .iter()
-                .enumerate()
-                .filter_map(|(i, x)| (i != dim).then_some(*x))
-                .collect(),
-        );
-        let trimmed_tensor = TensorData::new_from_parts(
-            tensor.device(),
-            tensor.buffer().clone(),
-            trimmed_tensor_layout,
-            tensor.datatype(),
-        );
-        kernel.run_with_query(
-            tensor.device(),
-            [
-                KernelInputValue::Tensor(trimmed_tensor.clone()),
-                KernelInputValue::Tensor(output_tensor.clone()),
-                KernelInputValue::Integer(tensor.layout().shape()[dim] as u32),
-                KernelInputValue::Integer(tensor.layout().strides()[dim] as u32),
-            ],
+        let device = tensor.device();
+        let limits = device.wgpu_device().limits();
+        let reduce_size = axes
+            .iter()
+            .map(|axis| tensor.layout().shape()[*axis])
+            .product::<usize>();
+        let output_size = output_tensors[0].layout().shape().iter().product::<usize>();
+        let splits = reduce_split_count(tensor, axes);
+        // The workgroup size must be a multiple of the subgroup size so every subgroup is full,
+        // and each output keeps one value per thread in workgroup memory
+        let max_blocksize = limits
+            .max_compute_workgroup_size_x
+            .min(limits.max_compute_workgroup_storage_size / self.workgroup_memory_per_thread());
+        let blocksize = |size: usize| {
+            (size as u32)
+                .next_power_of_two()
+                .min(prev_power_of_two(max_blocksize))
+                .max(limits.min_subgroup_size)
+                .max(32)
+        };
+        let subgroups = device.subgroups_supported();
+        let rank = tensor.layout().rank() as u32;
+
+        if splits == 1 {
+            let kernel = self.kernel.get_or_init(|| {
+                self.tiled_map(
+                    blocksize(reduce_size),
+                    rank,
+                    axes,
+                    subgroups,
+                    ReduceStage::Full,
+                )
+            });
+            kernel.run_with_query(
+                device,
+                std::iter::once(KernelInputValue::Tensor(tensor.clone()))
+                    .chain(
+                        output_tensors
+                            .iter()
+                            .map(|output| KernelInputValue::Tensor(output.clone())),
+                    )
+                    .chain([KernelInputValue::Integer(reduce_size as u32)])
+                    .collect::<Vec<_>>(),
+                query,
+                command_encoder,
+                [output_size as u32, 1, 1],
+            );
+            return;
+        }
+
+        // Each output keeps one merged state (and index) per chunk between the two kernels
+        let partials = self
+            .outputs
+            .iter()
+            .flat_map(|output| {
+                let count = output_size * splits;
+                let states =
+                    create_storage_buffer(device, count * output.reduce.state_size() as usize);
+                let indices = output
+                    .reduce
+                    .returns_index
+                    .then(|| create_storage_buffer(device, count * size_of::<u32>()));
+                std::iter::once(states).chain(indices)
+            })
+            .map(KernelInputValue::Buffer)
+            .collect::<Vec<_>>();
+        let chunk_size = reduce_size.div_ceil(splits);
+        let partial_kernel = self.partial_kernel.get_or_init(|| {
+            self.tiled_map(
+                blocksize(chunk_size),
+                rank,
+                axes,
+                subgroups,
+                ReduceStage::Partial,
+            )
+        });
+        // The partial kernel reads the whole input, so it is the one that is timed
+        partial_kernel.run_with_query(
+            device,
+            std::iter::once(KernelInputValue::Tensor(tensor.clone()))
+                .chain(partials.iter().cloned())
+                .chain([
+                    KernelInputValue::Integer(reduce_size as u32),
+                    KernelInputValue::Integer(splits as u32),
+                ])
+                .collect::<Vec<_>>(),
             query,
             command_encoder,
-            workgroup_dispatch_size,
+            [(output_size * splits) as u32, 1, 1],
+        );
+        let combine_kernel = self.combine_kernel.get_or_init(|| {
+            self.tiled_map(
+                blocksize(splits),
+                rank,
+                axes,
+                subgroups,
+                ReduceStage::Combine,
+            )
+        });
+        combine_kernel.run_with_query(
+            device,
+            partials
+                .into_iter()
+                .chain(
+                    output_tensors
+                        .iter()
+                        .map(|output| KernelInputValue::Tensor(output.clone())),
+                )
+                .chain([
+                    KernelInputValue::Integer(reduce_size as u32),
+                    KernelInputValue::Integer(splits as u32),
+                ])
+                .collect::<Vec<_>>(),
+            None,
+            command_encoder,
+            [output_size as u32, 1, 1],
         );
     }
 }
 
-#[derive(Clone)]
+/// Which part of a reduction a kernel runs
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ReduceStage {
+    /// Merge the whole reduce axis of each output element in one workgroup
+    Full,
+    /// Merge one chunk of the reduce axis in each workgroup into a partial state
+    Partial,
+    /// Merge and finish the partial states of each output element
+    Combine,
+}
+
+/// The fewest elements a workgroup merges in a split reduction
+const MIN_SPLIT_SIZE: usize = 4096;
+/// Split long reductions until there are about this many workgroups to keep the whole GPU busy
+const TARGET_WORKGROUPS: usize = 512;
+
+/// The number of chunks to split each reduction into. Short reductions and reductions with enough
+/// output elements to fill the GPU run in a single kernel with one workgroup per output element
+fn split_count(reduce_size: usize, output_size: usize, max_workgroups: usize) -> usize {
+    let output_size = output_size.max(1);
+    (reduce_size / MIN_SPLIT_SIZE)
+        .min(TARGET_WORKGROUPS / output_size)
+        .min(max_workgroups / output_size)
+        .max(1)
+}
+
+/// The number of chunks a reduction of `tensor` over `axes` is split into
+pub(crate) fn reduce_split_count(tensor: &TensorData, axes: &[usize]) -> usize {
+    let shape = tensor.layout().shape();
+    let reduce_size = axes.iter().map(|axis| shape[*axis]).product();
+    let output_size = reduced_shape(shape, axes, false).iter().product();
+    let limits = tensor.device().wgpu_device().limits();
+    split_count(
+        reduce_size,
+        output_size,
+        limits.max_compute_workgroups_per_dimension as usize,
+    )
+}
+
+pub(crate) fn create_storage_buffer(device: &crate::Device, size: usize) -> wgpu::Buffer {
+    device.wgpu_device().create_buffer(&wgpu::BufferDescriptor {
+        label: None,
+        size: padded_tensor_size(size as u64),
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    })
+}
+
+fn prev_power_of_two(value: u32) -> u32 {
+    1 << value.ilog2()
+}
+
+/// Merge within each subgroup with shuffles, then merge the results of each subgroup in the first
+/// subgroup
+fn merge_subgroups(kernel: &mut GenericKernel, kernel_body: &mut String, outputs: &[KernelOutput]) {
+    let subgroup_id = kernel.subgroup_index();
+    let subgroup_local_id = kernel.subgroup_local_index();
+    let subgroups_per_workgroup = kernel.subgroups_per_workgroup();
+    let subgroup_size = kernel.subgroup_size();
+
+    // First merge within each subgroup with shuffle down
+    writeln!(
+        kernel_body,
+        "for (var offset = {subgroup_size} / 2u; offset > 0u; offset /= 2u) {{"
+    )
+    .unwrap();
+    for (i, output) in outputs.iter().enumerate() {
+        output.merge_shuffle_down(kernel_body, i);
+    }
+    writeln!(kernel_body, "}}").unwrap();
+    writeln!(kernel_body).unwrap();
+
+    // Write the output to the workgroup memory if this is the first thread in the subgroup
+    writeln!(kernel_body, "if {subgroup_local_id} == 0u {{").unwrap();
+    for (i, output) in outputs.iter().enumerate() {
+        let local_data = &output.local_data;
+        writeln!(kernel_body, "{local_data}[{subgroup_id}] = merged_{i};").unwrap();
+        if let Some(local_indices) = &output.local_indices {
+            writeln!(
+                kernel_body,
+                "{local_indices}[{subgroup_id}] = merged_index_{i};"
+            )
+            .unwrap();
+        }
+    }
+    writeln!(kernel_body, "}}").unwrap();
+
+    // Wait until all threads have written to the workgroup shared memory
+    writeln!(kernel_body, "workgroupBarrier();").unwrap();
+
+    // Then if this is the first subgroup, do one final shuffle down reduction
+    writeln!(kernel_body, "if {subgroup_id} == 0u {{").unwrap();
+    // Copy over the best value from each subgroup from the workgroup shared memory to the merged variable
+    writeln!(
+        kernel_body,
+        "if {subgroup_local_id} < {subgroups_per_workgroup} {{"
+    )
+    .unwrap();
+    for (i, output) in outputs.iter().enumerate() {
+        let local_data = &output.local_data;
+        writeln!(
+            kernel_body,
+            "merged_{i} = {local_data}[{subgroup_local_id}];"
+        )
+        .unwrap();
+        if let Some(local_indices) = &output.local_indices {
+            writeln!(
+                kernel_body,
+                "merged_index_{i} = {local_indices}[{subgroup_local_id}];"
+            )
+            .unwrap();
+        }
+    }
+    writeln!(kernel_body, "}}").unwrap();
+    writeln!(kernel_body, "else {{").unwrap();
+    for (i, output) in outputs.iter().enumerate() {
+        writeln!(kernel_body, "merged_{i} = {};", output.initial_value).unwrap();
+        if output.local_indices.is_some() {
+            writeln!(kernel_body, "merged_index_{i} = {NO_INDEX};").unwrap();
+        }
+    }
+    writeln!(kernel_body, "}}").unwrap();
+    writeln!(
+        kernel_body,
+        "for (var offset = {subgroup_size} / 2u; offset > 0u; offset /= 2u) {{"
+    )
+    .unwrap();
+    for (i, output) in outputs.iter().enumerate() {
+        output.merge_shuffle_down(kernel_body, i);
+    }
+    writeln!(kernel_body, "}}").unwrap();
+    writeln!(kernel_body, "}}").unwrap();
+}
+
+/// Merge the values of every thread through workgroup memory. This doesn't need subgroup
+/// operations, so it works on every adapter
+fn merge_workgroup_tree(
+    kernel_body: &mut String,
+    outputs: &[KernelOutput],
+    blocksize: u32,
+    workgroup_local_index: &str,
+) {
+    for (i, output) in outputs.iter().enumerate() {
+        let local_data = &output.local_data;
+        writeln!(
+            kernel_body,
+            "{local_data}[{workgroup_local_index}] = merged_{i};"
+        )
+        .unwrap();
+        if let Some(local_indices) = &output.local_indices {
+            writeln!(
+                kernel_body,
+                "{local_indices}[{workgroup_local_index}] = merged_index_{i};"
+            )
+            .unwrap();
+        }
+    }
+    writeln!(kernel_body, "workgroupBarrier();").unwrap();
+
+    // Each step merges the upper half of the remaining values into the lower half. The blocksize
+    // is a power of two so every value is merged
+    writeln!(
+        kernel_body,
+        "for (var offset = {blocksize}u / 2u; offset > 0u; offset /= 2u) {{"
+    )
+    .unwrap();
+    writeln!(kernel_body, "if {workgroup_local_index} < offset {{").unwrap();
+    for (i, output) in outputs.iter().enumerate() {
+        let local_data = &output.local_data;
+        let neighbor_index = output
+            .local_indices
+            .as_ref()
+            .map(|local_indices| format!("{local_indices}[{workgroup_local_index} + offset]"));
+        output.merge_neighbor(
+            kernel_body,
+            i,
+            &format!("{local_data}[{workgroup_local_index} + offset]"),
+            neighbor_index.as_deref(),
+        );
+        writeln!(
+            kernel_body,
+            "{local_data}[{workgroup_local_index}] = merged_{i};"
+        )
+        .unwrap();
+        if let Some(local_indices) = &output.local_indices {
+            writeln!(
+                kernel_body,
+                "{local_indices}[{workgroup_local_index}] = merged_index_{i};"
+            )
+            .unwrap();
+        }
+    }
+    writeln!(kernel_body, "}}").unwrap();
+    // Barriers must be in uniform control flow, so every thread waits outside of the branch
+    writeln!(kernel_body, "workgroupBarrier();").unwrap();
+    writeln!(kernel_body, "}}").unwrap();
+}
+
+/// The functions and workgroup memory of one output in a generated reduce kernel
+struct KernelOutput {
+    initial_value: String,
+    local_data: KernelGlobal,
+    local_indices: Option<KernelGlobal>,
+    reduce: Function,
+    accumulator: Option<(Function, Function)>,
+    pre_element_wise: Vec<Function>,
+    post_element_wise: Vec<Function>,
+}
+
+impl KernelOutput {
+    /// Merge a value and its index into `merged_{i}` and `merged_index_{i}`
+    fn merge(&self, kernel_body: &mut String, i: usize, value: &str, index: &str) {
+        let next = self
+            .reduce
+            .call(vec![value.to_string(), format!("merged_{i}")]);
+        if self.local_indices.is_some() {
+            // Slots without an element are skipped, and the first element is always taken
+            // even if it isn't better than the initial value (for example -inf in max)
+            writeln!(kernel_body, "if {index} != {NO_INDEX} {{").unwrap();
+            writeln!(kernel_body, "if merged_index_{i} == {NO_INDEX} {{").unwrap();
+            writeln!(kernel_body, "merged_{i} = {value};").unwrap();
+            writeln!(kernel_body, "merged_index_{i} = {index};").unwrap();
+            writeln!(kernel_body, "}} else {{").unwrap();
+            writeln!(kernel_body, "let next = {next};").unwrap();
+            // Ties go to the smaller index so the result doesn't depend on the merge order
+            writeln!(
+                kernel_body,
+                "if next == {value} && (next != merged_{i} || {index} < merged_index_{i}) {{"
+            )
+            .unwrap();
+            writeln!(kernel_body, "merged_index_{i} = {index};").unwrap();
+            writeln!(kernel_body, "}}").unwrap();
+            writeln!(kernel_body, "merged_{i} = next;").unwrap();
+            writeln!(kernel_body, "}}").unwrap();
+            writeln!(kernel_body, "}}").unwrap();
+        } else {
+            writeln!(kernel_body, "merged_{i} = {next};").unwrap();
+        }
+    }
+
+    /// Merge the value of another thread
+    fn merge_neighbor(
+        &self,
+        kernel_body: &mut String,
+        i: usize,
+        neighbor: &str,
+        neighbor_index: Option<&str>,
+    ) {
+        writeln!(kernel_body, "{{").unwrap();
+        writeln!(kernel_body, "let neighbor = {neighbor};").unwrap();
+        if let Some(neighbor_index) = neighbor_index {
+            writeln!(kernel_body, "let neighbor_index = {neighbor_index};").unwrap();
+        }
+        self.merge(kernel_body, i, "neighbor", "neighbor_index");
+        writeln!(kernel_body, "}}").unwrap();
+    }
+
+    /// Merge the value of the thread `offset` lanes down in the subgroup
+    fn merge_shuffle_down(&self, kernel_body: &mut String, i: usize) {
+        let neighbor_index = self
+            .local_indices
+            .as_ref()
+            .map(|_| format!("subgroupShuffleDown(merged_index_{i}, offset)"));
+        self.merge_neighbor(
+            kernel_body,
+            i,
+            &format!("subgroupShuffleDown(merged_{i}, offset)"),
+            neighbor_index.as_deref(),
+        );
+    }
+}
+
+/// The index of a reduction that has not seen any elements yet
+pub(crate) const NO_INDEX: &str = "0xffffffffu";
+
+#[derive(Clone, Debug)]
 pub struct ReduceFunction {
     name: Option<String>,
     operation: String,
     initial_value: String,
     datatype: DataTypeEnum,
+    /// Output the index of the element the operation selects instead of the value
+    returns_index: bool,
+    /// Merge a wider state than the element type
+    accumulator: Option<ReduceAccumulator>,
+}
+
+/// The state a reduction merges when it isn't the element type. Each element is lifted into the
+/// state before it is merged, and the final state is finished into the output.
+#[derive(Clone, Debug)]
+struct ReduceAccumulator {
+    /// The WGSL type of the state
+    state: String,
+    /// The size of the state in an array in bytes
+    state_size: u32,
+    /// Converts the element `a` into the state
+    lift: String,
+    /// Converts the state `a` into the output. `count` is the number of elements reduced
+    finish: String,
 }
 
 impl ReduceFunction {
@@ -401,27 +962,258 @@ impl ReduceFunction {
             operation: operation.to_string(),
             initial_value: initial_value.to_string(),
             datatype,
+            returns_index: false,
+            accumulator: None,
         }
     }
 
-    pub fn name(&self) -> &str {
-        self.name.as_deref().unwrap_or("reduce")
+    fn with_accumulator(
+        mut self,
+        state: impl Display,
+        state_size: u32,
+        lift: impl Display,
+        finish: impl Display,
+    ) -> Self {
+        self.accumulator = Some(ReduceAccumulator {
+            state: state.to_string(),
+            state_size,
+            lift: lift.to_string(),
+            finish: finish.to_string(),
+        });
+        self
     }
 
-    pub fn with_name(mut self, name: impl ToString) -> Self {
-        self.name = Some(name.to_string());
-        self
+    /// The WGSL type of the value the reduction merges
+    pub(crate) fn state_type(&self) -> String {
+        match &self.accumulator {
+            Some(accumulator) => accumulator.state.clone(),
+            None => self.datatype.to_string(),
+        }
     }
 
-    pub(crate) fn datatype(&self) -> DataTypeEnum {
-        self.datatype
+    pub(crate) fn state_size(&self) -> u32 {
+        match &self.accumulator {
+            Some(accumulator) => accumulator.state_size,
+            None => self.datatype.element_size() as u32,
+        }
     }
-}
 
-macro_rules! impl_reduce {
-    ($R:expr, $T:ident, $f_untyped:ident, $f:ident, $($arg:ident: $arg_type:ty),*) => {
-        impl<D: DataType> $T for Tensor<$R, D> {
-            type Output = Tensor<{ $R - 1 }, D>;
+    /// The state merging starts from. Merging it with another state leaves the other state
+    /// unchanged
+    pub(crate) fn initial_state(&self) -> String {
+        format!("{}({})", self.state_type(), self.initial_value)
+    }
+
+    /// Add the function that merges the states `a` and `b` to a kernel
+    pub(crate) fn add_function(&self, kernel: &mut GenericKernel) -> Function {
+        let state = self.state_type();
+        kernel.add_function(
+            &state,
+            self.operation.clone(),
+            [
+                ("a".to_string(), state.clone()),
+                ("b".to_string(), state.clone()),
+            ],
+        )
+    }
+
+    /// Add the functions that convert an element into the accumulator state and the merged
+    /// state into the output if the reduction has an accumulator
+    pub(crate) fn add_accumulator_functions(
+        &self,
+        kernel: &mut GenericKernel,
+    ) -> Option<(Function, Function)> {
+        let accumulator = self.accumulator.as_ref()?;
+        let lift = kernel.add_function(
+            &accumulator.state,
+            &accumulator.lift,
+            [("a".to_string(), self.datatype.to_string())],
+        );
+        let finish = kernel.add_function(
+            self.datatype,
+            &accumulator.finish,
+            [
+                ("a".to_string(), accumulator.state.clone()),
+                ("count".to_string(), "f32".to_string()),
+            ],
+        );
+        Some((lift, finish))
+    }
+
+    fn returning_index(mut self) -> Self {
+        self.returns_index = true;
+        self
+    }
+
+    pub(crate) fn returns_index(&self) -> bool {
+        self.returns_index
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or("reduce")
+    }
+
+    pub fn with_name(mut self, name: impl ToString) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    pub(crate) fn datatype(&self) -> DataTypeEnum {
+        self.datatype
+    }
+
+    pub(crate) fn output_datatype(&self) -> DataTypeEnum {
+        if self.returns_index {
+            DataTypeEnum::U32
+        } else {
+            self.datatype
+        }
+    }
+
+    pub(crate) fn sum(datatype: DataTypeEnum) -> Self {
+        Self::new("let output = a + b;", "0.0", datatype).with_name("sum")
+    }
+
+    pub(crate) fn max(datatype: DataTypeEnum) -> Self {
+        Self::new("let output = max(a, b);", datatype.min_value(), datatype).with_name("max")
+    }
+
+    fn min(datatype: DataTypeEnum) -> Self {
+        Self::new("let output = min(a, b);", datatype.max_value(), datatype).with_name("min")
+    }
+
+    pub(crate) fn product(datatype: DataTypeEnum) -> Self {
+        Self::new("let output = a * b;", "1.0", datatype).with_name("product")
+    }
+
+    fn argmax(datatype: DataTypeEnum) -> Self {
+        Self::max(datatype).returning_index().with_name("argmax")
+    }
+
+    fn argmin(datatype: DataTypeEnum) -> Self {
+        Self::min(datatype).returning_index().with_name("argmin")
+    }
+
+    // The reductions below accumulate in f32 so they stay accurate for f16 inputs
+
+    fn mean(datatype: DataTypeEnum) -> Self {
+        Self::new("let output = a + b;", "0.0", datatype)
+            .with_accumulator(
+                "f32",
+                4,
+                "let output = f32(a);",
+                format_args!("let output = {datatype}(a / count);"),
+            )
+            .with_name("mean")
+    }
+
+    /// The variance with Bessel's `correction` using Welford's algorithm. The state is the
+    /// (count, mean, sum of squared differences from the mean) of the elements merged so far.
+    fn var(datatype: DataTypeEnum, correction: usize) -> Self {
+        Self::welford(datatype, format_args!("a.z / (a.x - {correction}.0)")).with_name("var")
+    }
+
+    fn std(datatype: DataTypeEnum, correction: usize) -> Self {
+        Self::welford(datatype, format_args!("sqrt(a.z / (a.x - {correction}.0))")).with_name("std")
+    }
+
+    fn welford(datatype: DataTypeEnum, finish: impl Display) -> Self {
+        // Chan et al.'s parallel update. Merging two empty states leaves the state empty
+        let merge = "let count = a.x + b.x;
+let delta = b.y - a.y;
+let b_weight = select(0.0, b.x / count, count > 0.0);
+let output = vec3<f32>(count, a.y + delta * b_weight, a.z + b.z + delta * delta * a.x * b_weight);";
+        Self::new(merge, "0.0", datatype).with_accumulator(
+            "vec3<f32>",
+            // vec3 is aligned to 16 bytes
+            16,
+            "let output = vec3<f32>(1.0, f32(a), 0.0);",
+            format_args!("let output = {datatype}({finish});"),
+        )
+    }
+
+    /// log(sum(exp(x))) without overflowing. The state is the running maximum and the sum of
+    /// exp(x - maximum)
+    fn logsumexp(datatype: DataTypeEnum) -> Self {
+        let merge = "let maximum = max(a.x, b.x);
+let output = vec2<f32>(maximum, a.y * exp(a.x - maximum) + b.y * exp(b.x - maximum));";
+        Self::new(merge, "-3.40282e+38, 0.0", datatype)
+            .with_accumulator(
+                "vec2<f32>",
+                8,
+                "let output = vec2<f32>(f32(a), 1.0);",
+                format_args!("let output = {datatype}(a.x + log(a.y));"),
+            )
+            .with_name("logsumexp")
+    }
+}
+
+/// A user defined reduction over elements of type `D`.
+///
+/// The body is a WGSL snippet that merges the two values `a` and `b` and defines `output`. The
+/// identity is a WGSL expression that leaves any value unchanged when merged with it. Merging
+/// must be associative because the order elements are merged in is not fixed. Custom reductions
+/// fuse with the element-wise operations before and after them like the built in reductions.
+///
+/// ```rust, no_run
+/// # use wgpu_compute::CustomReduce;
+/// let log_add_exp = CustomReduce::<f32>::new(
+///     "log_add_exp",
+///     "let maximum = max(a, b);
+/// let output = maximum + log(exp(a - maximum) + exp(b - maximum));",
+///     "-3.40282e+38",
+/// )
+/// .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct CustomReduce<D> {
+    function: ReduceFunction,
+    datatype: PhantomData<D>,
+}
+
+impl<D: DataType> CustomReduce<D> {
+    /// Create a new reduction with a name that shows up in the graph visualization. The body and
+    /// identity are validated up front so errors are reported here instead of when the kernel runs.
+    pub fn new(
+        name: impl ToString,
+        body: impl Display,
+        identity: impl Display,
+    ) -> Result<Self, InvalidFunctionError> {
+        let name = name.to_string();
+        let body = body.to_string();
+        check_function_body(&name, &body)?;
+
+        let function = ReduceFunction::new(body, identity, D::WGSL_TYPE).with_name(&name);
+
+        let mut kernel = GenericKernel::new();
+        let output = kernel.add_tensor_input(1, true, D::WGSL_TYPE);
+        let merge = function.add_function(&mut kernel);
+        let initial = function.initial_state();
+        let result = merge.call(vec![initial.clone(), initial]);
+        kernel.set_body(format!("{output}[0] = {result};"));
+        kernel
+            .validate()
+            .map_err(|message| InvalidFunctionError::new(&name, message))?;
+
+        Ok(Self {
+            function,
+            datatype: PhantomData,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        self.function.name()
+    }
+
+    pub(crate) fn function(&self) -> &ReduceFunction {
+        &self.function
+    }
+}
+
+macro_rules! impl_reduce {
+    ($R:expr, $T:ident, $f_untyped:ident, $f:ident, $($arg:ident: $arg_type:ty),*) => {
+        impl<D: DataType> $T for Tensor<$R, D> {
+            type Output = Tensor<{ $R - 1 }, D>;
 
             fn $f(&self $(, $arg: $arg_type)*) -> Self::Output {
                 $f_untyped(self $(, $arg)*)
@@ -440,11 +1232,7 @@ fn unchecked_sum<const R1: usize, const R2: usize, D: DataType>(
     tensor: &Tensor<R1, D>,
     dim: usize,
 ) -> Tensor<R2, D> {
-    tensor.reduce(
-        ReduceFunction::new("let output = a + b;".to_string(), "0.0", D::WGSL_TYPE)
-            .with_name("sum"),
-        dim,
-    )
+    tensor.reduce(ReduceFunction::sum(D::WGSL_TYPE), &[dim], false)
 }
 
 impl_reduce!(1, Sum, unchecked_sum, sum, dim: usize);
@@ -634,15 +1422,7 @@ fn unchecked_max<const R1: usize, const R2: usize, D: DataType>(
     tensor: &Tensor<R1, D>,
     dim: usize,
 ) -> Tensor<R2, D> {
-    tensor.reduce(
-        ReduceFunction::new(
-            "let output = max(a, b);".to_string(),
-            "-3.40282e+38",
-            D::WGSL_TYPE,
-        )
-        .with_name("max"),
-        dim,
-    )
+    tensor.reduce(ReduceFunction::max(D::WGSL_TYPE), &[dim], false)
 }
 
 pub trait Max {
@@ -707,15 +1487,7 @@ fn unchecked_min<const R1: usize, const R2: usize, D: DataType>(
     tensor: &Tensor<R1, D>,
     dim: usize,
 ) -> Tensor<R2, D> {
-    tensor.reduce(
-        ReduceFunction::new(
-            "let output = min(a, b);".to_string(),
-            "3.40282e+38",
-            D::WGSL_TYPE,
-        )
-        .with_name("min"),
-        dim,
-    )
+    tensor.reduce(ReduceFunction::min(D::WGSL_TYPE), &[dim], false)
 }
 
 pub trait Min {
@@ -780,11 +1552,7 @@ fn unchecked_product<const R1: usize, const R2: usize, D: DataType>(
     tensor: &Tensor<R1, D>,
     dim: usize,
 ) -> Tensor<R2, D> {
-    tensor.reduce(
-        ReduceFunction::new("let output = a * b;".to_string(), "1.0", D::WGSL_TYPE)
-            .with_name("product"),
-        dim,
-    )
+    tensor.reduce(ReduceFunction::product(D::WGSL_TYPE), &[dim], false)
 }
 
 pub trait Product {
@@ -844,3 +1612,1137 @@ async fn test_reduce_product() {
     assert_eq!(output[[1]], 12.);
     assert_eq!(output[[2]], 30.);
 }
+
+fn unchecked_mean<const R1: usize, const R2: usize, D: DataType>(
+    tensor: &Tensor<R1, D>,
+    dim: usize,
+) -> Tensor<R2, D> {
+    tensor.reduce(ReduceFunction::mean(D::WGSL_TYPE), &[dim], false)
+}
+
+pub trait Mean {
+    type Output;
+
+    fn mean(&self, dim: usize) -> Self::Output;
+}
+
+impl_reduce!(1, Mean, unchecked_mean, mean, dim: usize);
+impl_reduce!(2, Mean, unchecked_mean, mean, dim: usize);
+impl_reduce!(3, Mean, unchecked_mean, mean, dim: usize);
+impl_reduce!(4, Mean, unchecked_mean, mean, dim: usize);
+impl_reduce!(5, Mean, unchecked_mean, mean, dim: usize);
+impl_reduce!(6, Mean, unchecked_mean, mean, dim: usize);
+impl_reduce!(7, Mean, unchecked_mean, mean, dim: usize);
+impl_reduce!(8, Mean, unchecked_mean, mean, dim: usize);
+impl_reduce!(9, Mean, unchecked_mean, mean, dim: usize);
+impl_reduce!(10, Mean, unchecked_mean, mean, dim: usize);
+impl_reduce!(11, Mean, unchecked_mean, mean, dim: usize);
+impl_reduce!(12, Mean, unchecked_mean, mean, dim: usize);
+impl_reduce!(13, Mean, unchecked_mean, mean, dim: usize);
+impl_reduce!(14, Mean, unchecked_mean, mean, dim: usize);
+impl_reduce!(15, Mean, unchecked_mean, mean, dim: usize);
+impl_reduce!(16, Mean, unchecked_mean, mean, dim: usize);
+impl_reduce!(17, Mean, unchecked_mean, mean, dim: usize);
+impl_reduce!(18, Mean, unchecked_mean, mean, dim: usize);
+impl_reduce!(19, Mean, unchecked_mean, mean, dim: usize);
+impl_reduce!(20, Mean, unchecked_mean, mean, dim: usize);
+
+fn unchecked_var<const R1: usize, const R2: usize, D: DataType>(
+    tensor: &Tensor<R1, D>,
+    dim: usize,
+    correction: usize,
+) -> Tensor<R2, D> {
+    tensor.reduce(ReduceFunction::var(D::WGSL_TYPE, correction), &[dim], false)
+}
+
+pub trait Var {
+    type Output;
+
+    /// The variance along `dim`, divided by the number of elements minus `correction`. Use a
+    /// correction of 1 for the unbiased sample variance and 0 for the population variance
+    fn var(&self, dim: usize, correction: usize) -> Self::Output;
+}
+
+impl_reduce!(1, Var, unchecked_var, var, dim: usize, correction: usize);
+impl_reduce!(2, Var, unchecked_var, var, dim: usize, correction: usize);
+impl_reduce!(3, Var, unchecked_var, var, dim: usize, correction: usize);
+impl_reduce!(4, Var, unchecked_var, var, dim: usize, correction: usize);
+impl_reduce!(5, Var, unchecked_var, var, dim: usize, correction: usize);
+impl_reduce!(6, Var, unchecked_var, var, dim: usize, correction: usize);
+impl_reduce!(7, Var, unchecked_var, var, dim: usize, correction: usize);
+impl_reduce!(8, Var, unchecked_var, var, dim: usize, correction: usize);
+impl_reduce!(9, Var, unchecked_var, var, dim: usize, correction: usize);
+impl_reduce!(10, Var, unchecked_var, var, dim: usize, correction: usize);
+impl_reduce!(11, Var, unchecked_var, var, dim: usize, correction: usize);
+impl_reduce!(12, Var, unchecked_var, var, dim: usize, correction: usize);
+impl_reduce!(13, Var, unchecked_var, var, dim: usize, correction: usize);
+impl_reduce!(14, Var, unchecked_var, var, dim: usize, correction: usize);
+impl_reduce!(15, Var, unchecked_var, var, dim: usize, correction: usize);
+impl_reduce!(16, Var, unchecked_var, var, dim: usize, correction: usize);
+impl_reduce!(17, Var, unchecked_var, var, dim: usize, correction: usize);
+impl_reduce!(18, Var, unchecked_var, var, dim: usize, correction: usize);
+impl_reduce!(19, Var, unchecked_var, var, dim: usize, correction: usize);
+impl_reduce!(20, Var, unchecked_var, var, dim: usize, correction: usize);
+
+fn unchecked_std<const R1: usize, const R2: usize, D: DataType>(
+    tensor: &Tensor<R1, D>,
+    dim: usize,
+    correction: usize,
+) -> Tensor<R2, D> {
+    tensor.reduce(ReduceFunction::std(D::WGSL_TYPE, correction), &[dim], false)
+}
+
+pub trait Std {
+    type Output;
+
+    /// The standard deviation along `dim`. See [`Var::var`] for the meaning of `correction`
+    fn std(&self, dim: usize, correction: usize) -> Self::Output;
+}
+
+impl_reduce!(1, Std, unchecked_std, std, dim: usize, correction: usize);
+impl_reduce!(2, Std, unchecked_std, std, dim: usize, correction: usize);
+impl_reduce!(3, Std, unchecked_std, std, dim: usize, correction: usize);
+impl_reduce!(4, Std, unchecked_std, std, dim: usize, correction: usize);
+impl_reduce!(5, Std, unchecked_std, std, dim: usize, correction: usize);
+impl_reduce!(6, Std, unchecked_std, std, dim: usize, correction: usize);
+impl_reduce!(7, Std, unchecked_std, std, dim: usize, correction: usize);
+impl_reduce!(8, Std, unchecked_std, std, dim: usize, correction: usize);
+impl_reduce!(9, Std, unchecked_std, std, dim: usize, correction: usize);
+impl_reduce!(10, Std, unchecked_std, std, dim: usize, correction: usize);
+impl_reduce!(11, Std, unchecked_std, std, dim: usize, correction: usize);
+impl_reduce!(12, Std, unchecked_std, std, dim: usize, correction: usize);
+impl_reduce!(13, Std, unchecked_std, std, dim: usize, correction: usize);
+impl_reduce!(14, Std, unchecked_std, std, dim: usize, correction: usize);
+impl_reduce!(15, Std, unchecked_std, std, dim: usize, correction: usize);
+impl_reduce!(16, Std, unchecked_std, std, dim: usize, correction: usize);
+impl_reduce!(17, Std, unchecked_std, std, dim: usize, correction: usize);
+impl_reduce!(18, Std, unchecked_std, std, dim: usize, correction: usize);
+impl_reduce!(19, Std, unchecked_std, std, dim: usize, correction: usize);
+impl_reduce!(20, Std, unchecked_std, std, dim: usize, correction: usize);
+
+fn unchecked_logsumexp<const R1: usize, const R2: usize, D: DataType>(
+    tensor: &Tensor<R1, D>,
+    dim: usize,
+) -> Tensor<R2, D> {
+    tensor.reduce(ReduceFunction::logsumexp(D::WGSL_TYPE), &[dim], false)
+}
+
+pub trait LogSumExp {
+    type Output;
+
+    fn logsumexp(&self, dim: usize) -> Self::Output;
+}
+
+impl_reduce!(1, LogSumExp, unchecked_logsumexp, logsumexp, dim: usize);
+impl_reduce!(2, LogSumExp, unchecked_logsumexp, logsumexp, dim: usize);
+impl_reduce!(3, LogSumExp, unchecked_logsumexp, logsumexp, dim: usize);
+impl_reduce!(4, LogSumExp, unchecked_logsumexp, logsumexp, dim: usize);
+impl_reduce!(5, LogSumExp, unchecked_logsumexp, logsumexp, dim: usize);
+impl_reduce!(6, LogSumExp, unchecked_logsumexp, logsumexp, dim: usize);
+impl_reduce!(7, LogSumExp, unchecked_logsumexp, logsumexp, dim: usize);
+impl_reduce!(8, LogSumExp, unchecked_logsumexp, logsumexp, dim: usize);
+impl_reduce!(9, LogSumExp, unchecked_logsumexp, logsumexp, dim: usize);
+impl_reduce!(10, LogSumExp, unchecked_logsumexp, logsumexp, dim: usize);
+impl_reduce!(11, LogSumExp, unchecked_logsumexp, logsumexp, dim: usize);
+impl_reduce!(12, LogSumExp, unchecked_logsumexp, logsumexp, dim: usize);
+impl_reduce!(13, LogSumExp, unchecked_logsumexp, logsumexp, dim: usize);
+impl_reduce!(14, LogSumExp, unchecked_logsumexp, logsumexp, dim: usize);
+impl_reduce!(15, LogSumExp, unchecked_logsumexp, logsumexp, dim: usize);
+impl_reduce!(16, LogSumExp, unchecked_logsumexp, logsumexp, dim: usize);
+impl_reduce!(17, LogSumExp, unchecked_logsumexp, logsumexp, dim: usize);
+impl_reduce!(18, LogSumExp, unchecked_logsumexp, logsumexp, dim: usize);
+impl_reduce!(19, LogSumExp, unchecked_logsumexp, logsumexp, dim: usize);
+impl_reduce!(20, LogSumExp, unchecked_logsumexp, logsumexp, dim: usize);
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_reduce_mean() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., 2.], [3., 4.], [5., 6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let output = tensor.mean(0).as_slice().await.unwrap();
+    assert_eq!(output[[0]], 3.);
+    assert_eq!(output[[1]], 4.);
+
+    let output = tensor.mean(1).as_slice().await.unwrap();
+    assert_eq!(output[[0]], 1.5);
+    assert_eq!(output[[1]], 3.5);
+    assert_eq!(output[[2]], 5.5);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_reduce_mean_f16() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    // The sum of these overflows f16, but the mean doesn't
+    let data = vec![half::f16::from_f32(1000.); 4096];
+    let tensor = DynTensor::new(&device, &[4096], &data);
+
+    let output = tensor.mean(0).as_slice().await.unwrap();
+    assert_eq!(output[[]], half::f16::from_f32(1000.));
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_reduce_var_std() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    // A large offset loses all precision with the naive sum of squares
+    let data = [[1e6 + 1., 1e6 + 2., 1e6 + 3., 1e6 + 4.], [2., 4., 4., 6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let output = tensor.var(1, 0).as_slice().await.unwrap();
+    assert!((output[[0]] - 1.25).abs() < 0.01);
+    assert!((output[[1]] - 2.).abs() < 0.001);
+
+    let output = tensor.var(1, 1).as_slice().await.unwrap();
+    assert!((output[[0]] - 5. / 3.).abs() < 0.01);
+    assert!((output[[1]] - 8. / 3.).abs() < 0.001);
+
+    let output = tensor.std(1, 0).as_slice().await.unwrap();
+    assert!((output[[0]] - 1.25f32.sqrt()).abs() < 0.01);
+    assert!((output[[1]] - 2f32.sqrt()).abs() < 0.001);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_reduce_logsumexp() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., 2., 3.], [1000., 1000., 990.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let output = tensor.logsumexp(1).as_slice().await.unwrap();
+    let expected = (1f32.exp() + 2f32.exp() + 3f32.exp()).ln();
+    assert!((output[[0]] - expected).abs() < 0.001);
+    // exp(1000) overflows f32
+    let expected = 1000. + (2. + (-10f32).exp()).ln();
+    assert!((output[[1]] - expected).abs() < 0.001);
+
+    let output = DynTensor::from(tensor)
+        .logsumexp_all()
+        .as_slice()
+        .await
+        .unwrap();
+    assert!((output[[]] - expected).abs() < 0.001);
+}
+
+macro_rules! impl_dyn_reduce {
+    ($T:ident, $function:ident, $f:ident) => {
+        impl<D: DataType> $T for DynTensor<D> {
+            type Output = DynTensor<D>;
+
+            fn $f(&self, dim: usize) -> Self::Output {
+                self.reduce(ReduceFunction::$function(D::WGSL_TYPE), &[dim], false)
+            }
+        }
+    };
+}
+
+impl_dyn_reduce!(Sum, sum, sum);
+impl_dyn_reduce!(Max, max, max);
+impl_dyn_reduce!(Min, min, min);
+impl_dyn_reduce!(Product, product, product);
+impl_dyn_reduce!(Mean, mean, mean);
+impl_dyn_reduce!(LogSumExp, logsumexp, logsumexp);
+
+impl<D: DataType> Var for DynTensor<D> {
+    type Output = DynTensor<D>;
+
+    fn var(&self, dim: usize, correction: usize) -> Self::Output {
+        self.reduce(ReduceFunction::var(D::WGSL_TYPE, correction), &[dim], false)
+    }
+}
+
+impl<D: DataType> Std for DynTensor<D> {
+    type Output = DynTensor<D>;
+
+    fn std(&self, dim: usize, correction: usize) -> Self::Output {
+        self.reduce(ReduceFunction::std(D::WGSL_TYPE, correction), &[dim], false)
+    }
+}
+
+macro_rules! impl_reduce_dims {
+    ($function:ident, $dims:ident, $all:ident, $name:literal) => {
+        impl<const R: usize, D: DataType> Tensor<R, D> {
+            #[doc = concat!("Take the ", $name, " over several axes in a single kernel. If `keepdim` is true, the reduced axes stay in the output with a size of one")]
+            pub fn $dims<const R2: usize>(&self, axes: &[usize], keepdim: bool) -> Tensor<R2, D> {
+                self.reduce(ReduceFunction::$function(D::WGSL_TYPE), axes, keepdim)
+            }
+
+            #[doc = concat!("Take the ", $name, " of every element in the tensor")]
+            pub fn $all(&self) -> Tensor<0, D> {
+                self.$dims(&std::array::from_fn::<_, R, _>(|i| i), false)
+            }
+        }
+
+        impl<D: DataType> DynTensor<D> {
+            #[doc = concat!("Take the ", $name, " over several axes in a single kernel. If `keepdim` is true, the reduced axes stay in the output with a size of one")]
+            pub fn $dims(&self, axes: &[usize], keepdim: bool) -> Self {
+                self.reduce(ReduceFunction::$function(D::WGSL_TYPE), axes, keepdim)
+            }
+
+            #[doc = concat!("Take the ", $name, " of every element in the tensor")]
+            pub fn $all(&self) -> Self {
+                self.$dims(&(0..self.rank()).collect::<Vec<_>>(), false)
+            }
+        }
+    };
+}
+
+impl_reduce_dims!(sum, sum_dims, sum_all, "sum");
+impl_reduce_dims!(max, max_dims, max_all, "maximum");
+impl_reduce_dims!(min, min_dims, min_all, "minimum");
+impl_reduce_dims!(product, product_dims, product_all, "product");
+impl_reduce_dims!(mean, mean_dims, mean_all, "mean");
+impl_reduce_dims!(
+    logsumexp,
+    logsumexp_dims,
+    logsumexp_all,
+    "log of the summed exponentials"
+);
+
+pub trait ReduceWith<D> {
+    type Output;
+
+    /// Reduce along `dim` with a custom reduction
+    fn reduce_with(&self, function: &CustomReduce<D>, dim: usize) -> Self::Output;
+}
+
+macro_rules! impl_reduce_with {
+    ($R:expr) => {
+        impl<D: DataType> ReduceWith<D> for Tensor<$R, D> {
+            type Output = Tensor<{ $R - 1 }, D>;
+
+            fn reduce_with(&self, function: &CustomReduce<D>, dim: usize) -> Self::Output {
+                self.reduce(function.function.clone(), &[dim], false)
+            }
+        }
+    };
+}
+
+impl_reduce_with!(1);
+impl_reduce_with!(2);
+impl_reduce_with!(3);
+impl_reduce_with!(4);
+impl_reduce_with!(5);
+impl_reduce_with!(6);
+impl_reduce_with!(7);
+impl_reduce_with!(8);
+impl_reduce_with!(9);
+impl_reduce_with!(10);
+impl_reduce_with!(11);
+impl_reduce_with!(12);
+impl_reduce_with!(13);
+impl_reduce_with!(14);
+impl_reduce_with!(15);
+impl_reduce_with!(16);
+impl_reduce_with!(17);
+impl_reduce_with!(18);
+impl_reduce_with!(19);
+impl_reduce_with!(20);
+
+impl<D: DataType> ReduceWith<D> for DynTensor<D> {
+    type Output = DynTensor<D>;
+
+    fn reduce_with(&self, function: &CustomReduce<D>, dim: usize) -> Self::Output {
+        self.reduce(function.function.clone(), &[dim], false)
+    }
+}
+
+impl<const R: usize, D: DataType> Tensor<R, D> {
+    /// Reduce over several axes in a single kernel with a custom reduction. If `keepdim` is true,
+    /// the reduced axes stay in the output with a size of one
+    pub fn reduce_dims_with<const R2: usize>(
+        &self,
+        function: &CustomReduce<D>,
+        axes: &[usize],
+        keepdim: bool,
+    ) -> Tensor<R2, D> {
+        self.reduce(function.function.clone(), axes, keepdim)
+    }
+
+    /// Reduce every element in the tensor with a custom reduction
+    pub fn reduce_all_with(&self, function: &CustomReduce<D>) -> Tensor<0, D> {
+        self.reduce_dims_with(function, &std::array::from_fn::<_, R, _>(|i| i), false)
+    }
+}
+
+impl<D: DataType> DynTensor<D> {
+    /// Reduce over several axes in a single kernel with a custom reduction. If `keepdim` is true,
+    /// the reduced axes stay in the output with a size of one
+    pub fn reduce_dims_with(
+        &self,
+        function: &CustomReduce<D>,
+        axes: &[usize],
+        keepdim: bool,
+    ) -> Self {
+        self.reduce(function.function.clone(), axes, keepdim)
+    }
+
+    /// Reduce every element in the tensor with a custom reduction
+    pub fn reduce_all_with(&self, function: &CustomReduce<D>) -> Self {
+        self.reduce_dims_with(function, &(0..self.rank()).collect::<Vec<_>>(), false)
+    }
+}
+
+pub trait ArgReduce {
+    type Values;
+    type Indices;
+
+    /// The index of the largest element along `dim`. Ties go to the smallest index
+    fn argmax(&self, dim: usize) -> Self::Indices;
+
+    /// The index of the smallest element along `dim`. Ties go to the smallest index
+    fn argmin(&self, dim: usize) -> Self::Indices;
+
+    /// The largest elements along `dim` along with their indices
+    fn max_with_indices(&self, dim: usize) -> (Self::Values, Self::Indices);
+
+    /// The smallest elements along `dim` along with their indices
+    fn min_with_indices(&self, dim: usize) -> (Self::Values, Self::Indices);
+}
+
+macro_rules! impl_arg_reduce {
+    ($R:expr) => {
+        impl<D: DataType> ArgReduce for Tensor<$R, D> {
+            type Values = Tensor<{ $R - 1 }, D>;
+            type Indices = Tensor<{ $R - 1 }, u32>;
+
+            fn argmax(&self, dim: usize) -> Self::Indices {
+                self.reduce(ReduceFunction::argmax(D::WGSL_TYPE), &[dim], false)
+            }
+
+            fn argmin(&self, dim: usize) -> Self::Indices {
+                self.reduce(ReduceFunction::argmin(D::WGSL_TYPE), &[dim], false)
+            }
+
+            fn max_with_indices(&self, dim: usize) -> (Self::Values, Self::Indices) {
+                self.reduce_pair(
+                    [
+                        ReduceFunction::max(D::WGSL_TYPE),
+                        ReduceFunction::argmax(D::WGSL_TYPE),
+                    ],
+                    dim,
+                )
+            }
+
+            fn min_with_indices(&self, dim: usize) -> (Self::Values, Self::Indices) {
+                self.reduce_pair(
+                    [
+                        ReduceFunction::min(D::WGSL_TYPE),
+                        ReduceFunction::argmin(D::WGSL_TYPE),
+                    ],
+                    dim,
+                )
+            }
+        }
+    };
+}
+
+impl_arg_reduce!(1);
+impl_arg_reduce!(2);
+impl_arg_reduce!(3);
+impl_arg_reduce!(4);
+impl_arg_reduce!(5);
+impl_arg_reduce!(6);
+impl_arg_reduce!(7);
+impl_arg_reduce!(8);
+impl_arg_reduce!(9);
+impl_arg_reduce!(10);
+impl_arg_reduce!(11);
+impl_arg_reduce!(12);
+impl_arg_reduce!(13);
+impl_arg_reduce!(14);
+impl_arg_reduce!(15);
+impl_arg_reduce!(16);
+impl_arg_reduce!(17);
+impl_arg_reduce!(18);
+impl_arg_reduce!(19);
+impl_arg_reduce!(20);
+
+impl<D: DataType> ArgReduce for DynTensor<D> {
+    type Values = DynTensor<D>;
+    type Indices = DynTensor<u32>;
+
+    fn argmax(&self, dim: usize) -> Self::Indices {
+        self.reduce(ReduceFunction::argmax(D::WGSL_TYPE), &[dim], false)
+    }
+
+    fn argmin(&self, dim: usize) -> Self::Indices {
+        self.reduce(ReduceFunction::argmin(D::WGSL_TYPE), &[dim], false)
+    }
+
+    fn max_with_indices(&self, dim: usize) -> (Self::Values, Self::Indices) {
+        self.reduce_pair(
+            [
+                ReduceFunction::max(D::WGSL_TYPE),
+                ReduceFunction::argmax(D::WGSL_TYPE),
+            ],
+            dim,
+        )
+    }
+
+    fn min_with_indices(&self, dim: usize) -> (Self::Values, Self::Indices) {
+        self.reduce_pair(
+            [
+                ReduceFunction::min(D::WGSL_TYPE),
+                ReduceFunction::argmin(D::WGSL_TYPE),
+            ],
+            dim,
+        )
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_argmax() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., 6.], [3., 4.], [5., 2.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let output = tensor.argmax(0).as_slice().await.unwrap();
+    assert_eq!(output[[0]], 2);
+    assert_eq!(output[[1]], 0);
+
+    let output = tensor.argmin(1).as_slice().await.unwrap();
+    assert_eq!(output[[0]], 0);
+    assert_eq!(output[[1]], 0);
+    assert_eq!(output[[2]], 1);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_argmax_ties() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    // Long enough that the maximums are merged in different threads and subgroups
+    let mut data = vec![0.; 4096];
+    for index in [3000, 17, 1500, 4095] {
+        data[index] = 1.;
+    }
+    let tensor = DynTensor::new(&device, &[4096], &data);
+
+    let (values, indices) = tensor.max_with_indices(0);
+    assert_eq!(values.as_slice().await.unwrap()[[]], 1.);
+    assert_eq!(indices.as_slice().await.unwrap()[[]], 17);
+
+    let output = tensor.argmin(0).as_slice().await.unwrap();
+    assert_eq!(output[[]], 0);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_argmax_fused() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., -6.], [-3., 4.], [5., 2.]];
+    let tensor = Tensor::new(&device, &data);
+
+    // The element-wise op before the reduction runs in the same kernel
+    let output = tensor.abs().argmax(0);
+    let as_slice = output.as_slice().await.unwrap();
+    assert_eq!(as_slice[[0]], 2);
+    assert_eq!(as_slice[[1]], 0);
+    assert_eq!(output.all_timing_information().await.len(), 1);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_dyn_reduce() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let tensor = DynTensor::new(&device, &[3, 2], &[1., 2., 3., 4., 5., 6.]);
+
+    let output = tensor.sum(0);
+    assert_eq!(output.shape(), [2]);
+    let output = output.as_slice().await.unwrap();
+    assert_eq!(output[[0]], 9.);
+    assert_eq!(output[[1]], 12.);
+
+    let output = tensor.max(1).as_slice().await.unwrap();
+    assert_eq!(output[[0]], 2.);
+    assert_eq!(output[[1]], 4.);
+    assert_eq!(output[[2]], 6.);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_reduce_dims() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [
+        [[1., 2.], [3., 4.], [5., 6.]],
+        [[7., 8.], [9., 10.], [11., 12.]],
+    ];
+    let tensor = Tensor::new(&device, &data);
+
+    let output: Tensor<1, f32> = tensor.sum_dims(&[0, 2], false);
+    assert_eq!(*output.shape(), [3]);
+    let output = output.as_slice().await.unwrap();
+    for j in 0..3 {
+        let expected: f32 = (0..2).flat_map(|i| data[i][j]).sum();
+        assert_eq!(output[[j]], expected);
+    }
+
+    let output: Tensor<3, f32> = tensor.max_dims(&[2, 1], true);
+    assert_eq!(*output.shape(), [2, 1, 1]);
+    let output = output.as_slice().await.unwrap();
+    assert_eq!(output[[0, 0, 0]], 6.);
+    assert_eq!(output[[1, 0, 0]], 12.);
+
+    let output = tensor.min_all().as_slice().await.unwrap();
+    assert_eq!(output[[]], 1.);
+
+    // The element-wise ops before and after fuse into the reduction
+    let tensor = Tensor::new(&device, &data);
+    let output = (tensor / 2.).product_all() * 2.;
+    let as_slice = output.as_slice().await.unwrap();
+    let expected: f32 = data
+        .iter()
+        .flatten()
+        .flatten()
+        .map(|x| x / 2.)
+        .product::<f32>()
+        * 2.;
+    assert!((as_slice[[]] - expected).abs() / expected < 0.001);
+    assert_eq!(output.all_timing_information().await.len(), 1);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_reduce_dims_sliced() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., 2., 3.], [4., 5., 6.], [7., 8., 9.]];
+    let tensor = Tensor::new(&device, &data);
+    // The slice starts in the middle of the buffer
+    let tensor = tensor.slice([1..3, 1..3]);
+
+    let output = tensor.sum_all().as_slice().await.unwrap();
+    assert_eq!(output[[]], 5. + 6. + 8. + 9.);
+
+    let output: Tensor<2, f32> = tensor.sum_dims(&[0], true);
+    let output = output.as_slice().await.unwrap();
+    assert_eq!(output[[0, 0]], 5. + 8.);
+    assert_eq!(output[[0, 1]], 6. + 9.);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_dyn_reduce_dims() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = (0..24).map(|x| x as f32).collect::<Vec<_>>();
+    let tensor = DynTensor::new(&device, &[2, 3, 4], &data);
+
+    let output = tensor.sum_dims(&[0, 1], true);
+    assert_eq!(output.shape(), [1, 1, 4]);
+    let output = output.as_slice().await.unwrap();
+    for k in 0..4 {
+        let expected: f32 = (0..6).map(|i| data[i * 4 + k]).sum();
+        assert_eq!(output[[0, 0, k]], expected);
+    }
+
+    let output = tensor.sum_all();
+    assert!(output.shape().is_empty());
+    let output = output.as_slice().await.unwrap();
+    assert_eq!(output[[]], data.iter().sum::<f32>());
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_sibling_reductions_fused() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., 2.], [3., 4.], [5., 6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    // Both reductions read the same tensor, so they run in one kernel
+    let mean = tensor.mean(1);
+    let mean_of_squares = tensor.sqr().mean(1);
+    let max = tensor.max(1);
+
+    let as_slice = mean.as_slice().await.unwrap();
+    assert_eq!(as_slice[[0]], 1.5);
+    assert_eq!(as_slice[[1]], 3.5);
+    assert_eq!(as_slice[[2]], 5.5);
+    let as_slice = mean_of_squares.as_slice().await.unwrap();
+    assert_eq!(as_slice[[0]], 2.5);
+    assert_eq!(as_slice[[1]], 12.5);
+    assert_eq!(as_slice[[2]], 30.5);
+    let as_slice = max.as_slice().await.unwrap();
+    assert_eq!(as_slice[[0]], 2.);
+    assert_eq!(as_slice[[1]], 4.);
+    assert_eq!(as_slice[[2]], 6.);
+    assert_eq!(max.all_timing_information().await.len(), 1);
+    // Every sibling was read, so none of them are still cached
+    assert_eq!(max.data().cached_results(), 0);
+
+    // A reduction over other axes needs its own kernel
+    let sum = tensor.sum(0);
+    let as_slice = sum.as_slice().await.unwrap();
+    assert_eq!(as_slice[[0]], 9.);
+    assert_eq!(as_slice[[1]], 12.);
+    assert_eq!(sum.all_timing_information().await.len(), 2);
+    assert_eq!(sum.data().ran_reductions(), 4);
+
+    // Reductions nothing can read anymore aren't computed as siblings
+    let tensor = Tensor::new(&device, &data);
+    let sum = tensor.sum(1);
+    drop(tensor.max(1));
+    let _unused = tensor.product(1) + 1.;
+    let min = tensor.min(1);
+    let as_slice = sum.as_slice().await.unwrap();
+    assert_eq!(as_slice[[0]], 3.);
+    // The sum and min ran together. The product is still read by a live tensor
+    assert_eq!(sum.data().ran_reductions(), 3);
+    assert_eq!(sum.data().cached_results(), 2);
+    assert_eq!(min.as_slice().await.unwrap()[[2]], 5.);
+    assert_eq!(sum.data().cached_results(), 1);
+    // The cached product is released once nothing can read it
+    drop(_unused);
+    let shifted = sum + 1.;
+    assert_eq!(shifted.as_slice().await.unwrap()[[0]], 4.);
+    assert_eq!(shifted.data().cached_results(), 0);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_max_with_indices_fused() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., 6.], [3., 4.], [5., 2.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let (values, indices) = tensor.max_with_indices(0);
+    let logsumexp = tensor.logsumexp(0);
+
+    let as_slice = indices.as_slice().await.unwrap();
+    assert_eq!(as_slice[[0]], 2);
+    assert_eq!(as_slice[[1]], 0);
+    let as_slice = values.as_slice().await.unwrap();
+    assert_eq!(as_slice[[0]], 5.);
+    assert_eq!(as_slice[[1]], 6.);
+    let as_slice = logsumexp.as_slice().await.unwrap();
+    let expected = (1f32.exp() + 3f32.exp() + 5f32.exp()).ln();
+    assert!((as_slice[[0]] - expected).abs() < 0.001);
+    assert_eq!(values.all_timing_information().await.len(), 1);
+
+    // The values and indices are one reduction with two outputs
+    let tensor = Tensor::new(&device, &data);
+    let (values, indices) = tensor.min_with_indices(1);
+    let as_slice = values.as_slice().await.unwrap();
+    assert_eq!(as_slice[[0]], 1.);
+    assert_eq!(as_slice[[2]], 2.);
+    assert_eq!(values.data().ran_reductions(), 2);
+    assert_eq!(values.data().cached_results(), 1);
+    let as_slice = indices.as_slice().await.unwrap();
+    assert_eq!(as_slice[[0]], 0);
+    assert_eq!(as_slice[[2]], 1);
+    assert_eq!(indices.all_timing_information().await.len(), 1);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_reduce_without_subgroups() {
+    use crate::Device;
+
+    let devices = [
+        Device::new().await.unwrap(),
+        Device::without_subgroups().await.unwrap(),
+    ];
+    assert!(!devices[1].subgroups_supported());
+    for device in &devices {
+        std::thread::spawn({
+            let device = device.clone();
+            move || loop {
+                device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+            }
+        });
+    }
+    // Small integers so the sums are exact in any order
+    let data = (0..3000)
+        .map(|i| ((i * 7919) % 13) as f32)
+        .collect::<Vec<_>>();
+
+    let mut results = Vec::new();
+    for device in &devices {
+        let tensor = DynTensor::new(device, &[3, 1000], &data);
+        let sum = tensor.sum(1).as_slice().await.unwrap();
+        let max = tensor.max(1).as_slice().await.unwrap();
+        let argmin = tensor.argmin(1).as_slice().await.unwrap();
+        let var = tensor.var(1, 1).as_slice().await.unwrap();
+        let logsumexp = tensor.logsumexp(1).as_slice().await.unwrap();
+        results.push((sum, max, argmin, var, logsumexp));
+    }
+
+    let (sum, max, argmin, var, logsumexp) = &results[0];
+    let (fallback_sum, fallback_max, fallback_argmin, fallback_var, fallback_logsumexp) =
+        &results[1];
+    for i in 0..3 {
+        let row = &data[i * 1000..(i + 1) * 1000];
+        assert_eq!(sum[[i]], row.iter().sum::<f32>());
+        assert_eq!(fallback_sum[[i]], sum[[i]]);
+        assert_eq!(max[[i]], 12.);
+        assert_eq!(fallback_max[[i]], max[[i]]);
+        let first_zero = row.iter().position(|x| *x == 0.).unwrap() as u32;
+        assert_eq!(argmin[[i]], first_zero);
+        assert_eq!(fallback_argmin[[i]], argmin[[i]]);
+        assert!((fallback_var[[i]] - var[[i]]).abs() < 0.001);
+        assert!((fallback_logsumexp[[i]] - logsumexp[[i]]).abs() < 0.001);
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_split_reduce() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    // Long enough rows that each one is split across many workgroups. Small integers keep
+    // the sums exact in any order
+    let row_size = 1 << 19;
+    let data = (0..2 * row_size)
+        .map(|i| ((i * 7919) % 13) as f32)
+        .collect::<Vec<_>>();
+    let tensor = DynTensor::new(&device, &[2, row_size], &data);
+    let sum = tensor.sum(1).as_slice().await.unwrap();
+    let max = tensor.max(1).as_slice().await.unwrap();
+    let argmax = tensor.argmax(1).as_slice().await.unwrap();
+    let mean = tensor.mean(1).as_slice().await.unwrap();
+    let total = tensor.sum_all().as_slice().await.unwrap();
+
+    for i in 0..2 {
+        let row = &data[i * row_size..(i + 1) * row_size];
+        let row_sum = row.iter().sum::<f32>();
+        assert_eq!(sum[[i]], row_sum);
+        assert_eq!(max[[i]], 12.);
+        let first_max = row.iter().position(|x| *x == 12.).unwrap() as u32;
+        assert_eq!(argmax[[i]], first_max);
+        assert!((mean[[i]] - row_sum / row_size as f32).abs() < 0.001);
+    }
+    assert_eq!(total[[]], data.iter().sum::<f32>());
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_split_sibling_reductions() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    // Long enough rows that the reductions are split, so each output also needs partial buffers
+    let row_size = 4 * MIN_SPLIT_SIZE;
+    let data = (0..2 * row_size)
+        .map(|i| ((i * 7919) % 13) as f32)
+        .collect::<Vec<_>>();
+    let tensor = DynTensor::new(&device, &[2, row_size], &data);
+    let sum = tensor.sum(1);
+    let max = tensor.max(1);
+    let argmax = tensor.argmax(1);
+    let mean = tensor.mean(1);
+
+    let sum = sum.as_slice().await.unwrap();
+    // The mean doesn't fit in the storage buffers left for the combine kernel
+    assert_eq!(tensor.data().ran_reductions(), 3);
+    let max = max.as_slice().await.unwrap();
+    let argmax = argmax.as_slice().await.unwrap();
+    let mean = mean.as_slice().await.unwrap();
+    for i in 0..2 {
+        let row = &data[i * row_size..(i + 1) * row_size];
+        let row_sum = row.iter().sum::<f32>();
+        assert_eq!(sum[[i]], row_sum);
+        assert_eq!(max[[i]], 12.);
+        let first_max = row.iter().position(|x| *x == 12.).unwrap() as u32;
+        assert_eq!(argmax[[i]], first_max);
+        assert!((mean[[i]] - row_sum / row_size as f32).abs() < 0.001);
+    }
+}
+
+#[test]
+fn test_split_count() {
+    // Short reductions and reductions with many outputs run in one pass
+    assert_eq!(split_count(1000, 1, 65535), 1);
+    assert_eq!(split_count(1 << 20, 4096, 65535), 1);
+    // Long reductions are split until the GPU is full
+    assert_eq!(split_count(1 << 14, 1, 65535), 4);
+    assert_eq!(split_count(1 << 24, 1, 65535), TARGET_WORKGROUPS);
+    assert_eq!(split_count(1 << 24, 4, 65535), TARGET_WORKGROUPS / 4);
+    assert_eq!(split_count(1 << 24, 4, 64), 16);
+}
+
+#[test]
+fn test_split_reduce_kernel() {
+    use crate::ElementWiseFunction;
+
+    let datatype = DataTypeEnum::F32;
+    let mut kernel = UntypedReduceKernel::new(ReduceFunction::sum(datatype), datatype);
+    let square = ElementWiseFunction::new("let output = input * input;", datatype);
+    kernel.set_post_element_wise(UntypedElementWiseKernel::new(vec![square], datatype));
+    kernel.add_output(
+        ReduceFunction::argmax(datatype),
+        UntypedElementWiseKernel::empty(datatype),
+    );
+    kernel.add_output(
+        ReduceFunction::std(datatype, 1),
+        UntypedElementWiseKernel::empty(datatype),
+    );
+    for stage in [ReduceStage::Partial, ReduceStage::Combine] {
+        for subgroups in [true, false] {
+            kernel
+                .tiled_map(256, 3, &[0, 2], subgroups, stage)
+                .validate()
+                .unwrap();
+        }
+    }
+}
+
+#[test]
+fn test_reduce_dims_kernel() {
+    let kernel =
+        UntypedReduceKernel::new(ReduceFunction::sum(DataTypeEnum::F32), DataTypeEnum::F32);
+    for axes in [&[][..], &[1], &[0, 2], &[0, 1, 2, 3]] {
+        for subgroups in [true, false] {
+            kernel
+                .tiled_map(256, 4, axes, subgroups, ReduceStage::Full)
+                .validate()
+                .unwrap();
+        }
+    }
+}
+
+#[test]
+fn test_arg_reduce_kernel() {
+    for datatype in [DataTypeEnum::F32, DataTypeEnum::F16, DataTypeEnum::U32] {
+        for function in [
+            ReduceFunction::max,
+            ReduceFunction::min,
+            ReduceFunction::argmax,
+            ReduceFunction::argmin,
+        ] {
+            let function = function(datatype);
+            let out_datatype = function.output_datatype();
+            let kernel = UntypedReduceKernel::new(function, datatype);
+            assert_eq!(kernel.outputs[0].out_datatype(), out_datatype);
+            for subgroups in [true, false] {
+                kernel
+                    .tiled_map(256, 2, &[1], subgroups, ReduceStage::Full)
+                    .validate()
+                    .unwrap();
+            }
+        }
+    }
+}
+
+#[test]
+fn test_accumulator_reduce_kernel() {
+    // The accumulators are f32 for every datatype
+    for datatype in [DataTypeEnum::F32, DataTypeEnum::F16, DataTypeEnum::U32] {
+        for function in [
+            ReduceFunction::mean(datatype),
+            ReduceFunction::var(datatype, 1),
+            ReduceFunction::std(datatype, 0),
+            ReduceFunction::logsumexp(datatype),
+        ] {
+            let kernel = UntypedReduceKernel::new(function, datatype);
+            assert_eq!(kernel.outputs[0].out_datatype(), datatype);
+            for subgroups in [true, false] {
+                kernel
+                    .tiled_map(256, 3, &[0, 2], subgroups, ReduceStage::Full)
+                    .validate()
+                    .unwrap();
+            }
+        }
+    }
+}
+
+#[test]
+fn test_multi_output_reduce_kernel() {
+    use crate::ElementWiseFunction;
+
+    let datatype = DataTypeEnum::F32;
+    let mut kernel = UntypedReduceKernel::new(ReduceFunction::sum(datatype), datatype);
+    let square = ElementWiseFunction::new("let output = input * input;", datatype);
+    kernel.add_output(
+        ReduceFunction::mean(datatype),
+        UntypedElementWiseKernel::new(vec![square], datatype),
+    );
+    kernel.add_output(
+        ReduceFunction::argmin(datatype),
+        UntypedElementWiseKernel::empty(datatype),
+    );
+    kernel.add_output(
+        ReduceFunction::var(datatype, 1),
+        UntypedElementWiseKernel::empty(datatype),
+    );
+    assert_eq!(kernel.outputs[2].out_datatype(), DataTypeEnum::U32);
+    for subgroups in [true, false] {
+        kernel
+            .tiled_map(256, 3, &[1], subgroups, ReduceStage::Full)
+            .validate()
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_custom_reduce() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[3., -4.], [1000., 1000.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let sum = CustomReduce::<f32>::new("sum", "let output = a + b;", "0.0").unwrap();
+    // The square and square root fuse into the reduction kernel
+    let norm = tensor.sqr().reduce_with(&sum, 1).sqrt();
+    let output = norm.as_slice().await.unwrap();
+    assert_eq!(output[[0]], 5.);
+    assert!((output[[1]] - 2f32.sqrt() * 1000.).abs() < 0.01);
+    assert_eq!(norm.all_timing_information().await.len(), 1);
+
+    let log_add_exp = CustomReduce::<f32>::new(
+        "log_add_exp",
+        "let maximum = max(a, b);
+let output = maximum + log(exp(a - maximum) + exp(b - maximum));",
+        "-3.40282e+38",
+    )
+    .unwrap();
+    let output = tensor
+        .reduce_with(&log_add_exp, 1)
+        .as_slice()
+        .await
+        .unwrap();
+    assert!((output[[0]] - (3f32.exp() + (-4f32).exp()).ln()).abs() < 0.001);
+    assert!((output[[1]] - (1000. + 2f32.ln())).abs() < 0.001);
+
+    let bitwise_or = CustomReduce::<u32>::new("or", "let output = a | b;", "0u").unwrap();
+    let bits = DynTensor::new(&device, &[2, 3], &[1u32, 2, 4, 8, 8, 16]);
+    let output = bits.reduce_with(&bitwise_or, 1).as_slice().await.unwrap();
+    assert_eq!(output[[0]], 7);
+    assert_eq!(output[[1]], 24);
+    let output = bits.reduce_all_with(&bitwise_or).as_slice().await.unwrap();
+    assert_eq!(output[[]], 31);
+}
+
+#[test]
+fn test_invalid_custom_reduce() {
+    let error = CustomReduce::<f32>::new("unmatched", "let output = a + b; }", "0.0").unwrap_err();
+    assert_eq!(error.name(), "unmatched");
+    assert!(CustomReduce::<f32>::new("missing output", "let x = a + b;", "0.0").is_err());
+    assert!(CustomReduce::<f32>::new("bad identity", "let output = a + b;", "zero").is_err());
+    assert!(CustomReduce::<u32>::new("or", "let output = a | b;", "0u").is_ok());
+}
@@ -3,8 +3,8 @@ use std::{fmt::Write, sync::OnceLock};
 use wgpu::CommandEncoder;
 
 use crate::{
-    DataTypeEnum, PerformanceQueries, TILE_SIZE, Tensor, TensorData, compute_graph::AnyComputeKey,
-    kernel::GenericKernel,
+    DataTypeEnum, DynTensor, PerformanceQueries, TILE_SIZE, Tensor, TensorData,
+    compute_graph::AnyComputeKey, kernel::GenericKernel,
 };
 
 const BLOCKSIZE: u32 = 256;
@@ -144,6 +144,32 @@ impl<const R: usize, T: crate::DataType> Tensor<R, T> {
     }
 }
 
+impl<T: crate::DataType> DynTensor<T> {
+    pub fn resize(&self, new_shape: &[usize]) -> Self {
+        assert_eq!(new_shape.len(), self.rank());
+        let input = self.key();
+        self.add_resize(ResizeOperation::new(
+            input,
+            new_shape.into(),
+            self.shape().into(),
+        ))
+    }
+
+    pub fn reshape(&self, new_shape: &[usize]) -> Self {
+        assert_eq!(
+            new_shape.iter().product::<usize>(),
+            self.shape().iter().product::<usize>()
+        );
+        let new_shape: Box<[usize]> = new_shape.into();
+        let input = self.key();
+        self.add_resize(ResizeOperation::new(
+            input,
+            new_shape.clone(),
+            new_shape.clone(),
+        ))
+    }
+}
+
 #[cfg(test)]
 #[tokio::test]
 async fn test_resize() {
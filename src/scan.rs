@@ -0,0 +1,835 @@
+use std::{fmt::Write, sync::OnceLock};
+
+use wgpu::CommandEncoder;
+
+use crate::{
+    CustomReduce, DataType, DataTypeEnum, DynTensor, PerformanceQueries, ReduceFunction, Tensor,
+    TensorData,
+    compute_graph::AnyComputeKey,
+    kernel::{Function, GenericKernel, KernelGlobal, KernelGlobalSpace, KernelInputValue},
+    reduce::create_storage_buffer,
+};
+
+const BLOCKSIZE: u32 = 256;
+/// The number of consecutive elements each thread scans on its own
+const ITEMS_PER_THREAD: u32 = 4;
+/// The number of elements each workgroup scans
+const ELEMENTS_PER_WORKGROUP: u32 = BLOCKSIZE * ITEMS_PER_THREAD;
+
+#[derive(Clone)]
+pub(crate) struct ScanOperation {
+    pub(crate) input: AnyComputeKey,
+    pub(crate) function: ReduceFunction,
+    pub(crate) axis: usize,
+    /// Leave the current element out of the result at each position
+    pub(crate) exclusive: bool,
+}
+
+impl ScanOperation {
+    pub fn new(
+        input: AnyComputeKey,
+        function: ReduceFunction,
+        axis: usize,
+        exclusive: bool,
+    ) -> Self {
+        assert!(
+            !function.returns_index(),
+            "cannot scan with {} because it returns an index",
+            function.name()
+        );
+        Self {
+            input,
+            function,
+            axis,
+            exclusive,
+        }
+    }
+}
+
+/// Which part of a scan a kernel runs. Axes that fit in one workgroup are scanned with the
+/// output stage alone. Longer axes are split into tiles that are reduced, then the tile totals
+/// are scanned, then each tile is scanned again starting from the total of the tiles before it
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ScanStage {
+    /// Write the total of each tile
+    Reduce,
+    /// Replace the totals of the tiles in each row with the exclusive scan of the totals
+    Partials,
+    /// Scan each tile into the output
+    Output,
+    /// Scan each tile into the output starting from the scanned total of the tiles before it
+    OutputWithCarry,
+}
+
+pub(crate) struct UntypedScanKernel {
+    function: ReduceFunction,
+    axis: usize,
+    exclusive: bool,
+    datatype: DataTypeEnum,
+    kernel: OnceLock<GenericKernel>,
+    reduce_kernel: OnceLock<GenericKernel>,
+    partials_kernel: OnceLock<GenericKernel>,
+    carry_kernel: OnceLock<GenericKernel>,
+}
+
+impl UntypedScanKernel {
+    pub(crate) fn new(
+        function: ReduceFunction,
+        axis: usize,
+        exclusive: bool,
+        datatype: DataTypeEnum,
+    ) -> Self {
+        Self {
+            function,
+            axis,
+            exclusive,
+            datatype,
+            kernel: OnceLock::new(),
+            reduce_kernel: OnceLock::new(),
+            partials_kernel: OnceLock::new(),
+            carry_kernel: OnceLock::new(),
+        }
+    }
+
+    fn tiled_scan(&self, rank: u32, stage: ScanStage) -> GenericKernel {
+        let mut kernel = GenericKernel::new();
+        let axis = self.axis as u32;
+        let state = self.function.state_type();
+        let initial_state = self.function.initial_state();
+        let input_tensor = (stage != ScanStage::Partials)
+            .then(|| kernel.add_tensor_input(rank, false, self.datatype));
+        let output_tensor = matches!(stage, ScanStage::Output | ScanStage::OutputWithCarry)
+            .then(|| kernel.add_tensor_input(rank, true, self.function.datatype()));
+        let partials = (stage != ScanStage::Output)
+            .then(|| kernel.add_array_input(stage != ScanStage::OutputWithCarry, &state));
+        // The length of the scanned axis
+        let scan_size = (stage != ScanStage::Partials).then(|| kernel.add_integer_input());
+        // The number of tiles in each row
+        let tiles = kernel.add_integer_input();
+        let merge = self.function.add_function(&mut kernel);
+        let accumulator = self.function.add_accumulator_functions(&mut kernel);
+        let local_data =
+            kernel.add_global_array(KernelGlobalSpace::Workgroup, &state, BLOCKSIZE.to_string());
+        let workgroup_index = kernel.workgroup_index();
+        let workgroup_local_index = kernel.workgroup_local_index();
+
+        let mut kernel_body = String::new();
+        writeln!(
+            &mut kernel_body,
+            "var values: array<{state}, {ITEMS_PER_THREAD}>;"
+        )
+        .unwrap();
+
+        let (Some(input_tensor), Some(scan_size)) = (&input_tensor, &scan_size) else {
+            // Each workgroup scans the tile totals of one row a tile at a time
+            let partials = partials.as_ref().unwrap();
+            writeln!(
+                &mut kernel_body,
+                "let row_start = {workgroup_index}.x * {tiles};"
+            )
+            .unwrap();
+            writeln!(&mut kernel_body, "var carry = {initial_state};").unwrap();
+            writeln!(
+                &mut kernel_body,
+                "for (var tile_start = 0u; tile_start < {tiles}; tile_start += {ELEMENTS_PER_WORKGROUP}u) {{"
+            )
+            .unwrap();
+            load_values(
+                &mut kernel_body,
+                &merge,
+                &initial_state,
+                &workgroup_local_index,
+                &tiles.to_string(),
+                |axis_index| format!("{partials}[row_start + {axis_index}]"),
+            );
+            scan_workgroup(
+                &mut kernel_body,
+                &merge,
+                &initial_state,
+                &local_data,
+                &workgroup_local_index,
+            );
+            writeln!(
+                &mut kernel_body,
+                "var running = {};",
+                merge.call(vec!["carry".to_string(), "thread_prefix".to_string()])
+            )
+            .unwrap();
+            writeln!(
+                &mut kernel_body,
+                "for (var item = 0u; item < {ITEMS_PER_THREAD}u; item += 1u) {{"
+            )
+            .unwrap();
+            writeln!(
+                &mut kernel_body,
+                "let axis_index = tile_start + {workgroup_local_index} * {ITEMS_PER_THREAD}u + item;"
+            )
+            .unwrap();
+            writeln!(&mut kernel_body, "if axis_index < {tiles} {{").unwrap();
+            writeln!(
+                &mut kernel_body,
+                "{partials}[row_start + axis_index] = running;"
+            )
+            .unwrap();
+            writeln!(&mut kernel_body, "}}").unwrap();
+            writeln!(
+                &mut kernel_body,
+                "running = {};",
+                merge.call(vec!["running".to_string(), "values[item]".to_string()])
+            )
+            .unwrap();
+            writeln!(&mut kernel_body, "}}").unwrap();
+            writeln!(
+                &mut kernel_body,
+                "carry = {};",
+                merge.call(vec!["carry".to_string(), "tile_total".to_string()])
+            )
+            .unwrap();
+            writeln!(&mut kernel_body, "}}").unwrap();
+
+            kernel.set_body(kernel_body);
+            kernel.set_workgroup_size([BLOCKSIZE, 1, 1]);
+            return kernel;
+        };
+
+        // Each workgroup scans one tile of a row. This code calculates the start offset of the
+        // row in the input and output tensors
+        writeln!(
+            &mut kernel_body,
+            "let tile = {workgroup_index}.x % {tiles};"
+        )
+        .unwrap();
+        writeln!(
+            &mut kernel_body,
+            "var workgroup_index_remainder = {workgroup_index}.x / {tiles};"
+        )
+        .unwrap();
+        for kept_axis in (0..rank).filter(|i| *i != axis).rev() {
+            let shape = input_tensor.shape_binding(kept_axis);
+            writeln!(
+                &mut kernel_body,
+                "let index_{kept_axis} = workgroup_index_remainder % {shape};"
+            )
+            .unwrap();
+            writeln!(&mut kernel_body, "workgroup_index_remainder /= {shape};").unwrap();
+        }
+        write!(
+            &mut kernel_body,
+            "let in_start_offset = {}",
+            input_tensor.offset_binding()
+        )
+        .unwrap();
+        for kept_axis in (0..rank).filter(|i| *i != axis) {
+            let stride = input_tensor.stride_binding(kept_axis);
+            write!(&mut kernel_body, " + index_{kept_axis}*{stride}").unwrap();
+        }
+        writeln!(&mut kernel_body, ";").unwrap();
+        if let Some(output_tensor) = &output_tensor {
+            write!(&mut kernel_body, "let out_start_offset = ").unwrap();
+            output_tensor.strided_index(
+                &mut kernel_body,
+                (0..rank).map(|i| match i == axis {
+                    true => "0u".to_string(),
+                    false => format!("index_{i}"),
+                }),
+            );
+            writeln!(&mut kernel_body, ";").unwrap();
+        }
+        writeln!(
+            &mut kernel_body,
+            "let tile_start = tile * {ELEMENTS_PER_WORKGROUP}u;"
+        )
+        .unwrap();
+
+        let in_stride = input_tensor.stride_binding(axis);
+        load_values(
+            &mut kernel_body,
+            &merge,
+            &initial_state,
+            &workgroup_local_index,
+            &scan_size.to_string(),
+            |axis_index| {
+                let value = format!("{input_tensor}[in_start_offset + {axis_index} * {in_stride}]");
+                match &accumulator {
+                    Some((lift, _)) => lift.call(vec![value]),
+                    None => value,
+                }
+            },
+        );
+        scan_workgroup(
+            &mut kernel_body,
+            &merge,
+            &initial_state,
+            &local_data,
+            &workgroup_local_index,
+        );
+
+        let Some(output_tensor) = &output_tensor else {
+            let partials = partials.as_ref().unwrap();
+            writeln!(&mut kernel_body, "if {workgroup_local_index} == 0u {{").unwrap();
+            writeln!(
+                &mut kernel_body,
+                "{partials}[{workgroup_index}.x] = tile_total;"
+            )
+            .unwrap();
+            writeln!(&mut kernel_body, "}}").unwrap();
+
+            kernel.set_body(kernel_body);
+            kernel.set_workgroup_size([BLOCKSIZE, 1, 1]);
+            return kernel;
+        };
+
+        // Start from the scanned total of the tiles before this one and the threads before this one
+        let carry = match &partials {
+            Some(partials) => format!("{partials}[{workgroup_index}.x]"),
+            None => initial_state.clone(),
+        };
+        writeln!(
+            &mut kernel_body,
+            "var running = {};",
+            merge.call(vec![carry, "thread_prefix".to_string()])
+        )
+        .unwrap();
+        writeln!(
+            &mut kernel_body,
+            "for (var item = 0u; item < {ITEMS_PER_THREAD}u; item += 1u) {{"
+        )
+        .unwrap();
+        writeln!(
+            &mut kernel_body,
+            "let axis_index = tile_start + {workgroup_local_index} * {ITEMS_PER_THREAD}u + item;"
+        )
+        .unwrap();
+        let next = merge.call(vec!["running".to_string(), "values[item]".to_string()]);
+        if self.exclusive {
+            writeln!(&mut kernel_body, "let scanned = running;").unwrap();
+            writeln!(&mut kernel_body, "running = {next};").unwrap();
+        } else {
+            writeln!(&mut kernel_body, "running = {next};").unwrap();
+            writeln!(&mut kernel_body, "let scanned = running;").unwrap();
+        }
+        writeln!(&mut kernel_body, "if axis_index < {scan_size} {{").unwrap();
+        let output = match &accumulator {
+            Some((_, finish)) => {
+                let count = match self.exclusive {
+                    true => "f32(axis_index)",
+                    false => "f32(axis_index + 1u)",
+                };
+                finish.call(vec!["scanned".to_string(), count.to_string()])
+            }
+            None => "scanned".to_string(),
+        };
+        let out_stride = output_tensor.stride_binding(axis);
+        writeln!(
+            &mut kernel_body,
+            "{output_tensor}[out_start_offset + axis_index * {out_stride}] = {output};"
+        )
+        .unwrap();
+        writeln!(&mut kernel_body, "}}").unwrap();
+        writeln!(&mut kernel_body, "}}").unwrap();
+
+        kernel.set_body(kernel_body);
+        kernel.set_workgroup_size([BLOCKSIZE, 1, 1]);
+        kernel
+    }
+
+    pub fn run_with_query(
+        &self,
+        input: &TensorData,
+        query: Option<&PerformanceQueries>,
+        command_encoder: &mut CommandEncoder,
+    ) -> TensorData {
+        let device = input.device();
+        let shape = input.layout().shape();
+        let rank = shape.len() as u32;
+        let scan_size = shape[self.axis];
+        let rows = shape
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != self.axis)
+            .map(|(_, size)| *size)
+            .product::<usize>();
+        let tiles = scan_size.div_ceil(ELEMENTS_PER_WORKGROUP as usize).max(1);
+        let output = TensorData::new_for_shape(device, shape, self.function.datatype());
+        let tensors = |partials: Option<&wgpu::Buffer>| {
+            [
+                KernelInputValue::Tensor(input.clone()),
+                KernelInputValue::Tensor(output.clone()),
+            ]
+            .into_iter()
+            .chain(partials.map(|partials| KernelInputValue::Buffer(partials.clone())))
+            .chain([
+                KernelInputValue::Integer(scan_size as u32),
+                KernelInputValue::Integer(tiles as u32),
+            ])
+            .collect::<Vec<_>>()
+        };
+
+        if tiles == 1 {
+            let kernel = self
+                .kernel
+                .get_or_init(|| self.tiled_scan(rank, ScanStage::Output));
+            kernel.run_with_query(
+                device,
+                tensors(None),
+                query,
+                command_encoder,
+                [rows as u32, 1, 1],
+            );
+            return output;
+        }
+
+        // The total of each tile, then the scanned totals of the tiles before each tile
+        let partials =
+            create_storage_buffer(device, rows * tiles * self.function.state_size() as usize);
+        let reduce_kernel = self
+            .reduce_kernel
+            .get_or_init(|| self.tiled_scan(rank, ScanStage::Reduce));
+        // The reduce kernel reads the whole input, so it is the one that is timed
+        reduce_kernel.run_with_query(
+            device,
+            [
+                KernelInputValue::Tensor(input.clone()),
+                KernelInputValue::Buffer(partials.clone()),
+                KernelInputValue::Integer(scan_size as u32),
+                KernelInputValue::Integer(tiles as u32),
+            ],
+            query,
+            command_encoder,
+            [(rows * tiles) as u32, 1, 1],
+        );
+        let partials_kernel = self
+            .partials_kernel
+            .get_or_init(|| self.tiled_scan(rank, ScanStage::Partials));
+        partials_kernel.run_with_query(
+            device,
+            [
+                KernelInputValue::Buffer(partials.clone()),
+                KernelInputValue::Integer(tiles as u32),
+            ],
+            None,
+            command_encoder,
+            [rows as u32, 1, 1],
+        );
+        let carry_kernel = self
+            .carry_kernel
+            .get_or_init(|| self.tiled_scan(rank, ScanStage::OutputWithCarry));
+        carry_kernel.run_with_query(
+            device,
+            tensors(Some(&partials)),
+            None,
+            command_encoder,
+            [(rows * tiles) as u32, 1, 1],
+        );
+        output
+    }
+}
+
+/// Load the elements of this thread into `values` and merge them into `thread_total`. Elements
+/// past the end of the row are the initial state
+fn load_values(
+    kernel_body: &mut String,
+    merge: &Function,
+    initial_state: &str,
+    workgroup_local_index: &str,
+    size: &str,
+    read: impl FnOnce(&str) -> String,
+) {
+    writeln!(kernel_body, "var thread_total = {initial_state};").unwrap();
+    writeln!(
+        kernel_body,
+        "for (var item = 0u; item < {ITEMS_PER_THREAD}u; item += 1u) {{"
+    )
+    .unwrap();
+    writeln!(
+        kernel_body,
+        "let axis_index = tile_start + {workgroup_local_index} * {ITEMS_PER_THREAD}u + item;"
+    )
+    .unwrap();
+    writeln!(kernel_body, "var value = {initial_state};").unwrap();
+    writeln!(kernel_body, "if axis_index < {size} {{").unwrap();
+    writeln!(kernel_body, "value = {};", read("axis_index")).unwrap();
+    writeln!(kernel_body, "}}").unwrap();
+    writeln!(kernel_body, "values[item] = value;").unwrap();
+    writeln!(
+        kernel_body,
+        "thread_total = {};",
+        merge.call(vec!["thread_total".to_string(), "value".to_string()])
+    )
+    .unwrap();
+    writeln!(kernel_body, "}}").unwrap();
+}
+
+/// Scan the `thread_total` of every thread in the workgroup. This sets `thread_prefix` to the
+/// merged totals of the threads before this one and `tile_total` to the merged totals of all
+/// threads
+fn scan_workgroup(
+    kernel_body: &mut String,
+    merge: &Function,
+    initial_state: &str,
+    local_data: &KernelGlobal,
+    workgroup_local_index: &str,
+) {
+    // Wait until every thread is done reading the workgroup memory from the last scan
+    writeln!(kernel_body, "workgroupBarrier();").unwrap();
+    writeln!(
+        kernel_body,
+        "{local_data}[{workgroup_local_index}] = thread_total;"
+    )
+    .unwrap();
+    // Hillis-Steele scan. Each step merges the value `offset` threads back
+    writeln!(
+        kernel_body,
+        "for (var offset = 1u; offset < {BLOCKSIZE}u; offset *= 2u) {{"
+    )
+    .unwrap();
+    writeln!(kernel_body, "workgroupBarrier();").unwrap();
+    writeln!(
+        kernel_body,
+        "var scanned = {local_data}[{workgroup_local_index}];"
+    )
+    .unwrap();
+    writeln!(kernel_body, "if {workgroup_local_index} >= offset {{").unwrap();
+    writeln!(
+        kernel_body,
+        "scanned = {};",
+        merge.call(vec![
+            format!("{local_data}[{workgroup_local_index} - offset]"),
+            "scanned".to_string()
+        ])
+    )
+    .unwrap();
+    writeln!(kernel_body, "}}").unwrap();
+    writeln!(kernel_body, "workgroupBarrier();").unwrap();
+    writeln!(
+        kernel_body,
+        "{local_data}[{workgroup_local_index}] = scanned;"
+    )
+    .unwrap();
+    writeln!(kernel_body, "}}").unwrap();
+    writeln!(kernel_body, "workgroupBarrier();").unwrap();
+    writeln!(
+        kernel_body,
+        "let tile_total = {local_data}[{}u];",
+        BLOCKSIZE - 1
+    )
+    .unwrap();
+    writeln!(kernel_body, "var thread_prefix = {initial_state};").unwrap();
+    writeln!(kernel_body, "if {workgroup_local_index} > 0u {{").unwrap();
+    writeln!(
+        kernel_body,
+        "thread_prefix = {local_data}[{workgroup_local_index} - 1u];"
+    )
+    .unwrap();
+    writeln!(kernel_body, "}}").unwrap();
+}
+
+impl<const R: usize, D: DataType> Tensor<R, D> {
+    /// The running sum along `dim`
+    pub fn cumsum(&self, dim: usize) -> Self {
+        self.scan(
+            ReduceFunction::sum(D::WGSL_TYPE).with_name("cumsum"),
+            dim,
+            false,
+        )
+    }
+
+    /// The running sum along `dim` of the elements before each position
+    pub fn cumsum_exclusive(&self, dim: usize) -> Self {
+        self.scan(
+            ReduceFunction::sum(D::WGSL_TYPE).with_name("cumsum"),
+            dim,
+            true,
+        )
+    }
+
+    /// The running product along `dim`
+    pub fn cumprod(&self, dim: usize) -> Self {
+        self.scan(
+            ReduceFunction::product(D::WGSL_TYPE).with_name("cumprod"),
+            dim,
+            false,
+        )
+    }
+
+    /// The running product along `dim` of the elements before each position
+    pub fn cumprod_exclusive(&self, dim: usize) -> Self {
+        self.scan(
+            ReduceFunction::product(D::WGSL_TYPE).with_name("cumprod"),
+            dim,
+            true,
+        )
+    }
+
+    /// The running maximum along `dim`
+    pub fn cummax(&self, dim: usize) -> Self {
+        self.scan(
+            ReduceFunction::max(D::WGSL_TYPE).with_name("cummax"),
+            dim,
+            false,
+        )
+    }
+
+    /// The running maximum along `dim` of the elements before each position
+    pub fn cummax_exclusive(&self, dim: usize) -> Self {
+        self.scan(
+            ReduceFunction::max(D::WGSL_TYPE).with_name("cummax"),
+            dim,
+            true,
+        )
+    }
+
+    /// The running result of a custom reduction along `dim`. If `exclusive` is true, each
+    /// position only merges the elements before it
+    pub fn cumulative_with(&self, function: &CustomReduce<D>, dim: usize, exclusive: bool) -> Self {
+        self.scan(function.function().clone(), dim, exclusive)
+    }
+}
+
+impl<D: DataType> DynTensor<D> {
+    /// The running sum along `dim`
+    pub fn cumsum(&self, dim: usize) -> Self {
+        self.scan(
+            ReduceFunction::sum(D::WGSL_TYPE).with_name("cumsum"),
+            dim,
+            false,
+        )
+    }
+
+    /// The running sum along `dim` of the elements before each position
+    pub fn cumsum_exclusive(&self, dim: usize) -> Self {
+        self.scan(
+            ReduceFunction::sum(D::WGSL_TYPE).with_name("cumsum"),
+            dim,
+            true,
+        )
+    }
+
+    /// The running product along `dim`
+    pub fn cumprod(&self, dim: usize) -> Self {
+        self.scan(
+            ReduceFunction::product(D::WGSL_TYPE).with_name("cumprod"),
+            dim,
+            false,
+        )
+    }
+
+    /// The running product along `dim` of the elements before each position
+    pub fn cumprod_exclusive(&self, dim: usize) -> Self {
+        self.scan(
+            ReduceFunction::product(D::WGSL_TYPE).with_name("cumprod"),
+            dim,
+            true,
+        )
+    }
+
+    /// The running maximum along `dim`
+    pub fn cummax(&self, dim: usize) -> Self {
+        self.scan(
+            ReduceFunction::max(D::WGSL_TYPE).with_name("cummax"),
+            dim,
+            false,
+        )
+    }
+
+    /// The running maximum along `dim` of the elements before each position
+    pub fn cummax_exclusive(&self, dim: usize) -> Self {
+        self.scan(
+            ReduceFunction::max(D::WGSL_TYPE).with_name("cummax"),
+            dim,
+            true,
+        )
+    }
+
+    /// The running result of a custom reduction along `dim`. If `exclusive` is true, each
+    /// position only merges the elements before it
+    pub fn cumulative_with(&self, function: &CustomReduce<D>, dim: usize, exclusive: bool) -> Self {
+        self.scan(function.function().clone(), dim, exclusive)
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_cumsum() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., 2., 3.], [4., 5., 6.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let output = tensor.cumsum(1).as_slice().await.unwrap();
+    println!("{:?}", output);
+    assert_eq!(output[[0, 0]], 1.);
+    assert_eq!(output[[0, 1]], 3.);
+    assert_eq!(output[[0, 2]], 6.);
+    assert_eq!(output[[1, 0]], 4.);
+    assert_eq!(output[[1, 1]], 9.);
+    assert_eq!(output[[1, 2]], 15.);
+
+    let output = tensor.cumsum(0).as_slice().await.unwrap();
+    println!("{:?}", output);
+    assert_eq!(output[[0, 0]], 1.);
+    assert_eq!(output[[0, 2]], 3.);
+    assert_eq!(output[[1, 0]], 5.);
+    assert_eq!(output[[1, 2]], 9.);
+
+    let output = tensor.cumsum_exclusive(1).as_slice().await.unwrap();
+    println!("{:?}", output);
+    assert_eq!(output[[0, 0]], 0.);
+    assert_eq!(output[[0, 1]], 1.);
+    assert_eq!(output[[0, 2]], 3.);
+    assert_eq!(output[[1, 0]], 0.);
+    assert_eq!(output[[1, 2]], 9.);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_cumprod_cummax() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[1., 3., 2., 5.], [-1., -4., 2., 0.5]];
+    // The scanned axis is strided in the transposed tensor
+    let tensor = Tensor::new(&device, &data).transpose(0, 1);
+
+    let output = tensor.cumprod(0).as_slice().await.unwrap();
+    println!("{:?}", output);
+    assert_eq!(output[[0, 0]], 1.);
+    assert_eq!(output[[1, 0]], 3.);
+    assert_eq!(output[[2, 0]], 6.);
+    assert_eq!(output[[3, 0]], 30.);
+    assert_eq!(output[[3, 1]], 4.);
+
+    let output = tensor.cummax(0).as_slice().await.unwrap();
+    println!("{:?}", output);
+    assert_eq!(output[[0, 0]], 1.);
+    assert_eq!(output[[1, 0]], 3.);
+    assert_eq!(output[[2, 0]], 3.);
+    assert_eq!(output[[3, 0]], 5.);
+    assert_eq!(output[[0, 1]], -1.);
+    assert_eq!(output[[1, 1]], -1.);
+    assert_eq!(output[[2, 1]], 2.);
+    assert_eq!(output[[3, 1]], 2.);
+
+    let output = tensor.cumprod_exclusive(0).as_slice().await.unwrap();
+    assert_eq!(output[[0, 0]], 1.);
+    assert_eq!(output[[3, 0]], 6.);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_cumulative_with() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let bitwise_or = CustomReduce::<u32>::new("or", "let output = a | b;", "0u").unwrap();
+    let bits = Tensor::new(&device, &[[1u32, 2, 4, 8], [8, 8, 16, 1]]);
+
+    let output = bits
+        .cumulative_with(&bitwise_or, 1, false)
+        .as_slice()
+        .await
+        .unwrap();
+    println!("{:?}", output);
+    for (j, expected) in [1, 3, 7, 15].into_iter().enumerate() {
+        assert_eq!(output[[0, j]], expected);
+    }
+    for (j, expected) in [8, 8, 24, 25].into_iter().enumerate() {
+        assert_eq!(output[[1, j]], expected);
+    }
+
+    let bits = DynTensor::new(&device, &[2, 4], &[1u32, 2, 4, 8, 8, 8, 16, 1]);
+    let output = bits
+        .cumulative_with(&bitwise_or, 0, true)
+        .as_slice()
+        .await
+        .unwrap();
+    for (j, expected) in [1, 2, 4, 8].into_iter().enumerate() {
+        assert_eq!(output[&[0, j][..]], 0);
+        assert_eq!(output[&[1, j][..]], expected);
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_cumsum_long() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    // Long enough that each row is split into many tiles. Small integers keep the sums exact
+    let row_size = 100_000;
+    let data = (0..3 * row_size)
+        .map(|i| ((i * 7919) % 13) as f32)
+        .collect::<Vec<_>>();
+    let tensor = DynTensor::new(&device, &[3, row_size], &data);
+    let cumsum = tensor.cumsum(1).as_slice().await.unwrap();
+    let cumsum_exclusive = tensor.cumsum_exclusive(1).as_slice().await.unwrap();
+    let cummax = tensor.cummax(1).as_slice().await.unwrap();
+
+    for i in 0..3 {
+        let mut sum = 0.;
+        let mut max = f32::MIN;
+        for j in 0..row_size {
+            let value = data[i * row_size + j];
+            assert_eq!(cumsum_exclusive[[i, j]], sum);
+            sum += value;
+            max = max.max(value);
+            assert_eq!(cumsum[[i, j]], sum);
+            assert_eq!(cummax[[i, j]], max);
+        }
+    }
+}
+
+#[test]
+fn test_scan_kernel() {
+    for function in [
+        ReduceFunction::sum(DataTypeEnum::F32),
+        ReduceFunction::max(DataTypeEnum::F32),
+        ReduceFunction::sum(DataTypeEnum::F16),
+        ReduceFunction::product(DataTypeEnum::F16),
+        ReduceFunction::max(DataTypeEnum::F16),
+        ReduceFunction::sum(DataTypeEnum::U32),
+        ReduceFunction::max(DataTypeEnum::U32),
+        CustomReduce::<u32>::new("or", "let output = a | b;", "0u")
+            .unwrap()
+            .function()
+            .clone(),
+    ] {
+        let datatype = function.datatype();
+        for exclusive in [false, true] {
+            let kernel = UntypedScanKernel::new(function.clone(), 1, exclusive, datatype);
+            for stage in [
+                ScanStage::Reduce,
+                ScanStage::Partials,
+                ScanStage::Output,
+                ScanStage::OutputWithCarry,
+            ] {
+                kernel.tiled_scan(3, stage).validate().unwrap();
+            }
+        }
+    }
+}
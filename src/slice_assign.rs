@@ -3,7 +3,7 @@ use std::{ops::Range, sync::OnceLock};
 use wgpu::CommandEncoder;
 
 use crate::{
-    PerformanceQueries, TILE_SIZE, Tensor, TensorData, compute_graph::AnyComputeKey,
+    DynTensor, PerformanceQueries, TILE_SIZE, Tensor, TensorData, compute_graph::AnyComputeKey,
     visit_tiled::VisitTiledKernel,
 };
 
@@ -79,6 +79,12 @@ impl<const R: usize, T: crate::DataType> Tensor<R, T> {
     }
 }
 
+impl<T: crate::DataType> DynTensor<T> {
+    pub fn slice_assign(&self, slices: &[Range<usize>], value: &Self) -> Self {
+        self.add_slice_assign(value, slices)
+    }
+}
+
 #[cfg(test)]
 #[tokio::test]
 async fn test_slice_assign() {
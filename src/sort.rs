@@ -0,0 +1,676 @@
+use std::{fmt::Write, sync::OnceLock};
+
+use wgpu::CommandEncoder;
+
+use crate::{
+    DataType, DataTypeEnum, DynTensor, PerformanceQueries, Tensor, TensorData,
+    compute_graph::{AnyComputeKey, SortComputeNodeKey},
+    kernel::{Function, GenericKernel, KernelGlobalSpace, KernelInputValue, TensorInput},
+    reduce::{NO_INDEX, create_storage_buffer},
+};
+
+const BLOCKSIZE: u32 = 256;
+/// The number of elements each workgroup sorts in workgroup memory. Each thread compares one
+/// pair of elements in each step
+const TILE_SIZE: u32 = BLOCKSIZE * 2;
+
+/// Which result of a sort a node resolves to
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum SortOutput {
+    Values,
+    Indices,
+}
+
+#[derive(Clone)]
+pub(crate) struct SortOperation {
+    pub(crate) input: AnyComputeKey,
+    pub(crate) axis: usize,
+    pub(crate) descending: bool,
+    /// The number of elements kept from the start of each sorted row
+    pub(crate) k: usize,
+    pub(crate) output: SortOutput,
+    /// The node of the other output. Both outputs are computed by the same kernels
+    pub(crate) sibling: SortComputeNodeKey,
+}
+
+impl SortOperation {
+    pub(crate) fn name(&self) -> &'static str {
+        match self.output {
+            SortOutput::Values => "sort",
+            SortOutput::Indices => "sort indices",
+        }
+    }
+}
+
+/// Which part of the sort a kernel runs. Each row is padded to a power of two and sorted with a
+/// bitonic sorting network. Steps that compare elements less than a tile apart run in workgroup
+/// memory. Longer steps run one dispatch each over the whole buffer
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SortStage {
+    /// Load each tile from the input and sort it
+    Local,
+    /// Compare the elements `distance` apart in the bitonic sequences of length `sequence_size`
+    Global,
+    /// Finish merging the bitonic sequences of length `sequence_size` once the elements compared
+    /// are in the same tile
+    Merge,
+    /// Copy the first `k` elements of each sorted row to the outputs
+    Output,
+}
+
+pub(crate) struct UntypedSortKernel {
+    axis: usize,
+    descending: bool,
+    datatype: DataTypeEnum,
+    local_kernel: OnceLock<GenericKernel>,
+    global_kernel: OnceLock<GenericKernel>,
+    merge_kernel: OnceLock<GenericKernel>,
+    output_kernel: OnceLock<GenericKernel>,
+}
+
+impl UntypedSortKernel {
+    pub(crate) fn new(axis: usize, descending: bool, datatype: DataTypeEnum) -> Self {
+        Self {
+            axis,
+            descending,
+            datatype,
+            local_kernel: OnceLock::new(),
+            global_kernel: OnceLock::new(),
+            merge_kernel: OnceLock::new(),
+            output_kernel: OnceLock::new(),
+        }
+    }
+
+    /// Add a function that checks if the element `a` comes before the element `b` in the sorted
+    /// order. Padding goes after every element, and equal elements keep their order
+    fn add_before_function(&self, kernel: &mut GenericKernel) -> Function {
+        let compare = if self.descending { ">" } else { "<" };
+        let datatype = self.datatype.to_string();
+        kernel.add_function(
+            "bool",
+            format!(
+                "var output = a_index < b_index;
+if a_index == {NO_INDEX} {{
+output = false;
+}} else if b_index == {NO_INDEX} {{
+output = true;
+}} else if a_value != b_value {{
+output = a_value {compare} b_value;
+}}"
+            ),
+            [
+                ("a_value".to_string(), datatype.clone()),
+                ("a_index".to_string(), "u32".to_string()),
+                ("b_value".to_string(), datatype),
+                ("b_index".to_string(), "u32".to_string()),
+            ],
+        )
+    }
+
+    fn sort_kernel(&self, rank: u32, stage: SortStage) -> GenericKernel {
+        let mut kernel = GenericKernel::new();
+        let axis = self.axis as u32;
+        let input_tensor = (stage == SortStage::Local)
+            .then(|| kernel.add_tensor_input(rank, false, self.datatype));
+        let writes_scratch = stage != SortStage::Output;
+        let scratch_values = kernel.add_array_input(writes_scratch, self.datatype);
+        let scratch_indices = kernel.add_array_input(writes_scratch, DataTypeEnum::U32);
+        let output_tensors = (stage == SortStage::Output).then(|| {
+            [
+                kernel.add_tensor_input(rank, true, self.datatype),
+                kernel.add_tensor_input(rank, true, DataTypeEnum::U32),
+            ]
+        });
+        // The length of each row padded to a power of two
+        let padded_size = kernel.add_integer_input();
+        let before = self.add_before_function(&mut kernel);
+        let workgroup_index = kernel.workgroup_index();
+        let workgroup_local_index = kernel.workgroup_local_index();
+        let global_id = kernel.global_id();
+
+        let mut kernel_body = String::new();
+        match stage {
+            SortStage::Local | SortStage::Merge => {
+                let sequence_size = (stage == SortStage::Merge).then(|| kernel.add_integer_input());
+                let size = (stage == SortStage::Local).then(|| kernel.add_integer_input());
+                let local_values = kernel.add_global_array(
+                    KernelGlobalSpace::Workgroup,
+                    self.datatype,
+                    TILE_SIZE.to_string(),
+                );
+                let local_indices = kernel.add_global_array(
+                    KernelGlobalSpace::Workgroup,
+                    DataTypeEnum::U32,
+                    TILE_SIZE.to_string(),
+                );
+                // Each workgroup sorts one tile of a row
+                writeln!(
+                    &mut kernel_body,
+                    "let tile_size = min({TILE_SIZE}u, {padded_size});"
+                )
+                .unwrap();
+                writeln!(&mut kernel_body, "let tiles = {padded_size} / tile_size;").unwrap();
+                writeln!(&mut kernel_body, "let tile = {workgroup_index}.x % tiles;").unwrap();
+                writeln!(&mut kernel_body, "let row = {workgroup_index}.x / tiles;").unwrap();
+                writeln!(&mut kernel_body, "let tile_start = tile * tile_size;").unwrap();
+                writeln!(&mut kernel_body, "let row_start = row * {padded_size};").unwrap();
+
+                if let Some(input_tensor) = &input_tensor {
+                    row_indices(&mut kernel_body, rank, axis, input_tensor);
+                    write!(
+                        &mut kernel_body,
+                        "let in_start_offset = {}",
+                        input_tensor.offset_binding()
+                    )
+                    .unwrap();
+                    for i in (0..rank).filter(|i| *i != axis) {
+                        let stride = input_tensor.stride_binding(i);
+                        write!(&mut kernel_body, " + row_index_{i} * {stride}").unwrap();
+                    }
+                    writeln!(&mut kernel_body, ";").unwrap();
+                }
+                writeln!(
+                    &mut kernel_body,
+                    "for (var element = {workgroup_local_index}; element < tile_size; element += {BLOCKSIZE}u) {{"
+                )
+                .unwrap();
+                writeln!(&mut kernel_body, "let position = tile_start + element;").unwrap();
+                match (&input_tensor, &size) {
+                    (Some(input_tensor), Some(size)) => {
+                        let stride = input_tensor.stride_binding(axis);
+                        writeln!(&mut kernel_body, "if position < {size} {{").unwrap();
+                        writeln!(
+                            &mut kernel_body,
+                            "{local_values}[element] = {input_tensor}[in_start_offset + position * {stride}];"
+                        )
+                        .unwrap();
+                        writeln!(&mut kernel_body, "{local_indices}[element] = position;").unwrap();
+                        writeln!(&mut kernel_body, "}} else {{").unwrap();
+                        // Padding sorts after every element
+                        writeln!(
+                            &mut kernel_body,
+                            "{local_values}[element] = {}(0);",
+                            self.datatype
+                        )
+                        .unwrap();
+                        writeln!(&mut kernel_body, "{local_indices}[element] = {NO_INDEX};")
+                            .unwrap();
+                        writeln!(&mut kernel_body, "}}").unwrap();
+                    }
+                    _ => {
+                        writeln!(
+                            &mut kernel_body,
+                            "{local_values}[element] = {scratch_values}[row_start + position];"
+                        )
+                        .unwrap();
+                        writeln!(
+                            &mut kernel_body,
+                            "{local_indices}[element] = {scratch_indices}[row_start + position];"
+                        )
+                        .unwrap();
+                    }
+                }
+                writeln!(&mut kernel_body, "}}").unwrap();
+                writeln!(&mut kernel_body, "workgroupBarrier();").unwrap();
+
+                // Sort the whole tile, or finish merging the sequence the tile is part of
+                match &sequence_size {
+                    Some(sequence_size) => {
+                        writeln!(&mut kernel_body, "let sequence_size = {sequence_size};").unwrap();
+                        writeln!(&mut kernel_body, "{{").unwrap();
+                    }
+                    None => {
+                        writeln!(
+                            &mut kernel_body,
+                            "for (var sequence_size = 2u; sequence_size <= tile_size; sequence_size *= 2u) {{"
+                        )
+                        .unwrap();
+                    }
+                }
+                writeln!(
+                    &mut kernel_body,
+                    "for (var distance = min(sequence_size, tile_size) / 2u; distance > 0u; distance /= 2u) {{"
+                )
+                .unwrap();
+                writeln!(
+                    &mut kernel_body,
+                    "if {workgroup_local_index} < tile_size / 2u {{"
+                )
+                .unwrap();
+                writeln!(
+                    &mut kernel_body,
+                    "let first = ({workgroup_local_index} / distance) * 2u * distance + {workgroup_local_index} % distance;"
+                )
+                .unwrap();
+                compare_exchange(
+                    &mut kernel_body,
+                    &before,
+                    &local_values.to_string(),
+                    &local_indices.to_string(),
+                    "first",
+                    "first + distance",
+                    "tile_start + first",
+                );
+                writeln!(&mut kernel_body, "}}").unwrap();
+                writeln!(&mut kernel_body, "workgroupBarrier();").unwrap();
+                writeln!(&mut kernel_body, "}}").unwrap();
+                writeln!(&mut kernel_body, "}}").unwrap();
+
+                writeln!(
+                    &mut kernel_body,
+                    "for (var element = {workgroup_local_index}; element < tile_size; element += {BLOCKSIZE}u) {{"
+                )
+                .unwrap();
+                writeln!(
+                    &mut kernel_body,
+                    "{scratch_values}[row_start + tile_start + element] = {local_values}[element];"
+                )
+                .unwrap();
+                writeln!(
+                    &mut kernel_body,
+                    "{scratch_indices}[row_start + tile_start + element] = {local_indices}[element];"
+                )
+                .unwrap();
+                writeln!(&mut kernel_body, "}}").unwrap();
+            }
+            SortStage::Global => {
+                let sequence_size = kernel.add_integer_input();
+                let distance = kernel.add_integer_input();
+                // The number of pairs compared in all rows
+                let pairs = kernel.add_integer_input();
+                writeln!(&mut kernel_body, "let pair = {global_id}.x;").unwrap();
+                writeln!(&mut kernel_body, "if pair < {pairs} {{").unwrap();
+                writeln!(&mut kernel_body, "let row_pairs = {padded_size} / 2u;").unwrap();
+                writeln!(
+                    &mut kernel_body,
+                    "let row_start = (pair / row_pairs) * {padded_size};"
+                )
+                .unwrap();
+                writeln!(&mut kernel_body, "let row_pair = pair % row_pairs;").unwrap();
+                writeln!(&mut kernel_body, "let sequence_size = {sequence_size};").unwrap();
+                writeln!(
+                    &mut kernel_body,
+                    "let first = (row_pair / {distance}) * 2u * {distance} + row_pair % {distance};"
+                )
+                .unwrap();
+                compare_exchange(
+                    &mut kernel_body,
+                    &before,
+                    &scratch_values.to_string(),
+                    &scratch_indices.to_string(),
+                    "row_start + first",
+                    &format!("row_start + first + {distance}"),
+                    "first",
+                );
+                writeln!(&mut kernel_body, "}}").unwrap();
+            }
+            SortStage::Output => {
+                let [values_tensor, indices_tensor] = output_tensors.as_ref().unwrap();
+                // The number of elements kept in each row
+                let k = kernel.add_integer_input();
+                writeln!(&mut kernel_body, "let index = {global_id}.x;").unwrap();
+                writeln!(&mut kernel_body, "let kept = index % {k};").unwrap();
+                writeln!(&mut kernel_body, "let row = index / {k};").unwrap();
+                // Threads past the end of the outputs have a row past the last row
+                row_indices(&mut kernel_body, rank, axis, values_tensor);
+                writeln!(&mut kernel_body, "if row_remainder == 0u {{").unwrap();
+                for (tensor, scratch) in [
+                    (values_tensor, &scratch_values),
+                    (indices_tensor, &scratch_indices),
+                ] {
+                    let index = (0..rank).map(|i| match i == axis {
+                        true => "kept".to_string(),
+                        false => format!("row_index_{i}"),
+                    });
+                    write!(&mut kernel_body, "{tensor}[").unwrap();
+                    tensor.strided_index(&mut kernel_body, index);
+                    writeln!(
+                        &mut kernel_body,
+                        "] = {scratch}[row * {padded_size} + kept];"
+                    )
+                    .unwrap();
+                }
+                writeln!(&mut kernel_body, "}}").unwrap();
+            }
+        }
+
+        kernel.set_body(kernel_body);
+        kernel.set_workgroup_size([BLOCKSIZE, 1, 1]);
+        kernel
+    }
+
+    /// Sort the input and return the first `k` values and indices of each row
+    pub fn run_with_query(
+        &self,
+        input: &TensorData,
+        k: usize,
+        query: Option<&PerformanceQueries>,
+        command_encoder: &mut CommandEncoder,
+    ) -> [TensorData; 2] {
+        let device = input.device();
+        let shape = input.layout().shape();
+        let rank = shape.len() as u32;
+        let size = shape[self.axis];
+        let rows = shape
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != self.axis)
+            .map(|(_, size)| *size)
+            .product::<usize>();
+        let padded_size = size.next_power_of_two();
+        let tile_size = padded_size.min(TILE_SIZE as usize);
+        let scratch_values =
+            create_storage_buffer(device, rows * padded_size * self.datatype.element_size());
+        let scratch_indices = create_storage_buffer(device, rows * padded_size * size_of::<u32>());
+        let scratch = || {
+            [
+                KernelInputValue::Buffer(scratch_values.clone()),
+                KernelInputValue::Buffer(scratch_indices.clone()),
+            ]
+        };
+
+        let local_kernel = self
+            .local_kernel
+            .get_or_init(|| self.sort_kernel(rank, SortStage::Local));
+        // Every element passes through the local kernel, so it is the one that is timed
+        local_kernel.run_with_query(
+            device,
+            std::iter::once(KernelInputValue::Tensor(input.clone()))
+                .chain(scratch())
+                .chain([
+                    KernelInputValue::Integer(padded_size as u32),
+                    KernelInputValue::Integer(size as u32),
+                ])
+                .collect::<Vec<_>>(),
+            query,
+            command_encoder,
+            [(rows * padded_size / tile_size) as u32, 1, 1],
+        );
+
+        let mut sequence_size = tile_size * 2;
+        while sequence_size <= padded_size {
+            let mut distance = sequence_size / 2;
+            while distance >= tile_size {
+                let global_kernel = self
+                    .global_kernel
+                    .get_or_init(|| self.sort_kernel(rank, SortStage::Global));
+                let pairs = rows * padded_size / 2;
+                global_kernel.run_with_query(
+                    device,
+                    scratch()
+                        .into_iter()
+                        .chain([
+                            KernelInputValue::Integer(padded_size as u32),
+                            KernelInputValue::Integer(sequence_size as u32),
+                            KernelInputValue::Integer(distance as u32),
+                            KernelInputValue::Integer(pairs as u32),
+                        ])
+                        .collect::<Vec<_>>(),
+                    None,
+                    command_encoder,
+                    [pairs.div_ceil(BLOCKSIZE as usize) as u32, 1, 1],
+                );
+                distance /= 2;
+            }
+            let merge_kernel = self
+                .merge_kernel
+                .get_or_init(|| self.sort_kernel(rank, SortStage::Merge));
+            merge_kernel.run_with_query(
+                device,
+                scratch()
+                    .into_iter()
+                    .chain([
+                        KernelInputValue::Integer(padded_size as u32),
+                        KernelInputValue::Integer(sequence_size as u32),
+                    ])
+                    .collect::<Vec<_>>(),
+                None,
+                command_encoder,
+                [(rows * padded_size / tile_size) as u32, 1, 1],
+            );
+            sequence_size *= 2;
+        }
+
+        let mut output_shape: Box<[usize]> = shape.into();
+        output_shape[self.axis] = k;
+        let outputs = [
+            TensorData::new_for_shape(device, &output_shape, self.datatype),
+            TensorData::new_for_shape(device, &output_shape, DataTypeEnum::U32),
+        ];
+        let output_kernel = self
+            .output_kernel
+            .get_or_init(|| self.sort_kernel(rank, SortStage::Output));
+        output_kernel.run_with_query(
+            device,
+            scratch()
+                .into_iter()
+                .chain(outputs.iter().cloned().map(KernelInputValue::Tensor))
+                .chain([
+                    KernelInputValue::Integer(padded_size as u32),
+                    KernelInputValue::Integer(k as u32),
+                ])
+                .collect::<Vec<_>>(),
+            None,
+            command_encoder,
+            [((rows * k) as u32).div_ceil(BLOCKSIZE), 1, 1],
+        );
+        outputs
+    }
+}
+
+/// Swap the elements at `first` and `second` if they are out of order. The bitonic sequence
+/// sorts forward if `position` is in the first half of the sequence of length `sequence_size`
+fn compare_exchange(
+    kernel_body: &mut String,
+    before: &Function,
+    values: &str,
+    indices: &str,
+    first: &str,
+    second: &str,
+    position: &str,
+) {
+    writeln!(kernel_body, "{{").unwrap();
+    writeln!(kernel_body, "let first_value = {values}[{first}];").unwrap();
+    writeln!(kernel_body, "let first_index = {indices}[{first}];").unwrap();
+    writeln!(kernel_body, "let second_value = {values}[{second}];").unwrap();
+    writeln!(kernel_body, "let second_index = {indices}[{second}];").unwrap();
+    writeln!(
+        kernel_body,
+        "let forward = (({position}) & sequence_size) == 0u;"
+    )
+    .unwrap();
+    let first_before = before.call(vec![
+        "first_value".to_string(),
+        "first_index".to_string(),
+        "second_value".to_string(),
+        "second_index".to_string(),
+    ]);
+    let second_before = before.call(vec![
+        "second_value".to_string(),
+        "second_index".to_string(),
+        "first_value".to_string(),
+        "first_index".to_string(),
+    ]);
+    writeln!(
+        kernel_body,
+        "if select({first_before}, {second_before}, forward) {{"
+    )
+    .unwrap();
+    writeln!(kernel_body, "{values}[{first}] = second_value;").unwrap();
+    writeln!(kernel_body, "{indices}[{first}] = second_index;").unwrap();
+    writeln!(kernel_body, "{values}[{second}] = first_value;").unwrap();
+    writeln!(kernel_body, "{indices}[{second}] = first_index;").unwrap();
+    writeln!(kernel_body, "}}").unwrap();
+    writeln!(kernel_body, "}}").unwrap();
+}
+
+/// Split `row` into the index `row_index_{i}` of each axis of the tensor that isn't sorted. The
+/// part of `row` past the last row is left in `row_remainder`
+fn row_indices(kernel_body: &mut String, rank: u32, axis: u32, tensor: &TensorInput) {
+    writeln!(kernel_body, "var row_remainder = row;").unwrap();
+    for i in (0..rank).filter(|i| *i != axis).rev() {
+        let shape = tensor.shape_binding(i);
+        writeln!(kernel_body, "let row_index_{i} = row_remainder % {shape};").unwrap();
+        writeln!(kernel_body, "row_remainder /= {shape};").unwrap();
+    }
+}
+
+impl<const R: usize, D: DataType> Tensor<R, D> {
+    /// Sort the tensor along `dim`. Returns the sorted values and the index along `dim` each
+    /// value came from. Equal values keep their order
+    pub fn sort(&self, dim: usize, descending: bool) -> (Self, Tensor<R, u32>) {
+        self.sort_first(dim, descending, None)
+    }
+
+    /// The `k` largest values along `dim` in descending order and their indices
+    pub fn topk(&self, k: usize, dim: usize) -> (Self, Tensor<R, u32>) {
+        self.sort_first(dim, true, Some(k))
+    }
+}
+
+impl<D: DataType> DynTensor<D> {
+    /// Sort the tensor along `dim`. Returns the sorted values and the index along `dim` each
+    /// value came from. Equal values keep their order
+    pub fn sort(&self, dim: usize, descending: bool) -> (Self, DynTensor<u32>) {
+        self.sort_first(dim, descending, None)
+    }
+
+    /// The `k` largest values along `dim` in descending order and their indices
+    pub fn topk(&self, k: usize, dim: usize) -> (Self, DynTensor<u32>) {
+        self.sort_first(dim, true, Some(k))
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_sort() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[3., 1., 2., 1., 5.], [0., -2., 7., 4., 4.]];
+    let tensor = Tensor::new(&device, &data);
+
+    let (values, indices) = tensor.sort(1, false);
+    let values = values.as_slice().await.unwrap();
+    let indices = indices.as_slice().await.unwrap();
+    println!("{:?}", values);
+    println!("{:?}", indices);
+    let expected_values = [[1., 1., 2., 3., 5.], [-2., 0., 4., 4., 7.]];
+    // Equal values keep their order
+    let expected_indices = [[1, 3, 2, 0, 4], [1, 0, 3, 4, 2]];
+    for i in 0..2 {
+        for j in 0..5 {
+            assert_eq!(values[[i, j]], expected_values[i][j]);
+            assert_eq!(indices[[i, j]], expected_indices[i][j]);
+        }
+    }
+
+    let (values, indices) = tensor.sort(0, true);
+    let values = values.as_slice().await.unwrap();
+    let indices = indices.as_slice().await.unwrap();
+    println!("{:?}", values);
+    assert_eq!(values[[0, 0]], 3.);
+    assert_eq!(values[[1, 0]], 0.);
+    assert_eq!(indices[[0, 0]], 0);
+    assert_eq!(values[[0, 2]], 7.);
+    assert_eq!(indices[[0, 2]], 1);
+    assert_eq!(values[[1, 2]], 2.);
+
+    // The other output is only kept until it is read
+    let (values, indices) = tensor.sort(1, false);
+    assert_eq!(values.as_slice().await.unwrap()[[0, 0]], 1.);
+    assert_eq!(values.data().cached_results(), 1);
+    assert_eq!(indices.as_slice().await.unwrap()[[0, 0]], 1);
+    assert_eq!(indices.data().cached_results(), 0);
+
+    // Nothing is kept for an output that was dropped
+    let (values, indices) = tensor.sort(1, false);
+    drop(indices);
+    assert_eq!(values.as_slice().await.unwrap()[[1, 0]], -2.);
+    assert_eq!(values.data().cached_results(), 0);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_topk() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let data = [[3., 1., 2., 1., 5.], [0., -2., 7., 4., 4.]];
+    let tensor = DynTensor::new(&device, &[2, 5], data.as_flattened());
+
+    let (values, indices) = tensor.topk(2, 1);
+    assert_eq!(values.shape(), &[2, 2]);
+    let values = values.as_slice().await.unwrap();
+    let indices = indices.as_slice().await.unwrap();
+    assert_eq!(values[[0, 0]], 5.);
+    assert_eq!(values[[0, 1]], 3.);
+    assert_eq!(indices[[0, 0]], 4);
+    assert_eq!(indices[[0, 1]], 0);
+    assert_eq!(values[[1, 0]], 7.);
+    assert_eq!(values[[1, 1]], 4.);
+    assert_eq!(indices[[1, 0]], 2);
+    assert_eq!(indices[[1, 1]], 3);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_sort_long() {
+    use crate::Device;
+
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    // Longer than a tile and not a power of two, so the rows are padded and merged across tiles
+    let row_size = 5000;
+    let data = (0..2 * row_size)
+        .map(|i| ((i * 7919) % 1000) as f32)
+        .collect::<Vec<_>>();
+    let tensor = DynTensor::new(&device, &[2, row_size], &data);
+    let (values, indices) = tensor.sort(1, true);
+    let values = values.as_slice().await.unwrap();
+    let indices = indices.as_slice().await.unwrap();
+
+    for i in 0..2 {
+        let row = &data[i * row_size..(i + 1) * row_size];
+        let mut expected = (0..row_size as u32).collect::<Vec<_>>();
+        expected.sort_by(|a, b| row[*b as usize].total_cmp(&row[*a as usize]));
+        for (j, index) in expected.iter().enumerate() {
+            assert_eq!(indices[[i, j]], *index);
+            assert_eq!(values[[i, j]], row[*index as usize]);
+        }
+    }
+}
+
+#[test]
+fn test_sort_kernel() {
+    for datatype in [DataTypeEnum::F32, DataTypeEnum::F16, DataTypeEnum::U32] {
+        for descending in [false, true] {
+            let kernel = UntypedSortKernel::new(1, descending, datatype);
+            for stage in [
+                SortStage::Local,
+                SortStage::Global,
+                SortStage::Merge,
+                SortStage::Output,
+            ] {
+                kernel.sort_kernel(3, stage).validate().unwrap();
+            }
+        }
+    }
+}
@@ -7,15 +7,17 @@ use std::{
 
 use bytemuck::{AnyBitPattern, NoUninit};
 use tabbycat::Graph;
-use wgpu::{BufferDescriptor, COPY_BUFFER_ALIGNMENT, util::DownloadBuffer};
+use wgpu::{BufferDescriptor, util::DownloadBuffer};
 
 use crate::{
-    Device, ElementWiseOperation, MatMulOperation, PairWiseFunction, PairWiseOperation,
-    QueryResults, ReduceFunction, ReduceOperation,
+    Device, ElementWiseFunction, ElementWiseOperation, MatMulOperation, PairWiseFunction,
+    PairWiseOperation, Promote, QueryResults, ReduceFunction, ReduceOperation,
     compute_graph::{AnyComputeKey, ComputeGraph},
-    layout::Layout,
-    map_layout::MapLayoutOperation,
+    layout::{Layout, broadcast_shapes},
+    map_layout::{MapLayoutOperation, broadcast_to_operation},
+    nary::{NaryFunction, NaryOperation},
     resize::ResizeOperation,
+    scan::ScanOperation,
     slice_assign::SliceAssignOperation,
 };
 
@@ -64,11 +66,32 @@ impl DataType for half::f16 {
     }
 }
 
+/// A floating point [`DataType`]. Operations that WGSL only defines for floats, like `exp` or
+/// `sqrt`, are only available on tensors of these types
+pub trait FloatDataType: DataType {}
+
+impl FloatDataType for f32 {}
+
+impl FloatDataType for half::f16 {}
+
+impl DataType for u32 {
+    const WGSL_TYPE: DataTypeEnum = DataTypeEnum::U32;
+
+    fn zero() -> Self {
+        0
+    }
+
+    fn one() -> Self {
+        1
+    }
+}
+
 #[non_exhaustive]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum DataTypeEnum {
     F32,
     F16,
+    U32,
 }
 
 impl DataTypeEnum {
@@ -76,6 +99,7 @@ impl DataTypeEnum {
         match self {
             DataTypeEnum::F32 => "f32",
             DataTypeEnum::F16 => "f16",
+            DataTypeEnum::U32 => "u32",
         }
     }
 
@@ -83,6 +107,25 @@ impl DataTypeEnum {
         match self {
             DataTypeEnum::F32 => size_of::<f32>(),
             DataTypeEnum::F16 => size_of::<half::f16>(),
+            DataTypeEnum::U32 => size_of::<u32>(),
+        }
+    }
+
+    /// The smallest finite value of the type as a WGSL literal
+    pub(crate) fn min_value(&self) -> &'static str {
+        match self {
+            DataTypeEnum::F32 => "-3.40282e+38",
+            DataTypeEnum::F16 => "-65504.0",
+            DataTypeEnum::U32 => "0",
+        }
+    }
+
+    /// The largest finite value of the type as a WGSL literal
+    pub(crate) fn max_value(&self) -> &'static str {
+        match self {
+            DataTypeEnum::F32 => "3.40282e+38",
+            DataTypeEnum::F16 => "65504.0",
+            DataTypeEnum::U32 => "4294967295",
         }
     }
 }
@@ -159,35 +202,51 @@ pub(crate) struct LazyTensorData {
     info: TensorInfo,
     graph: ComputeGraph,
     key: AnyComputeKey,
+    /// Shared by the clones of this handle so assignment can tell if it is the only one
+    handle: Arc<()>,
 }
 
 impl LazyTensorData {
+    /// Create a handle to a node in the graph. The graph keeps a weak reference to the handle to
+    /// tell which nodes are still used
+    fn from_node(
+        device: Device,
+        info: TensorInfo,
+        graph: ComputeGraph,
+        key: AnyComputeKey,
+    ) -> Self {
+        let handle = Arc::new(());
+        graph.add_handle(key, &handle);
+        Self {
+            device,
+            info,
+            graph,
+            key,
+            handle,
+        }
+    }
+
     pub(crate) fn new(data: TensorData) -> Self {
         let graph = ComputeGraph::new();
         let device = data.device.clone();
         let info = data.info.clone();
         let key = graph.create_tensor(data);
 
-        Self {
+        Self::from_node(
             device,
-            info: TensorInfo::new(info.shape().into(), info.datatype()),
+            TensorInfo::new(info.shape().into(), info.datatype()),
             graph,
-            key: key.into(),
-        }
+            key.into(),
+        )
     }
 
     pub(crate) fn element_wise(&self, function: ElementWiseOperation) -> Self {
         let graph = self.graph.clone();
         let device = self.device.clone();
-        let info = self.info.clone();
+        let info = TensorInfo::new(self.info.shape().into(), function.function.datatype());
         let key = graph.create_element_wise(function);
 
-        Self {
-            device,
-            info,
-            graph,
-            key: key.into(),
-        }
+        Self::from_node(device, info, graph, key.into())
     }
 
     pub(crate) fn pair_wise(&self, function: PairWiseOperation) -> Self {
@@ -196,49 +255,125 @@ impl LazyTensorData {
         let info = self.info.clone();
         let key = graph.create_pair_wise(function);
 
-        Self {
-            device,
-            info,
-            graph,
-            key: key.into(),
+        Self::from_node(device, info, graph, key.into())
+    }
+
+    /// Cast the tensor to a datatype with an element-wise op if it has a different datatype
+    pub(crate) fn cast(&self, datatype: DataTypeEnum) -> Self {
+        if self.info.datatype() == datatype {
+            self.clone()
+        } else {
+            self.element_wise(ElementWiseOperation {
+                value: self.key,
+                function: ElementWiseFunction::cast(datatype),
+            })
+        }
+    }
+
+    /// Apply a pair-wise function after broadcasting both tensors to a common shape. The
+    /// broadcast tensors are stride 0 views, so nothing is copied.
+    pub(crate) fn broadcast_pair_wise(&self, other: &Self, function: PairWiseFunction) -> Self {
+        self.graph.merge(&other.graph);
+        let shape = broadcast_shapes(self.info.shape(), other.info.shape()).unwrap_or_else(|| {
+            panic!(
+                "pair wise operations require tensors with broadcastable shapes, but found {:?} and {:?}",
+                self.info.shape(),
+                other.info.shape()
+            )
+        });
+        let first = self.expand_to(&shape);
+        let second = other.expand_to(&shape);
+        // If both sides come from the same node, read it once with an element-wise op instead
+        if let Some((input, [first_functions, second_functions])) =
+            self.graph.common_element_wise_input(first.key, second.key)
+        {
+            let function = function.to_element_wise(&first_functions, &second_functions);
+            return first.element_wise(ElementWiseOperation {
+                value: input,
+                function,
+            });
+        }
+        first.pair_wise(PairWiseOperation::new(function, first.key, second.key))
+    }
+
+    /// Apply an n-ary function to this tensor followed by the other tensors after broadcasting them
+    /// all to a common shape
+    pub(crate) fn broadcast_nary(&self, others: &[&Self], function: NaryFunction) -> Self {
+        let mut shape: Box<[usize]> = self.info.shape().into();
+        for other in others {
+            self.graph.merge(&other.graph);
+            shape = broadcast_shapes(&shape, other.info.shape()).unwrap_or_else(|| {
+                panic!(
+                    "{} requires tensors with broadcastable shapes, but found {:?} and {:?}",
+                    function.name(),
+                    shape,
+                    other.info.shape()
+                )
+            });
+        }
+        let first = self.expand_to(&shape);
+        let inputs = std::iter::once(first.key)
+            .chain(others.iter().map(|other| other.expand_to(&shape).key))
+            .collect();
+        let graph = first.graph.clone();
+        let device = first.device.clone();
+        let info = TensorInfo::new(shape, function.datatype());
+        let key = graph.create_nary(NaryOperation::new(function, inputs));
+
+        Self::from_node(device, info, graph, key.into())
+    }
+
+    fn expand_to(&self, shape: &[usize]) -> Self {
+        if self.info.shape() == shape {
+            self.clone()
+        } else {
+            self.map_layout(broadcast_to_operation(self.key, self.info.shape(), shape).unwrap())
         }
     }
 
-    pub(crate) fn mat_mul(&self, function: MatMulOperation) -> Self {
+    pub(crate) fn mat_mul(&self, other: &Self) -> Self {
+        self.graph.merge(&other.graph);
         let graph = self.graph.clone();
         let device = self.device.clone();
-        let info = self.info.clone();
-        let key = graph.create_mat_mul(function);
+        let info = TensorInfo::new(
+            MatMulOperation::output_shape(self.info.shape(), other.info.shape()),
+            self.info.datatype(),
+        );
+        let key = graph.create_mat_mul(MatMulOperation::new(self.key, other.key));
 
-        Self {
-            device,
-            info,
-            graph,
-            key: key.into(),
-        }
+        Self::from_node(device, info, graph, key.into())
     }
 
     pub(crate) fn reduce(&self, function: ReduceOperation) -> Self {
         let graph = self.graph.clone();
         let device = self.device.clone();
-        let mut info = self.info.clone();
-        let dim = function.axis;
-        let new_shape: Box<[usize]> = self
-            .info
-            .shape()
-            .iter()
-            .enumerate()
-            .filter_map(|(i, x)| (i != dim).then_some(*x))
-            .collect();
-        info = TensorInfo::new(new_shape.into(), info.datatype());
+        let info = TensorInfo::new(
+            function.output_shape(self.info.shape()),
+            function.function.output_datatype(),
+        );
         let key = graph.create_reduce(function);
 
-        Self {
-            device,
-            info,
-            graph,
-            key: key.into(),
-        }
+        Self::from_node(device, info, graph, key.into())
+    }
+
+    /// Two reductions of this tensor that run in the same kernel
+    pub(crate) fn reduce_pair(&self, functions: [ReduceOperation; 2]) -> [Self; 2] {
+        let infos = functions.each_ref().map(|function| {
+            TensorInfo::new(
+                function.output_shape(self.info.shape()),
+                function.function.output_datatype(),
+            )
+        });
+        let keys = self.graph.create_reduce_pair(functions);
+        let mut infos = infos.into_iter();
+        keys.map(|key| {
+            Self::from_node(
+                self.device.clone(),
+                infos.next().unwrap(),
+                self.graph.clone(),
+                key.into(),
+            )
+        })
     }
 
     pub(crate) fn map_layout(&self, op: MapLayoutOperation) -> Self {
@@ -247,12 +382,7 @@ impl LazyTensorData {
         let graph = self.graph.clone();
         let key = self.graph.create_map_layout(op);
 
-        Self {
-            device,
-            info,
-            graph,
-            key: key.into(),
-        }
+        Self::from_node(device, info, graph, key.into())
     }
 
     pub(crate) fn resize(&self, op: ResizeOperation) -> Self {
@@ -261,12 +391,7 @@ impl LazyTensorData {
         let graph = self.graph.clone();
         let key = self.graph.create_resize(op);
 
-        Self {
-            device,
-            info,
-            graph,
-            key: key.into(),
-        }
+        Self::from_node(device, info, graph, key.into())
     }
 
     pub(crate) fn slice_assign(&self, op: SliceAssignOperation) -> Self {
@@ -275,12 +400,49 @@ impl LazyTensorData {
         let graph = self.graph.clone();
         let key = self.graph.create_slice_assign(op);
 
-        Self {
-            device,
-            info,
-            graph,
-            key: key.into(),
+        Self::from_node(device, info, graph, key.into())
+    }
+
+    pub(crate) fn scan(&self, op: ScanOperation) -> Self {
+        let device = self.device.clone();
+        let info = TensorInfo::new(self.info.shape().into(), op.function.datatype());
+        let graph = self.graph.clone();
+        let key = self.graph.create_scan(op);
+
+        Self::from_node(device, info, graph, key.into())
+    }
+
+    /// Sort along `axis` and keep the first `k` elements. Returns the values and their indices
+    pub(crate) fn sort(&self, axis: usize, descending: bool, k: usize) -> [Self; 2] {
+        let keys = self.graph.create_sort(self.key, axis, descending, k);
+        let mut shape: Box<[usize]> = self.info.shape().into();
+        shape[axis] = k;
+        let datatypes = [self.info.datatype(), DataTypeEnum::U32];
+        std::array::from_fn(|i| {
+            Self::from_node(
+                self.device.clone(),
+                TensorInfo::new(shape.clone(), datatypes[i]),
+                self.graph.clone(),
+                keys[i].into(),
+            )
+        })
+    }
+
+    /// Replace this tensor with the result of an operation on it. If nothing else can read the
+    /// current value, the result is written into its buffer. Otherwise the current value is kept
+    /// and the result gets a new buffer
+    pub(crate) fn assign(&mut self, op: impl FnOnce(&Self) -> Self) {
+        let in_place = Arc::strong_count(&self.handle) == 1 && !self.graph.has_dependents(self.key);
+        let result = op(self);
+        assert_eq!(
+            self.info.shape(),
+            result.info.shape(),
+            "compound assignment cannot change the shape of a tensor"
+        );
+        if in_place {
+            result.graph.write_in_place(result.key, self.key);
         }
+        *self = result;
     }
 
     pub(crate) fn materialize(&self) -> TensorData {
@@ -295,6 +457,16 @@ impl LazyTensorData {
     pub fn graphvis(&self) -> Graph {
         self.graph.graphvis(self.key)
     }
+
+    #[cfg(test)]
+    pub(crate) fn ran_reductions(&self) -> usize {
+        self.graph.ran_reductions()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn cached_results(&self) -> usize {
+        self.graph.cached_results()
+    }
 }
 
 #[derive(Clone)]
@@ -415,6 +587,9 @@ impl TensorData {
     }
 }
 
+/// A tensor with a fixed rank `R`. Pair-wise operations broadcast dimensions of size 1, but both
+/// sides have rank `R`, so new leading dimensions have to be added with [`Tensor::broadcast_to`]
+/// first. [`DynTensor`] adds missing leading dimensions automatically.
 pub struct Tensor<const R: usize, D> {
     data: LazyTensorData,
     datatype: PhantomData<D>,
@@ -530,33 +705,36 @@ impl<D: DataType, const R: usize> Tensor<R, D> {
         }
     }
 
-    async fn as_slice_from_tensor_data(
-        tensor: &TensorData,
-    ) -> Result<TensorSlice<R, D>, wgpu::BufferAsyncError> {
-        let buffer = tensor.buffer();
-        let (sender, receiver) = futures_channel::oneshot::channel();
-        DownloadBuffer::read_buffer(
-            tensor.device.wgpu_device(),
-            tensor.device.wgpu_queue(),
-            &buffer.slice(..),
-            move |result| {
-                _ = sender.send(result);
-            },
-        );
-        let downloaded = receiver.await.map_err(|_| wgpu::BufferAsyncError)??;
+    pub async fn as_slice(&self) -> Result<TensorSlice<R, D>, wgpu::BufferAsyncError> {
+        let tensor = self.data.materialize();
+        let downloaded = download_tensor_data(&tensor).await?;
 
         Ok(TensorSlice::new(downloaded, tensor.layout().clone()))
     }
 
-    pub async fn as_slice(&self) -> Result<TensorSlice<R, D>, wgpu::BufferAsyncError> {
-        let tensor = self.data.materialize();
-        Self::as_slice_from_tensor_data(&tensor).await
+    pub fn into_dyn(self) -> DynTensor<D> {
+        DynTensor {
+            data: self.data,
+            datatype: PhantomData,
+        }
     }
 
     pub async fn all_timing_information(&self) -> Vec<QueryResults> {
         self.data.all_timing_information().await
     }
 
+    /// Replace this tensor with the result of an operation on it, reusing its buffer if
+    /// nothing else reads it
+    pub(crate) fn assign(&mut self, op: impl FnOnce(&Self) -> Self) {
+        self.data.assign(|data| {
+            op(&Self {
+                data: data.clone(),
+                datatype: PhantomData,
+            })
+            .data
+        });
+    }
+
     pub(crate) fn element_wise<D2: DataType>(
         &self,
         function: ElementWiseOperation,
@@ -568,20 +746,55 @@ impl<D: DataType, const R: usize> Tensor<R, D> {
     }
 
     pub(crate) fn pair_wise(&self, other: &Self, function: PairWiseFunction) -> Self {
-        self.data.graph.merge(&other.data.graph);
-        let operation = PairWiseOperation::new(function, self.data.key, other.data.key);
         Self {
-            data: self.data.pair_wise(operation),
+            data: self.data.broadcast_pair_wise(&other.data, function),
             datatype: PhantomData,
         }
     }
 
-    pub(crate) fn add_mat_mul(&self, other: &Self) -> Self {
-        self.data.graph.merge(&other.data.graph);
-        let operation = MatMulOperation::new(self.data.key, other.data.key);
+    /// Apply a pair-wise function after casting both tensors to their promoted datatype
+    pub(crate) fn promoted_pair_wise<D2: DataType>(
+        &self,
+        other: &Tensor<R, D2>,
+        function: PairWiseFunction,
+    ) -> Tensor<R, D::Promoted>
+    where
+        D: Promote<D2>,
+    {
+        let datatype = D::Promoted::WGSL_TYPE;
+        Tensor {
+            data: self
+                .data
+                .cast(datatype)
+                .broadcast_pair_wise(&other.data.cast(datatype), function),
+            datatype: PhantomData,
+        }
+    }
+
+    pub(crate) fn nary(&self, others: &[&Self], function: NaryFunction) -> Self {
+        let others = others.iter().map(|other| &other.data).collect::<Vec<_>>();
+        self.mixed_nary(&others, function)
+    }
+
+    /// Apply an n-ary function to this tensor and tensors of any datatype
+    pub(crate) fn mixed_nary<D2: DataType>(
+        &self,
+        others: &[&LazyTensorData],
+        function: NaryFunction,
+    ) -> Tensor<R, D2> {
+        Tensor {
+            data: self.data.broadcast_nary(others, function),
+            datatype: PhantomData,
+        }
+    }
+
+    pub(crate) fn data(&self) -> &LazyTensorData {
+        &self.data
+    }
 
+    pub(crate) fn add_mat_mul(&self, other: &Self) -> Self {
         Self {
-            data: self.data.mat_mul(operation),
+            data: self.data.mat_mul(&other.data),
             datatype: PhantomData,
         }
     }
@@ -602,19 +815,94 @@ impl<D: DataType, const R: usize> Tensor<R, D> {
         }
     }
 
-    pub(crate) fn reduce<const OUT: usize>(
+    pub(crate) fn reduce<const OUT: usize, D2: DataType>(
         &self,
         function: ReduceFunction,
-        dim: usize,
-    ) -> Tensor<OUT, D> {
+        axes: &[usize],
+        keepdim: bool,
+    ) -> Tensor<OUT, D2> {
+        assert_eq!(function.output_datatype(), D2::WGSL_TYPE);
+        let data = self
+            .data
+            .reduce(ReduceOperation::new(self.data.key, function, axes, keepdim));
+        assert_eq!(
+            data.info.rank(),
+            OUT,
+            "reducing a tensor with rank {R} over {axes:?} gives a tensor with rank {}, not {OUT}",
+            data.info.rank()
+        );
         Tensor {
-            data: self
-                .data
-                .reduce(ReduceOperation::new(self.data.key, function, dim)),
+            data,
             datatype: PhantomData,
         }
     }
 
+    /// Run two reductions over `dim` in one kernel
+    pub(crate) fn reduce_pair<const OUT: usize, D2: DataType>(
+        &self,
+        functions: [ReduceFunction; 2],
+        dim: usize,
+    ) -> (Tensor<OUT, D>, Tensor<OUT, D2>) {
+        assert_eq!(functions[0].output_datatype(), D::WGSL_TYPE);
+        assert_eq!(functions[1].output_datatype(), D2::WGSL_TYPE);
+        let [first, second] = self.data.reduce_pair(
+            functions.map(|function| ReduceOperation::new(self.data.key, function, &[dim], false)),
+        );
+        assert_eq!(first.info.rank(), OUT);
+        (
+            Tensor {
+                data: first,
+                datatype: PhantomData,
+            },
+            Tensor {
+                data: second,
+                datatype: PhantomData,
+            },
+        )
+    }
+
+    pub(crate) fn scan(&self, function: ReduceFunction, dim: usize, exclusive: bool) -> Self {
+        assert!(
+            dim < R,
+            "cannot scan over axis {dim} of a tensor with rank {R}"
+        );
+        let op = ScanOperation::new(self.data.key, function, dim, exclusive);
+        Self {
+            data: self.data.scan(op),
+            datatype: PhantomData,
+        }
+    }
+
+    /// Sort along `dim` and keep the first `k` elements, or all of them if `k` is `None`
+    pub(crate) fn sort_first(
+        &self,
+        dim: usize,
+        descending: bool,
+        k: Option<usize>,
+    ) -> (Self, Tensor<R, u32>) {
+        assert!(
+            dim < R,
+            "cannot sort over axis {dim} of a tensor with rank {R}"
+        );
+        let size = self.shape()[dim];
+        let k = k.unwrap_or(size);
+        assert!(
+            k <= size,
+            "cannot take {k} elements from an axis of length {size}"
+        );
+        let [values, indices] = self.data.sort(dim, descending, k);
+        (
+            Self {
+                data: values,
+                datatype: PhantomData,
+            },
+            Tensor {
+                data: indices,
+                datatype: PhantomData,
+            },
+        )
+    }
+
     pub(crate) fn add_map_layout<const R2: usize>(&self, op: MapLayoutOperation) -> Tensor<R2, D> {
         Tensor {
             data: self.data.map_layout(op),
@@ -626,6 +914,11 @@ impl<D: DataType, const R: usize> Tensor<R, D> {
         self.data.key
     }
 
+    #[cfg(test)]
+    pub(crate) fn materialize(&self) -> TensorData {
+        self.data.materialize()
+    }
+
     pub fn shape(&self) -> &[usize; R] {
         self.data.info.shape().try_into().unwrap()
     }
@@ -643,6 +936,397 @@ impl<D: DataType, const R: usize> Tensor<R, D> {
     }
 }
 
+async fn download_tensor_data(
+    tensor: &TensorData,
+) -> Result<DownloadBuffer, wgpu::BufferAsyncError> {
+    let buffer = tensor.buffer();
+    let (sender, receiver) = futures_channel::oneshot::channel();
+    DownloadBuffer::read_buffer(
+        tensor.device.wgpu_device(),
+        tensor.device.wgpu_queue(),
+        &buffer.slice(..),
+        move |result| {
+            _ = sender.send(result);
+        },
+    );
+    receiver.await.map_err(|_| wgpu::BufferAsyncError)?
+}
+
+/// Implements operations that don't depend on the rank of the tensor for both [`Tensor`] and
+/// [`DynTensor`]. The element type is always named `D` inside the macro.
+macro_rules! tensor_ops {
+    ($($body:tt)*) => {
+        impl<const R: usize, D: $crate::DataType> $crate::Tensor<R, D> {
+            $($body)*
+        }
+
+        impl<D: $crate::DataType> $crate::DynTensor<D> {
+            $($body)*
+        }
+    };
+}
+
+pub(crate) use tensor_ops;
+
+macro_rules! float_tensor_ops {
+    ($($body:tt)*) => {
+        impl<const R: usize, D: $crate::FloatDataType> $crate::Tensor<R, D> {
+            $($body)*
+        }
+
+        impl<D: $crate::FloatDataType> $crate::DynTensor<D> {
+            $($body)*
+        }
+    };
+}
+
+pub(crate) use float_tensor_ops;
+
+/// A tensor with a rank that is only known at runtime. It shares the same lazy compute graph as
+/// [`Tensor`], so converting between the two with [`Tensor::into_dyn`] and
+/// [`DynTensor::into_ranked`] is free.
+pub struct DynTensor<D> {
+    data: LazyTensorData,
+    datatype: PhantomData<D>,
+}
+
+impl<D> Clone for DynTensor<D> {
+    fn clone(&self) -> Self {
+        Self {
+            data: self.data.clone(),
+            datatype: PhantomData,
+        }
+    }
+}
+
+impl<const R: usize, D: DataType> From<Tensor<R, D>> for DynTensor<D> {
+    fn from(value: Tensor<R, D>) -> Self {
+        value.into_dyn()
+    }
+}
+
+impl<const R: usize, D: DataType> TryFrom<DynTensor<D>> for Tensor<R, D> {
+    type Error = RankMismatchError;
+
+    fn try_from(value: DynTensor<D>) -> Result<Self, Self::Error> {
+        value.into_ranked()
+    }
+}
+
+impl<D: DataType> DynTensor<D> {
+    pub fn new(device: &Device, shape: &[usize], data: &[D]) -> Self {
+        assert_eq!(
+            shape.iter().product::<usize>(),
+            data.len(),
+            "the shape {shape:?} does not match the number of elements"
+        );
+        Self {
+            data: LazyTensorData::new(TensorData::new_inner(device, data.iter(), shape)),
+            datatype: PhantomData,
+        }
+    }
+
+    pub fn into_ranked<const R: usize>(self) -> Result<Tensor<R, D>, RankMismatchError> {
+        let found = self.rank();
+        if found != R {
+            return Err(RankMismatchError { expected: R, found });
+        }
+        Ok(Tensor {
+            data: self.data,
+            datatype: PhantomData,
+        })
+    }
+
+    pub(crate) fn reinterpret<D2: DataType>(self) -> DynTensor<D2> {
+        assert_eq!(D::WGSL_TYPE, D2::WGSL_TYPE);
+        DynTensor {
+            data: self.data,
+            datatype: PhantomData,
+        }
+    }
+
+    pub async fn as_slice(&self) -> Result<DynTensorSlice<D>, wgpu::BufferAsyncError> {
+        let tensor = self.data.materialize();
+        let downloaded = download_tensor_data(&tensor).await?;
+
+        Ok(DynTensorSlice::new(downloaded, tensor.layout().clone()))
+    }
+
+    pub async fn all_timing_information(&self) -> Vec<QueryResults> {
+        self.data.all_timing_information().await
+    }
+
+    /// Replace this tensor with the result of an operation on it, reusing its buffer if
+    /// nothing else reads it
+    pub(crate) fn assign(&mut self, op: impl FnOnce(&Self) -> Self) {
+        self.data.assign(|data| {
+            op(&Self {
+                data: data.clone(),
+                datatype: PhantomData,
+            })
+            .data
+        });
+    }
+
+    pub(crate) fn element_wise<D2: DataType>(
+        &self,
+        function: ElementWiseOperation,
+    ) -> DynTensor<D2> {
+        DynTensor {
+            data: self.data.element_wise(function),
+            datatype: PhantomData,
+        }
+    }
+
+    pub(crate) fn pair_wise(&self, other: &Self, function: PairWiseFunction) -> Self {
+        Self {
+            data: self.data.broadcast_pair_wise(&other.data, function),
+            datatype: PhantomData,
+        }
+    }
+
+    /// Apply a pair-wise function after casting both tensors to their promoted datatype
+    pub(crate) fn promoted_pair_wise<D2: DataType>(
+        &self,
+        other: &DynTensor<D2>,
+        function: PairWiseFunction,
+    ) -> DynTensor<D::Promoted>
+    where
+        D: Promote<D2>,
+    {
+        let datatype = D::Promoted::WGSL_TYPE;
+        DynTensor {
+            data: self
+                .data
+                .cast(datatype)
+                .broadcast_pair_wise(&other.data.cast(datatype), function),
+            datatype: PhantomData,
+        }
+    }
+
+    pub(crate) fn nary(&self, others: &[&Self], function: NaryFunction) -> Self {
+        let others = others.iter().map(|other| &other.data).collect::<Vec<_>>();
+        self.mixed_nary(&others, function)
+    }
+
+    /// Apply an n-ary function to this tensor and tensors of any datatype
+    pub(crate) fn mixed_nary<D2: DataType>(
+        &self,
+        others: &[&LazyTensorData],
+        function: NaryFunction,
+    ) -> DynTensor<D2> {
+        DynTensor {
+            data: self.data.broadcast_nary(others, function),
+            datatype: PhantomData,
+        }
+    }
+
+    pub(crate) fn data(&self) -> &LazyTensorData {
+        &self.data
+    }
+
+    pub(crate) fn add_mat_mul(&self, other: &Self) -> Self {
+        Self {
+            data: self.data.mat_mul(&other.data),
+            datatype: PhantomData,
+        }
+    }
+
+    pub(crate) fn add_resize(&self, op: ResizeOperation) -> Self {
+        Self {
+            data: self.data.resize(op),
+            datatype: PhantomData,
+        }
+    }
+
+    pub(crate) fn add_slice_assign(&self, other: &Self, slices: &[Range<usize>]) -> Self {
+        assert_eq!(slices.len(), self.rank());
+        self.data.graph.merge(&other.data.graph);
+        let op = SliceAssignOperation::new(self.data.key, other.data.key, slices.into());
+        Self {
+            data: self.data.slice_assign(op),
+            datatype: PhantomData,
+        }
+    }
+
+    pub(crate) fn reduce<D2: DataType>(
+        &self,
+        function: ReduceFunction,
+        axes: &[usize],
+        keepdim: bool,
+    ) -> DynTensor<D2> {
+        assert_eq!(function.output_datatype(), D2::WGSL_TYPE);
+        DynTensor {
+            data: self
+                .data
+                .reduce(ReduceOperation::new(self.data.key, function, axes, keepdim)),
+            datatype: PhantomData,
+        }
+    }
+
+    /// Run two reductions over `dim` in one kernel
+    pub(crate) fn reduce_pair<D2: DataType>(
+        &self,
+        functions: [ReduceFunction; 2],
+        dim: usize,
+    ) -> (Self, DynTensor<D2>) {
+        assert_eq!(functions[0].output_datatype(), D::WGSL_TYPE);
+        assert_eq!(functions[1].output_datatype(), D2::WGSL_TYPE);
+        let [first, second] = self.data.reduce_pair(
+            functions.map(|function| ReduceOperation::new(self.data.key, function, &[dim], false)),
+        );
+        (
+            Self {
+                data: first,
+                datatype: PhantomData,
+            },
+            DynTensor {
+                data: second,
+                datatype: PhantomData,
+            },
+        )
+    }
+
+    pub(crate) fn scan(&self, function: ReduceFunction, dim: usize, exclusive: bool) -> Self {
+        let rank = self.rank();
+        assert!(
+            dim < rank,
+            "cannot scan over axis {dim} of a tensor with rank {rank}"
+        );
+        let op = ScanOperation::new(self.data.key, function, dim, exclusive);
+        Self {
+            data: self.data.scan(op),
+            datatype: PhantomData,
+        }
+    }
+
+    /// Sort along `dim` and keep the first `k` elements, or all of them if `k` is `None`
+    pub(crate) fn sort_first(
+        &self,
+        dim: usize,
+        descending: bool,
+        k: Option<usize>,
+    ) -> (Self, DynTensor<u32>) {
+        let rank = self.rank();
+        assert!(
+            dim < rank,
+            "cannot sort over axis {dim} of a tensor with rank {rank}"
+        );
+        let size = self.shape()[dim];
+        let k = k.unwrap_or(size);
+        assert!(
+            k <= size,
+            "cannot take {k} elements from an axis of length {size}"
+        );
+        let [values, indices] = self.data.sort(dim, descending, k);
+        (
+            Self {
+                data: values,
+                datatype: PhantomData,
+            },
+            DynTensor {
+                data: indices,
+                datatype: PhantomData,
+            },
+        )
+    }
+
+    pub(crate) fn add_map_layout(&self, op: MapLayoutOperation) -> Self {
+        Self {
+            data: self.data.map_layout(op),
+            datatype: PhantomData,
+        }
+    }
+
+    pub(crate) fn key(&self) -> AnyComputeKey {
+        self.data.key
+    }
+
+    #[cfg(test)]
+    pub(crate) fn materialize(&self) -> TensorData {
+        self.data.materialize()
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        self.data.info.shape()
+    }
+
+    pub fn rank(&self) -> usize {
+        self.data.info.rank()
+    }
+
+    pub fn datatype(&self) -> DataTypeEnum {
+        self.data.info.datatype()
+    }
+
+    pub fn graphvis(&self) -> Graph {
+        self.data.graphvis()
+    }
+}
+
+/// The error returned when a [`DynTensor`] is converted to a [`Tensor`] with a different rank.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RankMismatchError {
+    expected: usize,
+    found: usize,
+}
+
+impl RankMismatchError {
+    pub fn expected(&self) -> usize {
+        self.expected
+    }
+
+    pub fn found(&self) -> usize {
+        self.found
+    }
+}
+
+impl Display for RankMismatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "expected a tensor of rank {}, but found a tensor of rank {}",
+            self.expected, self.found
+        )
+    }
+}
+
+impl std::error::Error for RankMismatchError {}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_dyn_tensor() {
+    let device = Device::new().await.unwrap();
+    std::thread::spawn({
+        let device = device.clone();
+        move || loop {
+            device.wgpu_device().poll(wgpu::PollType::Wait).unwrap();
+        }
+    });
+    let tensor = DynTensor::new(&device, &[3, 2], &[1., 2., 3., 4., 5., 6.]);
+    assert_eq!(tensor.shape(), [3, 2]);
+    assert_eq!(tensor.rank(), 2);
+
+    let as_slice = tensor.as_slice().await.unwrap();
+    assert_eq!(as_slice[[0, 0]], 1.);
+    assert_eq!(as_slice[[0, 1]], 2.);
+    assert_eq!(as_slice[[2, 1]], 6.);
+    assert_eq!(as_slice.get(&[3, 0]), None);
+    assert_eq!(as_slice.get(&[0]), None);
+
+    let error = tensor.clone().into_ranked::<3>().err().unwrap();
+    assert_eq!(error.expected(), 3);
+    assert_eq!(error.found(), 2);
+
+    let ranked: Tensor<2, f32> = tensor.into_ranked().unwrap();
+    let as_slice = ranked.as_slice().await.unwrap();
+    assert_eq!(as_slice[[1, 0]], 3.);
+    assert_eq!(as_slice[[1, 1]], 4.);
+
+    let round_trip = ranked.into_dyn();
+    assert_eq!(round_trip.shape(), [3, 2]);
+}
+
 #[cfg(test)]
 #[tokio::test]
 async fn test_tensor_slice() {
@@ -772,14 +1456,19 @@ impl<'a, const N: usize, D: DataType> PartialEq<TensorSlice<1, D>> for &'a [D; N
     }
 }
 
+/// Tensor buffers are padded to the size of a `vec4<f32>` so contiguous kernels can always read
+/// and write them as `vec4`s, including the last partial vector.
+pub(crate) const TENSOR_BUFFER_ALIGNMENT: u64 = 16;
+
 pub(crate) fn padded_tensor_size(size: u64) -> u64 {
     // Valid vulkan usage is
     // 1. buffer size must be a multiple of COPY_BUFFER_ALIGNMENT.
     // 2. buffer size must be greater than 0.
     // Therefore we round the value up to the nearest multiple, and ensure it's at least COPY_BUFFER_ALIGNMENT.
-    let align_mask = COPY_BUFFER_ALIGNMENT - 1;
+    // We use TENSOR_BUFFER_ALIGNMENT which is a multiple of COPY_BUFFER_ALIGNMENT.
+    let align_mask = TENSOR_BUFFER_ALIGNMENT - 1;
 
-    ((size + align_mask) & !align_mask).max(COPY_BUFFER_ALIGNMENT)
+    ((size + align_mask) & !align_mask).max(TENSOR_BUFFER_ALIGNMENT)
 }
 
 #[cfg(test)]
@@ -831,18 +1520,7 @@ impl<D: DataType, const R: usize> TensorSlice<R, D> {
 
 impl<D: DataType, const R: usize> TensorSlice<R, D> {
     fn get(&self, index: [usize; R]) -> Option<&D> {
-        let mut index_sum = 0;
-        let layout = &self.layout;
-        for ((index_component, &stride), &size) in
-            index.into_iter().zip(layout.strides()).zip(layout.shape())
-        {
-            if index_component >= size {
-                return None;
-            }
-            index_sum += stride * index_component;
-        }
-
-        self.as_slice().get(index_sum)
+        get_strided(self.as_slice(), &self.layout, &index)
     }
 }
 
@@ -854,6 +1532,67 @@ impl<D: DataType, const R: usize> Index<[usize; R]> for TensorSlice<R, D> {
     }
 }
 
+fn get_strided<'a, D>(data: &'a [D], layout: &Layout, index: &[usize]) -> Option<&'a D> {
+    let mut index_sum = 0;
+    for ((index_component, &stride), &size) in
+        index.iter().zip(layout.strides()).zip(layout.shape())
+    {
+        if *index_component >= size {
+            return None;
+        }
+        index_sum += stride * index_component;
+    }
+
+    data.get(index_sum)
+}
+
+pub struct DynTensorSlice<D> {
+    buffer: DownloadBuffer,
+    layout: Layout,
+    datatype: PhantomData<D>,
+}
+
+impl<D: DataType> DynTensorSlice<D> {
+    fn new(buffer: DownloadBuffer, layout: Layout) -> Self {
+        Self {
+            buffer,
+            layout,
+            datatype: PhantomData,
+        }
+    }
+
+    fn as_slice(&self) -> &[D] {
+        bytemuck::cast_slice(&self.buffer.deref()[self.layout.offset() * size_of::<D>()..])
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        self.layout.shape()
+    }
+
+    pub fn get(&self, index: &[usize]) -> Option<&D> {
+        if index.len() != self.layout.shape().len() {
+            return None;
+        }
+        get_strided(self.as_slice(), &self.layout, index)
+    }
+}
+
+impl<D: DataType> Index<&[usize]> for DynTensorSlice<D> {
+    type Output = D;
+
+    fn index(&self, index: &[usize]) -> &Self::Output {
+        self.get(index).unwrap()
+    }
+}
+
+impl<D: DataType, const N: usize> Index<[usize; N]> for DynTensorSlice<D> {
+    type Output = D;
+
+    fn index(&self, index: [usize; N]) -> &Self::Output {
+        self.get(&index).unwrap()
+    }
+}
+
 #[cfg(test)]
 #[tokio::test]
 async fn test_tensor() {
@@ -10,10 +10,19 @@ use crate::{
 pub(crate) struct VisitTiledKernel {
     rank: u32,
     contiguous: bool,
+    vectorized: bool,
     tile_size: u32,
     kernel: GenericKernel,
 }
 
+/// Where a map kernel writes its result
+pub(crate) enum MapOutput {
+    /// Write the result back into the input at this index
+    InPlace(usize),
+    /// Write the result into a new tensor with this datatype
+    New(DataTypeEnum),
+}
+
 impl VisitTiledKernel {
     pub(crate) fn new(
         rank: u32,
@@ -42,17 +51,143 @@ impl VisitTiledKernel {
         Self {
             rank,
             contiguous,
+            vectorized: false,
             kernel,
             tile_size,
         }
     }
 
+    /// Create a kernel that writes `map_value(inputs)` to the output for every element. The
+    /// tensors passed to the kernel are the inputs followed by the output if it is a new tensor.
+    ///
+    /// Contiguous tensors are loaded and stored as `vec4`s. `map_value` is called with
+    /// `vectorized` set to true, so the values and the result are `vec4`s, and only the elements
+    /// of the last partial vector that are in the tensor are written.
+    pub(crate) fn map(
+        rank: u32,
+        tile_size: u32,
+        contiguous: bool,
+        inputs: Vec<DataTypeEnum>,
+        output: MapOutput,
+        mut map_value: impl FnMut(&mut GenericKernel, &[String], bool) -> String,
+    ) -> Self {
+        let input_count = inputs.len();
+        let mut datatypes = inputs;
+        let (output_index, output_datatype) = match output {
+            MapOutput::InPlace(index) => (index, datatypes[index]),
+            MapOutput::New(datatype) => {
+                datatypes.push(datatype);
+                (input_count, datatype)
+            }
+        };
+
+        if !contiguous {
+            return Self::new(
+                rank,
+                tile_size,
+                contiguous,
+                datatypes,
+                |kernel, indexes, tensors| {
+                    let values = (0..input_count)
+                        .map(|i| format!("{}[{}]", tensors[i], indexes[i]))
+                        .collect::<Vec<_>>();
+                    let result = map_value(kernel, &values, false);
+                    let output = &tensors[output_index];
+                    let output_index = &indexes[output_index];
+                    format!("{output}[{output_index}] = {result};")
+                },
+            );
+        }
+
+        let mut kernel = GenericKernel::new();
+        let global_id = kernel.global_id();
+        let tensors = datatypes
+            .iter()
+            .map(|ty| kernel.add_vectorized_tensor_input(rank, true, *ty))
+            .collect::<Vec<_>>();
+        let element_inputs = datatypes[..input_count]
+            .iter()
+            .enumerate()
+            .map(|(i, ty)| (format!("input_{i}"), format!("vec4<{ty}>")))
+            .collect::<Vec<_>>();
+        let element_input_names = element_inputs
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        let result = map_value(&mut kernel, &element_input_names, true);
+        let element = kernel.add_function(
+            format!("vec4<{output_datatype}>"),
+            format!("let output = {result};"),
+            element_inputs,
+        );
+
+        let output = &tensors[output_index];
+        let mut kernel_body = String::new();
+        writeln!(
+            &mut kernel_body,
+            "let element_count = {};",
+            tensors[0].element_count()
+        )
+        .unwrap();
+        for local_index in 0..tile_size {
+            let index = format!("index_{local_index}");
+            writeln!(
+                &mut kernel_body,
+                "let {index} = {global_id}.x * {tile_size} + {local_index};"
+            )
+            .unwrap();
+            let load_inputs = |kernel_body: &mut String| {
+                for (i, tensor) in tensors[..input_count].iter().enumerate() {
+                    writeln!(kernel_body, "let value_{i} = {tensor}[{index}];").unwrap();
+                }
+            };
+
+            let result = element.call((0..input_count).map(|i| format!("value_{i}")).collect());
+
+            writeln!(&mut kernel_body, "if {index} * 4 + 4 <= element_count {{").unwrap();
+            load_inputs(&mut kernel_body);
+            writeln!(&mut kernel_body, "{output}[{index}] = {result};").unwrap();
+
+            // The last partial vector. Its padding is computed but never written
+            writeln!(
+                &mut kernel_body,
+                "}} else if {index} * 4 < element_count {{"
+            )
+            .unwrap();
+            load_inputs(&mut kernel_body);
+            writeln!(&mut kernel_body, "let result = {result};").unwrap();
+            writeln!(
+                &mut kernel_body,
+                "for (var lane = 0u; lane < element_count - {index} * 4; lane++) {{"
+            )
+            .unwrap();
+            writeln!(&mut kernel_body, "{output}[{index}][lane] = result[lane];").unwrap();
+            writeln!(&mut kernel_body, "}}").unwrap();
+            writeln!(&mut kernel_body, "}}").unwrap();
+        }
+        kernel.set_body(kernel_body);
+        kernel.set_workgroup_size([Self::blocksize_raw(contiguous, rank), 1, 1]);
+
+        Self {
+            rank,
+            contiguous,
+            vectorized: true,
+            kernel,
+            tile_size,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn kernel(&self) -> &GenericKernel {
+        &self.kernel
+    }
+
     fn blocksize_raw(contiguous: bool, rank: u32) -> u32 {
         if contiguous {
             256
         } else {
-            // max_blocksize^R = 256
-            (256f64.powf(1. / rank as f64)).floor() as u32
+            // max_blocksize^R = 256 with at most 3 dispatch dimensions
+            (256f64.powf(1. / rank.min(3) as f64)).floor() as u32
         }
     }
 
@@ -68,8 +203,6 @@ impl VisitTiledKernel {
         kernel: &mut GenericKernel,
         mut modify_data: impl FnMut(&mut GenericKernel, &[String], &[TensorInput]) -> String,
     ) -> String {
-        assert!(rank <= 3, "TensorLayout only supports up to 3 rank tensors");
-
         let mut kernel_body = String::new();
         let global_id = kernel.global_id();
         let tensors = datatypes
@@ -98,7 +231,10 @@ impl VisitTiledKernel {
                 );
             }
         } else {
-            for i in 0..rank as usize {
+            // Tensors with more than 3 dimensions dispatch the leading dimensions flattened on x
+            let dispatch_rank = rank.min(3);
+            let flattened = rank - dispatch_rank;
+            for i in 0..dispatch_rank as usize {
                 let index = ["x", "y", "z"][i];
                 writeln!(
                     &mut kernel_body,
@@ -108,17 +244,36 @@ impl VisitTiledKernel {
             }
             writeln!(&mut kernel_body, "\n").unwrap();
 
-            for i in 0..rank {
+            for i in 0..dispatch_rank {
                 writeln!(&mut kernel_body, "for (var local_index_{i} = 0u; local_index_{i} < {tile_size}; local_index_{i}++) {{").unwrap();
             }
 
-            for i in 0..rank {
+            for i in 0..dispatch_rank {
+                let merged_index = if i == 0 && flattened > 0 {
+                    "flattened_index".to_string()
+                } else {
+                    format!("merged_index_{}", i + flattened)
+                };
                 writeln!(
                     &mut kernel_body,
-                    "let merged_index_{i} = tile_index_{i} + local_index_{i};"
+                    "let {merged_index} = tile_index_{i} + local_index_{i};"
                 )
                 .unwrap();
             }
+            if flattened > 0 {
+                // The first dimension isn't wrapped so out of bounds indexes fail the bounds check
+                writeln!(&mut kernel_body, "var remaining = flattened_index;").unwrap();
+                for i in (1..=flattened).rev() {
+                    let shape = tensors[0].shape_binding(i);
+                    writeln!(
+                        &mut kernel_body,
+                        "let merged_index_{i} = remaining % {shape};"
+                    )
+                    .unwrap();
+                    writeln!(&mut kernel_body, "remaining /= {shape};").unwrap();
+                }
+                writeln!(&mut kernel_body, "let merged_index_0 = remaining;").unwrap();
+            }
 
             tensors[0].check_bounds(
                 &mut kernel_body,
@@ -138,7 +293,7 @@ impl VisitTiledKernel {
                 },
             );
 
-            for _ in 0..rank {
+            for _ in 0..dispatch_rank {
                 writeln!(&mut kernel_body, "}}").unwrap();
             }
         }
@@ -157,16 +312,21 @@ impl VisitTiledKernel {
         let shape = layout.shape();
         let max_blocksize = self.blocksize();
         let workgroup_dispatch_size = if self.contiguous {
-            [
-                shape
-                    .iter()
-                    .map(|x| *x as u32)
-                    .product::<u32>()
-                    .div_ceil(self.tile_size * max_blocksize),
-                1,
-                1,
-            ]
+            let element_count = shape.iter().map(|x| *x as u32).product::<u32>();
+            let items = if self.vectorized {
+                element_count.div_ceil(4)
+            } else {
+                element_count
+            };
+            [items.div_ceil(self.tile_size * max_blocksize), 1, 1]
         } else {
+            // The leading dimensions of tensors with more than 3 dimensions are flattened on x
+            let shape = match shape.len().checked_sub(3) {
+                Some(flattened) => std::iter::once(shape[..=flattened].iter().product::<usize>())
+                    .chain(shape[flattened + 1..].iter().copied())
+                    .collect::<Vec<_>>(),
+                None => shape.to_vec(),
+            };
             let workgroup_size_x = shape
                 .get(0)
                 .map(|x| (*x as u32).div_ceil(self.tile_size * max_blocksize))
@@ -192,3 +352,42 @@ impl VisitTiledKernel {
         );
     }
 }
+
+#[test]
+fn test_vectorized_map_kernel() {
+    for (datatype, output, output_datatype) in [
+        (DataTypeEnum::F32, MapOutput::InPlace(1), DataTypeEnum::F32),
+        (
+            DataTypeEnum::F16,
+            MapOutput::New(DataTypeEnum::F32),
+            DataTypeEnum::F32,
+        ),
+    ] {
+        let kernel = VisitTiledKernel::map(
+            2,
+            4,
+            true,
+            vec![datatype, datatype],
+            output,
+            |_, values, _| format!("vec4<{output_datatype}>({} * {})", values[0], values[1]),
+        );
+        assert!(kernel.vectorized);
+        kernel.kernel.validate().unwrap();
+    }
+}
+
+#[test]
+fn test_high_rank_map_kernel() {
+    for rank in [4, 6] {
+        let kernel = VisitTiledKernel::map(
+            rank,
+            2,
+            false,
+            vec![DataTypeEnum::F32, DataTypeEnum::F32],
+            MapOutput::New(DataTypeEnum::F32),
+            |_, values, _| format!("{} + {}", values[0], values[1]),
+        );
+        assert!(!kernel.vectorized);
+        kernel.kernel.validate().unwrap();
+    }
+}